@@ -16,11 +16,13 @@ router.post("/create", async (c) => {
     const result = await contractCall({
       methodName: "create_subscription",
       args: {
-        merchant_id: merchantId,
-        amount,
-        frequency,
-        max_payments: maxPayments,
-        token_address: tokenAddress || null,
+        args: {
+          merchant_id: merchantId,
+          amount,
+          frequency,
+          max_payments: maxPayments,
+          token_address: tokenAddress || null,
+        },
       },
     });
 
@@ -39,7 +41,7 @@ router.post("/create", async (c) => {
  */
 router.post("/register-key", async (c) => {
   try {
-    const { subscriptionId, publicKey } = await c.req.json();
+    const { subscriptionId, publicKey, label } = await c.req.json();
 
     if (!subscriptionId || !publicKey) {
       return c.json({ error: "Missing required parameters" }, 400);
@@ -50,6 +52,7 @@ router.post("/register-key", async (c) => {
       args: {
         subscription_id: subscriptionId,
         public_key: publicKey,
+        label: label || null,
       },
     });
 
@@ -87,12 +90,16 @@ router.get("/:subscriptionId", async (c) => {
 router.get("/list/:accountId", async (c) => {
   try {
     const accountId = c.req.param("accountId");
-    const subscriptions = await contractView({
+    const page = await contractView({
       methodName: "get_user_subscriptions",
-      args: { account_id: accountId },
+      args: { user_id: accountId, from: c.req.query("cursor") ?? null },
     });
 
-    return c.json({ subscriptions });
+    return c.json({
+      subscriptions: page.subscriptions,
+      hasMore: page.has_more,
+      nextCursor: page.next_cursor,
+    });
   } catch (error) {
     console.error("Error listing subscriptions:", error);
     return c.json({ error: (error as Error).message }, 500);
@@ -0,0 +1,126 @@
+use near_sdk::{env, json_types::U128, log, near, require, AccountId};
+
+use crate::events::Event;
+use crate::models::{
+    ApprovalPolicy, PaymentMethod, Subscription, SubscriptionId, SubscriptionStatus,
+};
+use crate::{Contract, ContractExt};
+
+#[near]
+impl Contract {
+    /// Requires a co-signer to approve the caller's new subscriptions in a token whose
+    /// max_amount_per_charge exceeds `threshold`. `None` removes the requirement
+    pub fn set_approval_policy(
+        &mut self,
+        payment_method: PaymentMethod,
+        policy: Option<ApprovalPolicy>,
+    ) {
+        let user_id = env::predecessor_account_id();
+        let key = (user_id.clone(), payment_method);
+
+        match policy {
+            Some(policy) => {
+                require!(
+                    policy.approver_id != user_id,
+                    "Approver must be another account"
+                );
+                log!(
+                    "Approval by {} required above {}",
+                    policy.approver_id,
+                    policy.threshold.0
+                );
+                self.approval_policies.insert(key, policy);
+            }
+            None => {
+                self.approval_policies.remove(&key);
+                log!("Approval policy removed");
+            }
+        }
+    }
+
+    /// Gets a user's approval policy for a token
+    pub fn get_approval_policy(
+        &self,
+        user_id: AccountId,
+        payment_method: PaymentMethod,
+    ) -> Option<ApprovalPolicy> {
+        self.approval_policies
+            .get(&(user_id, payment_method))
+            .cloned()
+    }
+
+    /// Activates a subscription awaiting approval. Callable by the co-signer named on the
+    /// subscription; the first payment is scheduled as if it had been created now
+    pub fn approve_subscription(&mut self, subscription_id: SubscriptionId) {
+        let now = env::block_timestamp() / 1000000000;
+        let subscription = self.pending_approval_mut(&subscription_id);
+        let approver_id = subscription.approver_id.clone().expect("No approver");
+
+        let scheduled_date = subscription.next_payment_date;
+        subscription.next_payment_date = now + (scheduled_date - subscription.created_at);
+        let next_payment_date = subscription.next_payment_date;
+        self.index_due_date(&subscription_id, next_payment_date);
+        self.transition_subscription(&subscription_id, SubscriptionStatus::Active, now);
+
+        Event::SubscriptionApproved {
+            subscription_id: subscription_id.clone(),
+            approver_id,
+            approved: true,
+        }
+        .emit();
+        log!("Subscription approved: {}", subscription_id);
+    }
+
+    /// Rejects a subscription awaiting approval, canceling it. Callable by the co-signer
+    pub fn reject_subscription(&mut self, subscription_id: SubscriptionId) {
+        let now = env::block_timestamp() / 1000000000;
+        let subscription = self.pending_approval_mut(&subscription_id);
+        let approver_id = subscription.approver_id.clone().expect("No approver");
+
+        let user_id = subscription.user_id.clone();
+        self.transition_subscription(&subscription_id, SubscriptionStatus::Canceled, now);
+        self.revoke_subscription_keys(&subscription_id, &user_id);
+
+        Event::SubscriptionApproved {
+            subscription_id: subscription_id.clone(),
+            approver_id,
+            approved: false,
+        }
+        .emit();
+        log!("Subscription rejected: {}", subscription_id);
+    }
+}
+
+impl Contract {
+    /// The co-signer a new subscription needs, if its per-charge cap exceeds the user's
+    /// approval threshold for the token
+    pub(crate) fn required_approver(
+        &self,
+        user_id: &AccountId,
+        payment_method: &PaymentMethod,
+        max_amount_per_charge: U128,
+    ) -> Option<AccountId> {
+        self.approval_policies
+            .get(&(user_id.clone(), payment_method.clone()))
+            .filter(|policy| max_amount_per_charge.0 > policy.threshold.0)
+            .map(|policy| policy.approver_id.clone())
+    }
+
+    /// Gets a subscription awaiting approval, checking the caller is its co-signer
+    fn pending_approval_mut(&mut self, subscription_id: &SubscriptionId) -> &mut Subscription {
+        let caller_id = env::predecessor_account_id();
+        let subscription = self
+            .subscriptions
+            .get_mut(subscription_id)
+            .expect("Subscription not found");
+        require!(
+            matches!(subscription.status, SubscriptionStatus::PendingApproval),
+            "Subscription is not awaiting approval"
+        );
+        require!(
+            subscription.approver_id.as_ref() == Some(&caller_id),
+            "Not authorized to approve this subscription"
+        );
+        subscription
+    }
+}
@@ -0,0 +1,114 @@
+use near_sdk::{env, log, near, require, Promise};
+
+use crate::events::Event;
+use crate::models::{ArchivedSubscription, SubscriptionId, SubscriptionStatus};
+use crate::{Contract, ContractExt};
+
+#[near]
+impl Contract {
+    /// Sets how long terminated subscriptions are kept before they can be archived
+    pub fn set_archive_retention_period(&mut self, seconds: u64) {
+        self.require_owner();
+        self.archive_retention_period = seconds;
+        log!("Archive retention period set to {} seconds", seconds);
+    }
+
+    /// Moves a canceled or failed subscription out of the live state once the retention
+    /// period has passed, keeping a compact record. Callable by anyone; the freed storage
+    /// is returned to the storage deposit of whoever paid for the subscription, and the rest
+    /// refunded to the owner
+    pub fn archive_subscription(&mut self, subscription_id: SubscriptionId) -> ArchivedSubscription {
+        let now = env::block_timestamp() / 1000000000;
+        let subscription = self
+            .subscriptions
+            .get(&subscription_id)
+            .expect("Subscription not found")
+            .clone();
+
+        require!(
+            matches!(
+                subscription.status,
+                SubscriptionStatus::Canceled | SubscriptionStatus::Failed
+            ),
+            "Only canceled or failed subscriptions can be archived"
+        );
+        require!(
+            subscription.held_payment.is_none(),
+            "Subscription still has a held payment"
+        );
+        require!(
+            now >= subscription.updated_at + self.archive_retention_period,
+            "Retention period has not ended"
+        );
+
+        // Keys go first so their storage is returned to the subscriber who registered them
+        self.revoke_subscription_keys(&subscription_id, &subscription.user_id);
+        let storage_before = env::storage_usage();
+
+        let archived = ArchivedSubscription {
+            id: subscription_id.clone(),
+            user_id: subscription.user_id.clone(),
+            merchant_id: subscription.merchant_id.clone(),
+            status: subscription.status.clone(),
+            payment_method: subscription.payment_method.clone(),
+            payments_made: subscription.payments_made,
+            total_spent: subscription.total_spent,
+            created_at: subscription.created_at,
+            terminated_at: subscription.updated_at,
+            archived_at: now,
+        };
+
+        self.unindex_due_date(&subscription_id);
+        self.uncount_subscription(&subscription.merchant_id, &subscription.status);
+        self.subscriptions.remove(&subscription_id);
+        self.unindex_user_subscription(&subscription.user_id, &subscription_id);
+        self.remove_payment_history(&subscription_id);
+        self.leases.remove(&subscription_id);
+        let payer = self.subscription_storage.remove(&subscription_id);
+        if let Some(sequence_number) = Self::sequence_number(&subscription_id) {
+            self.subscription_sequence.remove(&sequence_number);
+        }
+        self.archived_subscriptions
+            .insert(subscription_id.clone(), archived.clone());
+        self.flush_subscription_storage();
+        self.payment_history.flush();
+        self.payment_history_heads.flush();
+        self.leases.flush();
+        self.subscriptions_by_user.flush();
+        self.archived_subscriptions.flush();
+
+        // Whoever paid for the subscription's storage gets back what they paid for; the owner,
+        // who paid for the rest, gets the remainder
+        let storage_freed = storage_before.saturating_sub(env::storage_usage());
+        let released = payer.map_or(0, |payer| {
+            self.release_storage(&payer.account_id, storage_freed.min(payer.bytes))
+        });
+        let owner_refund = storage_freed - released;
+        if owner_refund > 0 {
+            let refund = env::storage_byte_cost().saturating_mul(owner_refund as u128);
+            Promise::new(self.owner_id.clone()).transfer(refund);
+        }
+
+        Event::SubscriptionArchived {
+            subscription_id: subscription_id.clone(),
+            user_id: archived.user_id.clone(),
+            merchant_id: archived.merchant_id.clone(),
+            payments_made: archived.payments_made,
+            total_spent: archived.total_spent,
+            storage_freed,
+        }
+        .emit();
+
+        log!("Subscription archived: {}", subscription_id);
+
+        archived
+    }
+
+    /// Gets the archived record of a subscription
+    pub fn get_archived_subscription(
+        &self,
+        subscription_id: SubscriptionId,
+    ) -> Option<ArchivedSubscription> {
+        self.archived_subscriptions.get(&subscription_id).cloned()
+    }
+}
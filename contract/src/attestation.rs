@@ -0,0 +1,289 @@
+use dcap_qvl::quote::Report;
+use near_sdk::{bs58, env, log, near, require, AccountId};
+
+use crate::models::{AttestationNonce, PinnedCollateral, TeeType, Worker};
+use crate::{collateral, Contract, ContractExt};
+
+// How long an attestation nonce can be used for (10 minutes in seconds)
+const ATTESTATION_NONCE_TTL: u64 = 600;
+
+// TEE type field of a quote header
+const SGX_TEE_TYPE: u32 = 0x00;
+const TDX_TEE_TYPE: u32 = 0x81;
+
+#[near]
+impl Contract {
+    /// Issues a single-use nonce for the caller's next `register_worker`. The quote must carry
+    /// it, hex-decoded, in the last 32 bytes of its report_data, so only a quote generated after
+    /// the nonce was issued is accepted. A new request replaces any unused nonce
+    pub fn request_attestation_nonce(&mut self) -> AttestationNonce {
+        let now = env::block_timestamp() / 1000000000;
+        let account_id = env::predecessor_account_id();
+
+        let mut seed = env::random_seed();
+        seed.extend_from_slice(account_id.as_str().as_bytes());
+        let nonce = AttestationNonce {
+            nonce: hex::encode(env::sha256(&seed)),
+            expires_at: now + ATTESTATION_NONCE_TTL,
+        };
+        self.attestation_nonces
+            .insert(account_id.clone(), nonce.clone());
+
+        log!("Attestation nonce issued to {}", account_id);
+        nonce
+    }
+
+    /// Pins the collateral quotes are verified against under `id`, until `expires_at`
+    /// (seconds), normally the TCB info's nextUpdate. Replaces any collateral pinned under `id`
+    pub fn set_collateral(&mut self, id: String, collateral_json: String, expires_at: u64) {
+        self.require_owner();
+        let now = env::block_timestamp() / 1000000000;
+        require!(expires_at > now, "Collateral must expire in the future");
+        // Fails on malformed collateral now rather than at registration
+        collateral::get_collateral(collateral_json.clone());
+
+        self.collaterals.insert(
+            id.clone(),
+            PinnedCollateral {
+                collateral: collateral_json,
+                expires_at,
+            },
+        );
+        log!("Collateral {} pinned until {}", id, expires_at);
+    }
+
+    /// Unpins collateral so no more quotes are verified against it
+    pub fn remove_collateral(&mut self, id: String) {
+        self.require_owner();
+        self.collaterals.remove(&id);
+        log!("Collateral {} removed", id);
+    }
+
+    /// Gets collateral pinned under `id`
+    pub fn get_pinned_collateral(&self, id: String) -> Option<PinnedCollateral> {
+        self.collaterals.get(&id).cloned()
+    }
+
+    /// Sets how long a worker's attestation stays valid before it has to call
+    /// `renew_attestation`. `None` lets attestations last indefinitely
+    pub fn set_max_attestation_age(&mut self, seconds: Option<u64>) {
+        self.require_owner();
+        self.max_attestation_age = seconds;
+        log!("Max attestation age set to {:?} seconds", seconds);
+    }
+
+    /// Gets how long a worker's attestation stays valid, if it expires
+    pub fn get_max_attestation_age(&self) -> Option<u64> {
+        self.max_attestation_age
+    }
+
+    /// Re-attests a registered worker with a fresh quote for its codehash, restarting its
+    /// attestation age. The quote must be bound to the worker and carry a nonce from
+    /// `request_attestation_nonce`, as for `register_worker`
+    pub fn renew_attestation(&mut self, quote_hex: String, collateral_id: String) -> bool {
+        let now = env::block_timestamp() / 1000000000;
+        let account_id = env::predecessor_account_id();
+        let mut worker = self.get_worker(account_id.clone());
+
+        let Some(tee_type) =
+            self.verify_worker_quote(quote_hex, &collateral_id, &worker.codehash, now)
+        else {
+            log!("Attestation renewal failed");
+            return false;
+        };
+        worker.tee_type = Some(tee_type);
+        worker.verified_at = now;
+        self.worker_by_account_id
+            .insert(account_id.clone(), worker.into());
+        log!("Attestation renewed for worker {}", account_id);
+        true
+    }
+}
+
+impl Contract {
+    /// Verifies an SGX or TDX quote from the calling worker against unexpired pinned collateral,
+    /// requiring it to be bound to the caller and `codehash` and to carry the caller's nonce.
+    /// Returns the TEE the quote came from, or `None` if it does not verify
+    pub(crate) fn verify_worker_quote(
+        &mut self,
+        quote_hex: String,
+        collateral_id: &String,
+        codehash: &str,
+        now: u64,
+    ) -> Option<TeeType> {
+        let pinned = self
+            .collaterals
+            .get(collateral_id)
+            .expect("Collateral not found");
+        require!(now < pinned.expires_at, "Pinned collateral has expired");
+        let collateral = collateral::get_collateral(pinned.collateral.clone());
+        let quote = hex::decode(quote_hex).unwrap();
+        let tee_type = Self::quote_tee_type(&quote);
+        let tcb_info_id = match tee_type {
+            TeeType::Sgx => "SGX",
+            TeeType::Tdx => "TDX",
+        };
+        require!(
+            collateral::tcb_info_id(&collateral) == tcb_info_id,
+            "Collateral is not for this quote's TEE type"
+        );
+        let verified = dcap_qvl::verify::verify(&quote, &collateral, now).ok()?;
+        let report_matches = match tee_type {
+            TeeType::Sgx => verified.report.as_sgx().is_some(),
+            TeeType::Tdx => verified.report.as_td10().is_some(),
+        };
+        require!(report_matches, "Quote report does not match its TEE type");
+
+        let account_id = env::predecessor_account_id();
+        let public_key = bs58::encode(env::signer_account_pk().as_bytes()).into_string();
+        Self::require_report_binding(&verified.report, &account_id, &public_key, codehash);
+        self.require_report_nonce(&verified.report, &account_id, now);
+        Some(tee_type)
+    }
+
+    /// Reads which TEE produced a quote from its header, requiring a version this contract
+    /// verifies: 3 or 4 for SGX, 4 or 5 for TDX
+    fn quote_tee_type(quote: &[u8]) -> TeeType {
+        require!(quote.len() >= 8, "Quote is too short");
+        let version = u16::from_le_bytes([quote[0], quote[1]]);
+        let tee_type = u32::from_le_bytes([quote[4], quote[5], quote[6], quote[7]]);
+        match (tee_type, version) {
+            (SGX_TEE_TYPE, 3 | 4) => TeeType::Sgx,
+            (TDX_TEE_TYPE, 4 | 5) => TeeType::Tdx,
+            _ => env::panic_str("Unsupported quote type or version"),
+        }
+    }
+
+    /// Requires a worker's attestation to be younger than the maximum attestation age
+    pub(crate) fn require_fresh_attestation(&self, worker: &Worker) {
+        let now = env::block_timestamp() / 1000000000;
+        require!(
+            self.max_attestation_age
+                .is_none_or(|max_age| now < worker.verified_at.saturating_add(max_age)),
+            "Worker attestation expired; call renew_attestation"
+        );
+    }
+
+    /// Requires a verified quote's report_data to end with the unexpired nonce issued to
+    /// `account_id`, using the nonce up
+    pub(crate) fn require_report_nonce(
+        &mut self,
+        report: &Report,
+        account_id: &AccountId,
+        now: u64,
+    ) {
+        let nonce = self
+            .attestation_nonces
+            .remove(account_id)
+            .expect("No attestation nonce requested");
+        require!(now < nonce.expires_at, "Attestation nonce expired");
+        let nonce = hex::decode(&nonce.nonce).expect("Invalid attestation nonce");
+        require!(
+            Self::report_data(report)[32..] == nonce[..],
+            "Quote report_data does not carry the attestation nonce"
+        );
+    }
+
+    /// Requires a verified quote's report_data to commit to the worker registering with it,
+    /// so a quote cannot be replayed by another account, key or codehash. The first 32 bytes
+    /// must be `sha256("{account_id}\n{public_key}\n{codehash}")`, with the public key in the
+    /// base58 form subscription keys use
+    pub(crate) fn require_report_binding(
+        report: &Report,
+        account_id: &AccountId,
+        public_key: &str,
+        codehash: &str,
+    ) {
+        let commitment =
+            env::sha256(format!("{}\n{}\n{}", account_id, public_key, codehash).as_bytes());
+        require!(
+            Self::report_data(report)[..32] == commitment[..],
+            "Quote report_data does not commit to this worker"
+        );
+    }
+
+    /// The 64 bytes of user data an enclave or TD embedded in its quote
+    pub(crate) fn report_data(report: &Report) -> [u8; 64] {
+        match report {
+            Report::SgxEnclave(report) => report.report_data,
+            Report::TD10(report) => report.report_data,
+            Report::TD15(report) => report.base.report_data,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dcap_qvl::quote::{EnclaveReport, Report};
+    use near_sdk::test_utils::accounts;
+    use near_sdk::{env, AccountId};
+
+    use crate::Contract;
+
+    const PUBLIC_KEY: &str = "1BfDAYhYHPHyRPdVBrujnXojBEagMNEJtxwqEjZmwNRz";
+    const CODEHASH: &str = "bar";
+
+    /// An SGX report whose report_data commits to a worker, followed by a zeroed nonce
+    fn bound_report(account_id: &AccountId, public_key: &str, codehash: &str) -> Report {
+        let mut report_data = [0; 64];
+        report_data[..32].copy_from_slice(&env::sha256(
+            format!("{}\n{}\n{}", account_id, public_key, codehash).as_bytes(),
+        ));
+        Report::SgxEnclave(EnclaveReport {
+            cpu_svn: [0; 16],
+            misc_select: 0,
+            reserved1: [0; 28],
+            attributes: [0; 16],
+            mr_enclave: [0; 32],
+            reserved2: [0; 32],
+            mr_signer: [0; 32],
+            reserved3: [0; 96],
+            isv_prod_id: 0,
+            isv_svn: 0,
+            reserved4: [0; 60],
+            report_data,
+        })
+    }
+
+    #[test]
+    fn accepts_report_bound_to_worker() {
+        let report = bound_report(&accounts(0), PUBLIC_KEY, CODEHASH);
+        Contract::require_report_binding(&report, &accounts(0), PUBLIC_KEY, CODEHASH);
+    }
+
+    #[test]
+    #[should_panic(expected = "Quote report_data does not commit to this worker")]
+    fn rejects_report_bound_to_another_account() {
+        let report = bound_report(&accounts(0), PUBLIC_KEY, CODEHASH);
+        Contract::require_report_binding(&report, &accounts(1), PUBLIC_KEY, CODEHASH);
+    }
+
+    #[test]
+    #[should_panic(expected = "Quote report_data does not commit to this worker")]
+    fn rejects_report_bound_to_another_key() {
+        let report = bound_report(&accounts(0), PUBLIC_KEY, CODEHASH);
+        Contract::require_report_binding(
+            &report,
+            &accounts(0),
+            "6E8sCci9badyRkXb3JoRpBj5p8C6Tw41ELDZoiihKEtp",
+            CODEHASH,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Quote report_data does not commit to this worker")]
+    fn rejects_report_bound_to_another_codehash() {
+        let report = bound_report(&accounts(0), PUBLIC_KEY, CODEHASH);
+        Contract::require_report_binding(&report, &accounts(0), PUBLIC_KEY, "bar2");
+    }
+
+    #[test]
+    #[should_panic(expected = "Quote report_data does not commit to this worker")]
+    fn rejects_unbound_report() {
+        let mut report = bound_report(&accounts(0), PUBLIC_KEY, CODEHASH);
+        if let Report::SgxEnclave(enclave) = &mut report {
+            enclave.report_data = [0; 64];
+        }
+        Contract::require_report_binding(&report, &accounts(0), PUBLIC_KEY, CODEHASH);
+    }
+}
@@ -0,0 +1,147 @@
+use near_sdk::{env, json_types::U128, log, near, require, AccountId, NearToken, Promise};
+
+use crate::events::Event;
+use crate::models::{BondPolicy, PaymentMethod, WorkerBond};
+use crate::{Contract, ContractExt};
+
+#[near]
+impl Contract {
+    /// Sets the bond workers must attach to `register_worker`, how long a deregistered worker
+    /// waits to withdraw it, and what is slashed for repeatedly charging subscriptions that aren't
+    /// due. Registered workers holding less than a raised bond must top up before processing again
+    pub fn set_bond_policy(&mut self, bond_policy: BondPolicy) {
+        self.require_owner();
+        self.bond_policy = bond_policy;
+        log!("Bond policy updated");
+    }
+
+    /// Gets the bond workers must hold and its penalties
+    pub fn get_bond_policy(&self) -> BondPolicy {
+        self.bond_policy.clone()
+    }
+
+    /// Gets a worker's bond, if it has one
+    pub fn get_worker_bond(&self, account_id: AccountId) -> Option<WorkerBond> {
+        self.worker_bonds.get(&account_id).cloned()
+    }
+
+    /// Adds the attached NEAR to the calling worker's bond, such as after a slash
+    #[payable]
+    pub fn top_up_worker_bond(&mut self) -> WorkerBond {
+        let worker_id = env::predecessor_account_id();
+        require!(
+            self.worker_by_account_id.contains_key(&worker_id),
+            "Worker not found"
+        );
+        self.add_worker_bond(&worker_id, env::attached_deposit().as_yoctonear())
+    }
+
+    /// Slashes up to `amount` of a worker's bond, all of it by default, into the treasury.
+    /// Bonds can be slashed until they are withdrawn, including during the cooldown
+    pub fn slash_worker(
+        &mut self,
+        account_id: AccountId,
+        amount: Option<U128>,
+        reason: String,
+    ) -> U128 {
+        self.require_owner();
+        let bond = self
+            .worker_bonds
+            .get(&account_id)
+            .expect("Worker has no bond");
+        let amount = amount.map_or(bond.amount.0, |amount| amount.0);
+        U128(self.slash_bond(&account_id, amount, reason))
+    }
+
+    /// Withdraws the calling worker's bond once it has deregistered and the cooldown has passed
+    pub fn withdraw_worker_bond(&mut self) -> Promise {
+        let now = env::block_timestamp() / 1000000000;
+        let worker_id = env::predecessor_account_id();
+        let bond = self
+            .worker_bonds
+            .get(&worker_id)
+            .cloned()
+            .expect("Worker has no bond");
+        let unlocks_at = bond
+            .unlocks_at
+            .expect("Deregister the worker before withdrawing its bond");
+        require!(
+            now >= unlocks_at,
+            format!("Bond is locked until {}", unlocks_at)
+        );
+
+        self.worker_bonds.remove(&worker_id);
+        log!("Withdrawing bond of {} for {}", bond.amount.0, worker_id);
+        Promise::new(worker_id).transfer(NearToken::from_yoctonear(bond.amount.0))
+    }
+}
+
+impl Contract {
+    /// Adds to a worker's bond, cancelling any cooldown since the worker is registered again
+    pub(crate) fn add_worker_bond(&mut self, worker_id: &AccountId, amount: u128) -> WorkerBond {
+        let held = self
+            .worker_bonds
+            .get(worker_id)
+            .map_or(0, |bond| bond.amount.0);
+        let bond = WorkerBond {
+            amount: U128(held + amount),
+            unlocks_at: None,
+        };
+        self.worker_bonds.insert(worker_id.clone(), bond.clone());
+        log!("Bond of {} held for {}", bond.amount.0, worker_id);
+        bond
+    }
+
+    /// Requires a worker to hold at least the bond the policy asks for
+    pub(crate) fn require_worker_bond(&self, worker_id: &AccountId) {
+        let held = self
+            .worker_bonds
+            .get(worker_id)
+            .map_or(0, |bond| bond.amount.0);
+        require!(
+            held >= self.bond_policy.required.0,
+            "Worker bond is below the required amount; call top_up_worker_bond"
+        );
+    }
+
+    /// Starts the cooldown on a deregistered worker's bond
+    pub(crate) fn unlock_worker_bond(&mut self, worker_id: &AccountId, now: u64) {
+        let unlocks_at = now + self.bond_policy.cooldown;
+        if let Some(bond) = self.worker_bonds.get_mut(worker_id) {
+            bond.unlocks_at = Some(unlocks_at);
+        }
+    }
+
+    /// Moves up to `amount` of a worker's bond to the treasury. Returns the amount slashed
+    pub(crate) fn slash_bond(
+        &mut self,
+        worker_id: &AccountId,
+        amount: u128,
+        reason: String,
+    ) -> u128 {
+        let Some(bond) = self.worker_bonds.get_mut(worker_id) else {
+            return 0;
+        };
+        let slashed = amount.min(bond.amount.0);
+        if slashed == 0 {
+            return 0;
+        }
+        bond.amount = U128(bond.amount.0 - slashed);
+
+        let balance = self
+            .treasury_balances
+            .get(&PaymentMethod::Near)
+            .map_or(0, |balance| balance.0);
+        self.treasury_balances
+            .insert(PaymentMethod::Near, U128(balance + slashed));
+
+        Event::WorkerSlashed {
+            worker_id: worker_id.clone(),
+            amount: U128(slashed),
+            reason,
+        }
+        .emit();
+        log!("Slashed {} from the bond of {}", slashed, worker_id);
+        slashed
+    }
+}
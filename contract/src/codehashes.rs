@@ -0,0 +1,83 @@
+use near_sdk::{env, log, near, require};
+
+use crate::events::Event;
+use crate::models::CodehashWindow;
+use crate::{Contract, ContractExt};
+
+#[near]
+impl Contract {
+    /// Approves a codehash that workers are accepted on from `active_from`, so a new release
+    /// can be rolled out ahead of time alongside the codehash it replaces
+    pub fn schedule_codehash(&mut self, codehash: String, active_from: u64) {
+        self.require_owner();
+        self.approved_codehashes.insert(codehash.clone());
+        self.codehash_windows.insert(
+            codehash.clone(),
+            CodehashWindow {
+                active_from,
+                sunset_at: None,
+            },
+        );
+
+        Event::CodehashScheduled {
+            codehash,
+            active_from,
+        }
+        .emit();
+        log!("Codehash scheduled to activate at {}", active_from);
+    }
+
+    /// Retires an approved codehash at `sunset_at`, after which its workers stop passing
+    /// `is_verified_by_approved_codehash`. Leaves them time to upgrade to its replacement
+    pub fn sunset_codehash(&mut self, codehash: String, sunset_at: u64) {
+        self.require_owner();
+        let now = env::block_timestamp() / 1000000000;
+        require!(
+            self.approved_codehashes.contains(&codehash),
+            "Codehash is not approved"
+        );
+        require!(sunset_at > now, "Sunset must be in the future");
+
+        let mut window = self
+            .codehash_windows
+            .get(&codehash)
+            .cloned()
+            .unwrap_or(CodehashWindow {
+                active_from: 0,
+                sunset_at: None,
+            });
+        require!(
+            sunset_at > window.active_from,
+            "Sunset must be after the codehash activates"
+        );
+        window.sunset_at = Some(sunset_at);
+        self.codehash_windows.insert(codehash.clone(), window);
+
+        Event::CodehashSunset {
+            codehash,
+            sunset_at,
+        }
+        .emit();
+        log!("Codehash sunsets at {}", sunset_at);
+    }
+
+    /// Gets when an approved codehash is accepted; `None` if it is accepted indefinitely
+    pub fn get_codehash_window(&self, codehash: String) -> Option<CodehashWindow> {
+        self.codehash_windows.get(&codehash).cloned()
+    }
+}
+
+impl Contract {
+    /// Requires the current time to be within an approved codehash's window
+    pub(crate) fn require_codehash_window(&self, codehash: &str) {
+        let now = env::block_timestamp() / 1000000000;
+        let Some(window) = self.codehash_windows.get(codehash) else {
+            return;
+        };
+        require!(now >= window.active_from, "Codehash is not active yet");
+        require!(
+            window.sunset_at.is_none_or(|sunset_at| now < sunset_at),
+            "Codehash has been sunset"
+        );
+    }
+}
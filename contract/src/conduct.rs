@@ -0,0 +1,224 @@
+use near_sdk::{env, log, near, require, AccountId};
+
+use crate::events::Event;
+use crate::models::{PaymentError, PaymentResult, WorkerConduct};
+use crate::{Contract, ContractExt};
+
+#[near]
+impl Contract {
+    /// Sets how many rejected payment attempts a worker can make before it is suspended from
+    /// processing payments. `None` turns automatic suspension off
+    pub fn set_misbehavior_threshold(&mut self, threshold: Option<u32>) {
+        self.require_owner();
+        self.misbehavior_threshold = threshold;
+        log!("Misbehavior threshold set to {:?}", threshold);
+    }
+
+    /// Gets how many rejected payment attempts suspend a worker, if any
+    pub fn get_misbehavior_threshold(&self) -> Option<u32> {
+        self.misbehavior_threshold
+    }
+
+    /// Gets a worker's rejected payment attempts and whether it is suspended
+    pub fn get_worker_conduct(&self, account_id: AccountId) -> WorkerConduct {
+        self.worker_conduct
+            .get(&account_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Lifts a worker's suspension and clears its counters
+    pub fn reinstate_worker(&mut self, account_id: AccountId) {
+        self.require_owner();
+        require!(
+            self.worker_conduct.remove(&account_id).is_some(),
+            "Worker has no recorded misbehavior"
+        );
+        Event::WorkerReinstated {
+            worker_id: account_id.clone(),
+        }
+        .emit();
+        log!("Worker reinstated: {}", account_id);
+    }
+}
+
+impl Contract {
+    /// Requires the calling worker not to be suspended
+    pub(crate) fn require_not_suspended(&self) {
+        let suspended = self
+            .worker_conduct
+            .get(&env::predecessor_account_id())
+            .is_some_and(|conduct| conduct.suspended_at.is_some());
+        require!(
+            !suspended,
+            "Worker is suspended; the owner must reinstate it"
+        );
+    }
+
+    /// Counts a worker's payment attempt if it was rejected for a reason a correct worker never
+    /// hits, suspending the worker once its attempts exceed the threshold. Not-due attempts in a
+    /// row beyond the bond policy's allowance are also slashed from the worker's bond
+    pub(crate) fn record_worker_conduct(
+        &mut self,
+        worker_id: &AccountId,
+        result: &PaymentResult,
+        now: u64,
+    ) {
+        if result.success {
+            // A successful charge ends any run of not-due attempts
+            if self
+                .worker_conduct
+                .get(worker_id)
+                .is_some_and(|conduct| conduct.not_due_streak > 0)
+            {
+                if let Some(conduct) = self.worker_conduct.get_mut(worker_id) {
+                    conduct.not_due_streak = 0;
+                }
+            }
+            return;
+        }
+        if !matches!(
+            result.error,
+            Some(PaymentError::UnauthorizedKey | PaymentError::NotDue | PaymentError::NotActive)
+        ) {
+            return;
+        }
+
+        let mut conduct = self.get_worker_conduct(worker_id.clone());
+        match result.error {
+            Some(PaymentError::UnauthorizedKey) => conduct.unauthorized_key += 1,
+            Some(PaymentError::NotDue) => {
+                conduct.not_due += 1;
+                conduct.not_due_streak += 1;
+            }
+            _ => conduct.not_active += 1,
+        }
+        if conduct.not_due_streak > self.bond_policy.not_due_allowance
+            && matches!(result.error, Some(PaymentError::NotDue))
+        {
+            let penalty = self.bond_policy.not_due_penalty.0;
+            self.slash_bond(worker_id, penalty, "Charged a subscription not due".to_string());
+        }
+        let exceeded = self
+            .misbehavior_threshold
+            .is_some_and(|threshold| conduct.total() > threshold);
+        if exceeded && conduct.suspended_at.is_none() {
+            conduct.suspended_at = Some(now);
+            Event::WorkerSuspended {
+                worker_id: worker_id.clone(),
+                conduct: conduct.clone(),
+            }
+            .emit();
+            log!("Worker suspended: {}", worker_id);
+        }
+        self.worker_conduct.insert(worker_id.clone(), conduct);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{json_types::U128, testing_env};
+
+    use crate::models::{BondPolicy, PaymentError, PaymentMethod, PaymentResult};
+    use crate::Contract;
+
+    fn setup() -> Contract {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(0))
+            .build());
+        let mut contract = Contract::new(accounts(0));
+        contract.set_bond_policy(BondPolicy {
+            required: U128(1000),
+            cooldown: 0,
+            not_due_penalty: U128(100),
+            not_due_allowance: 2,
+        });
+        contract.add_worker_bond(&accounts(1), 1000);
+        contract
+    }
+
+    fn charge(contract: &mut Contract, error: Option<PaymentError>) {
+        let result = PaymentResult {
+            success: error.is_none(),
+            subscription_id: "sub-1".to_string(),
+            amount: U128(0),
+            timestamp: 0,
+            error,
+        };
+        contract.record_worker_conduct(&accounts(1), &result, 0);
+    }
+
+    fn bond(contract: &Contract) -> u128 {
+        contract
+            .get_worker_bond(accounts(1))
+            .expect("Worker has no bond")
+            .amount
+            .0
+    }
+
+    #[test]
+    fn slashes_not_due_charges_beyond_allowance() {
+        let mut contract = setup();
+
+        charge(&mut contract, Some(PaymentError::NotDue));
+        charge(&mut contract, Some(PaymentError::NotDue));
+        assert_eq!(bond(&contract), 1000);
+
+        charge(&mut contract, Some(PaymentError::NotDue));
+        charge(&mut contract, Some(PaymentError::NotDue));
+        assert_eq!(bond(&contract), 800);
+        assert_eq!(
+            contract
+                .treasury_balances
+                .get(&PaymentMethod::Near)
+                .map(|balance| balance.0),
+            Some(200)
+        );
+    }
+
+    #[test]
+    fn successful_charge_ends_not_due_streak() {
+        let mut contract = setup();
+
+        charge(&mut contract, Some(PaymentError::NotDue));
+        charge(&mut contract, Some(PaymentError::NotDue));
+        charge(&mut contract, None);
+        charge(&mut contract, Some(PaymentError::NotDue));
+        charge(&mut contract, Some(PaymentError::NotDue));
+
+        assert_eq!(bond(&contract), 1000);
+        let conduct = contract.get_worker_conduct(accounts(1));
+        assert_eq!(conduct.not_due, 4);
+        assert_eq!(conduct.not_due_streak, 2);
+    }
+
+    #[test]
+    fn does_not_slash_other_rejections() {
+        let mut contract = setup();
+
+        for _ in 0..4 {
+            charge(&mut contract, Some(PaymentError::NotActive));
+        }
+
+        assert_eq!(bond(&contract), 1000);
+    }
+
+    #[test]
+    fn suspends_worker_beyond_threshold() {
+        let mut contract = setup();
+        contract.set_misbehavior_threshold(Some(1));
+
+        charge(&mut contract, Some(PaymentError::UnauthorizedKey));
+        assert!(contract
+            .get_worker_conduct(accounts(1))
+            .suspended_at
+            .is_none());
+
+        charge(&mut contract, Some(PaymentError::UnauthorizedKey));
+        assert!(contract
+            .get_worker_conduct(accounts(1))
+            .suspended_at
+            .is_some());
+    }
+}
@@ -0,0 +1,74 @@
+use near_sdk::{near, AccountId};
+
+use crate::models::{SubscriptionCounts, SubscriptionStatus};
+use crate::{Contract, ContractExt};
+
+#[near]
+impl Contract {
+    /// Gets the number of subscriptions in the live state, in total and by status
+    pub fn get_subscription_counts(&self) -> SubscriptionCounts {
+        self.subscription_counts.clone()
+    }
+
+    /// Gets the number of a merchant's subscriptions in the live state, in total and by status
+    pub fn get_merchant_subscription_counts(&self, merchant_id: AccountId) -> SubscriptionCounts {
+        self.merchant_subscription_counts
+            .get(&merchant_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+impl Contract {
+    /// Counts a new subscription
+    pub(crate) fn count_subscription(
+        &mut self,
+        merchant_id: &AccountId,
+        status: &SubscriptionStatus,
+    ) {
+        self.update_counts(merchant_id, |counts| {
+            counts.total += 1;
+            *counts.for_status(status) += 1;
+        });
+    }
+
+    /// Stops counting a subscription removed from the live state
+    pub(crate) fn uncount_subscription(
+        &mut self,
+        merchant_id: &AccountId,
+        status: &SubscriptionStatus,
+    ) {
+        self.update_counts(merchant_id, |counts| {
+            counts.total = counts.total.saturating_sub(1);
+            let count = counts.for_status(status);
+            *count = count.saturating_sub(1);
+        });
+    }
+
+    /// Moves a subscription between status counts. Call whenever a stored subscription's
+    /// status changes
+    pub(crate) fn record_status_change(
+        &mut self,
+        merchant_id: &AccountId,
+        from: &SubscriptionStatus,
+        to: &SubscriptionStatus,
+    ) {
+        if from == to {
+            return;
+        }
+        self.update_counts(merchant_id, |counts| {
+            let count = counts.for_status(from);
+            *count = count.saturating_sub(1);
+            *counts.for_status(to) += 1;
+        });
+    }
+
+    /// Applies the same change to the global and the merchant's counts
+    fn update_counts(&mut self, merchant_id: &AccountId, update: impl Fn(&mut SubscriptionCounts)) {
+        update(&mut self.subscription_counts);
+        let mut counts = self.get_merchant_subscription_counts(merchant_id.clone());
+        update(&mut counts);
+        self.merchant_subscription_counts
+            .insert(merchant_id.clone(), counts);
+    }
+}
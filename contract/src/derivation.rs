@@ -0,0 +1,260 @@
+use near_sdk::{env, log, near, require, AccountId};
+
+use crate::models::{KeyDerivation, SubscriptionId};
+use crate::{Contract, ContractExt};
+
+// Longest derivation path a subscription can register
+const MAX_DERIVATION_PATH_LENGTH: usize = 128;
+
+#[near]
+impl Contract {
+    /// Registers where a subscription's payment key is derived from instead of the key itself:
+    /// the worker account whose TEE derives it and the derivation path. That worker publishes
+    /// the derived key with `rotate_derived_key` and can rotate it without the subscriber
+    /// registering again. Charged to the subscriber's storage deposit
+    pub fn register_subscription_derivation(
+        &mut self,
+        subscription_id: SubscriptionId,
+        account_id: AccountId,
+        path: String,
+    ) {
+        let user_id = env::predecessor_account_id();
+        require!(
+            !path.is_empty() && path.len() <= MAX_DERIVATION_PATH_LENGTH,
+            "Derivation path must be 1 to 128 bytes"
+        );
+        let subscription = self
+            .subscriptions
+            .get(&subscription_id)
+            .expect("Subscription not found");
+        require!(
+            subscription.user_id == user_id,
+            "Not authorized to register a derivation for this subscription"
+        );
+
+        self.flush_subscription_storage();
+        let initial_storage = env::storage_usage();
+        // A new derivation replaces the old one along with the key derived from it
+        self.clear_subscription_derivation(&subscription_id);
+        self.key_derivations.insert(
+            subscription_id.clone(),
+            KeyDerivation {
+                account_id,
+                path,
+                public_key: None,
+            },
+        );
+        self.charge_storage(&user_id, initial_storage);
+
+        log!(
+            "Key derivation registered for subscription: {}",
+            subscription_id
+        );
+    }
+
+    /// Removes a subscription's key derivation and the key derived from it, returning their
+    /// storage to the subscriber's storage deposit
+    pub fn remove_subscription_derivation(&mut self, subscription_id: SubscriptionId) {
+        let user_id = env::predecessor_account_id();
+        let subscription = self
+            .subscriptions
+            .get(&subscription_id)
+            .expect("Subscription not found");
+        require!(
+            subscription.user_id == user_id,
+            "Not authorized to remove this derivation"
+        );
+        require!(
+            self.key_derivations.contains_key(&subscription_id),
+            "Derivation not found"
+        );
+
+        self.flush_subscription_storage();
+        let initial_storage = env::storage_usage();
+        self.clear_subscription_derivation(&subscription_id);
+        self.flush_subscription_storage();
+        let storage_freed = initial_storage.saturating_sub(env::storage_usage());
+        self.release_storage(&user_id, storage_freed);
+
+        log!(
+            "Key derivation removed for subscription: {}",
+            subscription_id
+        );
+    }
+
+    /// Authorizes the key the calling worker derived for a subscription, replacing the one it
+    /// derived before. Only the worker named in the subscription's derivation can call this
+    pub fn rotate_derived_key(&mut self, subscription_id: SubscriptionId, public_key: String) {
+        require!(
+            self.is_verified_by_approved_codehash(),
+            "Not an approved worker"
+        );
+        let derivation = self
+            .key_derivations
+            .get(&subscription_id)
+            .cloned()
+            .expect("Derivation not found");
+        require!(
+            derivation.account_id == env::predecessor_account_id(),
+            "Not the worker deriving keys for this subscription"
+        );
+        require!(
+            self.subscription_keys
+                .get(&public_key)
+                .is_none_or(|linked| *linked == subscription_id),
+            "Key is authorized for another subscription"
+        );
+        let user_id = self
+            .subscriptions
+            .get(&subscription_id)
+            .expect("Subscription not found")
+            .user_id
+            .clone();
+
+        self.flush_subscription_storage();
+        let initial_storage = env::storage_usage();
+        if let Some(previous) = &derivation.public_key {
+            self.unlink_subscription_key(&subscription_id, previous);
+        }
+        self.link_subscription_key(&subscription_id, public_key.clone());
+        self.key_derivations.insert(
+            subscription_id.clone(),
+            KeyDerivation {
+                public_key: Some(public_key),
+                ..derivation
+            },
+        );
+        self.charge_storage(&user_id, initial_storage);
+
+        log!("Derived key rotated for subscription: {}", subscription_id);
+    }
+
+    /// Gets where a subscription's payment key is derived from, if it registered a derivation
+    pub fn get_subscription_derivation(
+        &self,
+        subscription_id: SubscriptionId,
+    ) -> Option<KeyDerivation> {
+        self.key_derivations.get(&subscription_id).cloned()
+    }
+}
+
+impl Contract {
+    /// Removes a subscription's key derivation and stops its derived key being authorized
+    pub(crate) fn clear_subscription_derivation(&mut self, subscription_id: &SubscriptionId) {
+        let Some(derivation) = self.key_derivations.remove(subscription_id) else {
+            return;
+        };
+        if let Some(public_key) = &derivation.public_key {
+            self.unlink_subscription_key(subscription_id, public_key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{json_types::U128, testing_env, AccountId};
+
+    use crate::models::{
+        KeyDerivation, PaymentMethod, StorageAccount, Subscription, SubscriptionFrequency,
+        SubscriptionStatusV0, SubscriptionV0,
+    };
+    use crate::Contract;
+
+    fn set_predecessor(predecessor: AccountId) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(predecessor)
+            .build());
+    }
+
+    /// A contract holding "sub-1" of `accounts(1)`, who has a storage deposit, with a key
+    /// "key-a" derived by worker `accounts(3)`
+    fn contract_with_derived_key() -> Contract {
+        set_predecessor(accounts(0));
+        let mut contract = Contract::new(accounts(0));
+        let subscription: Subscription = SubscriptionV0 {
+            id: "sub-1".to_string(),
+            user_id: accounts(1),
+            merchant_id: accounts(2),
+            amount: U128(10000),
+            frequency: SubscriptionFrequency::Monthly,
+            next_payment_date: 0,
+            status: SubscriptionStatusV0::Active,
+            created_at: 0,
+            updated_at: 0,
+            payment_method: PaymentMethod::Near,
+            max_payments: None,
+            payments_made: 0,
+            end_date: None,
+        }
+        .into();
+        contract
+            .subscriptions
+            .insert(subscription.id.clone(), subscription.into());
+        contract.storage_accounts.insert(
+            accounts(1),
+            StorageAccount {
+                deposit: U128(Contract::storage_cost(100000)),
+                used_bytes: 0,
+            },
+        );
+        contract.link_subscription_key(&"sub-1".to_string(), "key-a".to_string());
+        contract.key_derivations.insert(
+            "sub-1".to_string(),
+            KeyDerivation {
+                account_id: accounts(3),
+                path: "subscriptions/sub-1".to_string(),
+                public_key: Some("key-a".to_string()),
+            },
+        );
+        contract
+    }
+
+    #[test]
+    fn replaces_derivation_along_with_its_derived_key() {
+        let mut contract = contract_with_derived_key();
+
+        set_predecessor(accounts(1));
+        contract.register_subscription_derivation(
+            "sub-1".to_string(),
+            accounts(4),
+            "subscriptions/sub-1/2".to_string(),
+        );
+
+        let derivation = contract
+            .get_subscription_derivation("sub-1".to_string())
+            .unwrap();
+        assert_eq!(derivation.account_id, accounts(4));
+        assert_eq!(derivation.public_key, None);
+        assert!(!contract.subscription_keys.contains_key("key-a"));
+    }
+
+    #[test]
+    fn removes_derivation_and_its_derived_key() {
+        let mut contract = contract_with_derived_key();
+
+        set_predecessor(accounts(1));
+        contract.remove_subscription_derivation("sub-1".to_string());
+
+        assert!(contract
+            .get_subscription_derivation("sub-1".to_string())
+            .is_none());
+        assert!(contract
+            .subscription_public_keys(&"sub-1".to_string())
+            .is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Not authorized to register a derivation for this subscription")]
+    fn rejects_derivation_for_another_subscribers_subscription() {
+        let mut contract = contract_with_derived_key();
+
+        set_predecessor(accounts(3));
+        contract.register_subscription_derivation(
+            "sub-1".to_string(),
+            accounts(3),
+            "subscriptions/sub-1".to_string(),
+        );
+    }
+}
@@ -0,0 +1,399 @@
+use near_sdk::{env, json_types::U128, log, near, require, AccountId};
+
+use crate::events::Event;
+use crate::models::{
+    ChargeHold, Dispute, DisputeStatus, FundingSource, PaymentKind, PaymentRecord, PayoutPurpose,
+    Subscription, SubscriptionId,
+};
+use crate::{Contract, ContractExt};
+
+// Maximum length of a dispute reason or merchant response
+const MAX_DISPUTE_TEXT_LENGTH: usize = 512;
+
+#[near]
+impl Contract {
+    /// Sets how long the calling merchant's charges are held in the contract, open to disputes,
+    /// before they are paid out. `None` pays charges out immediately
+    pub fn set_dispute_window(&mut self, seconds: Option<u64>) {
+        let merchant_id = self.require_merchant();
+        let mut settings = self.get_merchant_settings(merchant_id.clone());
+        settings.dispute_window = seconds;
+        self.merchant_settings.insert(merchant_id.clone(), settings);
+        log!("Dispute window for {} set to {:?}", merchant_id, seconds);
+    }
+
+    /// Sets the account that resolves contested disputes alongside the owner
+    pub fn set_arbiter(&mut self, arbiter_id: Option<AccountId>) {
+        self.require_owner();
+        self.arbiter_id = arbiter_id;
+        log!("Arbiter updated");
+    }
+
+    /// Gets a charge held for its dispute window
+    pub fn get_charge_hold(
+        &self,
+        subscription_id: SubscriptionId,
+        payment_number: u32,
+    ) -> Option<ChargeHold> {
+        self.charge_holds
+            .get(&(subscription_id, payment_number))
+            .cloned()
+    }
+
+    /// Pays out a held charge once its dispute window has lapsed without a dispute.
+    /// Callable by anyone
+    pub fn release_charge(&mut self, subscription_id: SubscriptionId, payment_number: u32) {
+        let now = env::block_timestamp() / 1000000000;
+        let key = (subscription_id.clone(), payment_number);
+        let hold = self.charge_holds.get(&key).expect("No held charge").clone();
+        require!(now >= hold.release_at, "Dispute window has not ended");
+        require!(hold.dispute.is_none(), "Charge is disputed");
+
+        self.charge_holds.remove(&key);
+        self.pay_out_charge_hold(&subscription_id, &hold);
+    }
+
+    /// Disputes a held charge. Only the subscriber can call this, before the window lapses
+    pub fn open_dispute(
+        &mut self,
+        subscription_id: SubscriptionId,
+        payment_number: u32,
+        reason: String,
+    ) {
+        let now = env::block_timestamp() / 1000000000;
+        require!(
+            reason.len() <= MAX_DISPUTE_TEXT_LENGTH,
+            "Dispute reason is too long"
+        );
+        let user_id = self.subscription_for(&subscription_id).user_id;
+        require!(
+            env::predecessor_account_id() == user_id,
+            "Only the subscriber can dispute a charge"
+        );
+
+        let hold = self
+            .charge_holds
+            .get_mut(&(subscription_id.clone(), payment_number))
+            .expect("No held charge");
+        require!(now < hold.release_at, "Dispute window has ended");
+        require!(hold.dispute.is_none(), "Charge is already disputed");
+        hold.dispute = Some(Dispute {
+            reason: reason.clone(),
+            response: None,
+            status: DisputeStatus::Open,
+            opened_at: now,
+        });
+
+        Event::DisputeOpened {
+            subscription_id,
+            payment_number,
+            user_id,
+            reason,
+        }
+        .emit();
+    }
+
+    /// Accepts a dispute on one of the calling merchant's charges, refunding the subscriber
+    pub fn accept_dispute(&mut self, subscription_id: SubscriptionId, payment_number: u32) {
+        let merchant_id = self.subscription_for(&subscription_id).merchant_id;
+        require!(
+            env::predecessor_account_id() == merchant_id,
+            "Only the merchant can accept a dispute"
+        );
+        let hold = self.disputed_charge(&subscription_id, payment_number);
+        require!(
+            matches!(
+                hold.dispute,
+                Some(Dispute {
+                    status: DisputeStatus::Open,
+                    ..
+                })
+            ),
+            "Dispute is not open"
+        );
+
+        self.resolve_charge_hold(subscription_id, payment_number, hold, true, merchant_id);
+    }
+
+    /// Contests a dispute on one of the calling merchant's charges, leaving it to the arbiter
+    pub fn contest_dispute(
+        &mut self,
+        subscription_id: SubscriptionId,
+        payment_number: u32,
+        response: String,
+    ) {
+        require!(
+            response.len() <= MAX_DISPUTE_TEXT_LENGTH,
+            "Dispute response is too long"
+        );
+        let merchant_id = self.subscription_for(&subscription_id).merchant_id;
+        require!(
+            env::predecessor_account_id() == merchant_id,
+            "Only the merchant can contest a dispute"
+        );
+
+        let hold = self
+            .charge_holds
+            .get_mut(&(subscription_id.clone(), payment_number))
+            .expect("No held charge");
+        let dispute = hold.dispute.as_mut().expect("Charge is not disputed");
+        require!(
+            matches!(dispute.status, DisputeStatus::Open),
+            "Dispute is not open"
+        );
+        dispute.status = DisputeStatus::Contested;
+        dispute.response = Some(response);
+
+        log!(
+            "Dispute contested for subscription {} payment {}",
+            subscription_id,
+            payment_number
+        );
+    }
+
+    /// Resolves a dispute, refunding the subscriber or paying the merchant. Only the owner or
+    /// the arbiter can call this
+    pub fn resolve_dispute(
+        &mut self,
+        subscription_id: SubscriptionId,
+        payment_number: u32,
+        refund: bool,
+    ) {
+        let resolver_id = env::predecessor_account_id();
+        require!(
+            resolver_id == self.owner_id || Some(&resolver_id) == self.arbiter_id.as_ref(),
+            "Only the owner or arbiter can resolve disputes"
+        );
+        let hold = self.disputed_charge(&subscription_id, payment_number);
+
+        self.resolve_charge_hold(subscription_id, payment_number, hold, refund, resolver_id);
+    }
+}
+
+impl Contract {
+    /// Dispute window that applies to a merchant's charges
+    pub(crate) fn dispute_window_for(&self, merchant_id: &AccountId) -> Option<u64> {
+        self.merchant_settings
+            .get(merchant_id)
+            .and_then(|settings| settings.dispute_window)
+    }
+
+    fn subscription_for(&self, subscription_id: &SubscriptionId) -> Subscription {
+        self.subscriptions
+            .get(subscription_id)
+            .expect("Subscription not found")
+            .into()
+    }
+
+    fn disputed_charge(&self, subscription_id: &SubscriptionId, payment_number: u32) -> ChargeHold {
+        let hold = self
+            .charge_holds
+            .get(&(subscription_id.clone(), payment_number))
+            .expect("No held charge")
+            .clone();
+        require!(hold.dispute.is_some(), "Charge is not disputed");
+        hold
+    }
+
+    fn resolve_charge_hold(
+        &mut self,
+        subscription_id: SubscriptionId,
+        payment_number: u32,
+        hold: ChargeHold,
+        refund: bool,
+        resolved_by: AccountId,
+    ) {
+        self.charge_holds
+            .remove(&(subscription_id.clone(), payment_number));
+        if refund {
+            self.refund_charge_hold(&subscription_id, &hold);
+        } else {
+            self.pay_out_charge_hold(&subscription_id, &hold);
+        }
+
+        Event::DisputeResolved {
+            subscription_id,
+            payment_number,
+            refunded: refund,
+            resolved_by,
+        }
+        .emit();
+    }
+
+    /// Pays a held charge to the merchant, less the platform fee
+    fn pay_out_charge_hold(&mut self, subscription_id: &SubscriptionId, hold: &ChargeHold) {
+        let now = env::block_timestamp() / 1000000000;
+        let subscription = self.subscription_for(subscription_id);
+        let memo = self.payment_memo(&subscription, hold.payment_number);
+        self.pay_merchant(
+            &subscription,
+            &hold.funding,
+            hold.cycle_index,
+            memo,
+            PayoutPurpose::Release,
+            now,
+        );
+
+        Event::PaymentReleased {
+            subscription_id: subscription_id.clone(),
+            merchant_id: subscription.merchant_id,
+            amount: hold.funding.amount,
+        }
+        .emit();
+    }
+
+    /// Returns a held charge to the subscriber, recording the refund in payment history
+    fn refund_charge_hold(&mut self, subscription_id: &SubscriptionId, hold: &ChargeHold) {
+        let now = env::block_timestamp() / 1000000000;
+        let subscription = self.subscription_for(subscription_id);
+        let FundingSource {
+            payment_method,
+            amount,
+        } = hold.funding.clone();
+
+        self.transfer_funds(
+            &payment_method,
+            subscription.user_id.clone(),
+            amount.0,
+            format!("Subscription refund: {}", subscription_id),
+        );
+
+        self.push_payment_record(PaymentRecord {
+            subscription_id: subscription_id.clone(),
+            kind: PaymentKind::Refund,
+            payment_number: hold.payment_number,
+            amount,
+            payment_method,
+            line_items: Vec::new(),
+            memo: None,
+            fee: U128(0),
+            referral_commission: U128(0),
+            payouts: Vec::new(),
+            invoice_number: None,
+            usd_rate: None,
+            amount_override: None,
+            rate_source: None,
+            swap: None,
+            timestamp: now,
+            block_height: env::block_height(),
+            block_timestamp: env::block_timestamp(),
+        });
+
+        Event::PaymentRefunded {
+            subscription_id: subscription_id.clone(),
+            user_id: subscription.user_id,
+            amount,
+            reason: "dispute".to_string(),
+        }
+        .emit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{json_types::U128, testing_env, AccountId};
+
+    use crate::models::{
+        ChargeHold, DisputeStatus, FundingSource, PaymentMethod, Subscription,
+        SubscriptionFrequency, SubscriptionStatusV0, SubscriptionV0,
+    };
+    use crate::Contract;
+
+    fn set_context(predecessor: AccountId, now: u64) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(predecessor)
+            .block_timestamp(now * 1_000_000_000)
+            .build());
+    }
+
+    /// A contract holding the first charge of `accounts(1)`'s subscription to `accounts(2)`
+    /// until 2000
+    fn contract_with_held_charge() -> Contract {
+        set_context(accounts(0), 1_000);
+        let mut contract = Contract::new(accounts(0));
+        let subscription: Subscription = SubscriptionV0 {
+            id: "sub-1".to_string(),
+            user_id: accounts(1),
+            merchant_id: accounts(2),
+            amount: U128(10000),
+            frequency: SubscriptionFrequency::Monthly,
+            next_payment_date: 1_000 + 2592000,
+            status: SubscriptionStatusV0::Active,
+            created_at: 0,
+            updated_at: 0,
+            payment_method: PaymentMethod::Near,
+            max_payments: None,
+            payments_made: 1,
+            end_date: None,
+        }
+        .into();
+        contract
+            .subscriptions
+            .insert(subscription.id.clone(), subscription.into());
+        contract.charge_holds.insert(
+            ("sub-1".to_string(), 1),
+            ChargeHold {
+                payment_number: 1,
+                cycle_index: 0,
+                funding: FundingSource {
+                    payment_method: PaymentMethod::Near,
+                    amount: U128(10000),
+                },
+                release_at: 2_000,
+                dispute: None,
+            },
+        );
+        contract
+    }
+
+    #[test]
+    fn leaves_contested_dispute_to_arbiter() {
+        let mut contract = contract_with_held_charge();
+
+        set_context(accounts(1), 1_500);
+        contract.open_dispute("sub-1".to_string(), 1, "Not delivered".to_string());
+        set_context(accounts(2), 1_600);
+        contract.contest_dispute("sub-1".to_string(), 1, "Delivered".to_string());
+
+        let dispute = contract
+            .get_charge_hold("sub-1".to_string(), 1)
+            .unwrap()
+            .dispute
+            .unwrap();
+        assert_eq!(dispute.status, DisputeStatus::Contested);
+        assert_eq!(dispute.response, Some("Delivered".to_string()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Dispute window has ended")]
+    fn rejects_dispute_after_window() {
+        let mut contract = contract_with_held_charge();
+
+        set_context(accounts(1), 2_000);
+        contract.open_dispute("sub-1".to_string(), 1, "Not delivered".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Charge is disputed")]
+    fn holds_disputed_charge_past_window() {
+        let mut contract = contract_with_held_charge();
+        set_context(accounts(1), 1_500);
+        contract.open_dispute("sub-1".to_string(), 1, "Not delivered".to_string());
+
+        set_context(accounts(3), 2_000);
+        contract.release_charge("sub-1".to_string(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner or arbiter can resolve disputes")]
+    fn rejects_resolution_by_merchant() {
+        let mut contract = contract_with_held_charge();
+        set_context(accounts(1), 1_500);
+        contract.open_dispute("sub-1".to_string(), 1, "Not delivered".to_string());
+
+        set_context(accounts(2), 1_600);
+        contract.resolve_dispute("sub-1".to_string(), 1, false);
+    }
+}
@@ -0,0 +1,342 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use near_sdk::{env, log, near, require};
+
+use crate::models::{DueSummary, PaymentMode, Subscription, SubscriptionId, SubscriptionStatus};
+use crate::shards::SubscriptionShards;
+use crate::{Contract, ContractExt};
+
+// Most due subscriptions `get_due_summary` counts
+const MAX_DUE_SUMMARY_COUNT: u64 = 1000;
+// Most heap entries one walk visits, bounding the gas spent on entries it skips
+const MAX_DUE_ENTRIES_VISITED: u64 = 2000;
+
+#[near]
+impl Contract {
+    /// Indexes subscriptions created before the due-date index existed, `limit` at a time from
+    /// `from_index`, leaving out paused and ended ones. Returns how many were read
+    pub fn index_due_dates(&mut self, from_index: u64, limit: u64) -> u64 {
+        self.require_owner();
+        require!(limit > 0, "Limit must be positive");
+        let due_dates: Vec<(SubscriptionId, u64, bool)> = self
+            .subscriptions
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .map(|(subscription_id, subscription)| {
+                (
+                    subscription_id.clone(),
+                    subscription.next_payment_date,
+                    subscription.status.keeps_due_date(),
+                )
+            })
+            .collect();
+        for (subscription_id, due_date, _) in due_dates.iter().filter(|(_, _, keeps)| *keeps) {
+            self.index_due_date(subscription_id, *due_date);
+        }
+        log!("Indexed due dates of {} subscriptions", due_dates.len());
+        due_dates.len() as u64
+    }
+
+    /// Counts the subscriptions due for payment without revealing which they are, so anyone
+    /// can monitor the backlog. Counting stops at 1000, or after reading 2000 heap entries, with
+    /// `has_more` set. Workers fetch the due subscriptions themselves with `claim_due_batch`
+    pub fn get_due_summary(&self) -> DueSummary {
+        let now = env::block_timestamp() / 1000000000;
+        let mut summary = DueSummary {
+            due: 0,
+            has_more: false,
+            earliest_due_at: None,
+        };
+        let truncated = self.walk_due_dates(now, |subscription_id, due_date| {
+            let chargeable = self.subscriptions.get(subscription_id).is_some_and(|subscription| {
+                matches!(subscription.status, SubscriptionStatus::Active)
+                    && subscription.payment_mode == PaymentMode::Periodic
+            });
+            if !chargeable {
+                return true;
+            }
+            if summary.due == MAX_DUE_SUMMARY_COUNT {
+                summary.has_more = true;
+                return false;
+            }
+            summary.due += 1;
+            summary.earliest_due_at.get_or_insert(due_date);
+            true
+        });
+        summary.has_more |= truncated;
+        summary
+    }
+}
+
+impl Contract {
+    /// Finds active periodic subscriptions that are due, earliest first, reading only the
+    /// heap entries that are due instead of every subscription. Subscriptions outside the
+    /// caller's shards or leased to a worker other than the caller are left out. At most 2000
+    /// entries are read, so fewer than `limit` may be found while more are due
+    pub(crate) fn due_subscriptions(&self, now: u64, limit: u64) -> Vec<Subscription> {
+        let caller_id = env::predecessor_account_id();
+        let mut subscriptions = Vec::new();
+        if limit == 0 {
+            return subscriptions;
+        }
+        self.walk_due_dates(now, |subscription_id, _| {
+            if !self.is_assigned_to(subscription_id, &caller_id)
+                || self.is_leased_to_other(subscription_id, &caller_id, now)
+            {
+                return true;
+            }
+            let subscription = self
+                .subscriptions
+                .get(subscription_id)
+                .filter(|subscription| {
+                    matches!(subscription.status, SubscriptionStatus::Active)
+                        && subscription.payment_mode == PaymentMode::Periodic
+                })
+                .map(Subscription::from);
+            subscriptions.extend(subscription);
+            (subscriptions.len() as u64) < limit
+        });
+        subscriptions
+    }
+
+    /// Finds active subscriptions due after `now` and by `until` that match `filter`, earliest
+    /// first, from one shard when `shard` is given
+    pub(crate) fn subscriptions_due_between(
+        &self,
+        now: u64,
+        until: u64,
+        limit: u64,
+        shard: Option<u8>,
+        filter: impl Fn(&Subscription) -> bool,
+    ) -> Vec<(SubscriptionId, Subscription)> {
+        let mut subscriptions = Vec::new();
+        if limit == 0 {
+            return subscriptions;
+        }
+        self.walk_due_dates(until, |subscription_id, due_date| {
+            if due_date <= now || !SubscriptionShards::in_shard(subscription_id, shard) {
+                return true;
+            }
+            let subscription = self
+                .subscriptions
+                .get(subscription_id)
+                .filter(|subscription| matches!(subscription.status, SubscriptionStatus::Active))
+                .map(Subscription::from)
+                .filter(|subscription| filter(subscription))
+                .map(|subscription| (subscription_id.clone(), subscription));
+            subscriptions.extend(subscription);
+            (subscriptions.len() as u64) < limit
+        });
+        subscriptions
+    }
+
+    /// Adds a subscription to the heap at its due date, or moves it there if already in it.
+    /// Call whenever `next_payment_date` changes
+    pub(crate) fn index_due_date(&mut self, subscription_id: &SubscriptionId, due_date: u64) {
+        let entry = (due_date, subscription_id.clone());
+        match self.due_heap_positions.get(subscription_id) {
+            Some(&position) => {
+                self.due_heap[position] = entry;
+                self.sift_due_date(position);
+            }
+            None => {
+                let position = self.due_heap.len();
+                self.due_heap.push(entry);
+                self.due_heap_positions
+                    .insert(subscription_id.clone(), position);
+                self.sift_due_date(position);
+            }
+        }
+    }
+
+    /// Removes a subscription from the heap
+    pub(crate) fn unindex_due_date(&mut self, subscription_id: &SubscriptionId) {
+        let Some(position) = self.due_heap_positions.remove(subscription_id) else {
+            return;
+        };
+        let last = self.due_heap.len() - 1;
+        self.due_heap.swap_remove(position);
+        if position < last {
+            let moved_id = self.due_heap[position].1.clone();
+            self.due_heap_positions.insert(moved_id, position);
+            self.sift_due_date(position);
+        }
+    }
+
+    /// Visits heap entries due by `until` in due-date order, stopping once `visit` returns
+    /// false. Only the entries visited and their children are read, as an entry's children
+    /// are never due before it. Returns true if it stopped after visiting 2000 entries with
+    /// more due
+    fn walk_due_dates(
+        &self,
+        until: u64,
+        mut visit: impl FnMut(&SubscriptionId, u64) -> bool,
+    ) -> bool {
+        let mut frontier = BinaryHeap::new();
+        if let Some(root) = self.due_heap.get(0) {
+            frontier.push(Reverse((root.clone(), 0)));
+        }
+        let mut visited = 0;
+        while let Some(Reverse(((due_date, subscription_id), position))) = frontier.pop() {
+            if due_date > until {
+                break;
+            }
+            if visited == MAX_DUE_ENTRIES_VISITED {
+                return true;
+            }
+            visited += 1;
+            if !visit(&subscription_id, due_date) {
+                break;
+            }
+            for child in [2 * position + 1, 2 * position + 2] {
+                if let Some(entry) = self.due_heap.get(child) {
+                    frontier.push(Reverse((entry.clone(), child)));
+                }
+            }
+        }
+        false
+    }
+
+    /// Restores the heap order around an entry whose due date changed
+    fn sift_due_date(&mut self, mut position: u32) {
+        while position > 0 {
+            let parent = (position - 1) / 2;
+            if self.due_heap[parent] <= self.due_heap[position] {
+                break;
+            }
+            self.swap_due_dates(parent, position);
+            position = parent;
+        }
+        loop {
+            let mut smallest = position;
+            for child in [2 * position + 1, 2 * position + 2] {
+                if child < self.due_heap.len() && self.due_heap[child] < self.due_heap[smallest] {
+                    smallest = child;
+                }
+            }
+            if smallest == position {
+                break;
+            }
+            self.swap_due_dates(position, smallest);
+            position = smallest;
+        }
+    }
+
+    fn swap_due_dates(&mut self, a: u32, b: u32) {
+        self.due_heap.swap(a, b);
+        let id_a = self.due_heap[a].1.clone();
+        let id_b = self.due_heap[b].1.clone();
+        self.due_heap_positions.insert(id_a, a);
+        self.due_heap_positions.insert(id_b, b);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use super::MAX_DUE_ENTRIES_VISITED;
+    use crate::Contract;
+
+    fn setup() -> Contract {
+        testing_env!(VMContextBuilder::new()
+            .predecessor_account_id(accounts(0))
+            .build());
+        Contract::new(accounts(0))
+    }
+
+    /// Indexes `sub-0`, `sub-1`, ... at the given due dates
+    fn index(contract: &mut Contract, due_dates: &[u64]) {
+        for (i, due_date) in due_dates.iter().enumerate() {
+            contract.index_due_date(&format!("sub-{}", i), *due_date);
+        }
+    }
+
+    /// Requires every entry to be due no earlier than its parent and its position to be recorded
+    fn assert_heap_ordered(contract: &Contract) {
+        for position in 0..contract.due_heap.len() {
+            let (due_date, subscription_id) = &contract.due_heap[position];
+            if position > 0 {
+                assert!(contract.due_heap[(position - 1) / 2].0 <= *due_date);
+            }
+            assert_eq!(
+                contract.due_heap_positions.get(subscription_id),
+                Some(&position)
+            );
+        }
+    }
+
+    /// Due dates the heap walk visits by `until`, in the order it visits them
+    fn walk(contract: &Contract, until: u64) -> Vec<u64> {
+        let mut due_dates = Vec::new();
+        contract.walk_due_dates(until, |_, due_date| {
+            due_dates.push(due_date);
+            true
+        });
+        due_dates
+    }
+
+    #[test]
+    fn walks_due_dates_in_order() {
+        let mut contract = setup();
+        index(&mut contract, &[50, 10, 40, 20, 30, 10]);
+
+        assert_heap_ordered(&contract);
+        assert_eq!(walk(&contract, 30), vec![10, 10, 20, 30]);
+        assert_eq!(walk(&contract, 5), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn moves_reindexed_due_date() {
+        let mut contract = setup();
+        index(&mut contract, &[50, 10, 40, 20, 30]);
+
+        contract.index_due_date(&"sub-0".to_string(), 5);
+        assert_heap_ordered(&contract);
+        contract.index_due_date(&"sub-1".to_string(), 60);
+        assert_heap_ordered(&contract);
+
+        assert_eq!(contract.due_heap.len(), 5);
+        assert_eq!(walk(&contract, u64::MAX), vec![5, 20, 30, 40, 60]);
+    }
+
+    #[test]
+    fn unindexes_due_dates() {
+        let mut contract = setup();
+        index(&mut contract, &[50, 10, 40, 20, 30, 60, 70]);
+
+        // The earliest, one in the middle, the last and one that is not indexed
+        for subscription_id in ["sub-1", "sub-3", "sub-6", "sub-9"] {
+            contract.unindex_due_date(&subscription_id.to_string());
+            assert_heap_ordered(&contract);
+        }
+
+        assert_eq!(contract.due_heap.len(), 4);
+        assert!(contract
+            .due_heap_positions
+            .get(&"sub-1".to_string())
+            .is_none());
+        assert_eq!(walk(&contract, u64::MAX), vec![30, 40, 50, 60]);
+    }
+
+    #[test]
+    fn stops_walk_after_visiting_max_entries() {
+        let mut contract = setup();
+        let due_dates = vec![1; MAX_DUE_ENTRIES_VISITED as usize + 1];
+        index(&mut contract, &due_dates);
+
+        let mut visited = 0;
+        let truncated = contract.walk_due_dates(1, |_, _| {
+            visited += 1;
+            true
+        });
+        assert!(truncated);
+        assert_eq!(visited, MAX_DUE_ENTRIES_VISITED);
+
+        contract.unindex_due_date(&"sub-0".to_string());
+        assert!(!contract.walk_due_dates(1, |_, _| true));
+    }
+}
@@ -0,0 +1,341 @@
+use near_sdk::{
+    env, json_types::U128, log, near, require, serde_json, AccountId, Gas, Promise, PromiseError,
+    PromiseOrValue,
+};
+
+use crate::events::Event;
+use crate::models::{
+    BalanceCheck, FundingSource, PaymentMethod, PriceDenomination, Subscription,
+    SubscriptionStatus,
+};
+use crate::shards::SubscriptionShards;
+use crate::{Contract, ContractExt};
+
+// Gas for restoring escrow if a withdrawal transfer fails
+const GAS_FOR_WITHDRAW_CALLBACK: Gas = Gas::from_tgas(10);
+
+/// Optional `msg` of an escrow deposit made with `ft_transfer_call`
+#[near(serializers = [json])]
+struct DepositMessage {
+    beneficiary_id: Option<AccountId>,
+    unwrap: Option<bool>,
+}
+
+#[near]
+impl Contract {
+    /// Deposits attached NEAR into the caller's escrow, which subscription payments are drawn from
+    #[payable]
+    pub fn deposit(&mut self) -> U128 {
+        let user_id = env::predecessor_account_id();
+        let amount = env::attached_deposit().as_yoctonear();
+        require!(amount > 0, "Deposit must be positive");
+
+        let balance = self.credit_escrow(&user_id, &PaymentMethod::Near, amount);
+        log!("Deposited {} NEAR to escrow for {}", amount, user_id);
+
+        U128(balance)
+    }
+
+    /// Deposits fungible tokens into escrow. Called by the token contract through
+    /// `ft_transfer_call`; the whole amount is kept unless the token is not allowed.
+    /// `msg` may be `{"beneficiary_id": ...}` to deposit for another account, as funding
+    /// sources do, and `{"unwrap": true}` to deposit wNEAR as NEAR. It is otherwise ignored
+    pub fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let message = serde_json::from_str::<DepositMessage>(&msg).ok();
+        let unwrap = message
+            .as_ref()
+            .is_some_and(|message| message.unwrap == Some(true));
+        let beneficiary_id = message
+            .and_then(|message| message.beneficiary_id)
+            .unwrap_or(sender_id);
+        let token_id = env::predecessor_account_id();
+        let payment_method = PaymentMethod::Ft {
+            token_id: token_id.clone(),
+        };
+
+        // wNEAR deposits for NEAR-priced plans are unwrapped into NEAR escrow
+        if unwrap && self.is_wnear(&payment_method) {
+            self.unwrap_deposit(beneficiary_id, amount);
+            return PromiseOrValue::Value(U128(0));
+        }
+
+        // Deposits of tokens that are not allowed are returned to the sender
+        if !self.is_payment_method_allowed(&payment_method) {
+            log!("Rejecting deposit of {}: token is not on the allowlist", token_id);
+            return PromiseOrValue::Value(amount);
+        }
+
+        self.credit_escrow(&beneficiary_id, &payment_method, amount.0);
+        log!(
+            "Deposited {} of {} to escrow for {}",
+            amount.0,
+            token_id,
+            beneficiary_id
+        );
+
+        PromiseOrValue::Value(U128(0))
+    }
+
+    /// Withdraws funds from the caller's escrow
+    pub fn withdraw(&mut self, payment_method: PaymentMethod, amount: U128) -> Promise {
+        let user_id = env::predecessor_account_id();
+        require!(amount.0 > 0, "Amount must be positive");
+        require!(
+            self.debit_escrow(&user_id, &payment_method, amount.0),
+            "Insufficient escrow balance"
+        );
+
+        log!("Withdrawing {} from escrow for {}", amount.0, user_id);
+
+        self.transfer_funds(
+            &payment_method,
+            user_id.clone(),
+            amount.0,
+            "Escrow withdrawal".to_string(),
+        )
+        .then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_WITHDRAW_CALLBACK)
+                .on_escrow_withdrawn(user_id, payment_method, amount),
+        )
+    }
+
+    /// Restores escrow whose withdrawal transfer failed
+    #[private]
+    pub fn on_escrow_withdrawn(
+        &mut self,
+        user_id: AccountId,
+        payment_method: PaymentMethod,
+        amount: U128,
+        #[callback_result] result: Result<(), PromiseError>,
+    ) -> bool {
+        if result.is_ok() {
+            return true;
+        }
+
+        log!("Escrow withdrawal failed for {}, restoring balance", user_id);
+        self.credit_escrow(&user_id, &payment_method, amount.0);
+        false
+    }
+
+    /// Gets a user's escrow balance in a given token
+    pub fn get_escrow_balance(&self, user_id: AccountId, payment_method: PaymentMethod) -> U128 {
+        self.escrow_balances
+            .get(&(user_id, payment_method))
+            .copied()
+            .unwrap_or(U128(0))
+    }
+
+    /// Emits an `insufficient_balance_warning` event for each subscription due within the due
+    /// soon window whose escrow cannot cover the upcoming charge, once per billing cycle,
+    /// checking one shard when `shard` is given. Checks the `limit` subscriptions created after
+    /// `from` in creation order; pass the returned `next_cursor` as `from` to continue
+    pub fn check_balances(
+        &mut self,
+        limit: u64,
+        shard: Option<u8>,
+        from: Option<String>,
+    ) -> BalanceCheck {
+        let now = env::block_timestamp() / 1000000000;
+
+        // Verify caller is an approved worker
+        require!(
+            self.is_verified_by_approved_codehash(),
+            "Not an approved worker"
+        );
+
+        let window_end = now + self.due_soon_window;
+        let start = from.map_or(1, |cursor| {
+            cursor
+                .parse::<u64>()
+                .expect("Invalid cursor")
+                .saturating_add(1)
+        });
+        let end = self
+            .subscription_nonce
+            .min(start.saturating_add(limit).saturating_sub(1));
+        let upcoming: Vec<Subscription> = (start..=end)
+            .filter_map(|sequence_number| self.subscription_sequence.get(&sequence_number))
+            .filter(|subscription_id| SubscriptionShards::in_shard(subscription_id, shard))
+            .filter_map(|subscription_id| self.subscriptions.get(subscription_id))
+            .filter(|subscription| {
+                matches!(subscription.status, SubscriptionStatus::Active)
+                    && subscription.next_payment_date > now
+                    && subscription.next_payment_date <= window_end
+                    && subscription.balance_warned_for != Some(subscription.next_payment_date)
+            })
+            .map(Subscription::from)
+            .collect();
+
+        let mut warned = 0;
+        for mut subscription in upcoming {
+            // Estimate USD-priced charges at the current rate, skipping them when there is none
+            if let PriceDenomination::Usd { cents } = subscription.denomination {
+                let amount = self
+                    .fresh_usd_rate(&subscription.payment_method, now)
+                    .and_then(|rate| Self::usd_cents_to_token(cents, &rate));
+                let Some(amount) = amount else {
+                    continue;
+                };
+                subscription.amount = U128(amount);
+            }
+            if self.select_funding(&subscription, true).is_some() {
+                continue;
+            }
+
+            let payment_method = subscription.payment_method.clone();
+            let balance = self.get_escrow_balance(subscription.user_id.clone(), payment_method.clone());
+            Event::InsufficientBalanceWarning {
+                subscription_id: subscription.id.clone(),
+                user_id: subscription.user_id.clone(),
+                payment_method,
+                balance,
+                amount: subscription.amount,
+                next_payment_date: subscription.next_payment_date,
+            }
+            .emit();
+            if let Some(stored) = self.subscriptions.get_mut(&subscription.id) {
+                stored.balance_warned_for = Some(subscription.next_payment_date);
+            }
+            warned += 1;
+        }
+
+        BalanceCheck {
+            warned,
+            next_cursor: (end < self.subscription_nonce).then(|| end.to_string()),
+        }
+    }
+}
+
+impl Contract {
+    /// Adds to a user's escrow balance, returning the new balance
+    pub(crate) fn credit_escrow(
+        &mut self,
+        user_id: &AccountId,
+        payment_method: &PaymentMethod,
+        amount: u128,
+    ) -> u128 {
+        let key = (user_id.clone(), payment_method.clone());
+        let balance = self.escrow_balances.get(&key).map_or(0, |balance| balance.0) + amount;
+        self.escrow_balances.insert(key, U128(balance));
+        balance
+    }
+
+    /// Whether a user's escrow holds enough for a charge
+    pub(crate) fn escrow_covers(&self, user_id: &AccountId, funding: &FundingSource) -> bool {
+        self.escrow_balances
+            .get(&(user_id.clone(), funding.payment_method.clone()))
+            .is_some_and(|balance| balance.0 >= funding.amount.0)
+    }
+
+    /// Takes `amount` from a user's escrow balance. Returns false, leaving the balance
+    /// untouched, when it does not cover the amount
+    pub(crate) fn debit_escrow(
+        &mut self,
+        user_id: &AccountId,
+        payment_method: &PaymentMethod,
+        amount: u128,
+    ) -> bool {
+        let key = (user_id.clone(), payment_method.clone());
+        let balance = self.escrow_balances.get(&key).map_or(0, |balance| balance.0);
+        if balance < amount {
+            return false;
+        }
+        if balance == amount {
+            self.escrow_balances.remove(&key);
+        } else {
+            self.escrow_balances.insert(key, U128(balance - amount));
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{json_types::U128, testing_env, AccountId, PromiseError};
+
+    use crate::models::PaymentMethod;
+    use crate::Contract;
+
+    fn set_predecessor(predecessor: AccountId) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(predecessor)
+            .build());
+    }
+
+    fn balance(contract: &Contract, user_id: AccountId) -> u128 {
+        contract
+            .get_escrow_balance(user_id, PaymentMethod::Near)
+            .0
+    }
+
+    #[test]
+    fn debits_only_covered_amounts() {
+        set_predecessor(accounts(0));
+        let mut contract = Contract::new(accounts(0));
+        contract.credit_escrow(&accounts(1), &PaymentMethod::Near, 100);
+
+        assert!(!contract.debit_escrow(&accounts(1), &PaymentMethod::Near, 150));
+        assert_eq!(balance(&contract, accounts(1)), 100);
+
+        assert!(contract.debit_escrow(&accounts(1), &PaymentMethod::Near, 60));
+        assert_eq!(balance(&contract, accounts(1)), 40);
+
+        assert!(contract.debit_escrow(&accounts(1), &PaymentMethod::Near, 40));
+        assert!(contract
+            .escrow_balances
+            .get(&(accounts(1), PaymentMethod::Near))
+            .is_none());
+    }
+
+    #[test]
+    fn restores_escrow_when_withdrawal_fails() {
+        set_predecessor(accounts(0));
+        let mut contract = Contract::new(accounts(0));
+        contract.credit_escrow(&accounts(1), &PaymentMethod::Near, 100);
+
+        set_predecessor(accounts(1));
+        contract.withdraw(PaymentMethod::Near, U128(60));
+        assert_eq!(balance(&contract, accounts(1)), 40);
+
+        set_predecessor(accounts(0));
+        assert!(!contract.on_escrow_withdrawn(
+            accounts(1),
+            PaymentMethod::Near,
+            U128(60),
+            Err(PromiseError::Failed),
+        ));
+        assert_eq!(balance(&contract, accounts(1)), 100);
+    }
+
+    #[test]
+    fn keeps_escrow_debited_when_withdrawal_succeeds() {
+        set_predecessor(accounts(0));
+        let mut contract = Contract::new(accounts(0));
+        contract.credit_escrow(&accounts(1), &PaymentMethod::Near, 100);
+
+        set_predecessor(accounts(1));
+        contract.withdraw(PaymentMethod::Near, U128(60));
+
+        set_predecessor(accounts(0));
+        assert!(contract.on_escrow_withdrawn(accounts(1), PaymentMethod::Near, U128(60), Ok(())));
+        assert_eq!(balance(&contract, accounts(1)), 40);
+    }
+
+    #[test]
+    #[should_panic(expected = "Insufficient escrow balance")]
+    fn rejects_withdrawal_beyond_escrow() {
+        set_predecessor(accounts(1));
+        let mut contract = Contract::new(accounts(0));
+        contract.credit_escrow(&accounts(1), &PaymentMethod::Near, 100);
+
+        contract.withdraw(PaymentMethod::Near, U128(101));
+    }
+}
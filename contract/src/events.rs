@@ -0,0 +1,228 @@
+use near_sdk::{json_types::U128, near, AccountId};
+
+use crate::models::{
+    CancellationReason, PaymentError, PaymentMethod, PaymentRecord, SubscriptionId,
+    WorkerConduct,
+};
+
+/// NEP-297 events emitted by the subscription contract
+#[near(event_json(standard = "ping-subscription"))]
+pub enum Event {
+    #[event_version("1.0.0")]
+    PriceChangeScheduled {
+        subscription_id: SubscriptionId,
+        user_id: AccountId,
+        merchant_id: AccountId,
+        old_amount: U128,
+        new_amount: U128,
+        effective_at: u64,
+    },
+    #[event_version("1.0.0")]
+    PriceChangeCanceled {
+        subscription_id: SubscriptionId,
+        merchant_id: AccountId,
+    },
+    #[event_version("1.0.0")]
+    PriceChangeApplied {
+        subscription_id: SubscriptionId,
+        old_amount: U128,
+        new_amount: U128,
+    },
+    #[event_version("1.0.0")]
+    PaymentDueSoon {
+        subscription_id: SubscriptionId,
+        user_id: AccountId,
+        merchant_id: AccountId,
+        amount: U128,
+        next_payment_date: u64,
+    },
+    #[event_version("1.0.0")]
+    PaymentHeld {
+        subscription_id: SubscriptionId,
+        amount: U128,
+        release_at: u64,
+    },
+    #[event_version("1.0.0")]
+    PaymentReleased {
+        subscription_id: SubscriptionId,
+        merchant_id: AccountId,
+        amount: U128,
+    },
+    #[event_version("1.0.0")]
+    PaymentRefunded {
+        subscription_id: SubscriptionId,
+        user_id: AccountId,
+        amount: U128,
+        reason: String,
+    },
+    #[event_version("1.0.0")]
+    CreditIssued {
+        user_id: AccountId,
+        merchant_id: AccountId,
+        amount: U128,
+        reason: String,
+    },
+    #[event_version("1.0.0")]
+    SubscriptionArchived {
+        subscription_id: SubscriptionId,
+        user_id: AccountId,
+        merchant_id: AccountId,
+        payments_made: u32,
+        total_spent: U128,
+        storage_freed: u64,
+    },
+    #[event_version("1.0.0")]
+    FeeAccrued {
+        subscription_id: SubscriptionId,
+        payment_method: PaymentMethod,
+        amount: U128,
+    },
+    #[event_version("1.0.0")]
+    FeesWithdrawn {
+        payment_method: PaymentMethod,
+        amount: U128,
+        receiver_id: AccountId,
+    },
+    #[event_version("1.0.0")]
+    ReferralCommissionEarned {
+        subscription_id: SubscriptionId,
+        referrer_id: AccountId,
+        payment_method: PaymentMethod,
+        amount: U128,
+    },
+    #[event_version("1.0.0")]
+    PaymentSwapped {
+        subscription_id: SubscriptionId,
+        token_in: AccountId,
+        amount_in: U128,
+        payment_method: PaymentMethod,
+        amount_out: U128,
+    },
+    #[event_version("1.0.0")]
+    PaymentSettledViaIntents {
+        subscription_id: SubscriptionId,
+        receiver_id: AccountId,
+        payment_method: PaymentMethod,
+        amount: U128,
+    },
+    #[event_version("1.0.0")]
+    InsufficientBalanceWarning {
+        subscription_id: SubscriptionId,
+        user_id: AccountId,
+        payment_method: PaymentMethod,
+        amount: U128,
+        balance: U128,
+        next_payment_date: u64,
+    },
+    #[event_version("1.0.0")]
+    PayoutsSettled {
+        merchant_id: AccountId,
+        settlement_number: u64,
+        payment_method: PaymentMethod,
+        amount: U128,
+        payment_count: u32,
+    },
+    #[event_version("1.0.0")]
+    DisputeOpened {
+        subscription_id: SubscriptionId,
+        payment_number: u32,
+        user_id: AccountId,
+        reason: String,
+    },
+    #[event_version("1.0.0")]
+    DisputeResolved {
+        subscription_id: SubscriptionId,
+        payment_number: u32,
+        refunded: bool,
+        resolved_by: AccountId,
+    },
+    #[event_version("1.0.0")]
+    PaymentSkipped {
+        subscription_id: SubscriptionId,
+        user_id: AccountId,
+        next_payment_date: u64,
+    },
+    #[event_version("1.0.0")]
+    PaymentFailed {
+        subscription_id: SubscriptionId,
+        amount: U128,
+        error: PaymentError,
+    },
+    #[event_version("1.0.0")]
+    PaymentOverdue {
+        subscription_id: SubscriptionId,
+        user_id: AccountId,
+        next_payment_date: u64,
+    },
+    #[event_version("1.0.0")]
+    SubscriptionApproved {
+        subscription_id: SubscriptionId,
+        approver_id: AccountId,
+        approved: bool,
+    },
+    #[event_version("1.0.0")]
+    SubscriptionAutoCanceled {
+        subscription_id: SubscriptionId,
+        user_id: AccountId,
+        failures: u32,
+    },
+    #[event_version("1.0.0")]
+    CyclesPrepaid {
+        subscription_id: SubscriptionId,
+        user_id: AccountId,
+        cycles: u32,
+        amount: U128,
+    },
+    #[event_version("1.0.0")]
+    CreditApplied {
+        subscription_id: SubscriptionId,
+        user_id: AccountId,
+        amount: U128,
+    },
+    #[event_version("1.0.0")]
+    SubscriptionExpired {
+        subscription_id: SubscriptionId,
+        user_id: AccountId,
+        merchant_id: AccountId,
+        reason: CancellationReason,
+    },
+    #[event_version("1.0.0")]
+    PaymentRecordArchived {
+        subscription_id: SubscriptionId,
+        record: Box<PaymentRecord>,
+    },
+    #[event_version("1.0.0")]
+    CodehashRevoked {
+        codehash: String,
+    },
+    #[event_version("1.0.0")]
+    WorkerRemoved {
+        worker_id: AccountId,
+        removed_by: AccountId,
+    },
+    #[event_version("1.0.0")]
+    WorkerSlashed {
+        worker_id: AccountId,
+        amount: U128,
+        reason: String,
+    },
+    #[event_version("1.0.0")]
+    WorkerSuspended {
+        worker_id: AccountId,
+        conduct: WorkerConduct,
+    },
+    #[event_version("1.0.0")]
+    WorkerReinstated {
+        worker_id: AccountId,
+    },
+    #[event_version("1.0.0")]
+    CodehashScheduled {
+        codehash: String,
+        active_from: u64,
+    },
+    #[event_version("1.0.0")]
+    CodehashSunset {
+        codehash: String,
+        sunset_at: u64,
+    },
+}
@@ -0,0 +1,715 @@
+use near_sdk::{
+    env, json_types::U128, log, near, require, serde_json, AccountId, Gas, Promise, PromiseError,
+    PromiseResult,
+};
+
+use crate::events::Event;
+
+use crate::models::{
+    FundingSource, PaymentError, PaymentKind, PaymentMethod, PaymentRecord, PayoutLeg,
+    PayoutPurpose, Subscription, SubscriptionId,
+};
+use crate::{Contract, ContractExt};
+
+// Basis points in 100%
+pub(crate) const BPS_DENOMINATOR: u128 = 10000;
+// Gas for confirming a payment's payout transfers
+const GAS_FOR_PAYMENT_CALLBACK: Gas = Gas::from_tgas(20);
+// Gas for restoring a worker's fees if their claim transfer fails
+const GAS_FOR_WORKER_CLAIM_CALLBACK: Gas = Gas::from_tgas(10);
+// Gas for restoring the treasury if a fee withdrawal transfer fails
+const GAS_FOR_FEE_WITHDRAWAL_CALLBACK: Gas = Gas::from_tgas(10);
+
+/// Outcome of sending a payment's payouts
+pub(crate) struct Payouts {
+    pub fee: u128,
+    pub commission: u128,
+    pub transfers: Option<Promise>, // Joined transfers still in flight, if any
+    pub transferred_legs: Vec<PayoutLeg>, // Legs paid by `transfers`, in promise order
+}
+
+#[near]
+impl Contract {
+    /// Sets the platform fee taken from every successful payment, in basis points
+    pub fn set_fee_bps(&mut self, fee_bps: u16) {
+        self.require_owner();
+        require!(
+            fee_bps as u128 <= BPS_DENOMINATOR,
+            "Fee cannot exceed 10000 basis points"
+        );
+        self.fee_bps = fee_bps;
+        log!("Platform fee set to {} bps", fee_bps);
+    }
+
+    /// Gets the platform fee in basis points
+    pub fn get_fee_bps(&self) -> u16 {
+        self.fee_bps
+    }
+
+    /// Sets the share of each platform fee paid to the worker that processed the payment, in
+    /// basis points of the fee, so running a worker is worthwhile for third parties
+    pub fn set_worker_fee_bps(&mut self, worker_fee_bps: u16) {
+        self.require_owner();
+        require!(
+            worker_fee_bps as u128 <= BPS_DENOMINATOR,
+            "Worker fee cannot exceed 10000 basis points"
+        );
+        self.worker_fee_bps = worker_fee_bps;
+        log!("Worker fee set to {} bps of the platform fee", worker_fee_bps);
+    }
+
+    /// Gets the worker's share of the platform fee in basis points
+    pub fn get_worker_fee_bps(&self) -> u16 {
+        self.worker_fee_bps
+    }
+
+    /// Gets a worker's unclaimed processing fees in a given token
+    pub fn get_worker_balance(&self, worker_id: AccountId, payment_method: PaymentMethod) -> U128 {
+        self.worker_balances
+            .get(&(worker_id, payment_method))
+            .copied()
+            .unwrap_or(U128(0))
+    }
+
+    /// Gets a worker's unclaimed processing fees in every token it has earned in, ordered by
+    /// token
+    pub fn get_worker_rewards(&self, worker_id: AccountId) -> Vec<(PaymentMethod, U128)> {
+        let mut rewards: Vec<(PaymentMethod, U128)> = self
+            .worker_reward_tokens
+            .get(&worker_id)
+            .into_iter()
+            .flatten()
+            .map(|payment_method| {
+                let balance = self.get_worker_balance(worker_id.clone(), payment_method.clone());
+                (payment_method.clone(), balance)
+            })
+            .collect();
+        rewards.sort_by(|(a, _), (b, _)| a.cmp(b));
+        rewards
+    }
+
+    /// Claims the calling worker's processing fees in a token
+    pub fn claim_worker_fees(&mut self, payment_method: PaymentMethod) -> Promise {
+        let worker_id = env::predecessor_account_id();
+        self.claim_worker_balance(&worker_id, payment_method)
+            .expect("No worker fees to claim")
+    }
+
+    /// Restores a worker's fees whose claim transfer failed
+    #[private]
+    pub fn on_worker_fees_claimed(
+        &mut self,
+        worker_id: AccountId,
+        payment_method: PaymentMethod,
+        amount: U128,
+        #[callback_result] result: Result<(), PromiseError>,
+    ) -> bool {
+        if result.is_ok() {
+            return true;
+        }
+
+        log!("Worker fee claim failed for {}, restoring balance", worker_id);
+        self.credit_worker_fee(&worker_id, &payment_method, amount.0);
+        false
+    }
+
+    /// Claims the calling worker's processing fees in every token it has earned in
+    pub fn claim_worker_rewards(&mut self) -> Promise {
+        let worker_id = env::predecessor_account_id();
+        let tokens = self
+            .worker_reward_tokens
+            .remove(&worker_id)
+            .unwrap_or_default();
+
+        tokens
+            .into_iter()
+            .filter_map(|payment_method| self.claim_worker_balance(&worker_id, payment_method))
+            .reduce(Promise::and)
+            .expect("No worker fees to claim")
+    }
+
+    /// Gets the accrued, unwithdrawn platform fees per token, ordered by token
+    pub fn get_treasury_balances(&self) -> Vec<(PaymentMethod, U128)> {
+        let mut balances: Vec<(PaymentMethod, U128)> = self
+            .treasury_balances
+            .iter()
+            .map(|(payment_method, balance)| (payment_method.clone(), *balance))
+            .collect();
+        balances.sort_by(|(a, _), (b, _)| a.cmp(b));
+        balances
+    }
+
+    /// Withdraws accrued platform fees in a token to an account
+    pub fn withdraw_fees(&mut self, token: PaymentMethod, amount: U128, to: AccountId) -> Promise {
+        self.require_owner();
+        let balance = self.treasury_balances.get(&token).map_or(0, |balance| balance.0);
+        require!(amount.0 > 0, "Amount must be positive");
+        require!(amount.0 <= balance, "Insufficient treasury balance");
+
+        if amount.0 == balance {
+            self.treasury_balances.remove(&token);
+        } else {
+            self.treasury_balances.insert(token.clone(), U128(balance - amount.0));
+        }
+
+        Event::FeesWithdrawn {
+            payment_method: token.clone(),
+            amount,
+            receiver_id: to.clone(),
+        }
+        .emit();
+        log!("Withdrawing {} in fees to {}", amount.0, to);
+
+        self.transfer_funds(&token, to.clone(), amount.0, "Platform fee withdrawal".to_string())
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_FEE_WITHDRAWAL_CALLBACK)
+                    .on_fees_withdrawn(token, amount, to),
+            )
+    }
+
+    /// Returns withdrawn fees to the treasury if their transfer failed
+    #[private]
+    pub fn on_fees_withdrawn(
+        &mut self,
+        token: PaymentMethod,
+        amount: U128,
+        to: AccountId,
+        #[callback_result] result: Result<(), PromiseError>,
+    ) -> bool {
+        if result.is_ok() {
+            return true;
+        }
+
+        log!("Fee withdrawal to {} failed, restoring treasury balance", to);
+        let balance = self.treasury_balances.get(&token).map_or(0, |balance| balance.0);
+        self.treasury_balances.insert(token, U128(balance + amount.0));
+        false
+    }
+
+    /// Gets the total fees collected from a merchant's payments in a given token
+    pub fn get_merchant_fees(&self, merchant_id: AccountId, payment_method: PaymentMethod) -> U128 {
+        self.merchant_fees
+            .get(&(merchant_id, payment_method))
+            .copied()
+            .unwrap_or(U128(0))
+    }
+
+    /// Accounts for a payment's fee and commission once its payout transfers are confirmed.
+    /// A charge is counted only if all of them went through, at the amount and cycle it was
+    /// charged for, and only advances a subscription still chargeable for that cycle.
+    /// Otherwise whatever was not delivered, along with the fee and commission held back,
+    /// returns to escrow, the subscription is left as it was, a failed record and a transfer
+    /// failure event are written and a retry is scheduled.
+    /// A payment already recorded stands, and what was not delivered is left for the merchant
+    /// to claim
+    #[private]
+    pub fn on_payment_transferred(
+        &mut self,
+        subscription_id: SubscriptionId,
+        funding: FundingSource,
+        fee: U128,
+        commission: U128,
+        transferred_legs: Vec<PayoutLeg>,
+        purpose: PayoutPurpose,
+    ) -> bool {
+        let now = env::block_timestamp() / 1000000000;
+        let undelivered: u128 = transferred_legs
+            .iter()
+            .enumerate()
+            .map(|(index, leg)| Self::undelivered_amount(index as u64, leg))
+            .sum();
+
+        let subscription = self
+            .subscriptions
+            .get_mut(&subscription_id)
+            .expect("Subscription not found");
+        let PayoutPurpose::Charge(charge) = purpose else {
+            let subscription = Subscription::from(&*subscription);
+            self.record_payout_accounting(
+                &subscription,
+                &funding.payment_method,
+                fee.0,
+                commission.0,
+            );
+            if undelivered > 0 {
+                self.credit_claimable(
+                    &subscription.merchant_id,
+                    &funding.payment_method,
+                    undelivered,
+                );
+                log!(
+                    "Payout transfer failed for subscription {}: {} left for the merchant to claim",
+                    subscription_id,
+                    undelivered
+                );
+            }
+            return undelivered == 0;
+        };
+        subscription.settlement_pending = false;
+        let subscription = subscription.clone();
+
+        if undelivered == 0 {
+            self.record_payout_accounting(
+                &subscription,
+                &funding.payment_method,
+                fee.0,
+                commission.0,
+            );
+            self.update_subscription_after_payment(
+                &subscription,
+                &subscription_id,
+                &funding,
+                &charge,
+                now,
+            );
+            return true;
+        }
+
+        let returned = undelivered + fee.0 + commission.0;
+        self.credit_escrow(&subscription.user_id, &funding.payment_method, returned);
+        self.restore_pending_credit(&subscription_id);
+        self.push_payment_record(PaymentRecord {
+            subscription_id: subscription_id.clone(),
+            kind: PaymentKind::Failed,
+            payment_number: subscription.payments_made + 1,
+            amount: funding.amount,
+            payment_method: funding.payment_method,
+            line_items: Vec::new(),
+            memo: None,
+            fee: U128(0),
+            referral_commission: U128(0),
+            payouts: Vec::new(),
+            invoice_number: None,
+            usd_rate: None,
+            amount_override: None,
+            rate_source: None,
+            swap: None,
+            timestamp: now,
+            block_height: env::block_height(),
+            block_timestamp: env::block_timestamp(),
+        });
+        self.schedule_retry(&subscription_id, now);
+
+        Event::PaymentFailed {
+            subscription_id: subscription_id.clone(),
+            amount: funding.amount,
+            error: PaymentError::TransferFailed,
+        }
+        .emit();
+        log!(
+            "Payout transfer failed for subscription {}: {} returned to escrow",
+            subscription_id,
+            returned
+        );
+        false
+    }
+}
+
+impl Contract {
+    /// Platform fee owed on a payment of `amount`
+    pub(crate) fn fee_for(&self, amount: u128) -> u128 {
+        amount * self.fee_bps as u128 / BPS_DENOMINATOR
+    }
+
+    /// Pays a subscription's merchant and its revenue split recipients, keeping the platform
+    /// fee in the treasury and crediting any referral commission once the transfers are
+    /// confirmed in `on_payment_transferred`. Payouts that only accrued in the contract count
+    /// straight away, as does a charge paid entirely by them.
+    /// Returns the fee taken
+    pub(crate) fn pay_merchant(
+        &mut self,
+        subscription: &Subscription,
+        funding: &FundingSource,
+        cycle_index: u32,
+        memo: String,
+        purpose: PayoutPurpose,
+        now: u64,
+    ) -> u128 {
+        let Payouts {
+            fee,
+            commission,
+            transfers,
+            transferred_legs,
+        } = self.send_payouts(subscription, funding, cycle_index, memo);
+
+        let Some(transfers) = transfers else {
+            self.record_payout_accounting(subscription, &funding.payment_method, fee, commission);
+            if let PayoutPurpose::Charge(charge) = &purpose {
+                self.update_subscription_after_payment(
+                    subscription,
+                    &subscription.id,
+                    funding,
+                    charge,
+                    now,
+                );
+            }
+            return fee;
+        };
+
+        if matches!(purpose, PayoutPurpose::Charge(_)) {
+            if let Some(stored) = self.subscriptions.get_mut(&subscription.id) {
+                stored.settlement_pending = true;
+            }
+        }
+        transfers.then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_PAYMENT_CALLBACK)
+                .on_payment_transferred(
+                    subscription.id.clone(),
+                    funding.clone(),
+                    U128(fee),
+                    U128(commission),
+                    transferred_legs,
+                    purpose,
+                ),
+        );
+        fee
+    }
+
+    /// Sends a payment's payout legs to the merchant and its revenue split recipients, holding
+    /// back the platform fee and referral commission without accounting for them yet
+    fn send_payouts(
+        &mut self,
+        subscription: &Subscription,
+        funding: &FundingSource,
+        cycle_index: u32,
+        memo: String,
+    ) -> Payouts {
+        let amount = funding.amount.0;
+        let fee = self.fee_for(amount);
+        let payment_method = &funding.payment_method;
+        let commission = self.referral_commission_for(subscription, amount - fee);
+
+        // Merchants that opted in are paid with ft_transfer_call so their contract can fulfil the cycle,
+        // or accrue to a balance claimed or settled per window instead of being paid per charge
+        let payout_address = self.payout_address_for(&subscription.merchant_id);
+        let use_transfer_call = self
+            .merchant_settings
+            .get(&subscription.merchant_id)
+            .is_some_and(|settings| settings.ft_transfer_call);
+        let claimable = self.has_claimable_payouts(&subscription.merchant_id);
+
+        let mut transfers: Option<Promise> = None;
+        let mut transferred_legs = Vec::new();
+        for leg in self.payout_legs(&subscription.merchant_id, amount - fee - commission) {
+            let transfer = match payment_method {
+                _ if claimable && leg.recipient == payout_address => {
+                    self.credit_claimable(&subscription.merchant_id, payment_method, leg.amount.0);
+                    self.track_settlement(&subscription.merchant_id, payment_method);
+                    continue;
+                }
+                PaymentMethod::Ft { token_id }
+                    if use_transfer_call && leg.recipient == payout_address =>
+                {
+                    let msg = Self::payment_notification(subscription, cycle_index);
+                    self.ft_transfer_call(
+                        token_id,
+                        leg.recipient.clone(),
+                        leg.amount.0,
+                        memo.clone(),
+                        msg,
+                    )
+                }
+                _ => self.transfer_funds(
+                    payment_method,
+                    leg.recipient.clone(),
+                    leg.amount.0,
+                    memo.clone(),
+                ),
+            };
+            transfers = Some(match transfers {
+                Some(joined) => joined.and(transfer),
+                None => transfer,
+            });
+            transferred_legs.push(leg);
+        }
+
+        Payouts {
+            fee,
+            commission,
+            transfers,
+            transferred_legs,
+        }
+    }
+
+    /// Keeps a payment's platform fee in the treasury and credits its referral commission
+    fn record_payout_accounting(
+        &mut self,
+        subscription: &Subscription,
+        payment_method: &PaymentMethod,
+        fee: u128,
+        commission: u128,
+    ) {
+        self.credit_referrer(subscription, payment_method, commission);
+        self.accrue_fee(subscription, payment_method, fee);
+    }
+
+    /// Part of a transferred payout leg that did not reach its recipient, from the result of
+    /// the promise at `index`. `ft_transfer_call` reports how much the receiver kept
+    pub(crate) fn undelivered_amount(index: u64, leg: &PayoutLeg) -> u128 {
+        match env::promise_result(index) {
+            PromiseResult::Successful(value) => serde_json::from_slice::<U128>(&value)
+                .map_or(0, |used| leg.amount.0.saturating_sub(used.0)),
+            _ => leg.amount.0,
+        }
+    }
+
+    /// Transfers a worker's whole unclaimed balance in a token to it, restoring the balance if
+    /// the transfer fails. `None` when there is nothing to claim
+    fn claim_worker_balance(
+        &mut self,
+        worker_id: &AccountId,
+        payment_method: PaymentMethod,
+    ) -> Option<Promise> {
+        let key = (worker_id.clone(), payment_method.clone());
+        let balance = self.worker_balances.remove(&key).map_or(0, |balance| balance.0);
+        if let Some(tokens) = self.worker_reward_tokens.get_mut(worker_id) {
+            tokens.retain(|token| *token != payment_method);
+        }
+        if balance == 0 {
+            return None;
+        }
+
+        log!("Worker {} claiming {} in fees", worker_id, balance);
+        let transfer = self
+            .transfer_funds(
+                &payment_method,
+                worker_id.clone(),
+                balance,
+                "Worker fee claim".to_string(),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_WORKER_CLAIM_CALLBACK)
+                    .on_worker_fees_claimed(worker_id.clone(), payment_method, U128(balance)),
+            );
+        Some(transfer)
+    }
+
+    /// Adds to a worker's unclaimed processing fees in a token
+    fn credit_worker_fee(
+        &mut self,
+        worker_id: &AccountId,
+        payment_method: &PaymentMethod,
+        fee: u128,
+    ) {
+        let key = (worker_id.clone(), payment_method.clone());
+        let earned = self.worker_balances.get(&key).map_or(0, |earned| earned.0);
+        self.worker_balances.insert(key, U128(earned + fee));
+
+        let tokens = self.worker_reward_tokens.entry(worker_id.clone()).or_default();
+        if !tokens.contains(payment_method) {
+            tokens.push(payment_method.clone());
+        }
+    }
+
+    /// Adds a platform fee taken from a subscription's payment to the treasury
+    pub(crate) fn accrue_fee(
+        &mut self,
+        subscription: &Subscription,
+        payment_method: &PaymentMethod,
+        fee: u128,
+    ) {
+        if fee == 0 {
+            return;
+        }
+        self.record_fee_collected(payment_method, fee);
+
+        // The worker that processed the payment earns its share, the treasury keeps the rest
+        let worker_fee = match &subscription.processed_by {
+            Some(worker_id) => {
+                let worker_fee = fee * self.worker_fee_bps as u128 / BPS_DENOMINATOR;
+                if worker_fee > 0 {
+                    self.credit_worker_fee(worker_id, payment_method, worker_fee);
+                }
+                worker_fee
+            }
+            None => 0,
+        };
+
+        let balance = self.treasury_balances.get(payment_method).map_or(0, |balance| balance.0);
+        self.treasury_balances
+            .insert(payment_method.clone(), U128(balance + fee - worker_fee));
+
+        let key = (subscription.merchant_id.clone(), payment_method.clone());
+        let collected = self.merchant_fees.get(&key).map_or(0, |fees| fees.0);
+        self.merchant_fees.insert(key, U128(collected + fee));
+
+        Event::FeeAccrued {
+            subscription_id: subscription.id.clone(),
+            payment_method: payment_method.clone(),
+            amount: U128(fee),
+        }
+        .emit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{json_types::U128, testing_env, AccountId, PromiseError};
+
+    use crate::models::{
+        FundingSource, PaymentMethod, PayoutPurpose, Subscription, SubscriptionFrequency,
+        SubscriptionStatus, SubscriptionStatusV0, SubscriptionV0,
+    };
+    use crate::Contract;
+
+    fn set_predecessor(predecessor: AccountId) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(predecessor)
+            .build());
+    }
+
+    /// A NEAR subscription of `accounts(1)` to `accounts(2)`, last processed by `processed_by`
+    fn subscription(processed_by: Option<AccountId>) -> Subscription {
+        Subscription {
+            processed_by,
+            ..SubscriptionV0 {
+                id: "sub-1".to_string(),
+                user_id: accounts(1),
+                merchant_id: accounts(2),
+                amount: U128(10000),
+                frequency: SubscriptionFrequency::Monthly,
+                next_payment_date: 0,
+                status: SubscriptionStatusV0::Active,
+                created_at: 0,
+                updated_at: 0,
+                payment_method: PaymentMethod::Near,
+                max_payments: None,
+                payments_made: 0,
+                end_date: None,
+            }
+            .into()
+        }
+    }
+
+    fn treasury_balance(contract: &Contract) -> u128 {
+        contract
+            .treasury_balances
+            .get(&PaymentMethod::Near)
+            .map_or(0, |balance| balance.0)
+    }
+
+    #[test]
+    fn takes_fee_in_basis_points() {
+        set_predecessor(accounts(0));
+        let mut contract = Contract::new(accounts(0));
+        contract.set_fee_bps(250);
+
+        assert_eq!(contract.fee_for(10000), 250);
+        assert_eq!(contract.fee_for(39), 0);
+    }
+
+    #[test]
+    fn splits_fee_between_worker_and_treasury() {
+        set_predecessor(accounts(0));
+        let mut contract = Contract::new(accounts(0));
+        contract.set_worker_fee_bps(2000);
+
+        contract.accrue_fee(&subscription(Some(accounts(3))), &PaymentMethod::Near, 1000);
+        assert_eq!(
+            contract
+                .get_worker_balance(accounts(3), PaymentMethod::Near)
+                .0,
+            200
+        );
+        assert_eq!(treasury_balance(&contract), 800);
+
+        // Without a worker to pay, the treasury keeps the whole fee
+        contract.accrue_fee(&subscription(None), &PaymentMethod::Near, 1000);
+        assert_eq!(treasury_balance(&contract), 1800);
+        assert_eq!(
+            contract
+                .get_merchant_fees(accounts(2), PaymentMethod::Near)
+                .0,
+            2000
+        );
+    }
+
+    #[test]
+    fn restores_treasury_when_fee_withdrawal_fails() {
+        set_predecessor(accounts(0));
+        let mut contract = Contract::new(accounts(0));
+        contract
+            .treasury_balances
+            .insert(PaymentMethod::Near, U128(1000));
+
+        contract.withdraw_fees(PaymentMethod::Near, U128(400), accounts(4));
+        assert_eq!(treasury_balance(&contract), 600);
+
+        assert!(!contract.on_fees_withdrawn(
+            PaymentMethod::Near,
+            U128(400),
+            accounts(4),
+            Err(PromiseError::Failed),
+        ));
+        assert_eq!(treasury_balance(&contract), 1000);
+    }
+
+    #[test]
+    fn restores_worker_fees_when_claim_fails() {
+        set_predecessor(accounts(0));
+        let mut contract = Contract::new(accounts(0));
+        contract.credit_worker_fee(&accounts(3), &PaymentMethod::Near, 300);
+
+        set_predecessor(accounts(3));
+        contract.claim_worker_fees(PaymentMethod::Near);
+        assert!(contract.get_worker_rewards(accounts(3)).is_empty());
+
+        set_predecessor(accounts(0));
+        assert!(!contract.on_worker_fees_claimed(
+            accounts(3),
+            PaymentMethod::Near,
+            U128(300),
+            Err(PromiseError::Failed),
+        ));
+        assert_eq!(
+            contract.get_worker_rewards(accounts(3)),
+            vec![(PaymentMethod::Near, U128(300))]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "No worker fees to claim")]
+    fn rejects_claim_without_fees() {
+        set_predecessor(accounts(3));
+        let mut contract = Contract::new(accounts(0));
+
+        contract.claim_worker_rewards();
+    }
+
+    #[test]
+    fn keeps_canceled_subscription_canceled_when_charge_settles() {
+        set_predecessor(accounts(0));
+        let mut contract = Contract::new(accounts(0));
+        let subscription = subscription(None);
+        contract
+            .subscriptions
+            .insert(subscription.id.clone(), subscription.clone().into());
+        let charge = contract.charge_details(&subscription);
+
+        // The subscriber cancels while the charge's payouts are in flight
+        contract.transition_subscription(&subscription.id, SubscriptionStatus::Canceled, 0);
+        let funding = FundingSource {
+            payment_method: PaymentMethod::Near,
+            amount: U128(10000),
+        };
+        assert!(contract.on_payment_transferred(
+            subscription.id.clone(),
+            funding,
+            U128(0),
+            U128(0),
+            Vec::new(),
+            PayoutPurpose::Charge(charge),
+        ));
+
+        let stored = contract.get_subscription(subscription.id).unwrap();
+        assert_eq!(stored.status, SubscriptionStatus::Canceled);
+        assert_eq!(stored.payments_made, 1);
+        assert_eq!(stored.total_spent, U128(10000));
+        assert_eq!(stored.cycle_index, 0);
+    }
+}
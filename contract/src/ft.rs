@@ -0,0 +1,221 @@
+use near_sdk::{
+    env, ext_contract, json_types::U128, log, near, serde_json, AccountId, Gas, NearToken,
+    Promise, PromiseError,
+};
+
+use crate::models::Subscription;
+use crate::{Contract, ContractExt};
+
+// Gas for storage calls on a token contract
+const GAS_FOR_STORAGE_CALL: Gas = Gas::from_tgas(10);
+// Gas for handling a token's storage_balance_of response, including registering the account
+const GAS_FOR_STORAGE_CALLBACK: Gas = Gas::from_tgas(40);
+// Gas for handling a token's storage_deposit response
+const GAS_FOR_STORAGE_DEPOSIT_CALLBACK: Gas = Gas::from_tgas(10);
+// Gas for ft_transfer_call, including the receiver's ft_on_transfer hook
+const GAS_FOR_FT_TRANSFER_CALL: Gas = Gas::from_tgas(50);
+
+/// NEP-145 storage balance, as returned by token contracts
+#[near(serializers = [json])]
+pub struct StorageBalance {
+    pub total: U128,
+    pub available: U128,
+}
+
+/// NEP-148 token metadata; only the decimals are used
+#[near(serializers = [json])]
+pub struct FungibleTokenMetadata {
+    pub decimals: u8,
+}
+
+#[ext_contract(ext_ft)]
+pub trait FungibleToken {
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> U128;
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance>;
+    fn ft_metadata(&self) -> FungibleTokenMetadata;
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance;
+}
+
+#[near]
+impl Contract {
+    /// Checks that an account (the caller's payout address by default) is registered on a token,
+    /// since FT payments to unregistered accounts fail. Unregistered accounts are registered with
+    /// the attached deposit, returning whatever the token does not keep; without one the check
+    /// fails and payments in the token are refused
+    #[payable]
+    pub fn ensure_ft_registration(
+        &mut self,
+        token_id: AccountId,
+        account_id: Option<AccountId>,
+    ) -> Promise {
+        let merchant_id = self.require_merchant();
+        let account_id = account_id.unwrap_or_else(|| self.payout_address_for(&merchant_id));
+        let deposit = U128(env::attached_deposit().as_yoctonear());
+
+        ext_ft::ext(token_id.clone())
+            .with_static_gas(GAS_FOR_STORAGE_CALL)
+            .storage_balance_of(account_id.clone())
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_STORAGE_CALLBACK)
+                    .on_storage_balance_of(merchant_id, token_id, account_id, deposit),
+            )
+    }
+
+    /// Records the registration of an account already registered on the token, or registers it
+    /// with the attached deposit and records it once the token confirms. Returns whether the
+    /// account was already registered
+    #[private]
+    pub fn on_storage_balance_of(
+        &mut self,
+        merchant_id: AccountId,
+        token_id: AccountId,
+        account_id: AccountId,
+        deposit: U128,
+        #[callback_result] result: Result<Option<StorageBalance>, PromiseError>,
+    ) -> bool {
+        match result {
+            Ok(Some(_)) => {
+                if deposit.0 > 0 {
+                    Promise::new(merchant_id).transfer(NearToken::from_yoctonear(deposit.0));
+                }
+            }
+            Ok(None) if deposit.0 > 0 => {
+                ext_ft::ext(token_id.clone())
+                    .with_attached_deposit(NearToken::from_yoctonear(deposit.0))
+                    .with_static_gas(GAS_FOR_STORAGE_CALL)
+                    .storage_deposit(Some(account_id.clone()), Some(true))
+                    .then(
+                        Self::ext(env::current_account_id())
+                            .with_static_gas(GAS_FOR_STORAGE_DEPOSIT_CALLBACK)
+                            .on_storage_deposit(merchant_id, token_id, account_id, deposit),
+                    );
+                return false;
+            }
+            _ => {
+                if deposit.0 > 0 {
+                    Promise::new(merchant_id).transfer(NearToken::from_yoctonear(deposit.0));
+                }
+                log!(
+                    "{} is not registered on {}; attach a storage deposit to register it",
+                    account_id,
+                    token_id
+                );
+                return false;
+            }
+        }
+
+        self.ft_registrations
+            .insert((token_id.clone(), account_id.clone()));
+        log!("{} is registered on {}", account_id, token_id);
+        true
+    }
+
+    /// Records the registration once the token confirms the storage deposit, and forwards
+    /// whatever the token refunded to the merchant: the excess over the storage balance it
+    /// kept, or the whole deposit if the registration failed. Returns whether it succeeded
+    #[private]
+    pub fn on_storage_deposit(
+        &mut self,
+        merchant_id: AccountId,
+        token_id: AccountId,
+        account_id: AccountId,
+        deposit: U128,
+        #[callback_result] result: Result<StorageBalance, PromiseError>,
+    ) -> bool {
+        let Ok(storage_balance) = result else {
+            log!(
+                "Registering {} on {} failed, refunding the deposit",
+                account_id,
+                token_id
+            );
+            Promise::new(merchant_id).transfer(NearToken::from_yoctonear(deposit.0));
+            return false;
+        };
+
+        let refund = deposit.0.saturating_sub(storage_balance.total.0);
+        if refund > 0 {
+            Promise::new(merchant_id).transfer(NearToken::from_yoctonear(refund));
+        }
+        self.ft_registrations
+            .insert((token_id.clone(), account_id.clone()));
+        log!("{} is registered on {}", account_id, token_id);
+        true
+    }
+
+    /// Opts the calling merchant into being paid with `ft_transfer_call`, whose `msg` carries the
+    /// subscription and cycle so the receiving contract can fulfil it atomically
+    pub fn set_ft_transfer_call(&mut self, enabled: bool) {
+        let merchant_id = self.require_merchant();
+        let mut settings = self.get_merchant_settings(merchant_id.clone());
+        settings.ft_transfer_call = enabled;
+        self.merchant_settings.insert(merchant_id.clone(), settings);
+        log!("ft_transfer_call for {} set to {}", merchant_id, enabled);
+    }
+
+    /// Whether an account has been confirmed as registered on a token
+    pub fn is_ft_registered(&self, token_id: AccountId, account_id: AccountId) -> bool {
+        self.ft_registrations.contains(&(token_id, account_id))
+    }
+}
+
+impl Contract {
+    /// Sends tokens held by the contract with `ft_transfer_call`. Whatever the receiver does not
+    /// accept is returned to this contract by the token
+    pub(crate) fn ft_transfer_call(
+        &self,
+        token_id: &AccountId,
+        receiver_id: AccountId,
+        amount: u128,
+        memo: String,
+        msg: String,
+    ) -> Promise {
+        ext_ft::ext(token_id.clone())
+            .with_attached_deposit(NearToken::from_yoctonear(1))
+            .with_static_gas(GAS_FOR_FT_TRANSFER_CALL)
+            .ft_transfer_call(receiver_id, U128(amount), Some(memo), msg)
+    }
+
+    /// `msg` passed to merchants paid with `ft_transfer_call`, identifying the cycle paid for
+    pub(crate) fn payment_notification(subscription: &Subscription, cycle_index: u32) -> String {
+        serde_json::json!({
+            "subscription_id": subscription.id,
+            "user_id": subscription.user_id,
+            "cycle_index": cycle_index,
+        })
+        .to_string()
+    }
+
+    /// Whether every account a merchant's payments are paid out to is registered on a token
+    pub(crate) fn payout_accounts_registered(&self, merchant_id: &AccountId, token_id: &AccountId) -> bool {
+        let split_recipients = self
+            .merchant_settings
+            .get(merchant_id)
+            .map(|settings| {
+                settings
+                    .revenue_splits
+                    .iter()
+                    .map(|split| split.recipient.clone())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+
+        split_recipients
+            .into_iter()
+            .chain(std::iter::once(self.payout_address_for(merchant_id)))
+            .all(|account_id| {
+                self.ft_registrations
+                    .contains(&(token_id.clone(), account_id))
+            })
+    }
+}
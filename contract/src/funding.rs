@@ -0,0 +1,98 @@
+use near_sdk::{env, log, near, require};
+
+use crate::models::{FundingSource, PaymentMethod, Subscription, SubscriptionId};
+use crate::{Contract, ContractExt};
+
+// Most alternative tokens a subscription can be priced or funded in
+const MAX_FUNDING_SOURCES: usize = 5;
+
+#[near]
+impl Contract {
+    /// Sets the prices a merchant accepts for a subscription in tokens other than its own.
+    /// Subscribers can fall back to these tokens when their escrow in the main one runs short
+    pub fn set_token_prices(&mut self, subscription_id: SubscriptionId, prices: Vec<FundingSource>) {
+        let merchant_id = env::predecessor_account_id();
+        require!(prices.len() <= MAX_FUNDING_SOURCES, "Too many token prices");
+        for price in prices.iter() {
+            self.assert_payment_method_allowed(&price.payment_method);
+            self.assert_valid_token_amount(&price.payment_method, price.amount);
+            self.assert_above_min_charge(&merchant_id, &price.payment_method, price.amount);
+        }
+
+        let subscription = self
+            .subscriptions
+            .get_mut(&subscription_id)
+            .expect("Subscription not found");
+        require!(
+            subscription.merchant_id == merchant_id,
+            "Not authorized to update this subscription"
+        );
+        require!(
+            prices
+                .iter()
+                .all(|price| price.payment_method != subscription.payment_method),
+            "Token prices cannot include the subscription's own token"
+        );
+        subscription.token_prices = prices;
+        subscription.updated_at = env::block_timestamp() / 1000000000;
+
+        log!("Token prices updated for subscription: {}", subscription_id);
+    }
+
+    /// Sets the tokens, in order, a subscription falls back to when escrow in its own token
+    /// cannot cover a charge. Only tokens the merchant has priced are used
+    pub fn set_funding_order(
+        &mut self,
+        subscription_id: SubscriptionId,
+        payment_methods: Vec<PaymentMethod>,
+    ) {
+        let user_id = env::predecessor_account_id();
+        require!(
+            payment_methods.len() <= MAX_FUNDING_SOURCES,
+            "Too many funding tokens"
+        );
+
+        let subscription = self
+            .subscriptions
+            .get_mut(&subscription_id)
+            .expect("Subscription not found");
+        require!(
+            subscription.user_id == user_id,
+            "Not authorized to update this subscription"
+        );
+        subscription.funding_order = payment_methods;
+        subscription.updated_at = env::block_timestamp() / 1000000000;
+
+        log!("Funding order updated for subscription: {}", subscription_id);
+    }
+}
+
+impl Contract {
+    /// Picks what to charge a subscription in: its own token when the subscriber's escrow covers
+    /// it, otherwise (if allowed) the first fallback token the merchant priced and escrow covers
+    pub(crate) fn select_funding(
+        &self,
+        subscription: &Subscription,
+        allow_fallback: bool,
+    ) -> Option<FundingSource> {
+        let primary = subscription.funding();
+        if self.escrow_covers(&subscription.user_id, &primary) {
+            return Some(primary);
+        }
+        if !allow_fallback {
+            return None;
+        }
+
+        subscription
+            .funding_order
+            .iter()
+            .filter_map(|payment_method| {
+                subscription
+                    .token_prices
+                    .iter()
+                    .find(|price| price.payment_method == *payment_method)
+            })
+            .find(|price| self.escrow_covers(&subscription.user_id, price))
+            .cloned()
+    }
+}
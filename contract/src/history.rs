@@ -0,0 +1,93 @@
+use crate::events::Event;
+use crate::models::{PaymentRecord, SubscriptionId};
+use crate::Contract;
+
+// Most payment records kept on chain per subscription; older ones are only in events
+pub const MAX_PAYMENT_HISTORY: usize = 50;
+
+impl Contract {
+    /// Iterates a subscription's stored payment records, oldest first. Once the history is
+    /// full it is a ring buffer, with the oldest record at the head position
+    pub(crate) fn payment_records(
+        &self,
+        subscription_id: &SubscriptionId,
+    ) -> impl DoubleEndedIterator<Item = &PaymentRecord> {
+        let history = self
+            .payment_history
+            .get(subscription_id)
+            .map_or(&[][..], |history| history.as_slice());
+        let head = self
+            .payment_history_head(subscription_id)
+            .min(history.len());
+        let (newest, oldest) = history.split_at(head);
+        oldest.iter().chain(newest.iter())
+    }
+
+    /// The most recently stored payment record of a subscription
+    pub(crate) fn latest_payment_record_mut(
+        &mut self,
+        subscription_id: &SubscriptionId,
+    ) -> Option<&mut PaymentRecord> {
+        let head = self.payment_history_head(subscription_id);
+        let history = self.payment_history.get_mut(subscription_id)?;
+        let index = head.checked_sub(1).unwrap_or(history.len().checked_sub(1)?);
+        history.get_mut(index)
+    }
+
+    /// Stores a payment record, evicting the oldest once the history is full. Evicted
+    /// records are emitted first so indexers keep the full history
+    pub(crate) fn push_payment_record(&mut self, record: PaymentRecord) {
+        let subscription_id = record.subscription_id.clone();
+        let mut head = self.payment_history_head(&subscription_id);
+        let mut history = self
+            .payment_history
+            .get(&subscription_id)
+            .cloned()
+            .unwrap_or_default();
+
+        // Histories written before the cap was introduced are trimmed down to it first
+        if history.len() > MAX_PAYMENT_HISTORY {
+            let excess = history.len() - MAX_PAYMENT_HISTORY;
+            for evicted in history.drain(..excess) {
+                Self::archive_payment_record(evicted);
+            }
+            head = 0;
+        }
+
+        if history.len() < MAX_PAYMENT_HISTORY {
+            history.push(record);
+        } else {
+            let evicted = std::mem::replace(&mut history[head], record);
+            Self::archive_payment_record(evicted);
+            head = (head + 1) % MAX_PAYMENT_HISTORY;
+        }
+
+        if head == 0 {
+            self.payment_history_heads.remove(&subscription_id);
+        } else {
+            self.payment_history_heads
+                .insert(subscription_id.clone(), head as u32);
+        }
+        self.payment_history.insert(subscription_id, history);
+    }
+
+    /// Removes a subscription's stored payment history
+    pub(crate) fn remove_payment_history(&mut self, subscription_id: &SubscriptionId) {
+        self.payment_history.remove(subscription_id);
+        self.payment_history_heads.remove(subscription_id);
+    }
+
+    fn payment_history_head(&self, subscription_id: &SubscriptionId) -> usize {
+        self.payment_history_heads
+            .get(subscription_id)
+            .map_or(0, |head| *head as usize)
+    }
+
+    fn archive_payment_record(record: PaymentRecord) {
+        Event::PaymentRecordArchived {
+            subscription_id: record.subscription_id.clone(),
+            record: Box::new(record),
+        }
+        .emit();
+    }
+}
@@ -0,0 +1,64 @@
+use near_sdk::{log, near, require, serde_json, Gas, NearToken, Promise};
+
+use crate::models::{PaymentHook, PaymentRecord, Subscription};
+use crate::{Contract, ContractExt};
+
+// Gas given to a merchant's payment hook. Kept low so hooks stay cheap for whoever processes
+// the payment; hooks needing more should do their work in a later transaction
+const GAS_FOR_PAYMENT_HOOK: Gas = Gas::from_tgas(5);
+// Longest method name a payment hook can call
+const MAX_HOOK_METHOD_NAME_LENGTH: usize = 64;
+
+#[near]
+impl Contract {
+    /// Sets the contract method called after each of the calling merchant's successful payments,
+    /// with the subscription and payment record as `{"subscription": ..., "payment": ...}`.
+    /// `None` removes it
+    pub fn set_payment_hook(&mut self, payment_hook: Option<PaymentHook>) {
+        let merchant_id = self.require_merchant();
+        if let Some(payment_hook) = &payment_hook {
+            require!(
+                !payment_hook.method_name.is_empty()
+                    && payment_hook.method_name.len() <= MAX_HOOK_METHOD_NAME_LENGTH,
+                "Invalid payment hook method name"
+            );
+        }
+
+        let mut settings = self.get_merchant_settings(merchant_id.clone());
+        settings.payment_hook = payment_hook;
+        self.merchant_settings.insert(merchant_id.clone(), settings);
+        log!("Payment hook updated for {}", merchant_id);
+    }
+}
+
+impl Contract {
+    /// Calls the merchant's payment hook, if any, for a successful payment. The call is its own
+    /// receipt with no callback, so a failing or misbehaving hook cannot affect the payment
+    pub(crate) fn call_payment_hook(&self, subscription: &Subscription, record: &PaymentRecord) {
+        let Some(payment_hook) = self
+            .merchant_settings
+            .get(&subscription.merchant_id)
+            .and_then(|settings| settings.payment_hook.clone())
+        else {
+            return;
+        };
+
+        let args = serde_json::json!({
+            "subscription": subscription,
+            "payment": record,
+        })
+        .to_string()
+        .into_bytes();
+        Promise::new(payment_hook.receiver_id).function_call(
+            payment_hook.method_name,
+            args,
+            NearToken::from_yoctonear(0),
+            GAS_FOR_PAYMENT_HOOK,
+        );
+        log!(
+            "Payment hook called for subscription {} payment {}",
+            record.subscription_id,
+            record.payment_number
+        );
+    }
+}
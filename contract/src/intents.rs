@@ -0,0 +1,176 @@
+use near_sdk::{
+    env, json_types::U128, log, near, require, serde_json, AccountId, Gas, NearToken,
+    PromiseError,
+};
+
+use crate::events::Event;
+use crate::ft::ext_ft;
+use crate::models::{
+    ChargeDetails, FundingSource, PaymentMethod, PaymentResult, Subscription, SubscriptionId,
+};
+use crate::{Contract, ContractExt};
+
+// Gas for depositing into the intents contract with ft_transfer_call
+const GAS_FOR_INTENTS_DEPOSIT: Gas = Gas::from_tgas(50);
+// Gas for confirming an intents deposit and recording the charge
+const GAS_FOR_INTENTS_CALLBACK: Gas = Gas::from_tgas(30);
+
+#[near]
+impl Contract {
+    /// Sets the NEAR Intents verifier contract merchants can be settled through
+    pub fn set_intents_contract(&mut self, intents_contract: Option<AccountId>) {
+        self.require_owner();
+        self.intents_contract = intents_contract;
+        log!("Intents contract updated");
+    }
+
+    /// Opts the calling merchant into receiving FT payments as deposits to its payout address on
+    /// the intents contract, where solvers can convert and deliver them. Not available together
+    /// with revenue splits, claimable payouts or settlement windows
+    pub fn set_intents_settlement(&mut self, enabled: bool) {
+        let merchant_id = self.require_merchant();
+        let mut settings = self.get_merchant_settings(merchant_id.clone());
+        require!(
+            !enabled || settings.revenue_splits.is_empty(),
+            "Intents settlement cannot be combined with revenue splits"
+        );
+        require!(
+            !enabled || !settings.claimable_payouts,
+            "Intents settlement cannot be combined with claimable payouts"
+        );
+        require!(
+            !enabled || settings.settlement_window.is_none(),
+            "Intents settlement cannot be combined with settlement windows"
+        );
+        settings.intents_settlement = enabled;
+        self.merchant_settings.insert(merchant_id.clone(), settings);
+        log!("Intents settlement for {} set to {}", merchant_id, enabled);
+    }
+
+    /// Records a charge once the intents contract has accepted the deposit, or returns the funds
+    /// to escrow and schedules a retry if it did not
+    #[private]
+    pub fn on_intents_settlement(
+        &mut self,
+        subscription_id: SubscriptionId,
+        funding: FundingSource,
+        fee: U128,
+        commission: U128,
+        charge: ChargeDetails,
+        #[callback_result] used: Result<U128, PromiseError>,
+    ) -> bool {
+        let now = env::block_timestamp() / 1000000000;
+        let subscription = self
+            .subscriptions
+            .get_mut(&subscription_id)
+            .expect("Subscription not found");
+        subscription.settlement_pending = false;
+        let subscription = subscription.clone();
+
+        let deposited = funding.amount.0 - fee.0 - commission.0;
+        let used = used.map_or(0, |used| used.0).min(deposited);
+        if used < deposited {
+            self.credit_escrow(
+                &subscription.user_id,
+                &funding.payment_method,
+                funding.amount.0 - used,
+            );
+            self.restore_pending_credit(&subscription_id);
+            log!("Intents deposit failed for subscription: {}", subscription_id);
+            self.schedule_retry(&subscription_id, now);
+            return false;
+        }
+
+        self.accrue_fee(&subscription, &funding.payment_method, fee.0);
+        self.credit_referrer(&subscription, &funding.payment_method, commission.0);
+        self.update_subscription_after_payment(
+            &subscription,
+            &subscription_id,
+            &funding,
+            &charge,
+            now,
+        );
+
+        Event::PaymentSettledViaIntents {
+            subscription_id,
+            receiver_id: self.payout_address_for(&subscription.merchant_id),
+            payment_method: funding.payment_method,
+            amount: U128(deposited),
+        }
+        .emit();
+
+        true
+    }
+}
+
+impl Contract {
+    /// Whether a charge in `payment_method` is settled through the intents contract
+    pub(crate) fn settles_via_intents(&self, merchant_id: &AccountId, payment_method: &PaymentMethod) -> bool {
+        self.intents_contract.is_some()
+            && matches!(payment_method, PaymentMethod::Ft { .. })
+            && self
+                .merchant_settings
+                .get(merchant_id)
+                .is_some_and(|settings| settings.intents_settlement)
+    }
+
+    /// Deposits an escrowed charge, less fee and commission, to the merchant's account on the
+    /// intents contract. The charge is recorded once `on_intents_settlement` confirms it
+    pub(crate) fn start_intents_settlement(
+        &mut self,
+        subscription: &Subscription,
+        funding: FundingSource,
+        charge: ChargeDetails,
+        now: u64,
+    ) -> PaymentResult {
+        let intents_contract = self
+            .intents_contract
+            .clone()
+            .expect("Intents contract not configured");
+        let PaymentMethod::Ft { token_id } = funding.payment_method.clone() else {
+            env::panic_str("Intents settlement requires a fungible token");
+        };
+
+        let fee = self.fee_for(funding.amount.0);
+        let commission = self.referral_commission_for(subscription, funding.amount.0 - fee);
+        let deposit = funding.amount.0 - fee - commission;
+        let receiver_id = self.payout_address_for(&subscription.merchant_id);
+
+        if let Some(stored) = self.subscriptions.get_mut(&subscription.id) {
+            stored.settlement_pending = true;
+        }
+
+        let msg = serde_json::json!({ "receiver_id": receiver_id }).to_string();
+        let memo = self.payment_memo(subscription, subscription.payments_made + 1);
+        ext_ft::ext(token_id)
+            .with_attached_deposit(NearToken::from_yoctonear(1))
+            .with_static_gas(GAS_FOR_INTENTS_DEPOSIT)
+            .ft_transfer_call(intents_contract, U128(deposit), Some(memo), msg)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_INTENTS_CALLBACK)
+                    .on_intents_settlement(
+                        subscription.id.clone(),
+                        funding.clone(),
+                        U128(fee),
+                        U128(commission),
+                        charge,
+                    ),
+            );
+
+        log!(
+            "Depositing {} to intents account of {} for subscription {}",
+            deposit,
+            receiver_id,
+            subscription.id
+        );
+
+        PaymentResult {
+            success: true,
+            subscription_id: subscription.id.clone(),
+            amount: funding.amount,
+            timestamp: now,
+            error: None,
+        }
+    }
+}
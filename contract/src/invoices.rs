@@ -0,0 +1,55 @@
+use near_sdk::{near, AccountId};
+
+use crate::models::{Invoice, PaymentRecord, Subscription};
+use crate::{Contract, ContractExt};
+
+#[near]
+impl Contract {
+    /// Gets one of a merchant's invoices by number
+    pub fn get_invoice(&self, merchant_id: AccountId, invoice_number: u64) -> Option<Invoice> {
+        self.invoices.get(&(merchant_id, invoice_number)).cloned()
+    }
+
+    /// Gets a merchant's invoices in issue order, starting from `from_index` (0-based)
+    pub fn get_merchant_invoices(
+        &self,
+        merchant_id: AccountId,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<Invoice> {
+        let count = self.invoice_counts.get(&merchant_id).copied().unwrap_or(0);
+        let end = count.min(from_index.saturating_add(limit));
+
+        (from_index..end)
+            .filter_map(|index| self.invoices.get(&(merchant_id.clone(), index + 1)).cloned())
+            .collect()
+    }
+}
+
+impl Contract {
+    /// Issues the next sequential invoice for a merchant from a charge record and returns its number
+    pub(crate) fn issue_invoice(&mut self, subscription: &Subscription, record: &PaymentRecord) -> u64 {
+        let merchant_id = subscription.merchant_id.clone();
+        let invoice_number = self.invoice_counts.get(&merchant_id).copied().unwrap_or(0) + 1;
+        self.invoice_counts.insert(merchant_id.clone(), invoice_number);
+
+        let invoice = Invoice {
+            invoice_number,
+            merchant_id: merchant_id.clone(),
+            user_id: subscription.user_id.clone(),
+            subscription_id: record.subscription_id.clone(),
+            cycle_index: subscription.cycle_index,
+            payment_number: record.payment_number,
+            amount: record.amount,
+            fee: record.fee,
+            referral_commission: record.referral_commission,
+            payouts: record.payouts.clone(),
+            line_items: record.line_items.clone(),
+            payment_method: record.payment_method.clone(),
+            issued_at: record.timestamp,
+        };
+        self.invoices.insert((merchant_id, invoice_number), invoice);
+
+        invoice_number
+    }
+}
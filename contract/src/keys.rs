@@ -0,0 +1,411 @@
+use near_sdk::{env, log, near, require, AccountId};
+
+use crate::models::{SubscriptionId, SubscriptionKey};
+use crate::{Contract, ContractExt};
+
+// Most function call access keys a subscription can have registered at once
+const MAX_KEYS_PER_SUBSCRIPTION: usize = 10;
+// Longest label a key can be given
+const MAX_KEY_LABEL_LENGTH: usize = 64;
+
+#[near]
+impl Contract {
+    /// Gets the keys authorized to process payments for a subscription with their labels, in
+    /// the order they were registered
+    pub fn get_subscription_keys(&self, subscription_id: SubscriptionId) -> Vec<SubscriptionKey> {
+        self.subscription_public_keys(&subscription_id)
+            .into_iter()
+            .map(|public_key| SubscriptionKey {
+                label: self.key_labels.get(&public_key).cloned(),
+                public_key,
+            })
+            .collect()
+    }
+
+    /// Labels a key authorized for one of the caller's subscriptions, such as "primary worker"
+    /// or "backup worker". `None` removes the label
+    pub fn set_subscription_key_label(&mut self, public_key: String, label: Option<String>) {
+        let user_id = env::predecessor_account_id();
+        let subscription_id = self
+            .subscription_keys
+            .get(&public_key)
+            .expect("Key not found")
+            .clone();
+        let subscription = self
+            .subscriptions
+            .get(&subscription_id)
+            .expect("Subscription not found");
+        require!(
+            subscription.user_id == user_id,
+            "Not authorized to label this key"
+        );
+
+        self.flush_subscription_storage();
+        let initial_storage = env::storage_usage();
+        self.label_subscription_key(&public_key, label);
+        self.flush_subscription_storage();
+        let final_storage = env::storage_usage();
+        if final_storage > initial_storage {
+            self.charge_storage(&user_id, initial_storage);
+        } else {
+            self.release_storage(&user_id, initial_storage - final_storage);
+        }
+
+        log!("Key label updated for subscription: {}", subscription_id);
+    }
+
+    /// Replaces a key authorized for a subscription with a new one in one step, keeping its
+    /// place among the subscription's keys and its label. Storage is settled against the
+    /// subscriber's storage deposit. Keys derived from a registered derivation cannot be rotated
+    /// here
+    pub fn rotate_subscription_key(
+        &mut self,
+        subscription_id: SubscriptionId,
+        old_public_key: String,
+        new_public_key: String,
+    ) {
+        let user_id = env::predecessor_account_id();
+        let subscription = self
+            .subscriptions
+            .get(&subscription_id)
+            .expect("Subscription not found");
+        require!(
+            subscription.user_id == user_id,
+            "Not authorized to rotate keys for this subscription"
+        );
+        require!(
+            self.subscription_keys.get(&old_public_key) == Some(&subscription_id),
+            "Key is not authorized for this subscription"
+        );
+        require!(
+            !self.subscription_keys.contains_key(&new_public_key),
+            "New key is already registered"
+        );
+        // Derived keys are replaced by their worker with `rotate_derived_key`
+        require!(
+            self.key_derivations
+                .get(&subscription_id)
+                .is_none_or(|derivation| derivation.public_key.as_ref() != Some(&old_public_key)),
+            "Derived keys are rotated by their worker"
+        );
+
+        self.flush_subscription_storage();
+        let initial_storage = env::storage_usage();
+        self.subscription_keys.remove(&old_public_key);
+        self.subscription_keys
+            .insert(new_public_key.clone(), subscription_id.clone());
+        let label = self.key_labels.remove(&old_public_key);
+        self.label_subscription_key(&new_public_key, label);
+        if let Some(public_keys) = self.keys_by_subscription.get_mut(&subscription_id) {
+            for public_key in public_keys.iter_mut().filter(|key| **key == old_public_key) {
+                public_key.clone_from(&new_public_key);
+            }
+        }
+        self.flush_subscription_storage();
+        let final_storage = env::storage_usage();
+        if final_storage > initial_storage {
+            self.charge_storage(&user_id, initial_storage);
+        } else {
+            self.release_storage(&user_id, initial_storage - final_storage);
+        }
+
+        log!("Key rotated for subscription: {}", subscription_id);
+    }
+}
+
+impl Contract {
+    /// Gets the public keys authorized for a subscription, in the order they were registered
+    pub(crate) fn subscription_public_keys(&self, subscription_id: &SubscriptionId) -> Vec<String> {
+        self.keys_by_subscription
+            .get(subscription_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Sets or clears the label of an authorized key
+    pub(crate) fn label_subscription_key(&mut self, public_key: &str, label: Option<String>) {
+        match label {
+            Some(label) => {
+                require!(
+                    !label.is_empty() && label.len() <= MAX_KEY_LABEL_LENGTH,
+                    "Key label must be 1 to 64 bytes"
+                );
+                self.key_labels.insert(public_key.to_string(), label);
+            }
+            None => {
+                self.key_labels.remove(public_key);
+            }
+        }
+    }
+
+    /// Authorizes a key for a subscription, moving it off another subscription of the same
+    /// subscriber it was authorized for. Keys authorized for someone else's subscription are
+    /// rejected
+    pub(crate) fn link_subscription_key(
+        &mut self,
+        subscription_id: &SubscriptionId,
+        public_key: String,
+    ) {
+        if let Some(previous) = self.subscription_keys.get(&public_key).cloned() {
+            let subscriber = |subscription_id: &SubscriptionId| {
+                self.subscriptions
+                    .get(subscription_id)
+                    .map(|subscription| subscription.user_id.clone())
+            };
+            // A key left on a subscription that no longer exists is free to be taken
+            require!(
+                subscriber(&previous)
+                    .is_none_or(|previous_subscriber| {
+                        subscriber(subscription_id) == Some(previous_subscriber)
+                    }),
+                "Key already registered"
+            );
+            self.unlink_subscription_key(&previous, &public_key);
+        }
+
+        let mut public_keys = self.subscription_public_keys(subscription_id);
+        require!(
+            public_keys.len() < MAX_KEYS_PER_SUBSCRIPTION,
+            "Too many keys registered for this subscription"
+        );
+        public_keys.push(public_key.clone());
+        self.keys_by_subscription
+            .insert(subscription_id.clone(), public_keys);
+        self.subscription_keys
+            .insert(public_key, subscription_id.clone());
+    }
+
+    /// Stops a key being authorized for a subscription
+    pub(crate) fn unlink_subscription_key(
+        &mut self,
+        subscription_id: &SubscriptionId,
+        public_key: &String,
+    ) {
+        if self.subscription_keys.get(public_key) == Some(subscription_id) {
+            self.subscription_keys.remove(public_key);
+            self.key_labels.remove(public_key);
+        }
+        let Some(public_keys) = self.keys_by_subscription.get_mut(subscription_id) else {
+            return;
+        };
+        public_keys.retain(|key| key != public_key);
+        if public_keys.is_empty() {
+            self.keys_by_subscription.remove(subscription_id);
+        }
+    }
+
+    /// Revokes every key authorized for a subscription and its key derivation, returning their
+    /// storage to the subscriber who paid for it. Returns the number of keys revoked
+    pub(crate) fn revoke_subscription_keys(
+        &mut self,
+        subscription_id: &SubscriptionId,
+        user_id: &AccountId,
+    ) -> u32 {
+        let public_keys = self.subscription_public_keys(subscription_id);
+        if public_keys.is_empty() && !self.key_derivations.contains_key(subscription_id) {
+            return 0;
+        }
+
+        self.flush_subscription_storage();
+        let initial_storage = env::storage_usage();
+        self.clear_subscription_derivation(subscription_id);
+        for public_key in public_keys.iter() {
+            self.unlink_subscription_key(subscription_id, public_key);
+        }
+        self.flush_subscription_storage();
+        let storage_freed = initial_storage.saturating_sub(env::storage_usage());
+        self.release_storage(user_id, storage_freed);
+
+        log!(
+            "Revoked {} keys for subscription: {}",
+            public_keys.len(),
+            subscription_id
+        );
+        public_keys.len() as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{json_types::U128, testing_env, AccountId};
+
+    use crate::models::{
+        PaymentMethod, StorageAccount, Subscription, SubscriptionFrequency, SubscriptionStatusV0,
+        SubscriptionV0,
+    };
+    use crate::Contract;
+
+    fn set_predecessor(predecessor: AccountId) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(predecessor)
+            .build());
+    }
+
+    fn subscription(id: &str, user_id: AccountId) -> Subscription {
+        SubscriptionV0 {
+            id: id.to_string(),
+            user_id,
+            merchant_id: accounts(2),
+            amount: U128(10000),
+            frequency: SubscriptionFrequency::Monthly,
+            next_payment_date: 0,
+            status: SubscriptionStatusV0::Active,
+            created_at: 0,
+            updated_at: 0,
+            payment_method: PaymentMethod::Near,
+            max_payments: None,
+            payments_made: 0,
+            end_date: None,
+        }
+        .into()
+    }
+
+    /// A contract holding subscriptions "sub-1" and "sub-2" of `accounts(1)`, who has a storage
+    /// deposit, and "sub-3" of `accounts(3)`
+    fn contract_with_subscriptions() -> Contract {
+        set_predecessor(accounts(0));
+        let mut contract = Contract::new(accounts(0));
+        for (id, user_id) in [
+            ("sub-1", accounts(1)),
+            ("sub-2", accounts(1)),
+            ("sub-3", accounts(3)),
+        ] {
+            contract
+                .subscriptions
+                .insert(id.to_string(), subscription(id, user_id).into());
+        }
+        contract.storage_accounts.insert(
+            accounts(1),
+            StorageAccount {
+                deposit: U128(Contract::storage_cost(100000)),
+                used_bytes: 0,
+            },
+        );
+        contract
+    }
+
+    #[test]
+    fn moves_key_between_a_subscribers_subscriptions() {
+        let mut contract = contract_with_subscriptions();
+        contract.link_subscription_key(&"sub-1".to_string(), "key-a".to_string());
+        contract.link_subscription_key(&"sub-1".to_string(), "key-b".to_string());
+
+        contract.link_subscription_key(&"sub-2".to_string(), "key-a".to_string());
+
+        assert_eq!(
+            contract.subscription_keys.get("key-a"),
+            Some(&"sub-2".to_string())
+        );
+        assert_eq!(
+            contract.subscription_public_keys(&"sub-1".to_string()),
+            vec!["key-b".to_string()]
+        );
+        assert_eq!(
+            contract.subscription_public_keys(&"sub-2".to_string()),
+            vec!["key-a".to_string()]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Key already registered")]
+    fn rejects_key_of_another_subscriber() {
+        let mut contract = contract_with_subscriptions();
+        contract.link_subscription_key(&"sub-3".to_string(), "key-a".to_string());
+
+        contract.link_subscription_key(&"sub-1".to_string(), "key-a".to_string());
+    }
+
+    #[test]
+    fn revokes_every_key_of_a_subscription() {
+        let mut contract = contract_with_subscriptions();
+        contract.link_subscription_key(&"sub-1".to_string(), "key-a".to_string());
+        contract.link_subscription_key(&"sub-1".to_string(), "key-b".to_string());
+
+        assert_eq!(
+            contract.revoke_subscription_keys(&"sub-1".to_string(), &accounts(1)),
+            2
+        );
+        assert!(contract
+            .subscription_public_keys(&"sub-1".to_string())
+            .is_empty());
+        assert!(!contract.subscription_keys.contains_key("key-a"));
+        assert!(!contract.subscription_keys.contains_key("key-b"));
+    }
+
+    #[test]
+    fn rotates_key_in_place_keeping_its_label() {
+        let mut contract = contract_with_subscriptions();
+        contract.link_subscription_key(&"sub-1".to_string(), "key-a".to_string());
+        contract.link_subscription_key(&"sub-1".to_string(), "key-b".to_string());
+        contract.label_subscription_key("key-a", Some("primary worker".to_string()));
+
+        set_predecessor(accounts(1));
+        contract.rotate_subscription_key(
+            "sub-1".to_string(),
+            "key-a".to_string(),
+            "key-c".to_string(),
+        );
+
+        let keys = contract.get_subscription_keys("sub-1".to_string());
+        assert_eq!(keys[0].public_key, "key-c");
+        assert_eq!(keys[0].label, Some("primary worker".to_string()));
+        assert_eq!(keys[1].public_key, "key-b");
+        assert!(!contract.subscription_keys.contains_key("key-a"));
+    }
+
+    #[test]
+    #[should_panic(expected = "New key is already registered")]
+    fn rejects_rotating_to_a_registered_key() {
+        let mut contract = contract_with_subscriptions();
+        contract.link_subscription_key(&"sub-1".to_string(), "key-a".to_string());
+        contract.link_subscription_key(&"sub-3".to_string(), "key-b".to_string());
+
+        set_predecessor(accounts(1));
+        contract.rotate_subscription_key(
+            "sub-1".to_string(),
+            "key-a".to_string(),
+            "key-b".to_string(),
+        );
+    }
+
+    #[test]
+    fn labels_and_unlabels_a_subscribers_key() {
+        let mut contract = contract_with_subscriptions();
+        contract.link_subscription_key(&"sub-1".to_string(), "key-a".to_string());
+
+        set_predecessor(accounts(1));
+        contract.set_subscription_key_label("key-a".to_string(), Some("backup worker".to_string()));
+        assert_eq!(
+            contract.get_subscription_keys("sub-1".to_string())[0].label,
+            Some("backup worker".to_string())
+        );
+
+        contract.set_subscription_key_label("key-a".to_string(), None);
+        assert_eq!(
+            contract.get_subscription_keys("sub-1".to_string())[0].label,
+            None
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Not authorized to label this key")]
+    fn rejects_labeling_another_subscribers_key() {
+        let mut contract = contract_with_subscriptions();
+        contract.link_subscription_key(&"sub-3".to_string(), "key-a".to_string());
+
+        set_predecessor(accounts(1));
+        contract.set_subscription_key_label("key-a".to_string(), Some("mine".to_string()));
+    }
+
+    #[test]
+    #[should_panic(expected = "Key label must be 1 to 64 bytes")]
+    fn rejects_overlong_key_label() {
+        let mut contract = contract_with_subscriptions();
+        contract.link_subscription_key(&"sub-1".to_string(), "key-a".to_string());
+
+        set_predecessor(accounts(1));
+        contract.set_subscription_key_label("key-a".to_string(), Some("x".repeat(65)));
+    }
+}
@@ -0,0 +1,159 @@
+use near_sdk::{env, log, near, require, AccountId};
+
+use crate::models::{Lease, Subscription, SubscriptionId};
+use crate::{Contract, ContractExt};
+
+// Longest a worker can hold subscriptions for (1 hour in seconds)
+const MAX_LEASE_SECONDS: u64 = 3600;
+// Most subscriptions a worker can lease in one call
+const MAX_LEASE_BATCH: u64 = 100;
+
+#[near]
+impl Contract {
+    /// Leases up to `limit` (at most 100) due subscriptions in the calling worker's shards to
+    /// it for `lease_seconds`, earliest due first. Subscriptions leased to another worker are
+    /// skipped until their lease expires, so concurrent workers never charge the same
+    /// subscription
+    pub fn claim_due_batch(&mut self, limit: u64, lease_seconds: u64) -> Vec<Subscription> {
+        let now = env::block_timestamp() / 1000000000;
+
+        // Verify caller is an approved worker
+        require!(
+            self.is_verified_by_approved_codehash(),
+            "Not an approved worker"
+        );
+        require!(
+            lease_seconds > 0 && lease_seconds <= MAX_LEASE_SECONDS,
+            "Lease duration out of range"
+        );
+
+        let worker_id = env::predecessor_account_id();
+        self.record_worker_activity(&worker_id, now);
+        let subscriptions = self.due_subscriptions(now, limit.min(MAX_LEASE_BATCH));
+        for subscription in subscriptions.iter() {
+            self.leases.insert(
+                subscription.id.clone(),
+                Lease {
+                    worker_id: worker_id.clone(),
+                    expires_at: now + lease_seconds,
+                },
+            );
+        }
+
+        log!(
+            "Leased {} subscriptions to {} until {}",
+            subscriptions.len(),
+            worker_id,
+            now + lease_seconds
+        );
+        subscriptions
+    }
+
+    /// Gets the lease on a subscription, if a worker holds one
+    pub fn get_lease(&self, subscription_id: SubscriptionId) -> Option<Lease> {
+        let now = env::block_timestamp() / 1000000000;
+        self.leases
+            .get(&subscription_id)
+            .filter(|lease| self.is_lease_held(lease, now))
+            .cloned()
+    }
+}
+
+impl Contract {
+    /// Whether a subscription is leased to a worker other than `worker_id`
+    pub(crate) fn is_leased_to_other(
+        &self,
+        subscription_id: &SubscriptionId,
+        worker_id: &AccountId,
+        now: u64,
+    ) -> bool {
+        self.leases.get(subscription_id).is_some_and(|lease| {
+            lease.worker_id != *worker_id && self.is_lease_held(lease, now)
+        })
+    }
+
+    /// Whether a lease is still in force: unexpired and held by a registered worker
+    fn is_lease_held(&self, lease: &Lease, now: u64) -> bool {
+        lease.expires_at > now && self.worker_by_account_id.contains_key(&lease.worker_id)
+    }
+
+    /// Ends a worker's lease once it charges the subscription. Returns false, leaving the
+    /// lease in place, if the subscription is leased to another worker
+    pub(crate) fn release_lease(
+        &mut self,
+        subscription_id: &SubscriptionId,
+        worker_id: &AccountId,
+        now: u64,
+    ) -> bool {
+        if self.is_leased_to_other(subscription_id, worker_id, now) {
+            return false;
+        }
+        self.leases.remove(subscription_id);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    use crate::models::{Lease, Worker};
+    use crate::Contract;
+
+    /// A contract with workers `accounts(3)` and `accounts(4)`, where "sub-1" is leased to
+    /// `accounts(3)` until 2000
+    fn contract_with_lease() -> Contract {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(1_000 * 1_000_000_000)
+            .build());
+        let mut contract = Contract::new(accounts(0));
+        for worker_id in [accounts(3), accounts(4)] {
+            contract.worker_by_account_id.insert(
+                worker_id,
+                Worker {
+                    checksum: "checksum".to_string(),
+                    codehash: "codehash".to_string(),
+                    tee_type: None,
+                    verified_at: 0,
+                }
+                .into(),
+            );
+        }
+        contract.leases.insert(
+            "sub-1".to_string(),
+            Lease {
+                worker_id: accounts(3),
+                expires_at: 2_000,
+            },
+        );
+        contract
+    }
+
+    #[test]
+    fn keeps_lease_exclusive_to_its_worker() {
+        let mut contract = contract_with_lease();
+        let subscription_id = "sub-1".to_string();
+
+        assert!(contract.is_leased_to_other(&subscription_id, &accounts(4), 1_000));
+        assert!(!contract.release_lease(&subscription_id, &accounts(4), 1_000));
+        assert!(contract.get_lease(subscription_id.clone()).is_some());
+
+        assert!(contract.release_lease(&subscription_id, &accounts(3), 1_000));
+        assert!(contract.get_lease(subscription_id).is_none());
+    }
+
+    #[test]
+    fn frees_lease_once_expired_or_worker_removed() {
+        let mut contract = contract_with_lease();
+        let subscription_id = "sub-1".to_string();
+
+        assert!(!contract.is_leased_to_other(&subscription_id, &accounts(4), 2_000));
+
+        contract.worker_by_account_id.remove(&accounts(3));
+        assert!(!contract.is_leased_to_other(&subscription_id, &accounts(4), 1_000));
+        assert!(contract.release_lease(&subscription_id, &accounts(4), 1_000));
+    }
+}
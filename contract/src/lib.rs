@@ -2,18 +2,76 @@ use near_sdk::{
     bs58, env,
     json_types::U128,
     log, near, require, serde_json,
-    store::{IterableMap, IterableSet, LookupMap},
+    store::{IterableMap, IterableSet, LookupMap, LookupSet, Vector},
     AccountId, Gas, NearToken, PanicOnDefault, Promise,
 };
 
+pub mod approvals;
+pub mod archive;
+pub mod attestation;
+pub mod bonds;
+pub mod codehashes;
 pub mod collateral;
+pub mod conduct;
+pub mod counts;
+pub mod derivation;
+pub mod disputes;
+pub mod due_index;
+pub mod escrow;
+pub mod events;
+pub mod fees;
+pub mod ft;
+pub mod funding;
+pub mod history;
+pub mod hooks;
+pub mod intents;
+pub mod invoices;
+pub mod keys;
+pub mod lease;
+pub mod maintenance;
+pub mod merchant;
+pub mod merchant_index;
+pub mod migration;
 pub mod models;
+pub mod mt;
+pub mod oracle;
+pub mod output;
+pub mod payouts;
+pub mod prepay;
+pub mod rate_limits;
+pub mod referrals;
+pub mod refunds;
+pub mod retries;
+pub mod settlements;
+pub mod shards;
+pub mod staking;
+pub mod stats;
+pub mod storage;
+pub mod streams;
+pub mod swap;
+pub mod tokens;
+pub mod topup;
+pub mod transitions;
+pub mod user_index;
 pub mod utils;
+pub mod wnear;
+pub mod workers;
 
-use hex::decode;
+use events::Event;
+use utils::within_limit;
+use migration::{Migration, CURRENT_STATE_VERSION};
+use shards::SubscriptionShards;
 use models::{
-    PaymentMethod, PaymentResult, Subscription, SubscriptionFrequency, SubscriptionId,
-    SubscriptionStatus, Worker,
+    AmountOverride, ApprovalPolicy, ArchivedSubscription, AttestationNonce, BondPolicy,
+    CancellationReason, ChargeDetails, ChargeHold, CodehashWindow, CommitmentTerms, CreateSubscriptionArgs,
+    FundingRule, FundingSource,
+    HeldPayment, Invoice, KeyDerivation, Lease, LineItem, MerchantLimit, MerchantSettings,
+    PaymentError, PaymentKind, PaymentMethod, PaymentMode, PaymentRecord, PaymentResult,
+    PayoutPurpose, PendingSettlement, PinnedCollateral, PriceChange, PriceDenomination, ReferralEarnings,
+    RetryPolicy, SettlementReport, StakingPreference, StateVersion, StorageAccount, StoragePayer,
+    Subscription, SubscriptionCounts, SubscriptionFrequency, SubscriptionId, SubscriptionImport,
+    SubscriptionPage, SubscriptionStatus, SubscriptionTemplate, UpcomingPayment, UsdOracleConfig,
+    UsdRate, VWorker, Worker, WorkerBond, WorkerConduct, WorkerPaymentUsage, WorkerRateLimit,
 };
 
 #[near(contract_state)]
@@ -21,14 +79,117 @@ use models::{
 pub struct Contract {
     pub owner_id: AccountId,
     pub approved_codehashes: IterableSet<String>,
-    pub worker_by_account_id: IterableMap<AccountId, Worker>,
+    pub worker_by_account_id: IterableMap<AccountId, VWorker>,
 
     // Subscription-related state
-    pub subscriptions: IterableMap<SubscriptionId, Subscription>,
+    pub subscriptions: SubscriptionShards, // Sharded by a hash of the subscription ID
     pub subscription_keys: LookupMap<String, SubscriptionId>, // PublicKey -> SubscriptionId
     pub merchants: IterableSet<AccountId>,
+    pub subscription_nonce: u64, // Monotonic counter used to derive subscription IDs
+    pub merchant_limits: LookupMap<(AccountId, AccountId, PaymentMethod), MerchantLimit>, // (user, merchant, token) -> limit
+    pub price_change_notice_period: u64, // Minimum seconds between scheduling and applying a price change
+    pub payment_history: LookupMap<SubscriptionId, Vec<PaymentRecord>>, // Ring buffer of the latest records
+    pub due_soon_window: u64, // Seconds before next_payment_date that a subscription counts as due soon
+    pub templates: IterableMap<String, SubscriptionTemplate>, // "merchant_id:template_id" -> template
+    pub merchant_settings: LookupMap<AccountId, MerchantSettings>,
+    pub default_cooling_off_period: Option<u64>, // Seconds after creation during which charges are refundable
+    pub credits: LookupMap<(AccountId, AccountId, PaymentMethod), U128>, // (user, merchant, token) -> credit
+    pub archive_retention_period: u64, // Seconds a terminated subscription is kept before it can be archived
+    pub archived_subscriptions: LookupMap<SubscriptionId, ArchivedSubscription>,
+    pub fee_bps: u16, // Platform fee taken from every payment, in basis points
+    pub treasury_balances: IterableMap<PaymentMethod, U128>, // Accrued platform fees per token
+    pub referral_earnings: LookupMap<(AccountId, PaymentMethod), ReferralEarnings>, // (referrer, token) -> earnings
+    pub escrow_balances: LookupMap<(AccountId, PaymentMethod), U128>, // (user, token) -> funds payments are drawn from
+    pub retry_policy: RetryPolicy,
+    pub invoices: LookupMap<(AccountId, u64), Invoice>, // (merchant, invoice number) -> invoice
+    pub invoice_counts: LookupMap<AccountId, u64>, // Invoices issued per merchant
+    pub ft_registrations: LookupSet<(AccountId, AccountId)>, // (token, account) confirmed storage-registered
+    pub allowed_tokens: IterableSet<AccountId>, // FT contracts subscriptions can be paid in
+    pub usd_oracle: Option<UsdOracleConfig>,
+    pub usd_rates: LookupMap<PaymentMethod, UsdRate>, // Last oracle rate fetched per token
+    pub swap_adapter: Option<AccountId>, // Contract swapping escrowed tokens into payment tokens
+    pub intents_contract: Option<AccountId>, // NEAR Intents verifier merchants can be settled through
+    pub funding_rules: LookupMap<(AccountId, AccountId), FundingRule>, // (user, token) -> linked funding source
+    pub staking_pool: Option<AccountId>, // Liquid staking pool idle NEAR escrow can be staked with
+    pub staking_preferences: LookupMap<AccountId, StakingPreference>, // Users opted in to escrow staking
+    pub staked_escrow: LookupMap<AccountId, U128>, // Pool shares held per user
+    pub staked_escrow_total: U128, // Pool shares held across all users
+    pub claimable_balances: LookupMap<(AccountId, PaymentMethod), U128>, // (merchant, token) -> unclaimed payouts
+    pub pending_settlements: LookupMap<(AccountId, PaymentMethod), PendingSettlement>, // (merchant, token) -> open window
+    pub settlement_reports: LookupMap<(AccountId, u64), SettlementReport>, // (merchant, settlement number) -> report
+    pub settlement_counts: LookupMap<AccountId, u64>, // Settlements recorded per merchant
+    pub arbiter_id: Option<AccountId>, // Resolves contested disputes alongside the owner
+    pub charge_holds: LookupMap<(SubscriptionId, u32), ChargeHold>, // (subscription, payment number) -> held charge
+    pub refunded_amounts: LookupMap<(SubscriptionId, u32), U128>, // (subscription, payment number) -> refunded so far
+    pub wnear_contract: Option<AccountId>, // wrap.near, bridged to NEAR escrow
+    pub token_decimals: LookupMap<AccountId, u8>, // Cached ft_metadata decimals per token
+    pub amount_precisions: LookupMap<PaymentMethod, u8>, // Fractional digits amounts in each token may be given to
+    pub merchant_fees: LookupMap<(AccountId, PaymentMethod), U128>, // (merchant, token) -> fees collected
+    pub approval_policies: LookupMap<(AccountId, PaymentMethod), ApprovalPolicy>, // (user, token) -> co-signer
+    pub worker_fee_bps: u16, // Share of each platform fee paid to the worker that processed the payment
+    pub worker_balances: LookupMap<(AccountId, PaymentMethod), U128>, // (worker, token) -> unclaimed fees
+    pub worker_reward_tokens: LookupMap<AccountId, Vec<PaymentMethod>>, // Tokens each worker has unclaimed fees in
+    pub min_charge_amounts: LookupMap<PaymentMethod, U128>, // Contract-wide smallest non-free charge per token
+    pub allowed_mt_contracts: IterableSet<AccountId>, // NEP-245 contracts subscriptions can be paid in
+    pub due_heap: Vector<(u64, SubscriptionId)>, // Min-heap of (next_payment_date, subscription)
+    pub due_heap_positions: LookupMap<SubscriptionId, u32>, // Subscription -> its index in due_heap
+    pub subscription_counts: SubscriptionCounts, // Live subscriptions, in total and by status
+    pub merchant_subscription_counts: LookupMap<AccountId, SubscriptionCounts>, // Per-merchant counts
+    pub state_version: StateVersion, // Layout of this state, see `migrate`
+    pub migration: Option<Migration>, // Collections still in an earlier layout while a migration is running
+    pub max_attestation_age: Option<u64>, // Seconds a worker's attestation stays valid; `None` for no limit
+    pub storage_accounts: LookupMap<AccountId, StorageAccount>, // NEP-145 storage deposits
+    pub subscription_storage: LookupMap<SubscriptionId, StoragePayer>, // Who paid for each subscription's storage
+    pub subscription_sequence: LookupMap<u64, SubscriptionId>, // Creation sequence number -> subscription
+    pub subscriptions_by_user: LookupMap<AccountId, Vec<SubscriptionId>>, // Subscriptions of each user, in creation order
+    pub subscriptions_by_merchant: LookupMap<(AccountId, u64), SubscriptionId>, // (merchant, position from 1) -> subscription, in creation order
+    pub merchant_subscription_totals: LookupMap<AccountId, u64>, // Subscriptions indexed per merchant
+    pub merchant_index_cursor: u64, // Next creation sequence number to add to the merchant index
+    pub keys_by_subscription: LookupMap<SubscriptionId, Vec<String>>, // Keys authorized per subscription
+    pub key_derivations: LookupMap<SubscriptionId, KeyDerivation>, // Where each subscription's key is derived from
+    pub key_labels: LookupMap<String, String>, // PublicKey -> label given by the subscriber
+    pub payment_history_heads: LookupMap<SubscriptionId, u32>, // Position of the oldest record in a full history
+    pub leases: LookupMap<SubscriptionId, Lease>, // Worker currently claiming each due subscription
+    pub volume_processed: IterableMap<PaymentMethod, U128>, // Successful charges per token, all time
+    pub fees_collected: IterableMap<PaymentMethod, U128>, // Platform fees per token, all time
+    pub attestation_nonces: LookupMap<AccountId, AttestationNonce>, // Outstanding nonce per registering worker
+    pub collaterals: LookupMap<String, PinnedCollateral>, // Owner-pinned quote collateral by ID
+    pub worker_last_seen: LookupMap<AccountId, u64>, // When each worker last claimed or processed work
+    pub bond_policy: BondPolicy, // Bond workers must hold, and its penalties
+    pub worker_bonds: LookupMap<AccountId, WorkerBond>, // NEAR bonded per worker
+    pub misbehavior_threshold: Option<u32>, // Rejected payment attempts that suspend a worker
+    pub worker_conduct: LookupMap<AccountId, WorkerConduct>, // Rejected payment attempts per worker
+    pub shard_assignments: Vec<AccountId>, // Worker assigned to each subscription shard, by shard
+    pub codehash_windows: LookupMap<String, CodehashWindow>, // Validity windows of approved codehashes
+    pub worker_rate_limit: Option<WorkerRateLimit>, // Most payments a worker can process per period
+    pub worker_payment_usage: LookupMap<AccountId, WorkerPaymentUsage>, // Payments per worker this period
 }
 
+// Length of the rolling window used for per-merchant spending limits (30 days in seconds)
+const MERCHANT_LIMIT_PERIOD: u64 = 2592000;
+// Default notice merchants must give before a price change applies (30 days in seconds)
+const DEFAULT_PRICE_CHANGE_NOTICE_PERIOD: u64 = 2592000;
+// Default window ahead of a charge in which subscribers are warned (3 days in seconds)
+const DEFAULT_DUE_SOON_WINDOW: u64 = 259200;
+// Default time terminated subscriptions are kept before archival (90 days in seconds)
+const DEFAULT_ARCHIVE_RETENTION_PERIOD: u64 = 7776000;
+// Default delay before the first retry of a failed payment (1 hour in seconds)
+const DEFAULT_RETRY_BASE_DELAY: u64 = 3600;
+// Default cap on the delay between retries (3 days in seconds)
+const DEFAULT_RETRY_MAX_DELAY: u64 = 259200;
+// Default number of retries before a subscription is marked failed
+const DEFAULT_MAX_RETRIES: u32 = 5;
+// Period over which subscribers' payment skips are limited (365 days in seconds)
+const SKIP_YEAR: u64 = 31536000;
+// Gas reserved for each payment in a batch, including its outgoing transfers and their confirmation
+pub(crate) const GAS_PER_BATCH_PAYMENT: Gas = Gas::from_tgas(50);
+// Most subscriptions that can be looked up by ID in one call
+const MAX_BATCH_LOOKUP: usize = 100;
+// Most creation sequence numbers a page of subscriptions reads
+const MAX_PAGE_SCAN: u64 = 500;
+// Default wait before a deregistered worker can withdraw its bond (7 days in seconds)
+const DEFAULT_BOND_COOLDOWN: u64 = 604800;
+
 #[near]
 impl Contract {
     #[init]
@@ -40,9 +201,94 @@ impl Contract {
             worker_by_account_id: IterableMap::new(b"b"),
 
             // Initialize subscription-related state
-            subscriptions: IterableMap::new(b"c"),
+            subscriptions: SubscriptionShards::new(b'c'),
             subscription_keys: LookupMap::new(b"d"),
             merchants: IterableSet::new(b"g"),
+            subscription_nonce: 0,
+            merchant_limits: LookupMap::new(b"h"),
+            price_change_notice_period: DEFAULT_PRICE_CHANGE_NOTICE_PERIOD,
+            payment_history: LookupMap::new(b"i"),
+            due_soon_window: DEFAULT_DUE_SOON_WINDOW,
+            templates: IterableMap::new(b"j"),
+            merchant_settings: LookupMap::new(b"k"),
+            default_cooling_off_period: None,
+            credits: LookupMap::new(b"l"),
+            archive_retention_period: DEFAULT_ARCHIVE_RETENTION_PERIOD,
+            archived_subscriptions: LookupMap::new(b"m"),
+            fee_bps: 0,
+            treasury_balances: IterableMap::new(b"o"),
+            referral_earnings: LookupMap::new(b"p"),
+            escrow_balances: LookupMap::new(b"q"),
+            invoices: LookupMap::new(b"r"),
+            invoice_counts: LookupMap::new(b"s"),
+            ft_registrations: LookupSet::new(b"t"),
+            allowed_tokens: IterableSet::new(b"u"),
+            usd_oracle: None,
+            usd_rates: LookupMap::new(b"v"),
+            swap_adapter: None,
+            intents_contract: None,
+            funding_rules: LookupMap::new(b"w"),
+            staking_pool: None,
+            staking_preferences: LookupMap::new(b"x"),
+            staked_escrow: LookupMap::new(b"y"),
+            staked_escrow_total: U128(0),
+            claimable_balances: LookupMap::new(b"z"),
+            pending_settlements: LookupMap::new(b"A"),
+            settlement_reports: LookupMap::new(b"B"),
+            settlement_counts: LookupMap::new(b"C"),
+            arbiter_id: None,
+            charge_holds: LookupMap::new(b"D"),
+            refunded_amounts: LookupMap::new(b"E"),
+            wnear_contract: None,
+            token_decimals: LookupMap::new(b"F"),
+            amount_precisions: LookupMap::new(b"5"),
+            retry_policy: RetryPolicy {
+                base_delay: DEFAULT_RETRY_BASE_DELAY,
+                max_delay: DEFAULT_RETRY_MAX_DELAY,
+                max_retries: DEFAULT_MAX_RETRIES,
+            },
+            merchant_fees: LookupMap::new(b"n"),
+            approval_policies: LookupMap::new(b"G"),
+            worker_fee_bps: 0,
+            worker_balances: LookupMap::new(b"H"),
+            worker_reward_tokens: LookupMap::new(b"f"),
+            min_charge_amounts: LookupMap::new(b"I"),
+            allowed_mt_contracts: IterableSet::new(b"J"),
+            due_heap: Vector::new(b"S"),
+            due_heap_positions: LookupMap::new(b"T"),
+            leases: LookupMap::new(b"U"),
+            volume_processed: IterableMap::new(b"V"),
+            fees_collected: IterableMap::new(b"W"),
+            attestation_nonces: LookupMap::new(b"X"),
+            collaterals: LookupMap::new(b"Y"),
+            worker_last_seen: LookupMap::new(b"Z"),
+            bond_policy: BondPolicy {
+                cooldown: DEFAULT_BOND_COOLDOWN,
+                ..Default::default()
+            },
+            worker_bonds: LookupMap::new(b"4"),
+            misbehavior_threshold: None,
+            worker_conduct: LookupMap::new(b"e"),
+            shard_assignments: Vec::new(),
+            codehash_windows: LookupMap::new(b"0"),
+            worker_rate_limit: None,
+            worker_payment_usage: LookupMap::new(b"1"),
+            key_derivations: LookupMap::new(b"2"),
+            key_labels: LookupMap::new(b"3"),
+            subscription_counts: SubscriptionCounts::default(),
+            merchant_subscription_counts: LookupMap::new(b"M"),
+            state_version: CURRENT_STATE_VERSION,
+            migration: None,
+            max_attestation_age: None,
+            storage_accounts: LookupMap::new(b"N"),
+            subscription_storage: LookupMap::new(b"O"),
+            subscription_sequence: LookupMap::new(b"P"),
+            subscriptions_by_user: LookupMap::new(b"6"),
+            subscriptions_by_merchant: LookupMap::new(b"7"),
+            merchant_subscription_totals: LookupMap::new(b"8"),
+            merchant_index_cursor: 1,
+            keys_by_subscription: LookupMap::new(b"Q"),
+            payment_history_heads: LookupMap::new(b"R"),
         }
     }
 
@@ -65,23 +311,41 @@ impl Contract {
         log!("Merchant registered: {}", merchant_id);
     }
 
-    /// Gets all registered merchants
+    /// Sets the minimum notice period for scheduled price changes
+    pub fn set_price_change_notice_period(&mut self, seconds: u64) {
+        self.require_owner();
+        self.price_change_notice_period = seconds;
+        log!("Price change notice period set to {} seconds", seconds);
+    }
+
+    /// Sets how far ahead of a charge subscriptions are reported as due soon
+    pub fn set_due_soon_window(&mut self, seconds: u64) {
+        self.require_owner();
+        self.due_soon_window = seconds;
+        log!("Due soon window set to {} seconds", seconds);
+    }
+
+    /// Gets all registered merchants, ordered by account ID
     pub fn get_merchants(&self) -> Vec<AccountId> {
-        self.merchants.iter().map(|id| id.clone()).collect()
+        let mut merchants: Vec<AccountId> = self.merchants.iter().cloned().collect();
+        merchants.sort();
+        merchants
     }
 
     // WORKER METHODS
     pub fn require_worker(&self, codehash: String) {
-        let worker = self
+        let worker: Worker = self
             .worker_by_account_id
             .get(&env::predecessor_account_id())
             .unwrap()
-            .to_owned();
+            .to_owned()
+            .into();
 
         require!(
             worker.codehash == codehash,
             "Worker not verified for this codehash"
         );
+        self.require_fresh_attestation(&worker);
     }
 
     pub fn is_verified_by_codehash(&self, codehash: String) {
@@ -91,38 +355,71 @@ impl Contract {
 
     pub fn approve_codehash(&mut self, codehash: String) {
         self.require_owner();
+        self.codehash_windows.remove(&codehash);
         self.approved_codehashes.insert(codehash);
         log!("Codehash approved");
     }
 
+    /// Withdraws approval of a codehash. Workers registered under it stop passing
+    /// `is_verified_by_approved_codehash` straight away
+    pub fn revoke_codehash(&mut self, codehash: String) {
+        self.require_owner();
+        require!(
+            self.approved_codehashes.remove(&codehash),
+            "Codehash is not approved"
+        );
+        self.codehash_windows.remove(&codehash);
+        Event::CodehashRevoked { codehash }.emit();
+        log!("Codehash revoked");
+    }
+
     pub fn is_verified_by_approved_codehash(&self) -> bool {
         let worker = self.get_worker(env::predecessor_account_id());
         require!(
             self.approved_codehashes.contains(&worker.codehash),
             "Worker not approved"
         );
+        self.require_codehash_window(&worker.codehash);
+        self.require_fresh_attestation(&worker);
+        self.require_worker_bond(&env::predecessor_account_id());
         true
     }
 
+    /// Registers the caller as a worker once its quote verifies against the collateral the owner
+    /// pinned under `collateral_id`. The attached NEAR is added to the worker's bond, which
+    /// must reach the bond policy's requirement; it is refunded if registration fails
+    #[payable]
     pub fn register_worker(
         &mut self,
         quote_hex: String,
-        collateral: String,
+        collateral_id: String,
         checksum: String,
         codehash: String,
     ) -> bool {
-        let collateral = collateral::get_collateral(collateral);
-        let quote = decode(quote_hex).unwrap();
         let now = env::block_timestamp() / 1000000000;
-        let result = dcap_qvl::verify::verify(&quote, &collateral, now);
 
-        if result.ok().is_some() {
+        let deposit = env::attached_deposit().as_yoctonear();
+        let tee_type = self.verify_worker_quote(quote_hex, &collateral_id, &codehash, now);
+        if let Some(tee_type) = tee_type {
             let predecessor = env::predecessor_account_id();
+            self.add_worker_bond(&predecessor, deposit);
+            self.require_worker_bond(&predecessor);
+            let worker = Worker {
+                checksum,
+                codehash,
+                tee_type: Some(tee_type),
+                verified_at: now,
+            };
             self.worker_by_account_id
-                .insert(predecessor, Worker { checksum, codehash });
+                .insert(predecessor, worker.into());
+            self.assign_shards();
             log!("Worker registered successfully");
             return true;
         }
+        if deposit > 0 {
+            Promise::new(env::predecessor_account_id())
+                .transfer(NearToken::from_yoctonear(deposit));
+        }
         log!("Worker registration failed");
         false
     }
@@ -132,39 +429,132 @@ impl Contract {
             .get(&account_id)
             .unwrap_or_else(|| panic!("Worker not found for account: {}", account_id))
             .to_owned()
+            .into()
     }
 
     // SUBSCRIPTION METHODS
 
     /// Creates a new subscription
+    /// Fields left as `None` are taken from `template_id` when given. The subscriber's storage
+    /// deposit (see `storage_deposit`) must cover the storage it uses
     pub fn create_subscription( // can be called directly by user
         &mut self,
-        merchant_id: AccountId,
-        amount: U128,
-        frequency: SubscriptionFrequency,
-        payment_method: PaymentMethod,
-        max_payments: Option<u32>,
-        end_date: Option<u64>,
+        args: CreateSubscriptionArgs,
     ) -> SubscriptionId {
+        let CreateSubscriptionArgs {
+            merchant_id,
+            amount,
+            frequency,
+            payment_method,
+            max_payments,
+            end_date,
+            max_amount_per_charge,
+            max_total_spend,
+            line_items,
+            template_id,
+            metadata,
+            referrer_id,
+            denomination,
+            payment_mode,
+        } = args;
+        let initial_storage = env::storage_usage();
+
         // Verify merchant is registered
         require!(
             self.merchants.contains(&merchant_id),
             "Merchant not registered"
         );
 
+        // Resolve fields from the template, with explicit arguments taking precedence
+        let template = template_id.as_ref().map(|template_id| {
+            self.templates
+                .get(&Self::template_key(&merchant_id, template_id))
+                .expect("Template not found")
+                .clone()
+        });
+        // USD-priced subscriptions are converted at charge time, so they have no token amount yet
+        // and rely on max_amount_per_charge to bound the conversion
+        let denomination = denomination.unwrap_or_default();
+        let amount = match denomination {
+            PriceDenomination::Token => amount
+                .or(template.as_ref().map(|t| t.amount))
+                .expect("amount is required without a template"),
+            PriceDenomination::Usd { cents } => {
+                require!(cents > 0, "USD price must be positive");
+                require!(
+                    max_amount_per_charge.is_some(),
+                    "max_amount_per_charge is required for USD pricing"
+                );
+                U128(0)
+            }
+        };
+        let frequency = frequency
+            .or(template.as_ref().map(|t| t.frequency.clone()))
+            .expect("frequency is required without a template");
+        let payment_method = payment_method
+            .or(template.as_ref().map(|t| t.payment_method.clone()))
+            .expect("payment_method is required without a template");
+        let line_items = line_items.or(template.as_ref().map(|t| t.line_items.clone()));
+        let metadata = metadata.or(template.as_ref().and_then(|t| t.metadata.clone()));
+        let trial_period = template.as_ref().and_then(|t| t.trial_period);
+        let memo_template = template.as_ref().and_then(|t| t.memo_template.clone());
+
+        let payment_mode = payment_mode.unwrap_or_default();
+        require!(
+            payment_mode == PaymentMode::Periodic || denomination == PriceDenomination::Token,
+            "Streaming is not supported with USD pricing"
+        );
+
+        let line_items = line_items.unwrap_or_default();
+        require!(
+            denomination == PriceDenomination::Token || line_items.is_empty(),
+            "Line items are not supported with USD pricing"
+        );
+
+        // Spending caps default to the subscription amount
+        let max_amount_per_charge = max_amount_per_charge.unwrap_or(amount);
+        self.assert_valid_price(
+            &merchant_id,
+            &payment_method,
+            amount,
+            &line_items,
+            max_amount_per_charge,
+            max_total_spend,
+        );
+
         let user_id = env::predecessor_account_id();
         let now = env::block_timestamp() / 1000000000;
 
-        // Generate subscription ID
-        let subscription_id = format!("sub-{}-{}", user_id, now);
+        // Users and merchants cannot refer themselves
+        if let Some(referrer_id) = &referrer_id {
+            require!(
+                *referrer_id != user_id && *referrer_id != merchant_id,
+                "Invalid referrer"
+            );
+        }
+
+        // Generate subscription ID from the contract-wide nonce so two subscriptions
+        // created by the same user in the same block never share an ID
+        let subscription_id = self.next_subscription_id(&user_id, &merchant_id);
+
+        // Calculate next payment date based on frequency, or the template's trial period
+        let next_payment_date = match trial_period {
+            Some(trial_period) => now + trial_period,
+            None => match frequency {
+                SubscriptionFrequency::Daily => now + 86400, // 1 day in seconds
+                SubscriptionFrequency::Weekly => now + 604800, // 1 week in seconds
+                SubscriptionFrequency::Monthly => now + 2592000, // 30 days in seconds
+                SubscriptionFrequency::Quarterly => now + 7776000, // 90 days in seconds
+                SubscriptionFrequency::Yearly => now + 31536000, // 365 days in seconds
+            },
+        };
 
-        // Calculate next payment date based on frequency
-        let next_payment_date = match frequency {
-            SubscriptionFrequency::Daily => now + 86400, // 1 day in seconds
-            SubscriptionFrequency::Weekly => now + 604800, // 1 week in seconds
-            SubscriptionFrequency::Monthly => now + 2592000, // 30 days in seconds
-            SubscriptionFrequency::Quarterly => now + 7776000, // 90 days in seconds
-            SubscriptionFrequency::Yearly => now + 31536000, // 365 days in seconds
+        // Subscriptions above the user's approval threshold wait for their co-signer
+        let approver_id = self.required_approver(&user_id, &payment_method, max_amount_per_charge);
+        let status = if approver_id.is_some() {
+            SubscriptionStatus::PendingApproval
+        } else {
+            SubscriptionStatus::Active
         };
 
         // Create subscription (TODO: verify valid)
@@ -175,29 +565,209 @@ impl Contract {
             amount,
             frequency,
             next_payment_date,
-            status: SubscriptionStatus::Active,
+            status,
             created_at: now,
             updated_at: now,
             payment_method,
             max_payments,
             payments_made: 0,
             end_date,
+            max_amount_per_charge,
+            max_total_spend,
+            total_spent: U128(0),
+            pending_price_change: None,
+            line_items,
+            due_soon_notified_for: None,
+            approved_extension: None,
+            template_id,
+            metadata,
+            held_payment: None,
+            memo_template,
+            referrer_id,
+            cycle_index: 0,
+            retry_count: 0,
+            next_retry_at: None,
+            token_prices: Vec::new(),
+            funding_order: Vec::new(),
+            denomination,
+            swap_funding: None,
+            settlement_pending: false,
+            top_up_cycle: None,
+            balance_warned_for: None,
+            skips_used: 0,
+            skip_year_start: 0,
+            amount_override: None,
+            catching_up: false,
+            approver_id: approver_id.clone(),
+            processed_by: None,
+            failing_since: None,
+            payment_mode,
+            // Streams start accruing once any trial is over
+            stream_claimed_until: now + trial_period.unwrap_or(0),
+            prepaid_cycles: 0,
+            pending_credit: U128(0),
         };
 
         // Store subscription
+        self.index_due_date(&subscription_id, next_payment_date);
+        self.count_subscription(&subscription.merchant_id, &subscription.status);
         self.subscriptions
-            .insert(subscription_id.clone(), subscription);
+            .insert(subscription_id.clone(), subscription.into());
+        self.charge_subscription_storage(&subscription_id, &user_id, initial_storage);
 
+        if let Some(approver_id) = approver_id {
+            log!("Subscription {} awaits approval by {}", subscription_id, approver_id);
+        }
         log!("Subscription created: {}", subscription_id);
 
         subscription_id
     }
 
-    /// Registers a function call access key for a subscription
+    /// Imports existing subscriptions for a merchant migrating from another billing system.
+    /// Callable by the owner for any registered merchant, or by a merchant for its own subscribers.
+    /// Imported subscriptions are validated like new ones, and cannot be charged until the
+    /// subscriber registers a key for them. Merchants' storage deposits cover the storage of
+    /// what they import; the owner attaches a deposit for it instead, and any deposit not
+    /// used for storage is refunded
+    #[payable]
+    pub fn import_subscriptions(&mut self, imports: Vec<SubscriptionImport>) -> Vec<SubscriptionId> {
+        let caller = env::predecessor_account_id();
+        let is_owner = caller == self.owner_id;
+        let now = env::block_timestamp() / 1000000000;
+        let initial_import_storage = env::storage_usage();
+
+        let mut subscription_ids = Vec::with_capacity(imports.len());
+
+        for import in imports {
+            let initial_storage = env::storage_usage();
+            require!(
+                self.merchants.contains(&import.merchant_id),
+                "Merchant not registered"
+            );
+            require!(
+                is_owner || import.merchant_id == caller,
+                "Not authorized to import subscriptions for this merchant"
+            );
+            require!(
+                import.created_at <= now,
+                "created_at cannot be in the future"
+            );
+            if let Some(max) = import.max_payments {
+                require!(
+                    import.payments_made <= max,
+                    "payments_made exceeds max_payments"
+                );
+            }
+            // The next charge falls within the coming billing period
+            require!(
+                import.next_payment_date > now
+                    && import.next_payment_date <= now + import.frequency.seconds(),
+                "next_payment_date must be within the next billing period"
+            );
+
+            let line_items = import.line_items.unwrap_or_default();
+            self.assert_valid_price(
+                &import.merchant_id,
+                &import.payment_method,
+                import.amount,
+                &line_items,
+                import.amount,
+                None,
+            );
+
+            let subscription_id =
+                self.next_subscription_id(&import.user_id, &import.merchant_id);
+
+            let subscription = Subscription {
+                id: subscription_id.clone(),
+                user_id: import.user_id,
+                merchant_id: import.merchant_id,
+                amount: import.amount,
+                frequency: import.frequency,
+                next_payment_date: import.next_payment_date,
+                status: SubscriptionStatus::Active,
+                created_at: import.created_at,
+                updated_at: now,
+                payment_method: import.payment_method,
+                max_payments: import.max_payments,
+                payments_made: import.payments_made,
+                end_date: import.end_date,
+                max_amount_per_charge: import.amount,
+                max_total_spend: None,
+                total_spent: U128(0),
+                pending_price_change: None,
+                line_items,
+                due_soon_notified_for: None,
+                approved_extension: None,
+                template_id: None,
+                metadata: None,
+                held_payment: None,
+                memo_template: None,
+                referrer_id: None,
+                cycle_index: import.payments_made,
+                retry_count: 0,
+                next_retry_at: None,
+                token_prices: Vec::new(),
+                funding_order: Vec::new(),
+                denomination: PriceDenomination::Token,
+                swap_funding: None,
+                settlement_pending: false,
+                top_up_cycle: None,
+                balance_warned_for: None,
+                skips_used: 0,
+                skip_year_start: 0,
+                amount_override: None,
+                catching_up: false,
+                approver_id: None,
+                processed_by: None,
+                failing_since: None,
+                payment_mode: PaymentMode::Periodic,
+                stream_claimed_until: 0,
+                prepaid_cycles: 0,
+                pending_credit: U128(0),
+            };
+
+            self.index_due_date(&subscription_id, subscription.next_payment_date);
+            self.count_subscription(&subscription.merchant_id, &subscription.status);
+            self.subscriptions
+                .insert(subscription_id.clone(), subscription.into());
+
+            log!("Subscription imported: {}", subscription_id);
+
+            if !is_owner {
+                self.charge_subscription_storage(&subscription_id, &caller, initial_storage);
+            }
+
+            subscription_ids.push(subscription_id);
+        }
+
+        // The owner pays for what it imports with the attached deposit
+        let deposit = env::attached_deposit().as_yoctonear();
+        let storage_cost = if is_owner {
+            self.flush_subscription_storage();
+            Self::storage_cost(env::storage_usage().saturating_sub(initial_import_storage))
+        } else {
+            0
+        };
+        require!(
+            deposit >= storage_cost,
+            format!("Attach a deposit of at least {} for the imported subscriptions", storage_cost)
+        );
+        if deposit > storage_cost {
+            Promise::new(caller).transfer(NearToken::from_yoctonear(deposit - storage_cost));
+        }
+
+        subscription_ids
+    }
+
+    /// Registers a function call access key for a subscription, charged to the subscriber's
+    /// storage deposit. A subscription can have several keys, e.g. for a primary and a backup
+    /// worker, told apart by an optional `label`
     pub fn register_subscription_key(
         &mut self,
         public_key: String, // this is used later to generate key pair
         subscription_id: SubscriptionId,
+        label: Option<String>,
     ) {
         let user_id = env::predecessor_account_id();
 
@@ -212,34 +782,88 @@ impl Contract {
         );
 
         // Register key
-        self.subscription_keys
-            .insert(public_key, subscription_id.clone());
+        self.flush_subscription_storage();
+        let initial_storage = env::storage_usage();
+        self.link_subscription_key(&subscription_id, public_key.clone());
+        self.label_subscription_key(&public_key, label);
+        self.charge_storage(&user_id, initial_storage);
 
         log!("Key registered for subscription: {}", subscription_id);
     }
 
+    /// Removes a subscription's function call access key, returning its storage to the
+    /// subscriber's storage deposit
+    pub fn remove_subscription_key(&mut self, public_key: String) {
+        let user_id = env::predecessor_account_id();
+        let subscription_id = self
+            .subscription_keys
+            .get(&public_key)
+            .expect("Key not found")
+            .clone();
+        let subscription = self
+            .subscriptions
+            .get(&subscription_id)
+            .expect("Subscription not found");
+        require!(
+            subscription.user_id == user_id,
+            "Not authorized to remove this key"
+        );
+
+        self.flush_subscription_storage();
+        let initial_storage = env::storage_usage();
+        self.unlink_subscription_key(&subscription_id, &public_key);
+        self.flush_subscription_storage();
+        let storage_freed = initial_storage.saturating_sub(env::storage_usage());
+        self.release_storage(&user_id, storage_freed);
+
+        log!("Key removed for subscription: {}", subscription_id);
+    }
+
     /// Cancels a subscription
     pub fn cancel_subscription(&mut self, subscription_id: SubscriptionId) {
         let user_id = env::predecessor_account_id();
 
         // Verify subscription exists and belongs to user
-        let mut subscription = self
+        let subscription = self
             .subscriptions
             .get(&subscription_id)
-            .expect("Subscription not found")
-            .clone();
+            .expect("Subscription not found");
         require!(
             subscription.user_id == user_id,
             "Not authorized to cancel this subscription"
         );
+        let now = env::block_timestamp() / 1000000000;
+        let merchant_id = subscription.merchant_id.clone();
 
-        // Update subscription status
-        subscription.status = SubscriptionStatus::Canceled;
-        subscription.updated_at = env::block_timestamp() / 1000000000;
+        // Streams pay out what has accrued so far and stop accruing straight away
+        if subscription.payment_mode == PaymentMode::Stream {
+            self.settle_stream(&subscription_id, now);
+        }
 
-        // Store updated subscription
-        self.subscriptions
-            .insert(subscription_id.clone(), subscription);
+        // Update subscription status
+        self.transition_subscription(&subscription_id, SubscriptionStatus::Canceled, now);
+        let within_cooling_off = self.with_subscription_mut(&subscription_id, |subscription| {
+            subscription
+                .held_payment
+                .as_ref()
+                .is_some_and(|held| now < held.release_at)
+        });
+
+        // A canceled subscription can no longer be charged, so its keys are of no further use
+        self.revoke_subscription_keys(&subscription_id, &user_id);
+
+        // Canceling inside the cooling-off window refunds everything charged so far,
+        // otherwise the held funds belong to the merchant
+        if within_cooling_off {
+            self.refund_held_payment(&subscription_id, "cooling_off");
+        } else {
+            self.release_held_payment_internal(&subscription_id);
+
+            // Merchants can opt into crediting the unused part of the current cycle
+            if self.get_merchant_settings(merchant_id).prorate_on_cancel {
+                self.credit_prorated_refund(&subscription_id);
+            }
+        }
 
         log!("Subscription canceled: {}", subscription_id);
     }
@@ -249,98 +873,673 @@ impl Contract {
         let user_id = env::predecessor_account_id();
 
         // Verify subscription exists and belongs to user
-        let mut subscription = self
+        let subscription = self
             .subscriptions
             .get(&subscription_id)
-            .expect("Subscription not found")
-            .clone();
+            .expect("Subscription not found");
         require!(
             subscription.user_id == user_id,
             "Not authorized to pause this subscription"
         );
+        let now = env::block_timestamp() / 1000000000;
 
-        // Update subscription status
-        subscription.status = SubscriptionStatus::Paused;
-        subscription.updated_at = env::block_timestamp() / 1000000000;
+        // Streams do not accrue while paused
+        if subscription.payment_mode == PaymentMode::Stream {
+            self.settle_stream(&subscription_id, now);
+        }
 
-        // Store updated subscription
-        self.subscriptions
-            .insert(subscription_id.clone(), subscription);
+        // Update subscription status
+        self.transition_subscription(&subscription_id, SubscriptionStatus::Paused, now);
 
         log!("Subscription paused: {}", subscription_id);
     }
 
-    /// Resumes a paused subscription
-    pub fn resume_subscription(&mut self, subscription_id: SubscriptionId) {
+    /// Resumes a paused subscription. If its payment fell due while paused, `charge_now`
+    /// charges it straight away; otherwise it is left for the next worker pass and a
+    /// `payment_overdue` event is emitted
+    pub fn resume_subscription(
+        &mut self,
+        subscription_id: SubscriptionId,
+        charge_now: Option<bool>,
+    ) -> Option<PaymentResult> {
         let user_id = env::predecessor_account_id();
+        let now = env::block_timestamp() / 1000000000;
 
         // Verify subscription exists and belongs to user
-        let mut subscription = self
+        let subscription = self
             .subscriptions
             .get(&subscription_id)
-            .expect("Subscription not found")
-            .clone();
+            .expect("Subscription not found");
         require!(
             subscription.user_id == user_id,
             "Not authorized to resume this subscription"
         );
+        // Awaiting approval can also move to active, but only through the co-signer
         require!(
             matches!(subscription.status, SubscriptionStatus::Paused),
             "Subscription is not paused"
         );
 
         // Update subscription status
-        subscription.status = SubscriptionStatus::Active;
-        subscription.updated_at = env::block_timestamp() / 1000000000;
-
-        // Store updated subscription
-        self.subscriptions
-            .insert(subscription_id.clone(), subscription);
+        self.transition_subscription(&subscription_id, SubscriptionStatus::Active, now);
+        let (next_payment_date, streaming) =
+            self.with_subscription_mut(&subscription_id, |subscription| {
+                // Streams pick up accruing from now; there is nothing overdue to charge
+                let streaming = subscription.payment_mode == PaymentMode::Stream;
+                if streaming {
+                    subscription.stream_claimed_until = now;
+                }
+                (subscription.next_payment_date, streaming)
+            });
 
         log!("Subscription resumed: {}", subscription_id);
+        if streaming {
+            return None;
+        }
+
+        if next_payment_date > now {
+            return None;
+        }
+        if charge_now.unwrap_or(false) {
+            return Some(self.charge_subscription(subscription_id, None, None, now));
+        }
+
+        Event::PaymentOverdue {
+            subscription_id,
+            user_id,
+            next_payment_date,
+        }
+        .emit();
+        None
+    }
+
+    /// Skips the next payment, pushing it back one billing period without pausing the
+    /// subscription. Only available if the merchant allows skips, up to its yearly limit
+    pub fn skip_next_payment(&mut self, subscription_id: SubscriptionId) {
+        let user_id = env::predecessor_account_id();
+        let now = env::block_timestamp() / 1000000000;
+
+        let merchant_id = self
+            .subscriptions
+            .get(&subscription_id)
+            .expect("Subscription not found")
+            .merchant_id
+            .clone();
+        let max_skips = self.get_merchant_settings(merchant_id).max_skips_per_year;
+
+        let next_payment_date =
+            self.with_subscription_mut(&subscription_id, |subscription| {
+                require!(
+                    subscription.user_id == user_id,
+                    "Not authorized to skip this payment"
+                );
+                require!(
+                    matches!(subscription.status, SubscriptionStatus::Active),
+                    "Subscription is not active"
+                );
+                let max_skips = max_skips.expect("Merchant does not allow skipping payments");
+
+                // Skips are counted per year starting from the first skip
+                if now >= subscription.skip_year_start + SKIP_YEAR {
+                    subscription.skip_year_start = now;
+                    subscription.skips_used = 0;
+                }
+                require!(
+                    subscription.skips_used < max_skips,
+                    "No skips left this year"
+                );
+
+                subscription.skips_used += 1;
+                subscription.cycle_index += 1;
+                subscription.next_payment_date += subscription.frequency.seconds();
+                subscription.updated_at = now;
+                subscription.next_payment_date
+            });
+        self.index_due_date(&subscription_id, next_payment_date);
+
+        Event::PaymentSkipped {
+            subscription_id,
+            user_id,
+            next_payment_date,
+        }
+        .emit();
+    }
+
+    /// Updates the maximum number of payments. Subscribers may only tighten the limit unless
+    /// the merchant has approved an extension covering the new value
+    pub fn update_max_payments(&mut self, subscription_id: SubscriptionId, max_payments: Option<u32>) {
+        let user_id = env::predecessor_account_id();
+        let now = env::block_timestamp() / 1000000000;
+
+        self.with_subscription_mut(&subscription_id, |subscription| {
+            // Verify subscription belongs to user
+            require!(
+                subscription.user_id == user_id,
+                "Not authorized to update this subscription"
+            );
+            if let Some(max) = max_payments {
+                require!(
+                    max >= subscription.payments_made,
+                    "max_payments cannot be below payments already made"
+                );
+            }
+
+            // Loosening the commitment consumes the merchant's approved extension
+            if !within_limit(max_payments, subscription.max_payments) {
+                let approved = subscription
+                    .approved_extension
+                    .take()
+                    .expect("Extending max_payments requires merchant approval");
+                require!(
+                    within_limit(max_payments, approved.max_payments),
+                    "max_payments exceeds the merchant-approved extension"
+                );
+            }
+
+            subscription.max_payments = max_payments;
+            subscription.updated_at = now;
+        });
+
+        log!("Max payments updated for subscription: {}", subscription_id);
+    }
+
+    /// Updates the end date. Subscribers may only bring it forward unless the merchant has
+    /// approved an extension covering the new value
+    pub fn update_end_date(&mut self, subscription_id: SubscriptionId, end_date: Option<u64>) {
+        let user_id = env::predecessor_account_id();
+        let now = env::block_timestamp() / 1000000000;
+
+        self.with_subscription_mut(&subscription_id, |subscription| {
+            // Verify subscription belongs to user
+            require!(
+                subscription.user_id == user_id,
+                "Not authorized to update this subscription"
+            );
+            if let Some(end_date) = end_date {
+                require!(end_date > now, "end_date must be in the future");
+            }
+
+            // Loosening the commitment consumes the merchant's approved extension
+            if !within_limit(end_date, subscription.end_date) {
+                let approved = subscription
+                    .approved_extension
+                    .take()
+                    .expect("Extending end_date requires merchant approval");
+                require!(
+                    within_limit(end_date, approved.end_date),
+                    "end_date exceeds the merchant-approved extension"
+                );
+            }
+
+            subscription.end_date = end_date;
+            subscription.updated_at = now;
+        });
+
+        log!("End date updated for subscription: {}", subscription_id);
     }
 
     /// Gets a subscription by ID
     pub fn get_subscription(&self, subscription_id: SubscriptionId) -> Option<Subscription> {
-        self.subscriptions.get(&subscription_id).cloned()
+        self.subscriptions.get(&subscription_id).map(Subscription::from)
     }
 
-    /// Gets all subscriptions for a user
-    pub fn get_user_subscriptions(&self, user_id: AccountId) -> Vec<Subscription> {
-        let mut subscriptions = Vec::new();
+    /// Gets subscriptions by ID, in the order given, with `None` for IDs that do not exist
+    pub fn get_subscriptions_by_ids(
+        &self,
+        subscription_ids: Vec<SubscriptionId>,
+    ) -> Vec<Option<Subscription>> {
+        require!(
+            subscription_ids.len() <= MAX_BATCH_LOOKUP,
+            "Too many subscription IDs"
+        );
+
+        subscription_ids
+            .iter()
+            .map(|subscription_id| self.subscriptions.get(subscription_id).map(Subscription::from))
+            .collect()
+    }
 
-        for (_, subscription) in self.subscriptions.iter() {
-            if subscription.user_id == user_id {
-                subscriptions.push(subscription.clone());
-            }
+    /// Pages over every subscription in creation order, `limit` at a time. Pass the returned
+    /// `next_cursor` as `from` to get the next page; pages stay stable as subscriptions are
+    /// created and archived. A page may hold fewer than `limit` subscriptions when many in its
+    /// range were archived or it would be too large to return. Callable by the owner or an
+    /// approved worker, as a signed call since checking the caller cannot be done in a view
+    pub fn get_subscriptions(&mut self, from: Option<String>, limit: u64) -> SubscriptionPage {
+        require!(
+            env::predecessor_account_id() == self.owner_id
+                || self.is_verified_by_approved_codehash(),
+            "Not authorized to list subscriptions"
+        );
+
+        self.page_subscriptions(from, limit, |_| true)
+    }
+
+    /// Pages over a user's subscriptions in creation order, read from the user's index. A page
+    /// stops early with `has_more` set when it would be too large to return; pass
+    /// `next_cursor` as `from` to continue
+    pub fn get_user_subscriptions(
+        &self,
+        user_id: AccountId,
+        from: Option<String>,
+        limit: Option<u64>,
+    ) -> SubscriptionPage {
+        let from = from.map_or(0, |cursor| cursor.parse::<u64>().expect("Invalid cursor"));
+        let entries = self
+            .user_subscription_ids(&user_id)
+            .into_iter()
+            .filter_map(|subscription_id| {
+                Self::sequence_number(&subscription_id)
+                    .map(|sequence_number| (sequence_number, subscription_id))
+            })
+            .skip_while(move |(sequence_number, _)| *sequence_number <= from);
+        self.page_indexed_subscriptions(entries, from, limit.unwrap_or(u64::MAX))
+    }
+
+    /// Gets the next charge for each of a user's active subscriptions, soonest first, then by
+    /// subscription ID, `limit` at a time from `from_index`
+    pub fn get_upcoming_payments(
+        &self,
+        user_id: AccountId,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<UpcomingPayment> {
+        let mut upcoming: Vec<UpcomingPayment> = self
+            .user_subscriptions(&user_id)
+            .filter(|subscription| matches!(subscription.status, SubscriptionStatus::Active))
+            .map(|subscription| {
+                // Show the new price if a scheduled change will apply by the next charge
+                let amount = match &subscription.pending_price_change {
+                    Some(change) if change.effective_at <= subscription.next_payment_date => {
+                        change.new_amount
+                    }
+                    _ => subscription.amount,
+                };
+                UpcomingPayment {
+                    subscription_id: subscription.id,
+                    merchant_id: subscription.merchant_id,
+                    amount,
+                    payment_method: subscription.payment_method,
+                    next_payment_date: subscription.next_payment_date,
+                }
+            })
+            .collect();
+
+        upcoming.sort_by(|a, b| {
+            a.next_payment_date
+                .cmp(&b.next_payment_date)
+                .then_with(|| a.subscription_id.cmp(&b.subscription_id))
+        });
+        upcoming
+            .into_iter()
+            .skip(from_index.unwrap_or(0) as usize)
+            .take(limit.unwrap_or(u64::MAX) as usize)
+            .collect()
+    }
+
+    /// Returns true if the user currently holds an active subscription with the merchant,
+    /// whether free or paid
+    pub fn is_entitled(&self, user_id: AccountId, merchant_id: AccountId) -> bool {
+        let now = env::block_timestamp() / 1000000000;
+
+        self.user_subscriptions(&user_id).any(|subscription| {
+            subscription.merchant_id == merchant_id
+                && matches!(
+                    subscription.status,
+                    SubscriptionStatus::Active | SubscriptionStatus::PastDue
+                )
+                && subscription.end_date.is_none_or(|end_date| now < end_date)
+        })
+    }
+
+    /// Gets the stored payment history for a subscription, oldest first. Only the latest
+    /// records are kept; older ones were emitted as `payment_record_archived` events
+    pub fn get_payment_history(&self, subscription_id: SubscriptionId) -> Vec<PaymentRecord> {
+        self.payment_records(&subscription_id).cloned().collect()
+    }
+
+    /// Pages over a merchant's subscriptions in creation order, read from the merchant index.
+    /// A page stops early with `has_more` set when it would be too large to return; pass
+    /// `next_cursor` as `from` to continue
+    pub fn get_merchant_subscriptions(
+        &self,
+        merchant_id: AccountId,
+        from: Option<String>,
+        limit: Option<u64>,
+    ) -> SubscriptionPage {
+        let from = from.map_or(0, |cursor| cursor.parse::<u64>().expect("Invalid cursor"));
+        let entries = self.merchant_subscription_ids(&merchant_id, from);
+        self.page_indexed_subscriptions(entries, from, limit.unwrap_or(u64::MAX))
+    }
+
+    // MERCHANT METHODS
+
+    /// Schedules a new amount for a subscription, effective no earlier than the notice period
+    pub fn schedule_price_change(
+        &mut self,
+        subscription_id: SubscriptionId,
+        new_amount: U128,
+        effective_at: u64,
+        new_line_items: Option<Vec<LineItem>>,
+    ) {
+        let merchant_id = env::predecessor_account_id();
+        let now = env::block_timestamp() / 1000000000;
+
+        let subscription = self
+            .subscriptions
+            .get(&subscription_id)
+            .expect("Subscription not found");
+        require!(
+            subscription.merchant_id == merchant_id,
+            "Not authorized to change the price of this subscription"
+        );
+        require!(
+            effective_at >= now + self.price_change_notice_period,
+            "Price change does not respect the notice period"
+        );
+        self.assert_valid_token_amount(&subscription.payment_method, new_amount);
+        self.assert_above_min_charge(&merchant_id, &subscription.payment_method, new_amount);
+        match &new_line_items {
+            Some(items) => Self::assert_line_items_match(items, new_amount),
+            None => require!(
+                subscription.line_items.is_empty(),
+                "Line item subscriptions must reprice their line items"
+            ),
+        }
+
+        let (user_id, old_amount) = self.with_subscription_mut(&subscription_id, |subscription| {
+            subscription.pending_price_change = Some(PriceChange {
+                new_amount,
+                new_line_items,
+                effective_at,
+                scheduled_at: now,
+            });
+            subscription.updated_at = now;
+            (subscription.user_id.clone(), subscription.amount)
+        });
+
+        // Emit right away so subscribers can cancel before the change applies
+        Event::PriceChangeScheduled {
+            subscription_id,
+            user_id,
+            merchant_id,
+            old_amount,
+            new_amount,
+            effective_at,
         }
+        .emit();
+    }
+
+    /// Cancels a pending price change before it takes effect
+    pub fn cancel_price_change(&mut self, subscription_id: SubscriptionId) {
+        let merchant_id = env::predecessor_account_id();
+
+        let now = env::block_timestamp() / 1000000000;
+
+        self.with_subscription_mut(&subscription_id, |subscription| {
+            require!(
+                subscription.merchant_id == merchant_id,
+                "Not authorized to change the price of this subscription"
+            );
+            require!(
+                subscription.pending_price_change.is_some(),
+                "No pending price change"
+            );
+
+            subscription.pending_price_change = None;
+            subscription.updated_at = now;
+        });
+
+        Event::PriceChangeCanceled {
+            subscription_id,
+            merchant_id,
+        }
+        .emit();
+    }
+
+    /// Approves the subscriber extending max_payments/end_date up to the given terms
+    pub fn approve_commitment_extension(
+        &mut self,
+        subscription_id: SubscriptionId,
+        terms: Option<CommitmentTerms>,
+    ) {
+        let merchant_id = env::predecessor_account_id();
 
-        subscriptions
+        let now = env::block_timestamp() / 1000000000;
+
+        self.with_subscription_mut(&subscription_id, |subscription| {
+            require!(
+                subscription.merchant_id == merchant_id,
+                "Not authorized to approve extensions for this subscription"
+            );
+
+            subscription.approved_extension = terms;
+            subscription.updated_at = now;
+        });
+
+        log!("Commitment extension updated for subscription: {}", subscription_id);
     }
 
-    /// Gets all subscriptions for a merchant
-    pub fn get_merchant_subscriptions(&self, merchant_id: AccountId) -> Vec<Subscription> {
-        let mut subscriptions = Vec::new();
+    /// Creates or replaces one of the caller's subscription templates
+    #[allow(clippy::too_many_arguments)]
+    pub fn set_template(
+        &mut self,
+        template_id: String,
+        amount: U128,
+        frequency: SubscriptionFrequency,
+        payment_method: PaymentMethod,
+        line_items: Option<Vec<LineItem>>,
+        trial_period: Option<u64>,
+        metadata: Option<String>,
+        memo_template: Option<String>,
+    ) {
+        let merchant_id = env::predecessor_account_id();
+        require!(
+            self.merchants.contains(&merchant_id),
+            "Merchant not registered"
+        );
+
+        let line_items = line_items.unwrap_or_default();
+        Self::assert_line_items_match(&line_items, amount);
+        Self::assert_valid_memo_template(&memo_template);
+        self.assert_payment_method_allowed(&payment_method);
+        self.assert_valid_token_amount(&payment_method, amount);
+        self.assert_above_min_charge(&merchant_id, &payment_method, amount);
+
+        let key = Self::template_key(&merchant_id, &template_id);
+        self.templates.insert(
+            key,
+            SubscriptionTemplate {
+                template_id: template_id.clone(),
+                merchant_id,
+                amount,
+                frequency,
+                payment_method,
+                line_items,
+                trial_period,
+                metadata,
+                memo_template,
+            },
+        );
 
-        for (_, subscription) in self.subscriptions.iter() {
-            if subscription.merchant_id == merchant_id {
-                subscriptions.push(subscription.clone());
+        log!("Template saved: {}", template_id);
+    }
+
+    /// Removes one of the caller's subscription templates
+    pub fn remove_template(&mut self, template_id: String) {
+        let merchant_id = env::predecessor_account_id();
+        let key = Self::template_key(&merchant_id, &template_id);
+        require!(
+            self.templates.remove(&key).is_some(),
+            "Template not found"
+        );
+        log!("Template removed: {}", template_id);
+    }
+
+    /// Gets a merchant's template by ID
+    pub fn get_template(&self, merchant_id: AccountId, template_id: String) -> Option<SubscriptionTemplate> {
+        self.templates
+            .get(&Self::template_key(&merchant_id, &template_id))
+            .cloned()
+    }
+
+    /// Gets all templates defined by a merchant, ordered by template ID
+    pub fn get_merchant_templates(&self, merchant_id: AccountId) -> Vec<SubscriptionTemplate> {
+        let mut templates: Vec<SubscriptionTemplate> = self
+            .templates
+            .values()
+            .filter(|template| template.merchant_id == merchant_id)
+            .cloned()
+            .collect();
+        templates.sort_by(|a, b| a.template_id.cmp(&b.template_id));
+        templates
+    }
+
+    /// Sets (or clears, with `None`) the caller's monthly spending limit for a merchant
+    pub fn set_merchant_limit(
+        &mut self,
+        merchant_id: AccountId,
+        payment_method: PaymentMethod,
+        max_per_month: Option<U128>,
+    ) {
+        let user_id = env::predecessor_account_id();
+        let key = (user_id, merchant_id.clone(), payment_method);
+
+        match max_per_month {
+            Some(max_per_month) => {
+                // Keep the current window's spend so lowering a limit takes effect immediately
+                let (period_start, spent_in_period) = match self.merchant_limits.get(&key) {
+                    Some(limit) => (limit.period_start, limit.spent_in_period),
+                    None => (env::block_timestamp() / 1000000000, U128(0)),
+                };
+                self.merchant_limits.insert(
+                    key,
+                    MerchantLimit {
+                        max_per_month,
+                        period_start,
+                        spent_in_period,
+                    },
+                );
+                log!("Merchant limit set for {}: {}", merchant_id, max_per_month.0);
+            }
+            None => {
+                self.merchant_limits.remove(&key);
+                log!("Merchant limit removed for {}", merchant_id);
             }
         }
+    }
+
+    /// Gets a user's spending limit for a merchant and token
+    pub fn get_merchant_limit(
+        &self,
+        user_id: AccountId,
+        merchant_id: AccountId,
+        payment_method: PaymentMethod,
+    ) -> Option<MerchantLimit> {
+        self.merchant_limits
+            .get(&(user_id, merchant_id, payment_method))
+            .cloned()
+    }
+
+    // HELPER METHODS FOR SUBSCRIPTIONS
+
+    /// Storage key for a merchant's template
+    fn template_key(merchant_id: &AccountId, template_id: &str) -> String {
+        format!("{}:{}", merchant_id, template_id)
+    }
 
-        subscriptions
+    /// Requires a subscription's price to be chargeable: an allowed token, an amount in the
+    /// token's units and above the minimum charge, line items (if any) adding up to it and
+    /// spending caps covering at least one charge
+    fn assert_valid_price(
+        &self,
+        merchant_id: &AccountId,
+        payment_method: &PaymentMethod,
+        amount: U128,
+        line_items: &[LineItem],
+        max_amount_per_charge: U128,
+        max_total_spend: Option<U128>,
+    ) {
+        self.assert_payment_method_allowed(payment_method);
+        self.assert_valid_token_amount(payment_method, amount);
+        self.assert_above_min_charge(merchant_id, payment_method, amount);
+        Self::assert_line_items_match(line_items, amount);
+        require!(
+            amount.0 <= max_amount_per_charge.0,
+            "Amount exceeds max_amount_per_charge"
+        );
+        if let Some(max_total) = max_total_spend {
+            require!(
+                amount.0 <= max_total.0,
+                "Amount exceeds max_total_spend"
+            );
+        }
+    }
+
+    /// Requires that line items (if any) sum to the given amount
+    fn assert_line_items_match(line_items: &[LineItem], amount: U128) {
+        if line_items.is_empty() {
+            return;
+        }
+        let total: u128 = line_items.iter().map(|item| item.total()).sum();
+        require!(
+            total == amount.0,
+            "Amount must equal the sum of line items"
+        );
+    }
+
+    /// Generates a unique subscription ID and advances the nonce
+    fn next_subscription_id(
+        &mut self,
+        user_id: &AccountId,
+        merchant_id: &AccountId,
+    ) -> SubscriptionId {
+        self.subscription_nonce += 1;
+        let subscription_id = format!("sub-{}-{}", user_id, self.subscription_nonce);
+        require!(
+            !self.subscriptions.contains_key(&subscription_id),
+            "Subscription ID already exists"
+        );
+        self.subscription_sequence
+            .insert(self.subscription_nonce, subscription_id.clone());
+        self.index_user_subscription(user_id, &subscription_id);
+        self.index_merchant_subscription(merchant_id, &subscription_id, self.subscription_nonce);
+        subscription_id
+    }
+
+    /// Creation sequence number of a subscription, the nonce its ID was derived from
+    pub(crate) fn sequence_number(subscription_id: &SubscriptionId) -> Option<u64> {
+        subscription_id.rsplit('-').next()?.parse().ok()
     }
 
     // HELPER METHODS FOR PAYMENTS
     
+    /// What a charge of `subscription` bills at the current cycle and rates
+    pub(crate) fn charge_details(&self, subscription: &Subscription) -> ChargeDetails {
+        let usd_rate = match subscription.denomination {
+            PriceDenomination::Usd { .. } => self.usd_rates.get(&subscription.payment_method).cloned(),
+            _ => None,
+        };
+        ChargeDetails {
+            cycle_index: subscription.cycle_index,
+            amount: subscription.funding().amount,
+            usd_rate,
+        }
+    }
+
     /// Updates a subscription after a successful payment
-    /// Returns the updated subscription
+    /// Returns the number of the payment recorded
+    /// `funding` is what was actually charged; spending caps and limits count the amount
+    /// `charge` billed in the subscription's own token, which merchant token prices are
+    /// equivalent to. A charge that settles after the subscription was canceled, paused or
+    /// moved on to another cycle is recorded without advancing it
     fn update_subscription_after_payment(
         &mut self,
         subscription: &Subscription,
         subscription_id: &SubscriptionId,
+        funding: &FundingSource,
+        charge: &ChargeDetails,
         now: u64,
-    ) -> Subscription {
+    ) -> u32 {
         // Clone frequency and calculate next payment date
         let frequency = subscription.frequency.clone();
         let next_payment_date = match frequency {
@@ -350,26 +1549,205 @@ impl Contract {
             SubscriptionFrequency::Quarterly => now + 7776000,
             SubscriptionFrequency::Yearly => now + 31536000,
         };
+
+        // Catch-up charges pay for the missed cycle after the previous due date instead
+        let caught_up_date = subscription.next_payment_date + frequency.seconds();
+        let catching_up = subscription.catching_up && caught_up_date <= now;
+        let next_payment_date = if subscription.catching_up {
+            caught_up_date
+        } else {
+            next_payment_date
+        };
         
-        // Create a new subscription with updated values
-        let mut updated_subscription = subscription.clone();
-        updated_subscription.payments_made += 1;
-        updated_subscription.next_payment_date = next_payment_date;
-        updated_subscription.updated_at = now;
+        // Prepaid cycles were counted towards spending when they were paid for
+        let spent = if funding.amount.0 == 0 {
+            0
+        } else {
+            charge.amount.0
+        };
 
-        // Store updated subscription
-        self.subscriptions
-            .insert(subscription_id.clone(), updated_subscription.clone());
-            
-        updated_subscription
+        // Update the stored subscription in place. The payment always counts towards spending,
+        // but only advances a subscription still chargeable for the cycle it paid for. An
+        // overridden amount applies to a single charge, so the stored amount is only taken
+        // from plain charges
+        let (payments_made, status) = self.with_subscription_mut(subscription_id, |stored| {
+            let chargeable = matches!(
+                stored.status,
+                SubscriptionStatus::Active | SubscriptionStatus::PastDue
+            ) && stored.cycle_index == charge.cycle_index;
+
+            stored.payments_made += 1;
+            stored.total_spent = U128(stored.total_spent.0 + spent);
+            stored.pending_credit = U128(0);
+            stored.updated_at = now;
+            if subscription.amount_override.is_none() {
+                stored.amount = charge.amount;
+            }
+            stored.amount_override = None;
+            if chargeable {
+                stored.cycle_index += 1;
+                stored.prepaid_cycles = subscription.prepaid_cycles;
+                stored.retry_count = 0;
+                stored.next_retry_at = None;
+                stored.failing_since = None;
+                stored.next_payment_date = next_payment_date;
+                stored.catching_up = catching_up;
+            }
+            (stored.payments_made, chargeable.then(|| stored.status.clone()))
+        });
+
+        // A successful retry brings a past-due subscription back to active
+        match status {
+            Some(SubscriptionStatus::PastDue) => {
+                self.transition_subscription(subscription_id, SubscriptionStatus::Active, now);
+            }
+            Some(_) => self.index_due_date(subscription_id, next_payment_date),
+            None => log!(
+                "Payment for subscription {} settled after it stopped being chargeable for cycle {}",
+                subscription_id,
+                charge.cycle_index
+            ),
+        }
+        if spent > 0 {
+            self.record_merchant_spend(subscription, spent, now);
+        }
+
+        // Record the payment, itemized when the subscription has line items
+        self.record_volume(&funding.payment_method, funding.amount.0);
+        let fee = self.fee_for(funding.amount.0);
+        let commission = self.referral_commission_for(subscription, funding.amount.0 - fee);
+        let usd_rate = charge
+            .usd_rate
+            .clone()
+            .filter(|_| funding.payment_method == subscription.payment_method);
+        let rate_source = usd_rate
+            .as_ref()
+            .and(self.usd_oracle.as_ref())
+            .map(|oracle| oracle.oracle_id.clone());
+        let line_items = if funding.payment_method == subscription.payment_method
+            && subscription.amount_override.is_none()
+        {
+            subscription.line_items.clone()
+        } else {
+            Vec::new()
+        };
+        let mut record = PaymentRecord {
+            subscription_id: subscription_id.clone(),
+            kind: PaymentKind::Charge,
+            payment_number: payments_made,
+            amount: funding.amount,
+            payment_method: funding.payment_method.clone(),
+            line_items,
+            memo: Some(self.payment_memo(subscription, payments_made)),
+            fee: U128(fee),
+            referral_commission: U128(commission),
+            payouts: self.payout_legs(
+                &subscription.merchant_id,
+                funding.amount.0 - fee - commission,
+            ),
+            invoice_number: None,
+            usd_rate,
+            amount_override: subscription.amount_override.map(|amount| AmountOverride {
+                amount,
+                max_amount_per_charge: subscription.max_amount_per_charge,
+            }),
+            rate_source,
+            swap: None,
+            timestamp: now,
+            block_height: env::block_height(),
+            block_timestamp: env::block_timestamp(),
+        };
+        record.invoice_number = Some(self.issue_invoice(subscription, &record));
+        if let Some(updated_subscription) = self.subscriptions.get(subscription_id) {
+            self.call_payment_hook(updated_subscription, &record);
+        }
+        self.push_payment_record(record);
+
+        payments_made
+    }
+
+    /// Appends a record to a subscription's payment history
+    /// Sends NEAR or fungible tokens held by the contract to a receiver
+    fn transfer_funds(
+        &self,
+        payment_method: &PaymentMethod,
+        receiver_id: AccountId,
+        amount: u128,
+        memo: String,
+    ) -> Promise {
+        match payment_method {
+            PaymentMethod::Near => {
+                Promise::new(receiver_id).transfer(NearToken::from_yoctonear(amount))
+            }
+            PaymentMethod::Ft { token_id } => {
+                let ft_transfer_args = serde_json::json!({
+                    "receiver_id": receiver_id.to_string(),
+                    "amount": amount.to_string(),
+                    "memo": memo
+                })
+                .to_string()
+                .into_bytes();
+
+                Promise::new(token_id.clone()).function_call(
+                    "ft_transfer".to_string(),
+                    ft_transfer_args,
+                    NearToken::from_yoctonear(1), // 1 yoctoNEAR deposit
+                    Gas::from_tgas(10), // Allocate gas for the cross-contract call
+                )
+            }
+            PaymentMethod::Mt {
+                contract_id,
+                token_id,
+            } => self.mt_transfer(contract_id, token_id, receiver_id, amount, memo),
+        }
     }
     
+    /// Returns how much the user's merchant limit still allows this window, or None if no limit applies
+    fn merchant_limit_headroom(&self, subscription: &Subscription, now: u64) -> Option<u128> {
+        let key = (
+            subscription.user_id.clone(),
+            subscription.merchant_id.clone(),
+            subscription.payment_method.clone(),
+        );
+        self.merchant_limits.get(&key).map(|limit| {
+            let spent = if now >= limit.period_start + MERCHANT_LIMIT_PERIOD {
+                0
+            } else {
+                limit.spent_in_period.0
+            };
+            limit.max_per_month.0.saturating_sub(spent)
+        })
+    }
+
+    /// Records `amount` charged against the user's limit for the merchant, rolling the window if needed
+    fn record_merchant_spend(&mut self, subscription: &Subscription, amount: u128, now: u64) {
+        let key = (
+            subscription.user_id.clone(),
+            subscription.merchant_id.clone(),
+            subscription.payment_method.clone(),
+        );
+        if let Some(limit) = self.merchant_limits.get_mut(&key) {
+            if now >= limit.period_start + MERCHANT_LIMIT_PERIOD {
+                limit.period_start = now;
+                limit.spent_in_period = U128(0);
+            }
+            limit.spent_in_period = U128(limit.spent_in_period.0 + amount);
+        }
+    }
+
     // PAYMENT METHODS
 
     /// Processes a payment for a subscription
     /// This is called by the API with the generated key pair for stored public key
     /// And private key stored in API
-    pub fn process_payment(&mut self, subscription_id: SubscriptionId) -> PaymentResult {
+    /// When `cycle_index` is given the charge is rejected unless that cycle is still unpaid,
+    /// so two workers racing on the same subscription cannot both charge it
+    pub fn process_payment(
+        &mut self,
+        subscription_id: SubscriptionId,
+        cycle_index: Option<u32>,
+        amount_override: Option<U128>,
+    ) -> PaymentResult {
         let now = env::block_timestamp() / 1000000000;
 
         // Verify caller is an approved worker
@@ -377,201 +1755,945 @@ impl Contract {
             self.is_verified_by_approved_codehash(),
             "Not an approved worker"
         );
+        self.require_not_suspended();
 
         // Verify key is authorized for this subscription
         let public_key = env::signer_account_pk();
         let public_key_str = bs58::encode(public_key.as_bytes()).into_string();
         let authorized_subscription_id = self.subscription_keys.get(&public_key_str);
 
-        match authorized_subscription_id {
+        let result = match authorized_subscription_id {
             Some(id) if *id == subscription_id => {
                 // Key is authorized, proceed with payment
-                let subscription_clone: Subscription = self
-                    .subscriptions
-                    .get(&subscription_id)
-                    .expect("Subscription not found")
-                    .clone();
-
-                let mut subscription = subscription_clone.clone(); // mutable clone
-
-                // Verify subscription is active
-                if !matches!(subscription.status, SubscriptionStatus::Active) {
-                    // Clone the values we need
-                    let amount = subscription.amount.clone();
-                    let status = format!("{:?}", subscription.status);
-
-                    return PaymentResult {
-                        success: false,
-                        subscription_id,
-                        amount,
-                        timestamp: now,
-                        error: Some(format!("Subscription is not active: {}", status)),
-                    };
+                self.charge_subscription(subscription_id, cycle_index, amount_override, now)
+            }
+            _ => {
+                // Key is not authorized
+                PaymentResult {
+                    success: false,
+                    subscription_id,
+                    amount: U128(0),
+                    timestamp: now,
+                    error: Some(PaymentError::UnauthorizedKey),
                 }
+            }
+        };
+        self.record_worker_conduct(&env::predecessor_account_id(), &result, now);
+        result
+    }
 
-                // Verify payment is due
-                if subscription.next_payment_date > now {
-                    // Clone the values we need
-                    let amount = subscription.amount.clone();
-
-                    return PaymentResult {
-                        success: false,
-                        subscription_id,
-                        amount,
-                        timestamp: now,
-                        error: Some("Payment is not due yet".to_string()),
-                    };
-                }
+    /// Processes a batch of due subscriptions in one call, authorized by the approved worker
+    /// calling it. Stops early once the remaining gas could not cover another payment
+    pub fn process_payments(&mut self, subscription_ids: Vec<SubscriptionId>) -> Vec<PaymentResult> {
+        let now = env::block_timestamp() / 1000000000;
 
-                // Verify max payments limit
-                if let Some(max) = subscription.max_payments {
-                    if subscription.payments_made >= max {
-                        subscription.status = SubscriptionStatus::Canceled;
-                        self.subscriptions
-                            .insert(subscription_id.clone(), subscription);
-
-                        return PaymentResult {
-                            success: false,
-                            subscription_id,
-                            amount: subscription_clone.amount,
-                            timestamp: now,
-                            error: Some("Maximum number of payments reached".to_string()),
-                        };
-                    }
-                }
+        // Verify caller is an approved worker
+        require!(
+            self.is_verified_by_approved_codehash(),
+            "Not an approved worker"
+        );
+        self.require_not_suspended();
+
+        let worker_id = env::predecessor_account_id();
+        let mut results = Vec::new();
+        for subscription_id in subscription_ids {
+            let remaining_gas = env::prepaid_gas().saturating_sub(env::used_gas());
+            if remaining_gas < GAS_PER_BATCH_PAYMENT {
+                log!("Stopping batch after {} payments: insufficient gas", results.len());
+                break;
+            }
 
-                // Verify end date
-                if let Some(end_date) = subscription.end_date {
-                    if now >= end_date {
-                        subscription.status = SubscriptionStatus::Canceled;
-                        self.subscriptions
-                            .insert(subscription_id.clone(), subscription);
-
-                        return PaymentResult {
-                            success: false,
-                            subscription_id,
-                            amount: subscription_clone.amount,
-                            timestamp: now,
-                            error: Some("Subscription end date reached".to_string()),
-                        };
-                    }
-                }
+            if !self.subscriptions.contains_key(&subscription_id) {
+                results.push(PaymentResult {
+                    success: false,
+                    subscription_id,
+                    amount: U128(0),
+                    timestamp: now,
+                    error: Some(PaymentError::SubscriptionNotFound),
+                });
+                continue;
+            }
 
-                let merchant_id = subscription_clone.merchant_id.clone();
-                let amount = subscription_clone.amount.0;
-                let user_id = subscription_clone.user_id.clone();
-
-                // Process payment based on payment method
-                match subscription.payment_method {
-                    PaymentMethod::Near => {
-                        // Transfer NEAR from user to merchant
-                        Promise::new(merchant_id.clone())
-                            .transfer(NearToken::from_yoctonear(amount));
-
-                        log!(
-                            "Transferring {} NEAR from {} to {}",
-                            amount,
-                            user_id,
-                            merchant_id
-                        );
-
-                        // Update subscription using helper method
-                        self.update_subscription_after_payment(
-                            &subscription_clone,
-                            &subscription_id,
-                            now
-                        );
-
-                        PaymentResult {
-                            success: true,
-                            subscription_id,
-                            amount: subscription_clone.amount,
-                            timestamp: now,
-                            error: None,
-                        }
-                    }
-                    PaymentMethod::Ft { token_id } => {
-                        // Prepare the FT transfer arguments
-                        let ft_transfer_args = serde_json::json!({
-                            "receiver_id": merchant_id.to_string(),
-                            "amount": amount.to_string(),
-                            "memo": format!("Subscription payment: {}", subscription_id)
-                        })
-                        .to_string()
-                        .into_bytes();
-
-                        // Make the cross-contract call
-                        Promise::new(token_id.clone()).function_call(
-                            "ft_transfer".to_string(),
-                            ft_transfer_args,
-                            NearToken::from_yoctonear(1), // 1 yoctoNEAR deposit
-                            Gas::from_tgas(10), // Allocate gas for the cross-contract call
-                        );
-
-                        log!(
-                            "Transferring {} tokens from {} to {} via {}",
-                            amount,
-                            user_id,
-                            merchant_id,
-                            token_id
-                        );
-
-                        // Update subscription
-                        self.update_subscription_after_payment(
-                            &subscription_clone,
-                            &subscription_id,
-                            now
-                        );
-
-                        PaymentResult {
-                            success: true,
-                            subscription_id,
-                            amount: subscription_clone.amount,
-                            timestamp: now,
-                            error: None,
-                        }
-                    }
-                }
+            let result = self.charge_subscription(subscription_id, None, None, now);
+            self.record_worker_conduct(&worker_id, &result, now);
+            let rate_limited = matches!(result.error, Some(PaymentError::RateLimited));
+            results.push(result);
+            if rate_limited {
+                log!("Stopping batch after {} payments: rate limited", results.len() - 1);
+                break;
             }
-            _ => {
-                // Key is not authorized
-                PaymentResult {
+        }
+
+        results
+    }
+
+    /// Charges a subscription whose payment has been authorized
+    fn charge_subscription(
+        &mut self,
+        subscription_id: SubscriptionId,
+        cycle_index: Option<u32>,
+        amount_override: Option<U128>,
+        now: u64,
+    ) -> PaymentResult {
+        let caller_id = env::predecessor_account_id();
+        let is_worker = self.worker_by_account_id.contains_key(&caller_id);
+        if is_worker {
+            if !self.take_payment_allowance(&caller_id) {
+                return PaymentResult {
                     success: false,
                     subscription_id,
                     amount: U128(0),
                     timestamp: now,
-                    error: Some("Key is not authorized for this subscription".to_string()),
-                }
+                    error: Some(PaymentError::RateLimited),
+                };
+            }
+            // Leave subscriptions leased to another worker to that worker
+            if !self.release_lease(&subscription_id, &caller_id, now) {
+                return PaymentResult {
+                    success: false,
+                    subscription_id,
+                    amount: U128(0),
+                    timestamp: now,
+                    error: Some(PaymentError::Leased),
+                };
+            }
+            self.record_worker_activity(&caller_id, now);
+        }
+
+        // Working copy for this charge; changes that must persist are also made to the stored
+        // subscription in place, and the payment update advances it from this copy
+        let mut subscription = Subscription::from(
+            self.subscriptions
+                .get(&subscription_id)
+                .expect("Subscription not found"),
+        );
+
+        // Verify subscription is active, or past due and awaiting a retry
+        if !matches!(
+            subscription.status,
+            SubscriptionStatus::Active | SubscriptionStatus::PastDue
+        ) {
+            return PaymentResult {
+                success: false,
+                subscription_id,
+                amount: subscription.amount,
+                timestamp: now,
+                error: Some(PaymentError::NotActive),
+            };
+        }
+
+        // Streams are paid as they accrue rather than per cycle
+        if subscription.payment_mode == PaymentMode::Stream {
+            return PaymentResult {
+                success: false,
+                subscription_id,
+                amount: subscription.amount,
+                timestamp: now,
+                error: Some(PaymentError::Streaming),
+            };
+        }
+
+        // Wait for an in-flight swapped payment to settle
+        if subscription.settlement_pending {
+            return PaymentResult {
+                success: false,
+                subscription_id,
+                amount: subscription.amount,
+                timestamp: now,
+                error: Some(PaymentError::SettlementPending),
+            };
+        }
+
+        // Verify payment is due; past-due payments wait for their next retry
+        if subscription.next_retry_at.unwrap_or(subscription.next_payment_date) > now {
+            return PaymentResult {
+                success: false,
+                subscription_id,
+                amount: subscription.amount,
+                timestamp: now,
+                error: Some(PaymentError::NotDue),
+            };
+        }
+
+        // Never charge the same billing cycle twice
+        if cycle_index.is_some_and(|cycle_index| cycle_index != subscription.cycle_index) {
+            return PaymentResult {
+                success: false,
+                subscription_id,
+                amount: subscription.amount,
+                timestamp: now,
+                error: Some(PaymentError::CycleAlreadyCharged),
+            };
+        }
+
+        // Apply a scheduled price change once its notice period has passed
+        if subscription
+            .pending_price_change
+            .as_ref()
+            .is_some_and(|change| now >= change.effective_at)
+        {
+            let change = subscription.pending_price_change.take().expect("No price change");
+            Event::PriceChangeApplied {
+                subscription_id: subscription_id.clone(),
+                old_amount: subscription.amount,
+                new_amount: change.new_amount,
+            }
+            .emit();
+            subscription.amount = change.new_amount;
+            if let Some(line_items) = change.new_line_items {
+                subscription.line_items = line_items;
+            }
+            if let Some(stored) = self.subscriptions.get_mut(&subscription_id) {
+                stored.amount = subscription.amount;
+                stored.line_items.clone_from(&subscription.line_items);
+                stored.pending_price_change = None;
+            }
+        }
+
+        // Release earlier cooling-off charges once the window has lapsed
+        if subscription
+            .held_payment
+            .as_ref()
+            .is_some_and(|held| now >= held.release_at)
+        {
+            self.release_held_payment_internal(&subscription_id);
+            subscription.held_payment = None;
+        }
+
+        // Convert USD-priced subscriptions to the payment token at the current oracle rate
+        if let PriceDenomination::Usd { cents } = subscription.denomination {
+            let amount = self
+                .fresh_usd_rate(&subscription.payment_method, now)
+                .and_then(|rate| Self::usd_cents_to_token(cents, &rate));
+            let Some(amount) = amount else {
+                return PaymentResult {
+                    success: false,
+                    subscription_id,
+                    amount: subscription.amount,
+                    timestamp: now,
+                    error: Some(PaymentError::PriceUnavailable),
+                };
+            };
+            subscription.amount = U128(amount);
+        }
+
+        // Usage-based charges bill an amount reported for the cycle, up to the subscriber's cap.
+        // It is kept until charged so that retries and async settlement bill the same amount
+        if let Some(amount_override) = amount_override.or(subscription.amount_override) {
+            if amount_override.0 > subscription.max_amount_per_charge.0 {
+                return PaymentResult {
+                    success: false,
+                    subscription_id,
+                    amount: amount_override,
+                    timestamp: now,
+                    error: Some(PaymentError::ExceedsMaxAmountPerCharge),
+                };
+            }
+            subscription.amount = amount_override;
+            subscription.line_items = Vec::new();
+            subscription.amount_override = Some(amount_override);
+            if let Some(stored) = self.subscriptions.get_mut(&subscription_id) {
+                stored.amount_override = Some(amount_override);
             }
         }
+
+        // Subscriptions that have run their course end instead of being charged
+        if let Some(reason) = Self::expiry_reason(&subscription, now) {
+            let error = match reason {
+                CancellationReason::EndDateReached => PaymentError::EndDateReached,
+                _ => PaymentError::MaxPaymentsReached,
+            };
+            self.expire_subscription(&subscription_id, reason, now);
+
+            return PaymentResult {
+                success: false,
+                subscription_id,
+                amount: subscription.amount,
+                timestamp: now,
+                error: Some(error),
+            };
+        }
+
+        let charge = self.charge_details(&subscription);
+
+        // Prepaid cycles are used up before any funds move
+        if subscription.prepaid_cycles > 0 {
+            subscription.prepaid_cycles -= 1;
+            let funding = FundingSource {
+                payment_method: subscription.payment_method.clone(),
+                amount: U128(0),
+            };
+            self.update_subscription_after_payment(
+                &subscription,
+                &subscription_id,
+                &funding,
+                &charge,
+                now,
+            );
+            log!("Used a prepaid cycle for subscription: {}", subscription_id);
+
+            return PaymentResult {
+                success: true,
+                subscription_id,
+                amount: U128(0),
+                timestamp: now,
+                error: None,
+            };
+        }
+
+        // Verify spending caps authorized by the subscriber
+        if subscription.amount.0 > subscription.max_amount_per_charge.0 {
+            return PaymentResult {
+                success: false,
+                subscription_id,
+                amount: subscription.amount,
+                timestamp: now,
+                error: Some(PaymentError::ExceedsMaxAmountPerCharge),
+            };
+        }
+
+        if let Some(max_total) = subscription.max_total_spend {
+            if subscription.total_spent.0 + subscription.amount.0 > max_total.0 {
+                return PaymentResult {
+                    success: false,
+                    subscription_id,
+                    amount: subscription.amount,
+                    timestamp: now,
+                    error: Some(PaymentError::ExceedsMaxTotalSpend),
+                };
+            }
+        }
+
+        // Verify the user's spending limit for this merchant
+        if let Some(headroom) = self.merchant_limit_headroom(&subscription, now) {
+            if subscription.amount.0 > headroom {
+                return PaymentResult {
+                    success: false,
+                    subscription_id,
+                    amount: subscription.amount,
+                    timestamp: now,
+                    error: Some(PaymentError::ExceedsMerchantLimit),
+                };
+            }
+        }
+
+        let merchant_id = subscription.merchant_id.clone();
+        let amount = subscription.amount.0;
+        let user_id = subscription.user_id.clone();
+
+        // Dust charges cost workers more gas than they are worth
+        if amount > 0 && amount < self.min_charge_for(&merchant_id, &subscription.payment_method) {
+            return PaymentResult {
+                success: false,
+                subscription_id,
+                amount: subscription.amount,
+                timestamp: now,
+                error: Some(PaymentError::BelowMinimumCharge),
+            };
+        }
+
+        // Remember the worker processing the charge so it earns its fee once the charge settles.
+        // Only charges that passed validation are attributed to it
+        subscription.processed_by = is_worker.then_some(caller_id);
+        if let Some(stored) = self.subscriptions.get_mut(&subscription_id) {
+            stored.processed_by.clone_from(&subscription.processed_by);
+        }
+
+        // Free-tier subscriptions advance their cycle without moving any funds
+        if amount == 0 {
+            log!("Recording free cycle for {} ({})", subscription_id, user_id);
+
+            self.update_subscription_after_payment(
+                &subscription,
+                &subscription_id,
+                &subscription.funding(),
+                &charge,
+                now
+            );
+
+            return PaymentResult {
+                success: true,
+                subscription_id,
+                amount: subscription.amount,
+                timestamp: now,
+                error: None,
+            };
+        }
+
+        // Charges inside the cooling-off window stay in the contract so that
+        // canceling can refund them in full
+        let hold_until = self
+            .cooling_off_period_for(&merchant_id)
+            .map(|cooling_off| subscription.created_at + cooling_off)
+            .filter(|release_at| now < *release_at);
+        let dispute_window = self.dispute_window_for(&merchant_id);
+
+        // Credit the subscriber holds with the merchant covers the charge first, as long as
+        // escrow covers the remainder in the subscription's own token
+        let due = subscription.funding();
+        let credit = self
+            .get_credit(user_id.clone(), merchant_id.clone(), due.payment_method.clone())
+            .0
+            .min(due.amount.0);
+        let remainder = FundingSource {
+            payment_method: due.payment_method.clone(),
+            amount: U128(due.amount.0 - credit),
+        };
+        let credited_funding = if credit > 0
+            && (remainder.amount.0 == 0 || self.escrow_covers(&user_id, &remainder))
+        {
+            self.use_credit(&subscription, credit);
+            if remainder.amount.0 == 0 {
+                self.update_subscription_after_payment(
+                    &subscription,
+                    &subscription_id,
+                    &remainder,
+                    &charge,
+                    now,
+                );
+                log!("Charge covered by credit for subscription: {}", subscription_id);
+
+                return PaymentResult {
+                    success: true,
+                    subscription_id,
+                    amount: U128(0),
+                    timestamp: now,
+                    error: None,
+                };
+            }
+            Some(remainder)
+        } else {
+            None
+        };
+
+        // Charge the subscription's own token, or a fallback the subscriber funded. Held
+        // charges are refunded in the subscription's token, so they never fall back
+        let funding = credited_funding
+            .or_else(|| self.select_funding(&subscription, hold_until.is_none()));
+
+        // Otherwise subscribers funded in another token swap it for the payment token
+        if funding.is_none() && hold_until.is_none() {
+            if let Some(swap_funding) = subscription.swap_funding.clone() {
+                return self.start_swap_payment(&subscription, &swap_funding, charge, now);
+            }
+        }
+
+        // Or cover the shortfall from the subscriber's linked funding source, staked escrow or,
+        // for wNEAR plans, NEAR escrow
+        if funding.is_none() {
+            let top_up = self
+                .start_top_up(&subscription, now)
+                .or_else(|| self.start_unstake_for_charge(&subscription, now))
+                .or_else(|| self.start_wrap_for_charge(&subscription, now));
+            if let Some(result) = top_up {
+                return result;
+            }
+        }
+
+        let Some(funding) = funding else {
+            self.schedule_retry(&subscription_id, now);
+
+            return PaymentResult {
+                success: false,
+                subscription_id,
+                amount: subscription.amount,
+                timestamp: now,
+                error: Some(PaymentError::InsufficientEscrow),
+            };
+        };
+
+        // Merchants settled through intents are paid into the intents contract instead
+        let settle_via_intents = hold_until.is_none()
+            && dispute_window.is_none()
+            && self.settles_via_intents(&merchant_id, &funding.payment_method);
+
+        // FT transfers to accounts without token storage fail, so refuse the charge up front
+        if let PaymentMethod::Ft { token_id } = &funding.payment_method {
+            if !settle_via_intents && !self.payout_accounts_registered(&merchant_id, token_id) {
+                return PaymentResult {
+                    success: false,
+                    subscription_id,
+                    amount: funding.amount,
+                    timestamp: now,
+                    error: Some(PaymentError::PayoutNotRegistered),
+                };
+            }
+        }
+
+        // Draw the charge from the subscriber's escrow
+        if !self.debit_escrow(&user_id, &funding.payment_method, funding.amount.0) {
+            self.schedule_retry(&subscription_id, now);
+
+            return PaymentResult {
+                success: false,
+                subscription_id,
+                amount: funding.amount,
+                timestamp: now,
+                error: Some(PaymentError::InsufficientEscrow),
+            };
+        }
+
+        if let Some(release_at) = hold_until {
+            self.update_subscription_after_payment(
+                &subscription,
+                &subscription_id,
+                &funding,
+                &charge,
+                now,
+            );
+            if let Some(stored) = self.subscriptions.get_mut(&subscription_id) {
+                let held_amount = stored
+                    .held_payment
+                    .as_ref()
+                    .map_or(0, |held| held.amount.0)
+                    + funding.amount.0;
+                stored.held_payment = Some(HeldPayment {
+                    amount: U128(held_amount),
+                    release_at,
+                });
+            }
+
+            Event::PaymentHeld {
+                subscription_id: subscription_id.clone(),
+                amount: subscription.amount,
+                release_at,
+            }
+            .emit();
+
+            return PaymentResult {
+                success: true,
+                subscription_id,
+                amount: subscription.amount,
+                timestamp: now,
+                error: None,
+            };
+        }
+
+        // Merchants with a dispute window have each charge held until it lapses
+        if let Some(dispute_window) = dispute_window {
+            let payment_number = self.update_subscription_after_payment(
+                &subscription,
+                &subscription_id,
+                &funding,
+                &charge,
+                now
+            );
+            let release_at = now + dispute_window;
+            self.charge_holds.insert(
+                (subscription_id.clone(), payment_number),
+                ChargeHold {
+                    payment_number,
+                    cycle_index: subscription.cycle_index,
+                    funding: funding.clone(),
+                    release_at,
+                    dispute: None,
+                },
+            );
+
+            Event::PaymentHeld {
+                subscription_id: subscription_id.clone(),
+                amount: funding.amount,
+                release_at,
+            }
+            .emit();
+
+            return PaymentResult {
+                success: true,
+                subscription_id,
+                amount: funding.amount,
+                timestamp: now,
+                error: None,
+            };
+        }
+
+        if settle_via_intents {
+            return self.start_intents_settlement(&subscription, funding, charge, now);
+        }
+
+        // Pay the merchant, less the platform fee. The charge only counts once the transfers
+        // are confirmed
+        let memo = self.payment_memo(&subscription, subscription.payments_made + 1);
+        let fee = self.pay_merchant(
+            &subscription,
+            &funding,
+            subscription.cycle_index,
+            memo,
+            PayoutPurpose::Charge(charge),
+            now,
+        );
+
+        log!(
+            "Transferring {} ({} fee) from {} to {} via {:?}",
+            funding.amount.0,
+            fee,
+            user_id,
+            merchant_id,
+            funding.payment_method
+        );
+
+        PaymentResult {
+            success: true,
+            subscription_id,
+            amount: funding.amount,
+            timestamp: now,
+            error: None,
+        }
     }
 
-    /// Gets a list of subscriptions that are due for payment
-    pub fn get_due_subscriptions(&self, limit: u64) -> Vec<Subscription> {
+    /// Gets active subscriptions whose next payment falls within the given number of seconds,
+    /// from one shard when `shard` is given. A signed call, since only approved workers can
+    /// list them and checking the caller cannot be done in a view
+    pub fn get_subscriptions_due_within(
+        &mut self,
+        seconds: u64,
+        limit: u64,
+        shard: Option<u8>,
+    ) -> Vec<Subscription> {
         let now = env::block_timestamp() / 1000000000;
-        let mut due_subscriptions = Vec::new();
-        let mut count = 0;
 
         // Verify caller is an approved worker
         require!(
-            self.is_verified_by_approved_codehash(), // idk if we need this to be approved by codehash tho
+            self.is_verified_by_approved_codehash(),
             "Not an approved worker"
         );
 
-        // Iterate through subscriptions to find due payments
-        for (_, subscription) in self.subscriptions.iter() {
-            if count >= limit {
-                break;
+        self.subscriptions_due_between(now, now + seconds, limit, shard, |_| true)
+            .into_iter()
+            .map(|(_, subscription)| subscription)
+            .collect()
+    }
+
+    /// Emits a `payment_due_soon` event for each subscription entering the due soon window,
+    /// once per billing cycle, checking one shard when `shard` is given. Returns the number of
+    /// events emitted
+    pub fn emit_due_soon_events(&mut self, limit: u64, shard: Option<u8>) -> u64 {
+        let now = env::block_timestamp() / 1000000000;
+
+        // Verify caller is an approved worker
+        require!(
+            self.is_verified_by_approved_codehash(),
+            "Not an approved worker"
+        );
+
+        let window_end = now + self.due_soon_window;
+        let mut emitted = 0;
+
+        // Notified subscriptions stay in the window until they are charged, so look past them
+        let not_notified = |subscription: &Subscription| {
+            subscription.due_soon_notified_for != Some(subscription.next_payment_date)
+        };
+        let due_soon =
+            self.subscriptions_due_between(now, window_end, limit, shard, not_notified);
+        for (subscription_id, subscription) in due_soon {
+
+            Event::PaymentDueSoon {
+                subscription_id: subscription_id.clone(),
+                user_id: subscription.user_id.clone(),
+                merchant_id: subscription.merchant_id.clone(),
+                amount: subscription.amount,
+                next_payment_date: subscription.next_payment_date,
             }
+            .emit();
+            self.subscriptions
+                .get_mut(&subscription_id)
+                .expect("Subscription not found")
+                .due_soon_notified_for = Some(subscription.next_payment_date);
+            emitted += 1;
+        }
+
+        emitted
+    }
+}
 
-            if matches!(subscription.status, SubscriptionStatus::Active)
-                && subscription.next_payment_date <= now
-            {
-                due_subscriptions.push(subscription.clone());
-                count += 1;
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{env, json_types::U128, testing_env};
+
+    use crate::models::{
+        ChargeDetails, FundingSource, PaymentError, PaymentMethod, PriceChange, Subscription,
+        SubscriptionFrequency, SubscriptionImport, SubscriptionStatus, SubscriptionStatusV0,
+        SubscriptionV0,
+    };
+    use crate::{Contract, GAS_PER_BATCH_PAYMENT};
+
+    /// A contract holding a monthly NEAR subscription of `accounts(1)` to `accounts(2)` in `status`
+    fn contract_with_subscription(status: SubscriptionStatus) -> (Contract, Subscription) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(1_000 * 1_000_000_000)
+            .build());
+        let mut contract = Contract::new(accounts(0));
+        let subscription = Subscription {
+            status,
+            ..SubscriptionV0 {
+                id: "sub-1".to_string(),
+                user_id: accounts(1),
+                merchant_id: accounts(2),
+                amount: U128(10000),
+                frequency: SubscriptionFrequency::Monthly,
+                next_payment_date: 1_000,
+                status: SubscriptionStatusV0::Active,
+                created_at: 0,
+                updated_at: 0,
+                payment_method: PaymentMethod::Near,
+                max_payments: None,
+                payments_made: 0,
+                end_date: None,
             }
+            .into()
+        };
+        contract
+            .subscriptions
+            .insert(subscription.id.clone(), subscription.clone().into());
+        (contract, subscription)
+    }
+
+    #[test]
+    fn advances_subscription_after_payment() {
+        let (mut contract, subscription) = contract_with_subscription(SubscriptionStatus::Active);
+
+        let charge = contract.charge_details(&subscription);
+        let used_before = env::used_gas();
+        let payment_number = contract.update_subscription_after_payment(
+            &subscription,
+            &subscription.id,
+            &subscription.funding(),
+            &charge,
+            1_000,
+        );
+        let used = env::used_gas().as_gas() - used_before.as_gas();
+        println!("Payment update used {} gas", used);
+        assert!(used < GAS_PER_BATCH_PAYMENT.as_gas());
+
+        let stored = contract.get_subscription(subscription.id.clone()).unwrap();
+        assert_eq!(payment_number, 1);
+        assert_eq!(stored.payments_made, 1);
+        assert_eq!(stored.cycle_index, 1);
+        assert_eq!(stored.total_spent, U128(10000));
+        assert_eq!(stored.next_payment_date, 1_000 + 2592000);
+        assert_eq!(contract.get_payment_history(subscription.id).len(), 1);
+    }
+
+    #[test]
+    fn reactivates_past_due_subscription_after_payment() {
+        let (mut contract, subscription) = contract_with_subscription(SubscriptionStatus::PastDue);
+        contract.with_subscription_mut(&subscription.id, |stored| {
+            stored.retry_count = 2;
+            stored.next_retry_at = Some(1_500);
+        });
+
+        let funding = FundingSource {
+            payment_method: PaymentMethod::Near,
+            amount: U128(10000),
+        };
+        let charge = contract.charge_details(&subscription);
+        contract.update_subscription_after_payment(
+            &subscription,
+            &subscription.id,
+            &funding,
+            &charge,
+            1_000,
+        );
+
+        let stored = contract.get_subscription(subscription.id).unwrap();
+        assert_eq!(stored.status, SubscriptionStatus::Active);
+        assert_eq!(stored.retry_count, 0);
+        assert_eq!(stored.next_retry_at, None);
+    }
+
+    #[test]
+    fn counts_charged_amount_after_payment() {
+        let (mut contract, subscription) = contract_with_subscription(SubscriptionStatus::Active);
+
+        // Settled at the amount charged, not the one stored when it settles
+        let charge = ChargeDetails {
+            cycle_index: 0,
+            amount: U128(12000),
+            usd_rate: None,
+        };
+        let funding = FundingSource {
+            payment_method: PaymentMethod::Near,
+            amount: U128(12000),
+        };
+        contract.update_subscription_after_payment(
+            &subscription,
+            &subscription.id,
+            &funding,
+            &charge,
+            1_000,
+        );
+
+        let stored = contract.get_subscription(subscription.id).unwrap();
+        assert_eq!(stored.total_spent, U128(12000));
+        assert_eq!(stored.amount, U128(12000));
+    }
+
+    #[test]
+    fn records_payment_without_advancing_stale_cycle() {
+        let (mut contract, subscription) = contract_with_subscription(SubscriptionStatus::Active);
+        contract.with_subscription_mut(&subscription.id, |stored| stored.cycle_index = 1);
+
+        let charge = contract.charge_details(&subscription);
+        contract.update_subscription_after_payment(
+            &subscription,
+            &subscription.id,
+            &subscription.funding(),
+            &charge,
+            1_000,
+        );
+
+        let stored = contract.get_subscription(subscription.id).unwrap();
+        assert_eq!(stored.payments_made, 1);
+        assert_eq!(stored.total_spent, U128(10000));
+        assert_eq!(stored.cycle_index, 1);
+        assert_eq!(stored.next_payment_date, 1_000);
+    }
+
+    #[test]
+    fn counts_charged_amount_against_merchant_limit() {
+        let (mut contract, subscription) = contract_with_subscription(SubscriptionStatus::Active);
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(1_000 * 1_000_000_000)
+            .build());
+        contract.set_merchant_limit(accounts(2), PaymentMethod::Near, Some(U128(30000)));
+
+        // A usage charge bills its override rather than the subscription's amount
+        let subscription = Subscription {
+            amount_override: Some(U128(4000)),
+            ..subscription
+        };
+        let charge = contract.charge_details(&subscription);
+        contract.update_subscription_after_payment(
+            &subscription,
+            &subscription.id,
+            &subscription.funding(),
+            &charge,
+            1_000,
+        );
+
+        let limit = contract
+            .get_merchant_limit(accounts(1), accounts(2), PaymentMethod::Near)
+            .unwrap();
+        assert_eq!(limit.spent_in_period, U128(4000));
+        assert_eq!(
+            contract.merchant_limit_headroom(&subscription, 1_000),
+            Some(26000)
+        );
+    }
+
+    #[test]
+    fn rejects_charge_over_max_amount_per_charge() {
+        let (mut contract, subscription) = contract_with_subscription(SubscriptionStatus::Active);
+
+        let result =
+            contract.charge_subscription(subscription.id.clone(), None, Some(U128(10001)), 1_000);
+
+        assert!(!result.success);
+        assert_eq!(result.error, Some(PaymentError::ExceedsMaxAmountPerCharge));
+        let stored = contract.get_subscription(subscription.id).unwrap();
+        assert_eq!(stored.payments_made, 0);
+    }
+
+    #[test]
+    fn rejects_charge_over_max_total_spend() {
+        let (mut contract, subscription) = contract_with_subscription(SubscriptionStatus::Active);
+        contract.with_subscription_mut(&subscription.id, |stored| {
+            stored.max_total_spend = Some(U128(15000));
+            stored.total_spent = U128(10000);
+        });
+
+        let result = contract.charge_subscription(subscription.id.clone(), None, None, 1_000);
+
+        assert!(!result.success);
+        assert_eq!(result.error, Some(PaymentError::ExceedsMaxTotalSpend));
+        let stored = contract.get_subscription(subscription.id).unwrap();
+        assert_eq!(stored.total_spent, U128(10000));
+    }
+
+    #[test]
+    #[should_panic(expected = "Price change does not respect the notice period")]
+    fn rejects_price_change_within_notice_period() {
+        let (mut contract, subscription) = contract_with_subscription(SubscriptionStatus::Active);
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(2))
+            .block_timestamp(1_000 * 1_000_000_000)
+            .build());
+
+        contract.schedule_price_change(
+            subscription.id,
+            U128(12000),
+            1_000 + contract.price_change_notice_period - 1,
+            None,
+        );
+    }
+
+    #[test]
+    fn applies_price_change_after_notice_period() {
+        let (mut contract, subscription) = contract_with_subscription(SubscriptionStatus::Active);
+        contract.with_subscription_mut(&subscription.id, |stored| {
+            stored.pending_price_change = Some(PriceChange {
+                new_amount: U128(12000),
+                new_line_items: None,
+                effective_at: 1_000,
+                scheduled_at: 0,
+            });
+        });
+
+        contract.charge_subscription(subscription.id.clone(), None, None, 1_000);
+
+        let stored = contract.get_subscription(subscription.id).unwrap();
+        assert_eq!(stored.amount, U128(12000));
+        assert!(stored.pending_price_change.is_none());
+    }
+
+    fn import(next_payment_date: u64) -> SubscriptionImport {
+        SubscriptionImport {
+            user_id: accounts(1),
+            merchant_id: accounts(2),
+            amount: U128(10000),
+            frequency: SubscriptionFrequency::Monthly,
+            payment_method: PaymentMethod::Near,
+            created_at: 0,
+            next_payment_date,
+            max_payments: None,
+            payments_made: 0,
+            end_date: None,
+            line_items: None,
         }
+    }
+
+    #[test]
+    #[should_panic(expected = "next_payment_date must be within the next billing period")]
+    fn rejects_import_with_past_next_payment_date() {
+        let (mut contract, _) = contract_with_subscription(SubscriptionStatus::Active);
+        contract.merchants.insert(accounts(2));
+
+        contract.import_subscriptions(vec![import(500)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Amount is below the minimum charge")]
+    fn rejects_import_below_minimum_charge() {
+        let (mut contract, _) = contract_with_subscription(SubscriptionStatus::Active);
+        contract.merchants.insert(accounts(2));
+        contract
+            .min_charge_amounts
+            .insert(PaymentMethod::Near, U128(20000));
+
+        contract.import_subscriptions(vec![import(2_000)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "for the imported subscriptions")]
+    fn requires_deposit_for_owner_imports() {
+        let (mut contract, _) = contract_with_subscription(SubscriptionStatus::Active);
+        contract.merchants.insert(accounts(2));
 
-        due_subscriptions
+        contract.import_subscriptions(vec![import(2_000)]);
     }
 }
@@ -0,0 +1,101 @@
+use near_sdk::{env, log, near, require};
+
+use crate::events::Event;
+use crate::models::{
+    CancellationReason, PaymentMode, Subscription, SubscriptionId, SubscriptionStatus,
+};
+use crate::{Contract, ContractExt};
+
+#[near]
+impl Contract {
+    /// Ends up to `limit` subscriptions that are past their end date or have made all their
+    /// payments, which would otherwise stay active until a charge is attempted. Checks one shard
+    /// when `shard` is given. Returns the number of subscriptions ended
+    pub fn run_maintenance(&mut self, limit: u64, shard: Option<u8>) -> u64 {
+        let now = env::block_timestamp() / 1000000000;
+
+        // Verify caller is an approved worker
+        require!(
+            self.is_verified_by_approved_codehash(),
+            "Not an approved worker"
+        );
+
+        let expired: Vec<(SubscriptionId, CancellationReason)> = self
+            .subscriptions
+            .scan(shard)
+            .filter_map(|(subscription_id, subscription)| {
+                Self::expiry_reason(subscription, now)
+                    .map(|reason| (subscription_id.clone(), reason))
+            })
+            .take(limit as usize)
+            .collect();
+        for (subscription_id, reason) in expired.iter() {
+            self.expire_subscription(subscription_id, reason.clone(), now);
+        }
+
+        log!("Maintenance ended {} subscriptions", expired.len());
+        expired.len() as u64
+    }
+}
+
+impl Contract {
+    /// Why a chargeable subscription has run its course, if it has
+    pub(crate) fn expiry_reason(
+        subscription: &Subscription,
+        now: u64,
+    ) -> Option<CancellationReason> {
+        if !matches!(
+            subscription.status,
+            SubscriptionStatus::Active | SubscriptionStatus::PastDue
+        ) {
+            return None;
+        }
+        if subscription
+            .max_payments
+            .is_some_and(|max| subscription.payments_made >= max)
+        {
+            return Some(CancellationReason::MaxPaymentsReached);
+        }
+        if subscription
+            .end_date
+            .is_some_and(|end_date| now >= end_date)
+        {
+            return Some(CancellationReason::EndDateReached);
+        }
+        None
+    }
+
+    /// Ends a subscription that has run its course
+    pub(crate) fn expire_subscription(
+        &mut self,
+        subscription_id: &SubscriptionId,
+        reason: CancellationReason,
+        now: u64,
+    ) {
+        let subscription = self
+            .subscriptions
+            .get(subscription_id)
+            .expect("Subscription not found");
+        let payment_mode = subscription.payment_mode.clone();
+        // Streams pay out what accrued before they end
+        if payment_mode == PaymentMode::Stream {
+            self.settle_stream(subscription_id, now);
+        }
+
+        self.transition_subscription(subscription_id, SubscriptionStatus::Canceled, now);
+        let subscription = self.with_subscription_mut(subscription_id, |subscription| {
+            subscription.next_retry_at = None;
+            subscription.clone()
+        });
+        self.revoke_subscription_keys(subscription_id, &subscription.user_id);
+
+        Event::SubscriptionExpired {
+            subscription_id: subscription_id.clone(),
+            user_id: subscription.user_id,
+            merchant_id: subscription.merchant_id,
+            reason,
+        }
+        .emit();
+        log!("Subscription ended: {}", subscription_id);
+    }
+}
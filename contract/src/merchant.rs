@@ -0,0 +1,301 @@
+use near_sdk::{env, json_types::U128, log, near, require, AccountId};
+
+use crate::fees::BPS_DENOMINATOR;
+use crate::models::{
+    AutoCancelPolicy, FundingSource, MerchantSettings, PaymentMethod, PayoutLeg, RevenueSplit,
+    Subscription, SubscriptionId,
+};
+use crate::{Contract, ContractExt};
+
+#[near]
+impl Contract {
+    /// Sets the contract-wide default cooling-off period
+    pub fn set_default_cooling_off_period(&mut self, seconds: Option<u64>) {
+        self.require_owner();
+        self.default_cooling_off_period = seconds;
+        log!("Default cooling-off period set to {:?}", seconds);
+    }
+
+    /// Overrides the cooling-off period for the calling merchant's subscriptions. `Some(0)` opts
+    /// out of cooling-off even when the contract sets a default; `None` inherits the default
+    pub fn set_cooling_off_period(&mut self, seconds: Option<u64>) {
+        let merchant_id = self.require_merchant();
+        let mut settings = self.get_merchant_settings(merchant_id.clone());
+        settings.cooling_off_period = seconds;
+        self.merchant_settings.insert(merchant_id.clone(), settings);
+        log!("Cooling-off period for {} set to {:?}", merchant_id, seconds);
+    }
+
+    /// Lets the calling merchant's subscribers skip up to `max_skips` payments a year.
+    /// `None` disallows skipping
+    pub fn set_max_skips_per_year(&mut self, max_skips: Option<u32>) {
+        let merchant_id = self.require_merchant();
+        let mut settings = self.get_merchant_settings(merchant_id.clone());
+        settings.max_skips_per_year = max_skips;
+        self.merchant_settings.insert(merchant_id.clone(), settings);
+        log!("Max skips per year for {} set to {:?}", merchant_id, max_skips);
+    }
+
+    /// Sets the contract-wide minimum non-free charge in a token. `None` removes it
+    pub fn set_min_charge_amount(&mut self, payment_method: PaymentMethod, amount: Option<U128>) {
+        self.require_owner();
+        match amount {
+            Some(amount) => {
+                self.min_charge_amounts.insert(payment_method, amount);
+                log!("Minimum charge set to {}", amount.0);
+            }
+            None => {
+                self.min_charge_amounts.remove(&payment_method);
+                log!("Minimum charge removed");
+            }
+        }
+    }
+
+    /// Gets the minimum non-free charge for a merchant's subscriptions in a token, the larger of
+    /// the contract-wide and the merchant's own minimum
+    pub fn get_min_charge_amount(
+        &self,
+        merchant_id: AccountId,
+        payment_method: PaymentMethod,
+    ) -> U128 {
+        U128(self.min_charge_for(&merchant_id, &payment_method))
+    }
+
+    /// Sets the calling merchant's minimum non-free charge per token, on top of the
+    /// contract-wide minimums
+    pub fn set_merchant_min_charge_amounts(&mut self, min_amounts: Vec<FundingSource>) {
+        let merchant_id = self.require_merchant();
+        let mut settings = self.get_merchant_settings(merchant_id.clone());
+        settings.min_charge_amounts = min_amounts;
+        self.merchant_settings.insert(merchant_id.clone(), settings);
+        log!("Minimum charges updated for {}", merchant_id);
+    }
+
+    /// Cancels the calling merchant's subscriptions once their payments keep failing, before
+    /// the contract's retries run out. `None` leaves them to the retry policy
+    pub fn set_auto_cancel_policy(&mut self, policy: Option<AutoCancelPolicy>) {
+        let merchant_id = self.require_merchant();
+        if let Some(policy) = &policy {
+            require!(policy.max_failures > 0, "max_failures must be positive");
+        }
+        let mut settings = self.get_merchant_settings(merchant_id.clone());
+        settings.auto_cancel = policy;
+        self.merchant_settings.insert(merchant_id.clone(), settings);
+        log!("Auto-cancel policy updated for {}", merchant_id);
+    }
+
+    /// Enables or disables prorated credits when subscribers cancel mid-cycle
+    pub fn set_prorate_on_cancel(&mut self, enabled: bool) {
+        let merchant_id = self.require_merchant();
+        let mut settings = self.get_merchant_settings(merchant_id.clone());
+        settings.prorate_on_cancel = enabled;
+        self.merchant_settings.insert(merchant_id.clone(), settings);
+        log!("Prorate on cancel for {} set to {}", merchant_id, enabled);
+    }
+
+    /// Sets the default memo used for the calling merchant's payments
+    pub fn set_memo_template(&mut self, memo_template: Option<String>) {
+        let merchant_id = self.require_merchant();
+        Self::assert_valid_memo_template(&memo_template);
+        let mut settings = self.get_merchant_settings(merchant_id.clone());
+        settings.memo_template = memo_template;
+        self.merchant_settings.insert(merchant_id.clone(), settings);
+        log!("Memo template updated for {}", merchant_id);
+    }
+
+    /// Sets the account the calling merchant's payments are sent to
+    pub fn set_payout_address(&mut self, account_id: AccountId) {
+        let merchant_id = self.require_merchant();
+        let mut settings = self.get_merchant_settings(merchant_id.clone());
+        settings.payout_address = if account_id == merchant_id {
+            None
+        } else {
+            Some(account_id.clone())
+        };
+        self.merchant_settings.insert(merchant_id.clone(), settings);
+        log!("Payout address for {} set to {}", merchant_id, account_id);
+    }
+
+    /// Replaces the calling merchant's revenue splits. Shares are in basis points of each
+    /// payment after the platform fee; whatever remains goes to the payout address
+    pub fn set_revenue_splits(&mut self, splits: Vec<RevenueSplit>) {
+        let merchant_id = self.require_merchant();
+        require!(
+            splits.len() <= MAX_REVENUE_SPLITS,
+            "Too many revenue split recipients"
+        );
+        let total_bps: u128 = splits.iter().map(|split| split.share_bps as u128).sum();
+        require!(
+            total_bps <= BPS_DENOMINATOR,
+            "Revenue split shares cannot exceed 10000 basis points"
+        );
+        require!(
+            splits.iter().all(|split| split.share_bps > 0),
+            "Revenue split shares must be positive"
+        );
+
+        let mut settings = self.get_merchant_settings(merchant_id.clone());
+        require!(
+            splits.is_empty() || !settings.intents_settlement,
+            "Revenue splits cannot be combined with intents settlement"
+        );
+        settings.revenue_splits = splits;
+        self.merchant_settings.insert(merchant_id.clone(), settings);
+        log!("Revenue splits updated for {}", merchant_id);
+    }
+
+    /// Sets the memo used for one subscription's payments, overriding the merchant default
+    pub fn set_subscription_memo_template(
+        &mut self,
+        subscription_id: SubscriptionId,
+        memo_template: Option<String>,
+    ) {
+        let merchant_id = env::predecessor_account_id();
+        Self::assert_valid_memo_template(&memo_template);
+
+        let subscription = self
+            .subscriptions
+            .get_mut(&subscription_id)
+            .expect("Subscription not found");
+        require!(
+            subscription.merchant_id == merchant_id,
+            "Not authorized to update this subscription"
+        );
+        subscription.memo_template = memo_template;
+        subscription.updated_at = env::block_timestamp() / 1000000000;
+
+        log!("Memo template updated for subscription: {}", subscription_id);
+    }
+
+    /// Gets a merchant's settings
+    pub fn get_merchant_settings(&self, merchant_id: AccountId) -> MerchantSettings {
+        self.merchant_settings
+            .get(&merchant_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+// Longest memo template a merchant can store
+const MAX_MEMO_TEMPLATE_LENGTH: usize = 256;
+// Most revenue split recipients a merchant can configure
+const MAX_REVENUE_SPLITS: usize = 5;
+
+impl Contract {
+    /// Smallest non-free charge in a token for a merchant's subscriptions
+    pub(crate) fn min_charge_for(
+        &self,
+        merchant_id: &AccountId,
+        payment_method: &PaymentMethod,
+    ) -> u128 {
+        let contract_min = self.min_charge_amounts.get(payment_method).map_or(0, |min| min.0);
+        let merchant_min = self
+            .merchant_settings
+            .get(merchant_id)
+            .and_then(|settings| {
+                settings
+                    .min_charge_amounts
+                    .iter()
+                    .find(|min| min.payment_method == *payment_method)
+            })
+            .map_or(0, |min| min.amount.0);
+        contract_min.max(merchant_min)
+    }
+
+    /// Rejects non-free amounts below the minimum charge
+    pub(crate) fn assert_above_min_charge(
+        &self,
+        merchant_id: &AccountId,
+        payment_method: &PaymentMethod,
+        amount: U128,
+    ) {
+        require!(
+            amount.0 == 0 || amount.0 >= self.min_charge_for(merchant_id, payment_method),
+            "Amount is below the minimum charge"
+        );
+    }
+
+    pub(crate) fn assert_valid_memo_template(memo_template: &Option<String>) {
+        if let Some(memo_template) = memo_template {
+            require!(
+                memo_template.len() <= MAX_MEMO_TEMPLATE_LENGTH,
+                "Memo template is too long"
+            );
+        }
+    }
+
+    /// Renders the memo for a subscription's payment. Templates may use the placeholders
+    /// `{subscription_id}`, `{cycle}` and `{merchant_id}`
+    pub(crate) fn payment_memo(&self, subscription: &Subscription, cycle: u32) -> String {
+        let template = subscription.memo_template.clone().or_else(|| {
+            self.merchant_settings
+                .get(&subscription.merchant_id)
+                .and_then(|settings| settings.memo_template.clone())
+        });
+
+        match template {
+            Some(template) => template
+                .replace("{subscription_id}", &subscription.id)
+                .replace("{cycle}", &cycle.to_string())
+                .replace("{merchant_id}", subscription.merchant_id.as_str()),
+            None => format!("Subscription payment: {}", subscription.id),
+        }
+    }
+
+    /// Requires the caller to be a registered merchant and returns its account
+    pub(crate) fn require_merchant(&self) -> AccountId {
+        let merchant_id = env::predecessor_account_id();
+        require!(
+            self.merchants.contains(&merchant_id),
+            "Merchant not registered"
+        );
+        merchant_id
+    }
+
+    /// Account a merchant's payments are sent to
+    pub(crate) fn payout_address_for(&self, merchant_id: &AccountId) -> AccountId {
+        self.merchant_settings
+            .get(merchant_id)
+            .and_then(|settings| settings.payout_address.clone())
+            .unwrap_or_else(|| merchant_id.clone())
+    }
+
+    /// Splits a payment between a merchant's revenue split recipients and its payout address,
+    /// which also receives any rounding remainder. Zero-amount legs are dropped
+    pub(crate) fn payout_legs(&self, merchant_id: &AccountId, amount: u128) -> Vec<PayoutLeg> {
+        let mut legs = Vec::new();
+        let mut remaining = amount;
+
+        if let Some(settings) = self.merchant_settings.get(merchant_id) {
+            for split in settings.revenue_splits.iter() {
+                let share = amount * split.share_bps as u128 / BPS_DENOMINATOR;
+                if share > 0 {
+                    legs.push(PayoutLeg {
+                        recipient: split.recipient.clone(),
+                        amount: U128(share),
+                    });
+                    remaining -= share;
+                }
+            }
+        }
+
+        if remaining > 0 {
+            legs.push(PayoutLeg {
+                recipient: self.payout_address_for(merchant_id),
+                amount: U128(remaining),
+            });
+        }
+
+        legs
+    }
+
+    /// Cooling-off period that applies to a merchant's subscriptions, if any. A merchant's
+    /// period of zero opts out of the contract default
+    pub(crate) fn cooling_off_period_for(&self, merchant_id: &AccountId) -> Option<u64> {
+        self.merchant_settings
+            .get(merchant_id)
+            .and_then(|settings| settings.cooling_off_period)
+            .or(self.default_cooling_off_period)
+            .filter(|cooling_off| *cooling_off > 0)
+    }
+}
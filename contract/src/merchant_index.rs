@@ -0,0 +1,187 @@
+use near_sdk::{log, near, require, AccountId};
+
+use crate::models::SubscriptionId;
+use crate::{Contract, ContractExt};
+
+#[near]
+impl Contract {
+    /// Adds subscriptions created before the merchant index existed to it, reading up to
+    /// `limit` creation sequence numbers on from where the index has reached. Returns the
+    /// sequence number to continue from
+    pub fn index_merchant_subscriptions(&mut self, limit: u64) -> u64 {
+        self.require_owner();
+        require!(limit > 0, "Limit must be positive");
+        let from = self.merchant_index_cursor;
+        let end = from.saturating_add(limit).min(self.subscription_nonce + 1);
+        let mut indexed = 0;
+        for sequence_number in from..end {
+            let Some((merchant_id, subscription_id)) = self
+                .subscription_sequence
+                .get(&sequence_number)
+                .and_then(|subscription_id| self.subscriptions.get(subscription_id))
+                .map(|subscription| (subscription.merchant_id.clone(), subscription.id.clone()))
+            else {
+                continue;
+            };
+            self.push_merchant_subscription(&merchant_id, &subscription_id);
+            indexed += 1;
+        }
+        self.merchant_index_cursor = self.merchant_index_cursor.max(end);
+        log!("Indexed {} subscriptions by merchant", indexed);
+        self.merchant_index_cursor
+    }
+}
+
+impl Contract {
+    /// Adds a new subscription to its merchant's subscriptions. Until the index has caught up
+    /// with earlier subscriptions it is left to `index_merchant_subscriptions`, so each
+    /// merchant's subscriptions stay in creation order
+    pub(crate) fn index_merchant_subscription(
+        &mut self,
+        merchant_id: &AccountId,
+        subscription_id: &SubscriptionId,
+        sequence_number: u64,
+    ) {
+        if sequence_number != self.merchant_index_cursor {
+            return;
+        }
+        self.push_merchant_subscription(merchant_id, subscription_id);
+        self.merchant_index_cursor += 1;
+    }
+
+    /// Appends a subscription to its merchant's subscriptions
+    fn push_merchant_subscription(
+        &mut self,
+        merchant_id: &AccountId,
+        subscription_id: &SubscriptionId,
+    ) {
+        let position = self.merchant_subscription_total(merchant_id) + 1;
+        self.merchant_subscription_totals
+            .insert(merchant_id.clone(), position);
+        self.subscriptions_by_merchant
+            .insert((merchant_id.clone(), position), subscription_id.clone());
+    }
+
+    /// Number of subscriptions indexed for a merchant, including archived ones
+    fn merchant_subscription_total(&self, merchant_id: &AccountId) -> u64 {
+        self.merchant_subscription_totals
+            .get(merchant_id)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Gets the IDs of a merchant's subscriptions after position `from`, in creation order,
+    /// with their positions
+    pub(crate) fn merchant_subscription_ids(
+        &self,
+        merchant_id: &AccountId,
+        from: u64,
+    ) -> impl Iterator<Item = (u64, SubscriptionId)> + '_ {
+        let merchant_id = merchant_id.clone();
+        let total = self.merchant_subscription_total(&merchant_id);
+        (from.saturating_add(1)..=total).filter_map(move |position| {
+            self.subscriptions_by_merchant
+                .get(&(merchant_id.clone(), position))
+                .map(|subscription_id| (position, subscription_id.clone()))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{json_types::U128, testing_env, AccountId};
+
+    use crate::models::{
+        PaymentMethod, Subscription, SubscriptionFrequency, SubscriptionStatusV0, SubscriptionV0,
+    };
+    use crate::Contract;
+
+    /// Stores a new NEAR subscription of `accounts(1)` to `merchant_id`, returning its ID
+    fn add_subscription(contract: &mut Contract, merchant_id: AccountId) -> String {
+        let subscription_id = contract.next_subscription_id(&accounts(1), &merchant_id);
+        let subscription: Subscription = SubscriptionV0 {
+            id: subscription_id.clone(),
+            user_id: accounts(1),
+            merchant_id,
+            amount: U128(10000),
+            frequency: SubscriptionFrequency::Monthly,
+            next_payment_date: 0,
+            status: SubscriptionStatusV0::Active,
+            created_at: 0,
+            updated_at: 0,
+            payment_method: PaymentMethod::Near,
+            max_payments: None,
+            payments_made: 0,
+            end_date: None,
+        }
+        .into();
+        contract
+            .subscriptions
+            .insert(subscription_id.clone(), subscription.into());
+        subscription_id
+    }
+
+    fn merchant_subscription_ids(contract: &Contract, merchant_id: AccountId) -> Vec<String> {
+        contract
+            .get_merchant_subscriptions(merchant_id, None, None)
+            .subscriptions
+            .into_iter()
+            .map(|subscription| subscription.id)
+            .collect()
+    }
+
+    #[test]
+    fn pages_merchant_subscriptions_from_index() {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .build());
+        let mut contract = Contract::new(accounts(0));
+        let first = add_subscription(&mut contract, accounts(2));
+        add_subscription(&mut contract, accounts(3));
+        let second = add_subscription(&mut contract, accounts(2));
+
+        let page = contract.get_merchant_subscriptions(accounts(2), None, Some(1));
+        assert_eq!(page.subscriptions[0].id, first);
+        assert!(page.has_more);
+
+        let page = contract.get_merchant_subscriptions(accounts(2), page.next_cursor, Some(1));
+        assert_eq!(page.subscriptions[0].id, second);
+        assert!(!page.has_more);
+    }
+
+    #[test]
+    fn indexes_earlier_subscriptions_in_creation_order() {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .build());
+        let mut contract = Contract::new(accounts(0));
+
+        // Subscriptions created before the index existed
+        contract.merchant_index_cursor = 0;
+        let first = add_subscription(&mut contract, accounts(2));
+        let second = add_subscription(&mut contract, accounts(2));
+        assert!(merchant_subscription_ids(&contract, accounts(2)).is_empty());
+
+        // New subscriptions wait for the index to catch up
+        assert_eq!(contract.index_merchant_subscriptions(2), 2);
+        let third = add_subscription(&mut contract, accounts(2));
+        assert_eq!(
+            merchant_subscription_ids(&contract, accounts(2)),
+            vec![first.clone()]
+        );
+
+        assert_eq!(contract.index_merchant_subscriptions(10), 4);
+        assert_eq!(
+            merchant_subscription_ids(&contract, accounts(2)),
+            vec![first, second, third]
+        );
+        let fourth = add_subscription(&mut contract, accounts(2));
+        assert_eq!(
+            merchant_subscription_ids(&contract, accounts(2)).last(),
+            Some(&fourth)
+        );
+    }
+}
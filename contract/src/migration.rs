@@ -0,0 +1,584 @@
+use near_sdk::{
+    borsh::{BorshDeserialize, BorshSerialize},
+    env,
+    json_types::U128,
+    log, near, require,
+    store::{IterableMap, IterableSet, LookupMap, LookupSet},
+    AccountId,
+};
+
+use crate::models::{
+    ApprovalPolicy, ArchivedSubscription, ChargeHold, FundingRule, Invoice, MerchantLimit,
+    MerchantSettings, PaymentMethod, PaymentRecord, PendingSettlement, ReferralEarnings,
+    RetryPolicy, SettlementReport, StakingPreference, StateVersion, StoragePayer, StorageAccount,
+    Subscription, SubscriptionCounts, SubscriptionId, SubscriptionTemplate, SubscriptionV0,
+    UsdOracleConfig, UsdRate, VSubscription, VWorker, WorkerV1,
+};
+use crate::shards::SubscriptionShards;
+use crate::{Contract, ContractExt};
+
+// State layout this code reads and writes
+pub(crate) const CURRENT_STATE_VERSION: StateVersion = StateVersion::V3;
+
+/// Contract state as stored before state versions
+#[near(serializers = [borsh])]
+struct ContractV0 {
+    owner_id: AccountId,
+    approved_codehashes: IterableSet<String>,
+    worker_by_account_id: IterableMap<AccountId, WorkerV1>,
+    subscriptions: IterableMap<SubscriptionId, SubscriptionV0>,
+    subscription_keys: LookupMap<String, SubscriptionId>,
+    merchants: IterableSet<AccountId>,
+}
+
+/// Contract state as stored before subscriptions were sharded
+#[near(serializers = [borsh])]
+struct ContractV1 {
+    owner_id: AccountId,
+    approved_codehashes: IterableSet<String>,
+    worker_by_account_id: IterableMap<AccountId, VWorker>,
+    subscriptions: IterableMap<SubscriptionId, VSubscription>,
+    fields: LegacyFields,
+}
+
+/// Contract state as stored before next payment dates were indexed in a heap
+#[near(serializers = [borsh])]
+struct ContractV2 {
+    owner_id: AccountId,
+    approved_codehashes: IterableSet<String>,
+    worker_by_account_id: IterableMap<AccountId, VWorker>,
+    subscriptions: SubscriptionShards,
+    fields: LegacyFields,
+    keys_by_subscription: LookupMap<SubscriptionId, Vec<String>>,
+    payment_history_heads: LookupMap<SubscriptionId, u32>,
+}
+
+/// Fields `ContractV1` and `ContractV2` store after their subscriptions, in the same order
+#[near(serializers = [borsh])]
+struct LegacyFields {
+    subscription_keys: LookupMap<String, SubscriptionId>,
+    merchants: IterableSet<AccountId>,
+    subscription_nonce: u64,
+    merchant_limits: LookupMap<(AccountId, AccountId, PaymentMethod), MerchantLimit>,
+    price_change_notice_period: u64,
+    payment_history: LookupMap<SubscriptionId, Vec<PaymentRecord>>,
+    due_soon_window: u64,
+    templates: IterableMap<String, SubscriptionTemplate>,
+    merchant_settings: LookupMap<AccountId, MerchantSettings>,
+    default_cooling_off_period: Option<u64>,
+    credits: LookupMap<(AccountId, AccountId, PaymentMethod), U128>,
+    archive_retention_period: u64,
+    archived_subscriptions: LookupMap<SubscriptionId, ArchivedSubscription>,
+    fee_bps: u16,
+    treasury_balances: IterableMap<PaymentMethod, U128>,
+    referral_earnings: LookupMap<(AccountId, PaymentMethod), ReferralEarnings>,
+    escrow_balances: LookupMap<(AccountId, PaymentMethod), U128>,
+    retry_policy: RetryPolicy,
+    invoices: LookupMap<(AccountId, u64), Invoice>,
+    invoice_counts: LookupMap<AccountId, u64>,
+    ft_registrations: LookupSet<(AccountId, AccountId)>,
+    allowed_tokens: IterableSet<AccountId>,
+    usd_oracle: Option<UsdOracleConfig>,
+    usd_rates: LookupMap<PaymentMethod, UsdRate>,
+    swap_adapter: Option<AccountId>,
+    intents_contract: Option<AccountId>,
+    funding_rules: LookupMap<(AccountId, AccountId), FundingRule>,
+    staking_pool: Option<AccountId>,
+    staking_preferences: LookupMap<AccountId, StakingPreference>,
+    staked_escrow: LookupMap<AccountId, U128>,
+    staked_escrow_total: U128,
+    claimable_balances: LookupMap<(AccountId, PaymentMethod), U128>,
+    pending_settlements: LookupMap<(AccountId, PaymentMethod), PendingSettlement>,
+    settlement_reports: LookupMap<(AccountId, u64), SettlementReport>,
+    settlement_counts: LookupMap<AccountId, u64>,
+    arbiter_id: Option<AccountId>,
+    charge_holds: LookupMap<(SubscriptionId, u32), ChargeHold>,
+    refunded_amounts: LookupMap<(SubscriptionId, u32), U128>,
+    wnear_contract: Option<AccountId>,
+    token_decimals: LookupMap<AccountId, u8>,
+    merchant_fees: LookupMap<(AccountId, PaymentMethod), U128>,
+    approval_policies: LookupMap<(AccountId, PaymentMethod), ApprovalPolicy>,
+    worker_fee_bps: u16,
+    worker_balances: LookupMap<(AccountId, PaymentMethod), U128>,
+    min_charge_amounts: LookupMap<PaymentMethod, U128>,
+    allowed_mt_contracts: IterableSet<AccountId>,
+    due_index: LookupMap<u64, Vec<SubscriptionId>>,
+    due_buckets: IterableSet<u64>,
+    subscription_counts: SubscriptionCounts,
+    merchant_subscription_counts: LookupMap<AccountId, SubscriptionCounts>,
+    state_version: StateVersion,
+    migration_cursor: Option<u64>,
+    storage_accounts: LookupMap<AccountId, StorageAccount>,
+    subscription_storage: LookupMap<SubscriptionId, StoragePayer>,
+    subscription_sequence: LookupMap<u64, SubscriptionId>,
+}
+
+/// Collections `migrate` found in an earlier layout, moved into the current one a batch at a
+/// time by `migrate_subscriptions`
+#[near(serializers = [borsh])]
+#[derive(Default)]
+pub struct Migration {
+    unversioned_subscriptions: Option<IterableMap<SubscriptionId, SubscriptionV0>>,
+    unsharded_subscriptions: Option<IterableMap<SubscriptionId, VSubscription>>,
+    due_index: Option<LookupMap<u64, Vec<SubscriptionId>>>, // Hour bucket -> subscriptions due in it
+    due_buckets: Option<IterableSet<u64>>, // Hour buckets still to be moved into the heap
+}
+
+#[near]
+impl Contract {
+    /// Upgrades the contract state after new code is deployed. Top-level fields are converted
+    /// here in one go; subscriptions are moved afterwards with `migrate_subscriptions`, a
+    /// batch at a time, so large states do not run out of gas.
+    /// When a release changes the layout, read the previous layout here as well as `Contract`
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let state = env::storage_read(b"STATE").expect("Contract state not found");
+        if let Ok(old) = ContractV0::try_from_slice(&state) {
+            log!("State migrated from before state versions");
+            return Self::from_v0(old);
+        }
+        if let Ok(old) = ContractV1::try_from_slice(&state) {
+            log!("State migrated from before subscriptions were sharded");
+            return Self::from_v1(old);
+        }
+        if let Ok(old) = ContractV2::try_from_slice(&state) {
+            log!("State migrated from before next payment dates were indexed in a heap");
+            return Self::from_v2(old);
+        }
+
+        let mut contract =
+            Contract::try_from_slice(&state).expect("Cannot deserialize the contract state");
+        require!(
+            contract.state_version <= CURRENT_STATE_VERSION,
+            "Cannot migrate to an older state version"
+        );
+        contract.state_version = CURRENT_STATE_VERSION;
+
+        log!("State migrated to version {:?}", contract.state_version);
+        contract
+    }
+
+    /// Moves up to `limit` subscriptions, or hourly due-date buckets, that `migrate` found in
+    /// an earlier layout into the current one. Returns how many remain to be migrated
+    pub fn migrate_subscriptions(&mut self, limit: u64) -> u64 {
+        self.require_owner();
+        let Some(mut migration) = self.migration.take() else {
+            return 0;
+        };
+
+        let mut migrated = 0;
+        if let Some(subscriptions) = migration.unversioned_subscriptions.as_mut() {
+            for subscription in Self::take_subscriptions(subscriptions, limit) {
+                self.store_unversioned_subscription(subscription);
+                migrated += 1;
+            }
+        }
+        if let Some(subscriptions) = migration.unsharded_subscriptions.as_mut() {
+            for subscription in Self::take_subscriptions(subscriptions, limit - migrated) {
+                self.store_unsharded_subscription(subscription);
+                migrated += 1;
+            }
+        }
+
+        if let (Some(due_index), Some(due_buckets)) =
+            (migration.due_index.as_mut(), migration.due_buckets.as_mut())
+        {
+            // Moved buckets are removed, so every batch starts at the front of the set
+            let buckets: Vec<u64> = due_buckets
+                .iter()
+                .take((limit - migrated) as usize)
+                .copied()
+                .collect();
+            for bucket in buckets {
+                due_buckets.remove(&bucket);
+                let subscription_ids = due_index.remove(&bucket).unwrap_or_default();
+                for subscription_id in subscription_ids {
+                    self.index_legacy_due_date(&subscription_id);
+                }
+                migrated += 1;
+            }
+            due_buckets.flush();
+        }
+
+        let remaining = migration.remaining();
+        if remaining > 0 {
+            self.migration = Some(migration);
+        }
+        log!(
+            "Migrated {} subscriptions, {} remaining",
+            migrated,
+            remaining
+        );
+        remaining
+    }
+
+    /// Gets the version of the contract's state layout
+    pub fn get_state_version(&self) -> StateVersion {
+        self.state_version
+    }
+
+    /// Whether subscriptions are still being migrated after an upgrade
+    pub fn is_migrating(&self) -> bool {
+        self.migration.is_some()
+    }
+}
+
+impl Contract {
+    /// Maps state from before state versions into the current layout. Its workers are stored
+    /// again here as versioned entries; its subscriptions are left to `migrate_subscriptions`
+    fn from_v0(old: ContractV0) -> Self {
+        let ContractV0 {
+            owner_id,
+            approved_codehashes,
+            mut worker_by_account_id,
+            subscriptions,
+            subscription_keys,
+            merchants,
+        } = old;
+        let mut contract = Self::new(owner_id);
+        contract.approved_codehashes = approved_codehashes;
+        contract.subscription_keys = subscription_keys;
+        contract.merchants = merchants;
+
+        let workers: Vec<(AccountId, WorkerV1)> = worker_by_account_id
+            .iter()
+            .map(|(account_id, worker)| (account_id.clone(), worker.clone()))
+            .collect();
+        worker_by_account_id.clear();
+        worker_by_account_id.flush();
+        for (account_id, worker) in workers {
+            contract
+                .worker_by_account_id
+                .insert(account_id, VWorker::V1(worker));
+        }
+        contract.assign_shards();
+
+        if !subscriptions.is_empty() {
+            contract.migration = Some(Migration {
+                unversioned_subscriptions: Some(subscriptions),
+                ..Migration::default()
+            });
+        }
+        contract
+    }
+
+    /// Maps state from before subscriptions were sharded into the current layout. Its
+    /// subscriptions are left to `migrate_subscriptions`
+    fn from_v1(old: ContractV1) -> Self {
+        let mut contract = Self::from_legacy(
+            old.owner_id,
+            old.approved_codehashes,
+            old.worker_by_account_id,
+            old.fields,
+        );
+        if !old.subscriptions.is_empty() {
+            contract
+                .migration
+                .get_or_insert_with(Migration::default)
+                .unsharded_subscriptions = Some(old.subscriptions);
+        }
+        contract
+    }
+
+    /// Maps state from before next payment dates were indexed in a heap into the current
+    /// layout. Its due dates are left to `migrate_subscriptions`
+    fn from_v2(old: ContractV2) -> Self {
+        let mut contract = Self::from_legacy(
+            old.owner_id,
+            old.approved_codehashes,
+            old.worker_by_account_id,
+            old.fields,
+        );
+        contract.subscriptions = old.subscriptions;
+        contract.keys_by_subscription = old.keys_by_subscription;
+        contract.payment_history_heads = old.payment_history_heads;
+        contract
+    }
+
+    /// Maps the fields `ContractV1` and `ContractV2` share, keeping their hourly due-date
+    /// buckets for `migrate_subscriptions` to move into the heap
+    fn from_legacy(
+        owner_id: AccountId,
+        approved_codehashes: IterableSet<String>,
+        worker_by_account_id: IterableMap<AccountId, VWorker>,
+        fields: LegacyFields,
+    ) -> Self {
+        let mut contract = Self::new(owner_id);
+        contract.approved_codehashes = approved_codehashes;
+        contract.worker_by_account_id = worker_by_account_id;
+        contract.subscription_keys = fields.subscription_keys;
+        contract.merchants = fields.merchants;
+        contract.subscription_nonce = fields.subscription_nonce;
+        contract.merchant_limits = fields.merchant_limits;
+        contract.price_change_notice_period = fields.price_change_notice_period;
+        contract.payment_history = fields.payment_history;
+        contract.due_soon_window = fields.due_soon_window;
+        contract.templates = fields.templates;
+        contract.merchant_settings = fields.merchant_settings;
+        contract.default_cooling_off_period = fields.default_cooling_off_period;
+        contract.credits = fields.credits;
+        contract.archive_retention_period = fields.archive_retention_period;
+        contract.archived_subscriptions = fields.archived_subscriptions;
+        contract.fee_bps = fields.fee_bps;
+        contract.treasury_balances = fields.treasury_balances;
+        contract.referral_earnings = fields.referral_earnings;
+        contract.escrow_balances = fields.escrow_balances;
+        contract.retry_policy = fields.retry_policy;
+        contract.invoices = fields.invoices;
+        contract.invoice_counts = fields.invoice_counts;
+        contract.ft_registrations = fields.ft_registrations;
+        contract.allowed_tokens = fields.allowed_tokens;
+        contract.usd_oracle = fields.usd_oracle;
+        contract.usd_rates = fields.usd_rates;
+        contract.swap_adapter = fields.swap_adapter;
+        contract.intents_contract = fields.intents_contract;
+        contract.funding_rules = fields.funding_rules;
+        contract.staking_pool = fields.staking_pool;
+        contract.staking_preferences = fields.staking_preferences;
+        contract.staked_escrow = fields.staked_escrow;
+        contract.staked_escrow_total = fields.staked_escrow_total;
+        contract.claimable_balances = fields.claimable_balances;
+        contract.pending_settlements = fields.pending_settlements;
+        contract.settlement_reports = fields.settlement_reports;
+        contract.settlement_counts = fields.settlement_counts;
+        contract.arbiter_id = fields.arbiter_id;
+        contract.charge_holds = fields.charge_holds;
+        contract.refunded_amounts = fields.refunded_amounts;
+        contract.wnear_contract = fields.wnear_contract;
+        contract.token_decimals = fields.token_decimals;
+        contract.merchant_fees = fields.merchant_fees;
+        contract.approval_policies = fields.approval_policies;
+        contract.worker_fee_bps = fields.worker_fee_bps;
+        contract.worker_balances = fields.worker_balances;
+        contract.min_charge_amounts = fields.min_charge_amounts;
+        contract.allowed_mt_contracts = fields.allowed_mt_contracts;
+        contract.subscription_counts = fields.subscription_counts;
+        contract.merchant_subscription_counts = fields.merchant_subscription_counts;
+        contract.storage_accounts = fields.storage_accounts;
+        contract.subscription_storage = fields.subscription_storage;
+        contract.subscription_sequence = fields.subscription_sequence;
+        contract.assign_shards();
+
+        if !fields.due_buckets.is_empty() {
+            contract.migration = Some(Migration {
+                due_index: Some(fields.due_index),
+                due_buckets: Some(fields.due_buckets),
+                ..Migration::default()
+            });
+        }
+        contract
+    }
+
+    /// Removes up to `limit` subscriptions from an old subscription map. Moved entries are
+    /// removed, so every batch starts at the front of the map
+    fn take_subscriptions<V>(
+        subscriptions: &mut IterableMap<SubscriptionId, V>,
+        limit: u64,
+    ) -> Vec<Subscription>
+    where
+        V: BorshSerialize + BorshDeserialize + Into<Subscription>,
+    {
+        let subscription_ids: Vec<SubscriptionId> = subscriptions
+            .keys()
+            .take(limit as usize)
+            .cloned()
+            .collect();
+        let taken = subscription_ids
+            .iter()
+            .map(|subscription_id| {
+                subscriptions
+                    .remove(subscription_id)
+                    .expect("Subscription not found")
+                    .into()
+            })
+            .collect();
+        subscriptions.flush();
+        taken
+    }
+
+    /// Stores a subscription from before state versions in the current layout, counting it
+    fn store_unversioned_subscription(&mut self, subscription: Subscription) {
+        // Its ID was derived from its creation time rather than the nonce, so it is given the
+        // next sequence number to be paged with the rest
+        self.subscription_nonce += 1;
+        self.subscription_sequence
+            .insert(self.subscription_nonce, subscription.id.clone());
+        self.count_subscription(&subscription.merchant_id, &subscription.status);
+        self.store_unsharded_subscription(subscription);
+    }
+
+    /// Indexes the due date of a subscription listed in an hourly due-date bucket, unless it has
+    /// ended. Subscriptions not in their shards yet are indexed when they are moved there
+    fn index_legacy_due_date(&mut self, subscription_id: &SubscriptionId) {
+        let Some(due_date) = self
+            .subscriptions
+            .get(subscription_id)
+            .filter(|subscription| subscription.status.keeps_due_date())
+            .map(|subscription| subscription.next_payment_date)
+        else {
+            return;
+        };
+        self.index_due_date(subscription_id, due_date);
+    }
+
+    /// Stores a subscription from a single map in its shard, indexing it by user and its due
+    /// date unless it has ended
+    fn store_unsharded_subscription(&mut self, subscription: Subscription) {
+        if subscription.status.keeps_due_date() {
+            self.index_due_date(&subscription.id, subscription.next_payment_date);
+        }
+        self.index_user_subscription(&subscription.user_id, &subscription.id);
+        self.subscriptions
+            .insert(subscription.id.clone(), subscription.into());
+    }
+}
+
+impl Migration {
+    /// Entries still to be moved
+    fn remaining(&self) -> u64 {
+        let unversioned = self
+            .unversioned_subscriptions
+            .as_ref()
+            .map_or(0, |subscriptions| subscriptions.len());
+        let unsharded = self
+            .unsharded_subscriptions
+            .as_ref()
+            .map_or(0, |subscriptions| subscriptions.len());
+        let due_buckets = self
+            .due_buckets
+            .as_ref()
+            .map_or(0, |due_buckets| due_buckets.len());
+        (unversioned + unsharded + due_buckets) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::store::{IterableMap, IterableSet, LookupMap};
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{env, json_types::U128, testing_env};
+
+    use super::{ContractV0, CURRENT_STATE_VERSION};
+    use crate::models::{
+        PaymentMethod, SubscriptionFrequency, SubscriptionStatus, SubscriptionStatusV0,
+        SubscriptionV0, WorkerV1,
+    };
+    use crate::Contract;
+
+    fn setup() {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .build());
+    }
+
+    fn subscription_v0(id: &str, next_payment_date: u64, status: SubscriptionStatusV0) -> SubscriptionV0 {
+        SubscriptionV0 {
+            id: id.to_string(),
+            user_id: accounts(1),
+            merchant_id: accounts(2),
+            amount: U128(100),
+            frequency: SubscriptionFrequency::Monthly,
+            next_payment_date,
+            status,
+            created_at: 0,
+            updated_at: 0,
+            payment_method: PaymentMethod::Near,
+            max_payments: None,
+            payments_made: 0,
+            end_date: None,
+        }
+    }
+
+    /// Writes state as stored before state versions, with a worker and three subscriptions
+    fn write_v0_state() {
+        let mut old = ContractV0 {
+            owner_id: accounts(0),
+            approved_codehashes: IterableSet::new(b"a"),
+            worker_by_account_id: IterableMap::new(b"b"),
+            subscriptions: IterableMap::new(b"c"),
+            subscription_keys: LookupMap::new(b"d"),
+            merchants: IterableSet::new(b"g"),
+        };
+        old.worker_by_account_id.insert(
+            accounts(3),
+            WorkerV1 {
+                checksum: "foo".to_string(),
+                codehash: "bar".to_string(),
+            },
+        );
+        old.merchants.insert(accounts(2));
+        for subscription in [
+            subscription_v0("sub-1", 300, SubscriptionStatusV0::Active),
+            subscription_v0("sub-2", 100, SubscriptionStatusV0::Active),
+            subscription_v0("sub-3", 200, SubscriptionStatusV0::Canceled),
+        ] {
+            old.subscriptions.insert(subscription.id.clone(), subscription);
+        }
+        old.worker_by_account_id.flush();
+        old.merchants.flush();
+        old.subscriptions.flush();
+        env::state_write(&old);
+    }
+
+    #[test]
+    fn migrates_unversioned_state_in_batches() {
+        setup();
+        write_v0_state();
+
+        let mut contract = Contract::migrate();
+        assert_eq!(contract.get_state_version(), CURRENT_STATE_VERSION);
+        assert_eq!(contract.get_worker(accounts(3)).codehash, "bar");
+        assert!(contract.merchants.contains(&accounts(2)));
+        assert!(contract.is_migrating());
+
+        assert_eq!(contract.migrate_subscriptions(2), 1);
+        assert!(contract.is_migrating());
+        assert_eq!(contract.migrate_subscriptions(2), 0);
+        assert!(!contract.is_migrating());
+
+        for subscription_id in ["sub-1", "sub-2", "sub-3"] {
+            assert!(contract
+                .get_subscription(subscription_id.to_string())
+                .is_some());
+        }
+        assert!(matches!(
+            contract
+                .get_subscription("sub-3".to_string())
+                .map(|subscription| subscription.status),
+            Some(SubscriptionStatus::Canceled)
+        ));
+        assert_eq!(contract.subscription_nonce, 3);
+        assert_eq!(contract.user_subscription_ids(&accounts(1)).len(), 3);
+
+        // Only the subscriptions still charged are indexed by due date, earliest first
+        assert_eq!(contract.due_heap.len(), 2);
+        assert_eq!(contract.due_heap[0], (100, "sub-2".to_string()));
+        assert!(contract
+            .due_heap_positions
+            .get(&"sub-3".to_string())
+            .is_none());
+    }
+
+    #[test]
+    fn migrates_current_state_in_place() {
+        setup();
+        let mut contract = Contract::new(accounts(0));
+        contract.set_fee_bps(250);
+        env::state_write(&contract);
+
+        let contract = Contract::migrate();
+        assert_eq!(contract.get_state_version(), CURRENT_STATE_VERSION);
+        assert_eq!(contract.get_fee_bps(), 250);
+        assert!(!contract.is_migrating());
+    }
+
+    #[test]
+    #[should_panic(expected = "Contract state not found")]
+    fn rejects_migration_without_state() {
+        setup();
+
+        Contract::migrate();
+    }
+}
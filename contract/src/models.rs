@@ -1,3 +1,5 @@
+use std::ops::{Deref, DerefMut};
+
 use near_sdk::{
     AccountId,
     json_types::U128,
@@ -11,15 +13,229 @@ pub type SubscriptionId = String;
 pub struct Worker {
     pub checksum: String,
     pub codehash: String,
+    pub tee_type: Option<TeeType>, // TEE its attestation quote came from; None if not recorded
+    pub verified_at: u64, // When its attestation was last verified; 0 if it never was
 }
 
+/// Trusted execution environment a worker runs in
 #[near(serializers = [json, borsh])]
-#[derive(Debug, Clone)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TeeType {
+    Sgx,
+    Tdx,
+}
+
+/// Worker as stored before attestation times were kept
+#[near(serializers = [borsh])]
+#[derive(Clone, Debug)]
+pub struct WorkerV1 {
+    pub checksum: String,
+    pub codehash: String,
+}
+
+/// Worker as stored before TEE types were kept
+#[near(serializers = [borsh])]
+#[derive(Clone, Debug)]
+pub struct WorkerV2 {
+    pub checksum: String,
+    pub codehash: String,
+    pub verified_at: u64,
+}
+
+/// A key authorized to process payments for a subscription
+#[near(serializers = [json])]
+pub struct SubscriptionKey {
+    pub public_key: String,
+    pub label: Option<String>, // e.g. "primary worker" or "backup worker"
+}
+
+/// Where a subscription's payment key is derived from inside a worker's TEE
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct KeyDerivation {
+    pub account_id: AccountId, // Worker whose TEE derives the key
+    pub path: String,
+    pub public_key: Option<String>, // Key currently derived and authorized, once published
+}
+
+/// When an approved codehash is accepted, for upgrading worker fleets gradually
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct CodehashWindow {
+    pub active_from: u64, // Workers on the codehash are accepted from this time
+    pub sunset_at: Option<u64>, // And until this time, if it is being retired
+}
+
+/// Collateral the owner pinned for verifying worker quotes
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct PinnedCollateral {
+    pub collateral: String, // JSON, in the form `register_worker` used to take
+    pub expires_at: u64, // When the TCB info must be refreshed
+}
+
+/// NEAR workers must bond to register, and how misbehavior is penalized
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, Default)]
+pub struct BondPolicy {
+    pub required: U128, // Bond a worker must hold to register and process payments
+    pub cooldown: u64, // Seconds after deregistering before a bond can be withdrawn
+    pub not_due_penalty: U128, // Slashed for each not-due charge beyond the allowance
+    pub not_due_allowance: u32, // Not-due charges in a row tolerated for clock skew and lost races
+}
+
+/// NEAR a worker has bonded
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct WorkerBond {
+    pub amount: U128,
+    pub unlocks_at: Option<u64>, // When a deregistered worker can withdraw it; None while registered
+}
+
+/// Most payments one worker can process per period of blocks
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct WorkerRateLimit {
+    pub max_payments: u32,
+    pub period_blocks: u64, // e.g. 1 for per block, 43200 for roughly an epoch
+}
+
+/// Payments a worker has processed in its current rate limit period
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct WorkerPaymentUsage {
+    pub period_start: u64, // Block height the period began at
+    pub payments: u32,
+}
+
+/// Payment attempts by a worker that were rejected for reasons a correct worker never hits
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, Default)]
+pub struct WorkerConduct {
+    pub unauthorized_key: u32, // Signed with a key not authorized for the subscription
+    pub not_due: u32,
+    pub not_due_streak: u32, // Not-due attempts since the worker's last successful charge
+    pub not_active: u32,
+    pub suspended_at: Option<u64>, // When the worker was suspended from processing payments
+}
+
+impl WorkerConduct {
+    /// Rejected attempts of every kind
+    pub fn total(&self) -> u32 {
+        self.unauthorized_key
+            .saturating_add(self.not_due)
+            .saturating_add(self.not_active)
+    }
+}
+
+/// Single-use challenge a worker's attestation quote must carry
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct AttestationNonce {
+    pub nonce: String, // Hex-encoded 32 bytes
+    pub expires_at: u64,
+}
+
+/// Version of the contract's state layout, bumped by releases that change it
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum StateVersion {
+    V1, // Subscriptions and workers stored as versioned entries
+    V2, // Subscriptions sharded by a hash of their ID
+    V3, // Next payment dates indexed in a heap instead of hourly buckets
+}
+
+/// Stored form of a worker. New versions are added as variants, and older ones converted to
+/// the latest when read
+#[near(serializers = [borsh])]
+#[derive(Clone, Debug)]
+pub enum VWorker {
+    V1(WorkerV1),
+    V2(WorkerV2),
+    V3(Worker),
+}
+
+impl From<Worker> for VWorker {
+    fn from(worker: Worker) -> Self {
+        VWorker::V3(worker)
+    }
+}
+
+impl From<VWorker> for Worker {
+    fn from(worker: VWorker) -> Self {
+        match worker {
+            // Workers registered before attestation times were kept have to renew
+            VWorker::V1(worker) => Worker {
+                checksum: worker.checksum,
+                codehash: worker.codehash,
+                tee_type: None,
+                verified_at: 0,
+            },
+            VWorker::V2(worker) => Worker {
+                checksum: worker.checksum,
+                codehash: worker.codehash,
+                tee_type: None,
+                verified_at: worker.verified_at,
+            },
+            VWorker::V3(worker) => worker,
+        }
+    }
+}
+
+#[near(serializers = [json, borsh])]
+#[derive(Debug, Clone, PartialEq)]
 pub enum SubscriptionStatus {
     Active,
+    PastDue, // A payment failed and is waiting to be retried
     Paused,
     Canceled,
     Failed,
+    PendingApproval, // Waiting for the subscriber's co-signer to approve it
+}
+
+impl SubscriptionStatus {
+    /// Whether a subscription in this status may move to `to`
+    pub fn can_transition_to(&self, to: &SubscriptionStatus) -> bool {
+        use SubscriptionStatus::*;
+        match to {
+            Active => matches!(self, Active | PastDue | Paused | PendingApproval),
+            PastDue | Failed => matches!(self, Active | PastDue),
+            Paused => matches!(self, Active | PastDue | Paused),
+            Canceled => !matches!(self, Canceled),
+            PendingApproval => false,
+        }
+    }
+
+    /// Whether a subscription in this status keeps its place in the due-date index. Paused
+    /// subscriptions leave it and are indexed again when resumed
+    pub fn keeps_due_date(&self) -> bool {
+        !matches!(
+            self,
+            SubscriptionStatus::Paused | SubscriptionStatus::Canceled | SubscriptionStatus::Failed
+        )
+    }
+}
+
+/// Subscription status as stored before state versions, which had no `PastDue` or
+/// `PendingApproval`
+#[near(serializers = [borsh])]
+#[derive(Clone, Debug)]
+pub enum SubscriptionStatusV0 {
+    Active,
+    Paused,
+    Canceled,
+    Failed,
+}
+
+impl From<SubscriptionStatusV0> for SubscriptionStatus {
+    fn from(status: SubscriptionStatusV0) -> Self {
+        match status {
+            SubscriptionStatusV0::Active => SubscriptionStatus::Active,
+            SubscriptionStatusV0::Paused => SubscriptionStatus::Paused,
+            SubscriptionStatusV0::Canceled => SubscriptionStatus::Canceled,
+            SubscriptionStatusV0::Failed => SubscriptionStatus::Failed,
+        }
+    }
 }
 
 #[near(serializers = [json, borsh])]
@@ -32,11 +248,25 @@ pub enum SubscriptionFrequency {
     Yearly,
 }
 
+impl SubscriptionFrequency {
+    /// Length of a billing period in seconds
+    pub fn seconds(&self) -> u64 {
+        match self {
+            SubscriptionFrequency::Daily => 86400,
+            SubscriptionFrequency::Weekly => 604800,
+            SubscriptionFrequency::Monthly => 2592000,
+            SubscriptionFrequency::Quarterly => 7776000,
+            SubscriptionFrequency::Yearly => 31536000,
+        }
+    }
+}
+
 #[near(serializers = [json, borsh])]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum PaymentMethod {
     Near,
     Ft { token_id: AccountId },
+    Mt { contract_id: AccountId, token_id: String }, // A token of a NEP-245 multi-token contract
 }
 
 #[near(serializers = [json, borsh])]
@@ -55,6 +285,770 @@ pub struct Subscription {
     pub max_payments: Option<u32>,
     pub payments_made: u32,
     pub end_date: Option<u64>,
+    pub max_amount_per_charge: U128, // Hard cap on a single charge, set by the subscriber
+    pub max_total_spend: Option<U128>, // Lifetime cap across all charges
+    pub total_spent: U128,
+    pub pending_price_change: Option<PriceChange>,
+    pub line_items: Vec<LineItem>, // Empty for single-amount subscriptions
+    pub due_soon_notified_for: Option<u64>, // next_payment_date a due-soon event was last emitted for
+    pub approved_extension: Option<CommitmentTerms>, // Merchant consent to loosen max_payments/end_date
+    pub template_id: Option<String>, // Template the subscription was created from, if any
+    pub metadata: Option<String>,
+    pub held_payment: Option<HeldPayment>, // Charges collected during the cooling-off window
+    pub memo_template: Option<String>, // Overrides the merchant's default payment memo
+    pub referrer_id: Option<AccountId>, // Earns the merchant's referral commission on payments
+    pub cycle_index: u32, // Billing cycle the next charge pays for
+    pub retry_count: u32, // Failed attempts at the current charge
+    pub next_retry_at: Option<u64>, // When a past-due charge is next attempted
+    pub token_prices: Vec<FundingSource>, // Merchant-accepted prices in other tokens
+    pub funding_order: Vec<PaymentMethod>, // Subscriber's fallback tokens, tried in order
+    pub denomination: PriceDenomination, // For USD pricing `amount` is the last converted charge
+    pub swap_funding: Option<SwapFunding>, // Pay by swapping another escrowed token
+    pub settlement_pending: bool, // A swapped or intents-settled payment, or a top-up, is in flight
+    pub top_up_cycle: Option<u32>, // Cycle escrow was last topped up (funding source or unstake) for
+    pub balance_warned_for: Option<u64>, // next_payment_date a low-balance warning was last emitted for
+    pub skips_used: u32, // Payments skipped in the year starting at skip_year_start
+    pub skip_year_start: u64,
+    pub amount_override: Option<U128>, // Usage-based amount of the charge in progress, replacing `amount` once
+    pub catching_up: bool, // Charging missed cycles; due dates advance from the previous due date
+    pub approver_id: Option<AccountId>, // Co-signer that must approve the subscription before it activates
+    pub processed_by: Option<AccountId>, // Worker that last processed a charge, paid its fee share
+    pub failing_since: Option<u64>, // First of the current run of failed payments
+    pub payment_mode: PaymentMode,
+    pub stream_claimed_until: u64, // Time a streaming subscription has been paid up to
+    pub prepaid_cycles: u32, // Cycles paid for in advance, used up before charging again
+    pub pending_credit: U128, // Credit applied to the charge in progress, restored if it fails
+}
+
+/// Subscription as stored before state versions
+#[near(serializers = [borsh])]
+#[derive(Clone)]
+pub struct SubscriptionV0 {
+    pub id: SubscriptionId,
+    pub user_id: AccountId,
+    pub merchant_id: AccountId,
+    pub amount: U128,
+    pub frequency: SubscriptionFrequency,
+    pub next_payment_date: u64,
+    pub status: SubscriptionStatusV0,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub payment_method: PaymentMethod,
+    pub max_payments: Option<u32>,
+    pub payments_made: u32,
+    pub end_date: Option<u64>,
+}
+
+impl From<SubscriptionV0> for Subscription {
+    fn from(subscription: SubscriptionV0) -> Self {
+        Subscription {
+            id: subscription.id,
+            user_id: subscription.user_id,
+            merchant_id: subscription.merchant_id,
+            amount: subscription.amount,
+            frequency: subscription.frequency,
+            next_payment_date: subscription.next_payment_date,
+            status: subscription.status.into(),
+            created_at: subscription.created_at,
+            updated_at: subscription.updated_at,
+            payment_method: subscription.payment_method,
+            max_payments: subscription.max_payments,
+            payments_made: subscription.payments_made,
+            end_date: subscription.end_date,
+            // Spending caps default to the subscription amount, as for new subscriptions
+            max_amount_per_charge: subscription.amount,
+            max_total_spend: None,
+            total_spent: U128(0),
+            pending_price_change: None,
+            line_items: Vec::new(),
+            due_soon_notified_for: None,
+            approved_extension: None,
+            template_id: None,
+            metadata: None,
+            held_payment: None,
+            memo_template: None,
+            referrer_id: None,
+            cycle_index: subscription.payments_made,
+            retry_count: 0,
+            next_retry_at: None,
+            token_prices: Vec::new(),
+            funding_order: Vec::new(),
+            denomination: PriceDenomination::Token,
+            swap_funding: None,
+            settlement_pending: false,
+            top_up_cycle: None,
+            balance_warned_for: None,
+            skips_used: 0,
+            skip_year_start: 0,
+            amount_override: None,
+            catching_up: false,
+            approver_id: None,
+            processed_by: None,
+            failing_since: None,
+            payment_mode: PaymentMode::Periodic,
+            stream_claimed_until: 0,
+            prepaid_cycles: 0,
+            pending_credit: U128(0),
+        }
+    }
+}
+
+/// Stored form of a subscription, so fields can be added without breaking the deserialization
+/// of those already stored. New versions are added as variants: older ones are upgraded to the
+/// latest when read and written back in it, and the current one is what this dereferences to
+#[near(serializers = [borsh])]
+#[derive(Clone)]
+pub enum VSubscription {
+    V1(Subscription),
+}
+
+impl From<Subscription> for VSubscription {
+    fn from(subscription: Subscription) -> Self {
+        VSubscription::V1(subscription)
+    }
+}
+
+impl From<VSubscription> for Subscription {
+    fn from(subscription: VSubscription) -> Self {
+        match subscription {
+            VSubscription::V1(subscription) => subscription,
+        }
+    }
+}
+
+impl From<&VSubscription> for Subscription {
+    fn from(subscription: &VSubscription) -> Self {
+        Subscription::clone(subscription)
+    }
+}
+
+impl Deref for VSubscription {
+    type Target = Subscription;
+
+    fn deref(&self) -> &Subscription {
+        match self {
+            VSubscription::V1(subscription) => subscription,
+        }
+    }
+}
+
+impl DerefMut for VSubscription {
+    fn deref_mut(&mut self) -> &mut Subscription {
+        match self {
+            VSubscription::V1(subscription) => subscription,
+        }
+    }
+}
+
+/// A subscriber's linked source that escrow is topped up from when it runs short
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct FundingRule {
+    pub source_id: AccountId, // Contract implementing `pull_funds`
+    pub token_id: AccountId,
+    pub max_per_pull: U128,
+}
+
+/// A subscriber's opt-in to staking idle NEAR escrow
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct StakingPreference {
+    pub liquid_buffer: U128, // NEAR escrow always kept unstaked for upcoming charges
+}
+
+/// Escrowed token a subscriber pays with by swapping it for the subscription's token
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct SwapFunding {
+    pub token_in: AccountId,
+    pub max_slippage_bps: u16, // Allowed input over the oracle quote
+}
+
+/// How a subscription is paid
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum PaymentMode {
+    #[default]
+    Periodic, // Charged `amount` once per billing period
+    Stream, // Accrues `amount / period` per second, claimed by the merchant with `claim_stream`
+}
+
+/// What a subscription's price is set in
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum PriceDenomination {
+    #[default]
+    Token, // `amount` in the payment token
+    Usd { cents: u64 }, // Converted to the payment token at charge time
+}
+
+/// Oracle used to price USD-denominated subscriptions
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct UsdOracleConfig {
+    pub oracle_id: AccountId,
+    pub near_asset_id: AccountId, // Asset NEAR is priced as, e.g. wrap.near
+    pub max_age: u64, // Seconds a fetched rate can be used for
+}
+
+/// USD price of one smallest token unit: `multiplier / 10^decimals`
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct UsdRate {
+    pub multiplier: U128,
+    pub decimals: u8,
+    pub updated_at: u64,
+}
+
+impl Subscription {
+    /// The subscription's own token and the amount to charge, including any override
+    pub fn funding(&self) -> FundingSource {
+        FundingSource {
+            payment_method: self.payment_method.clone(),
+            amount: self.amount_override.unwrap_or(self.amount),
+        }
+    }
+}
+
+/// A token a charge is paid in and the amount charged in it
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct FundingSource {
+    pub payment_method: PaymentMethod,
+    pub amount: U128,
+}
+
+/// Backoff applied between attempts at a failed payment
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub base_delay: u64, // Seconds before the first retry; doubles on each attempt
+    pub max_delay: u64,
+    pub max_retries: u32, // The subscription fails once these are used up
+}
+
+/// A worker's claim on a due subscription, keeping other workers from charging it
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct Lease {
+    pub worker_id: AccountId,
+    pub expires_at: u64,
+}
+
+/// Funds charged during a cooling-off window, kept in the contract until it lapses
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct HeldPayment {
+    pub amount: U128,
+    pub release_at: u64,
+}
+
+/// Refunds a merchant lets subscribers claim themselves
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct RefundPolicy {
+    pub window: u64, // Seconds after a charge during which it can be refunded
+    pub refund_bps: u16, // Share of the charge refunded, in basis points (10000 for a full refund)
+}
+
+/// Where a refund goes
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum RefundDestination {
+    #[default]
+    Wallet, // Transferred to the subscriber's account
+    Escrow, // Added to the subscriber's escrow, instantly and without a transfer
+    Credit, // Credit with the merchant towards later charges
+}
+
+impl RefundDestination {
+    /// Memo recorded on refunds sent to this destination
+    pub fn memo(&self) -> &'static str {
+        match self {
+            RefundDestination::Wallet => "Refunded to wallet",
+            RefundDestination::Escrow => "Refunded to escrow",
+            RefundDestination::Credit => "Refunded as credit",
+        }
+    }
+}
+
+/// A charge held in the contract for the merchant's dispute window
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct ChargeHold {
+    pub payment_number: u32,
+    pub cycle_index: u32,
+    pub funding: FundingSource,
+    pub release_at: u64,
+    pub dispute: Option<Dispute>,
+}
+
+/// A subscriber's dispute of a held charge
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct Dispute {
+    pub reason: String,
+    pub response: Option<String>, // Merchant's response when contesting
+    pub status: DisputeStatus,
+    pub opened_at: u64,
+}
+
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, PartialEq)]
+pub enum DisputeStatus {
+    Open,      // Awaiting the merchant
+    Contested, // Awaiting the owner or arbiter
+}
+
+/// Per-merchant configuration; unset fields fall back to contract defaults
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, Default)]
+pub struct MerchantSettings {
+    pub cooling_off_period: Option<u64>, // `Some(0)` opts out of the contract default
+    pub prorate_on_cancel: bool, // Credit the unused part of the last charge when a subscriber cancels
+    pub memo_template: Option<String>, // Default payment memo, see `Contract::payment_memo`
+    pub payout_address: Option<AccountId>, // Receives payments instead of the merchant account
+    pub revenue_splits: Vec<RevenueSplit>, // Shares paid to other recipients; the rest goes to the payout address
+    pub referral_commission: Option<ReferralCommission>,
+    pub ft_transfer_call: bool, // Pay the payout address with ft_transfer_call instead of ft_transfer
+    pub intents_settlement: bool, // Deposit FT payments to the payout address on the intents contract
+    pub claimable_payouts: bool, // Accrue payments to a claimable balance instead of paying per charge
+    pub settlement_window: Option<SettlementWindow>, // Accrue payments and pay them out once per window
+    pub dispute_window: Option<u64>, // Seconds each charge is held and open to disputes before payout
+    pub refund_policy: Option<RefundPolicy>, // Refunds subscribers can claim without the merchant
+    pub max_skips_per_year: Option<u32>, // Payments a subscriber may skip per year; None disallows skipping
+    pub min_charge_amounts: Vec<FundingSource>, // Smallest non-free charge accepted per token
+    pub auto_cancel: Option<AutoCancelPolicy>, // Cancel subscriptions whose payments keep failing
+    pub prepay_discount_bps: u16, // Discount on cycles paid for in advance
+    pub refund_destination: RefundDestination, // Where refunds go unless a refund says otherwise
+    pub payment_hook: Option<PaymentHook>, // Contract notified of each successful payment
+}
+
+/// A merchant contract method called after each successful payment
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct PaymentHook {
+    pub receiver_id: AccountId,
+    pub method_name: String,
+}
+
+/// When a merchant's subscriptions with consecutive failed payments are canceled
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct AutoCancelPolicy {
+    pub max_failures: u32, // Consecutive failed attempts that cancel the subscription
+    pub period: u64, // Seconds after the first failure that cancel it, whichever comes first
+}
+
+/// Commission a merchant pays the referrer of a subscription
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct ReferralCommission {
+    pub commission_bps: u16, // Share of each payment after the platform fee
+    pub duration: Option<u64>, // Seconds after subscription creation commissions are paid for
+}
+
+/// A referrer's commission balance in one token
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, Default)]
+pub struct ReferralEarnings {
+    pub claimable: U128,
+    pub total_earned: U128,
+}
+
+/// A recipient's share of a merchant's revenue
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct RevenueSplit {
+    pub recipient: AccountId,
+    pub share_bps: u16,
+}
+
+/// One transfer made when paying out a charge
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct PayoutLeg {
+    pub recipient: AccountId,
+    pub amount: U128,
+}
+
+/// What a payment's payouts settle, deciding what happens once their transfers are confirmed
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, PartialEq)]
+pub enum PayoutPurpose {
+    Charge(ChargeDetails), // A due charge, which only advances the subscription once its payouts arrive
+    Release,               // A payment already recorded, such as a released hold, prepayment or stream
+}
+
+/// What a charge billed, carried through asynchronous settlement so that it is finalised with
+/// the amounts it was charged at rather than whatever the subscription holds by then
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChargeDetails {
+    pub cycle_index: u32, // Billing cycle the charge pays for
+    pub amount: U128, // Billed in the subscription's token, including any credit applied
+    pub usd_rate: Option<UsdRate>, // Rate a USD-priced amount was converted at
+}
+
+/// Merchant-defined defaults that `create_subscription` can start from
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct SubscriptionTemplate {
+    pub template_id: String,
+    pub merchant_id: AccountId,
+    pub amount: U128,
+    pub frequency: SubscriptionFrequency,
+    pub payment_method: PaymentMethod,
+    pub line_items: Vec<LineItem>,
+    pub trial_period: Option<u64>, // Seconds before the first charge; defaults to one interval
+    pub metadata: Option<String>,
+    pub memo_template: Option<String>,
+}
+
+/// Subscription a user asks `create_subscription` for. Fields left as `None` are taken from
+/// the template when `template_id` is given
+#[near(serializers = [json])]
+#[derive(Clone, Debug)]
+pub struct CreateSubscriptionArgs {
+    pub merchant_id: AccountId,
+    pub amount: Option<U128>, // Required unless priced in USD or set by the template
+    pub frequency: Option<SubscriptionFrequency>,
+    pub payment_method: Option<PaymentMethod>,
+    pub max_payments: Option<u32>,
+    pub end_date: Option<u64>,
+    pub max_amount_per_charge: Option<U128>, // Defaults to the amount; required for USD pricing
+    pub max_total_spend: Option<U128>,
+    pub line_items: Option<Vec<LineItem>>,
+    pub template_id: Option<String>,
+    pub metadata: Option<String>,
+    pub referrer_id: Option<AccountId>,
+    pub denomination: Option<PriceDenomination>,
+    pub payment_mode: Option<PaymentMode>,
+}
+
+/// Bounds on how long a subscription runs; `None` means unbounded
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct CommitmentTerms {
+    pub max_payments: Option<u32>,
+    pub end_date: Option<u64>,
+}
+
+/// One component of a subscription's charge (e.g. base plan or add-on)
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct LineItem {
+    pub name: String,
+    pub amount: U128, // Unit price
+    pub quantity: Option<u32>, // Defaults to 1
+}
+
+impl LineItem {
+    /// Amount charged for this item per cycle
+    pub fn total(&self) -> u128 {
+        self.amount.0 * self.quantity.unwrap_or(1) as u128
+    }
+}
+
+/// NEP-145 storage deposit of an account and the bytes its subscriptions and keys use
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct StorageAccount {
+    pub deposit: U128,
+    pub used_bytes: u64,
+}
+
+/// Who paid for a subscription's storage, and how many bytes
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct StoragePayer {
+    pub account_id: AccountId,
+    pub bytes: u64,
+}
+
+/// A page of subscriptions and the cursor to the next one, if any
+#[near(serializers = [json])]
+pub struct SubscriptionPage {
+    pub subscriptions: Vec<Subscription>,
+    pub has_more: bool, // Whether `next_cursor` leads to more subscriptions to scan
+    pub next_cursor: Option<String>,
+}
+
+/// Outcome of one `check_balances` pass over a range of subscriptions
+#[near(serializers = [json])]
+pub struct BalanceCheck {
+    pub warned: u64, // Insufficient balance warnings emitted
+    pub next_cursor: Option<String>, // Pass as `from` to check the next range; `None` once all were checked
+}
+
+/// Number of subscriptions in the live state, in total and by status
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, Default)]
+pub struct SubscriptionCounts {
+    pub total: u64,
+    pub active: u64,
+    pub past_due: u64,
+    pub paused: u64,
+    pub canceled: u64,
+    pub failed: u64,
+    pub pending_approval: u64,
+}
+
+impl SubscriptionCounts {
+    /// The count of subscriptions with a given status
+    pub fn for_status(&mut self, status: &SubscriptionStatus) -> &mut u64 {
+        match status {
+            SubscriptionStatus::Active => &mut self.active,
+            SubscriptionStatus::PastDue => &mut self.past_due,
+            SubscriptionStatus::Paused => &mut self.paused,
+            SubscriptionStatus::Canceled => &mut self.canceled,
+            SubscriptionStatus::Failed => &mut self.failed,
+            SubscriptionStatus::PendingApproval => &mut self.pending_approval,
+        }
+    }
+}
+
+/// A merchant-scheduled change to a subscription's amount
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct PriceChange {
+    pub new_amount: U128,
+    pub new_line_items: Option<Vec<LineItem>>,
+    pub effective_at: u64,
+    pub scheduled_at: u64,
+}
+
+/// A co-signer that must approve a user's subscriptions above a per-charge threshold
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct ApprovalPolicy {
+    pub approver_id: AccountId,
+    pub threshold: U128, // Subscriptions whose max_amount_per_charge exceeds this need approval
+}
+
+/// Subscriber-configured cap on what a merchant can charge them per 30-day window,
+/// summed across all of their subscriptions with that merchant in one token
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct MerchantLimit {
+    pub max_per_month: U128,
+    pub period_start: u64,
+    pub spent_in_period: U128,
+}
+
+/// An existing subscription brought over from another billing system
+#[near(serializers = [json])]
+#[derive(Clone, Debug)]
+pub struct SubscriptionImport {
+    pub user_id: AccountId,
+    pub merchant_id: AccountId,
+    pub amount: U128,
+    pub frequency: SubscriptionFrequency,
+    pub payment_method: PaymentMethod,
+    pub created_at: u64,
+    pub next_payment_date: u64,
+    pub max_payments: Option<u32>,
+    pub payments_made: u32,
+    pub end_date: Option<u64>,
+    pub line_items: Option<Vec<LineItem>>,
+}
+
+/// Next scheduled charge for one of a user's subscriptions
+#[near(serializers = [json])]
+#[derive(Clone, Debug)]
+pub struct UpcomingPayment {
+    pub subscription_id: SubscriptionId,
+    pub merchant_id: AccountId,
+    pub amount: U128,
+    pub payment_method: PaymentMethod,
+    pub next_payment_date: u64,
+}
+
+/// Compact record kept for a subscription after it has been archived
+#[near(serializers = [json, borsh])]
+#[derive(Clone)]
+pub struct ArchivedSubscription {
+    pub id: SubscriptionId,
+    pub user_id: AccountId,
+    pub merchant_id: AccountId,
+    pub status: SubscriptionStatus,
+    pub payment_method: PaymentMethod,
+    pub payments_made: u32,
+    pub total_spent: U128,
+    pub created_at: u64,
+    pub terminated_at: u64,
+    pub archived_at: u64,
+}
+
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, PartialEq)]
+pub enum PaymentKind {
+    Charge,
+    Refund,
+    Failed, // A charge whose payouts did not all go through; it was not counted
+    Canceled { reason: CancellationReason }, // The subscription was ended by the contract
+}
+
+/// Why the contract canceled a subscription
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, PartialEq)]
+pub enum CancellationReason {
+    PaymentFailure, // The merchant's auto-cancel policy was reached
+    MaxPaymentsReached, // Every payment the subscription was created for has been made
+    EndDateReached, // The subscription's end date has passed
+}
+
+/// A registered worker as listed for auditing the fleet
+#[near(serializers = [json])]
+pub struct WorkerInfo {
+    pub account_id: AccountId,
+    pub codehash: String,
+    pub checksum: String,
+    pub tee_type: Option<TeeType>,
+    pub attestation_age: Option<u64>, // Seconds since its attestation was verified; None if never
+    pub last_seen_at: Option<u64>, // When it last claimed or processed subscriptions
+}
+
+/// How many subscriptions are due for payment, without identifying them
+#[near(serializers = [json])]
+pub struct DueSummary {
+    pub due: u64,
+    pub has_more: bool, // More are due than were counted
+    pub earliest_due_at: Option<u64>, // Due date of the longest-waiting due subscription
+}
+
+/// Contract-wide totals for dashboards
+#[near(serializers = [json])]
+pub struct ContractStats {
+    pub subscriptions: SubscriptionCounts, // Subscriptions in the live state, by status
+    pub merchants: u32,
+    pub workers: u32,
+    pub volume_processed: Vec<(PaymentMethod, U128)>, // Successful charges per token, ordered by token
+    pub fees_collected: Vec<(PaymentMethod, U128)>, // Platform fees per token, ordered by token
+}
+
+/// A merchant's failed charges and past-due subscriptions, for recovering payments
+#[near(serializers = [json])]
+pub struct FailedPayments {
+    pub failed_records: Vec<PaymentRecord>,
+    pub past_due: Vec<Subscription>,
+    pub next_cursor: Option<String>, // Where the next page of the merchant's subscriptions starts
+}
+
+/// Stored record of a processed payment
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct PaymentRecord {
+    pub subscription_id: SubscriptionId,
+    pub kind: PaymentKind,
+    pub payment_number: u32,
+    pub amount: U128,
+    pub payment_method: PaymentMethod,
+    pub line_items: Vec<LineItem>,
+    pub memo: Option<String>,
+    pub fee: U128, // Platform fee taken from the charge
+    pub referral_commission: U128, // Credited to the subscription's referrer
+    pub payouts: Vec<PayoutLeg>, // Transfers the charge was paid out in, after the fee and commission
+    pub invoice_number: Option<u64>, // Merchant invoice issued for a charge
+    pub usd_rate: Option<UsdRate>, // Rate a USD-denominated charge was converted at
+    pub rate_source: Option<AccountId>, // Oracle the charge's rates were fetched from
+    pub swap: Option<SwapConversion>, // Swap a charge was paid through
+    pub amount_override: Option<AmountOverride>, // Usage-based amount a charge billed instead of the subscription amount
+    pub timestamp: u64,
+    pub block_height: u64, // Block the payment was processed in, for explorer links
+    pub block_timestamp: u64, // Nanoseconds, as reported by the block
+}
+
+/// Swap a charge was paid through, with the oracle rates it was quoted at
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct SwapConversion {
+    pub adapter_id: AccountId,
+    pub token_in: AccountId,
+    pub amount_in: U128, // Input used; the quote's maximum while the swap is in flight
+    pub rate_in: UsdRate,
+    pub rate_out: UsdRate,
+}
+
+/// Usage-based amount a charge billed, with the subscriber's cap it was checked against
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct AmountOverride {
+    pub amount: U128,
+    pub max_amount_per_charge: U128,
+}
+
+/// How often a merchant's accrued payments are paid out in one transfer
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, PartialEq)]
+pub enum SettlementWindow {
+    Daily,
+    Weekly,
+}
+
+impl SettlementWindow {
+    /// Length of the window in seconds
+    pub fn seconds(&self) -> u64 {
+        match self {
+            SettlementWindow::Daily => 86400,
+            SettlementWindow::Weekly => 604800,
+        }
+    }
+}
+
+/// Payments accrued for a merchant in a token since its last settlement
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct PendingSettlement {
+    pub window_start: u64, // Time of the first payment accrued in the window
+    pub payment_count: u32,
+}
+
+/// Record of one settlement window paid out to a merchant, numbered sequentially per merchant
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct SettlementReport {
+    pub settlement_number: u64,
+    pub merchant_id: AccountId,
+    pub payout_address: AccountId,
+    pub payment_method: PaymentMethod,
+    pub amount: U128,
+    pub payment_count: u32,
+    pub window_start: u64,
+    pub window_end: u64,
+}
+
+/// A merchant's payments in one token over a reporting period
+#[near(serializers = [json])]
+#[derive(Clone, Debug)]
+pub struct SettlementSummary {
+    pub payment_method: PaymentMethod,
+    pub gross_volume: U128, // Charges before fees and commissions
+    pub fees: U128,
+    pub referral_commissions: U128,
+    pub refunds: U128,
+    pub net_payout: U128, // Gross volume less fees, commissions and refunds
+    pub charge_count: u32,
+    pub refund_count: u32,
+}
+
+/// Invoice issued to a merchant for a successful charge, numbered sequentially per merchant
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug)]
+pub struct Invoice {
+    pub invoice_number: u64,
+    pub merchant_id: AccountId,
+    pub user_id: AccountId,
+    pub subscription_id: SubscriptionId,
+    pub cycle_index: u32, // Billing cycle the charge paid for
+    pub payment_number: u32,
+    pub amount: U128,
+    pub fee: U128,
+    pub referral_commission: U128,
+    pub payouts: Vec<PayoutLeg>,
+    pub line_items: Vec<LineItem>,
+    pub payment_method: PaymentMethod,
+    pub issued_at: u64,
 }
 
 #[near(serializers = [json, borsh])]
@@ -64,5 +1058,32 @@ pub struct PaymentResult {
     pub subscription_id: SubscriptionId,
     pub amount: U128,
     pub timestamp: u64,
-    pub error: Option<String>,
+    pub error: Option<PaymentError>,
+}
+
+/// Why a payment was not made
+#[near(serializers = [json, borsh])]
+#[derive(Clone, Debug, PartialEq)]
+pub enum PaymentError {
+    NotDue,
+    NotActive,
+    InsufficientEscrow,
+    MaxPaymentsReached,
+    EndDateReached,
+    UnauthorizedKey,
+    TransferFailed,
+    SubscriptionNotFound,
+    CycleAlreadyCharged,
+    ExceedsMaxAmountPerCharge,
+    ExceedsMaxTotalSpend,
+    ExceedsMerchantLimit,
+    PayoutNotRegistered, // A payout account is not storage-registered on the token
+    PriceUnavailable, // No fresh USD rate to price the charge with
+    SettlementPending, // An earlier charge is still settling
+    SwapUnavailable, // No swap adapter, or the payment token cannot be swapped into
+    TopUpRequested, // Escrow is being topped up; the charge is retried when funds arrive
+    BelowMinimumCharge, // The amount is below the contract's or merchant's minimum charge
+    Streaming, // Streaming subscriptions are paid with `claim_stream` instead
+    Leased, // Another worker holds a lease on the subscription
+    RateLimited, // The worker has processed as many payments as it may this period
 }
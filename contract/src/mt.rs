@@ -0,0 +1,114 @@
+use near_sdk::{
+    env, json_types::U128, log, near, require, serde_json, AccountId, Gas, NearToken, Promise,
+    PromiseOrValue,
+};
+
+use crate::models::PaymentMethod;
+use crate::{Contract, ContractExt};
+
+// Gas for an mt_transfer on a multi-token contract
+const GAS_FOR_MT_TRANSFER: Gas = Gas::from_tgas(10);
+
+/// Optional `msg` of an escrow deposit made with `mt_transfer_call`
+#[near(serializers = [json])]
+struct MtDepositMessage {
+    beneficiary_id: Option<AccountId>,
+}
+
+#[near]
+impl Contract {
+    /// Allows subscriptions to be paid in any token of a NEP-245 multi-token contract
+    pub fn add_allowed_mt_contract(&mut self, contract_id: AccountId) {
+        self.require_owner();
+        self.allowed_mt_contracts.insert(contract_id.clone());
+        log!("Multi-token contract allowed: {}", contract_id);
+    }
+
+    /// Stops new subscriptions from being paid in a multi-token contract's tokens
+    pub fn remove_allowed_mt_contract(&mut self, contract_id: AccountId) {
+        self.require_owner();
+        self.allowed_mt_contracts.remove(&contract_id);
+        log!("Multi-token contract removed: {}", contract_id);
+    }
+
+    /// Gets the multi-token contracts subscriptions can be paid in, ordered by account ID
+    pub fn get_allowed_mt_contracts(&self) -> Vec<AccountId> {
+        let mut contracts: Vec<AccountId> = self.allowed_mt_contracts.iter().cloned().collect();
+        contracts.sort();
+        contracts
+    }
+
+    /// Deposits multi-tokens into escrow. Called by the token contract through
+    /// `mt_transfer_call` or `mt_batch_transfer_call`; tokens of contracts that are not allowed
+    /// are returned. `msg` may be `{"beneficiary_id": ...}` to deposit for another account
+    pub fn mt_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        previous_owner_ids: Vec<AccountId>,
+        token_ids: Vec<String>,
+        amounts: Vec<U128>,
+        msg: String,
+    ) -> PromiseOrValue<Vec<U128>> {
+        require!(
+            token_ids.len() == amounts.len() && previous_owner_ids.len() == amounts.len(),
+            "Token IDs, amounts and previous owners must have the same length"
+        );
+        let contract_id = env::predecessor_account_id();
+        if !self.allowed_mt_contracts.contains(&contract_id) {
+            log!(
+                "Rejecting deposit from {}: contract is not on the allowlist",
+                contract_id
+            );
+            return PromiseOrValue::Value(amounts);
+        }
+
+        let beneficiary_id = serde_json::from_str::<MtDepositMessage>(&msg)
+            .ok()
+            .and_then(|message| message.beneficiary_id)
+            .unwrap_or(sender_id);
+        for (token_id, amount) in token_ids.into_iter().zip(amounts.iter()) {
+            let payment_method = PaymentMethod::Mt {
+                contract_id: contract_id.clone(),
+                token_id: token_id.clone(),
+            };
+            self.credit_escrow(&beneficiary_id, &payment_method, amount.0);
+            log!(
+                "Deposited {} of {}:{} to escrow for {}",
+                amount.0,
+                contract_id,
+                token_id,
+                beneficiary_id
+            );
+        }
+
+        PromiseOrValue::Value(vec![U128(0); amounts.len()])
+    }
+}
+
+impl Contract {
+    /// Sends a multi-token held by the contract with `mt_transfer`
+    pub(crate) fn mt_transfer(
+        &self,
+        contract_id: &AccountId,
+        token_id: &str,
+        receiver_id: AccountId,
+        amount: u128,
+        memo: String,
+    ) -> Promise {
+        let mt_transfer_args = serde_json::json!({
+            "receiver_id": receiver_id,
+            "token_id": token_id,
+            "amount": amount.to_string(),
+            "memo": memo,
+        })
+        .to_string()
+        .into_bytes();
+
+        Promise::new(contract_id.clone()).function_call(
+            "mt_transfer".to_string(),
+            mt_transfer_args,
+            NearToken::from_yoctonear(1),
+            GAS_FOR_MT_TRANSFER,
+        )
+    }
+}
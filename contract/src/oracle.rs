@@ -0,0 +1,138 @@
+use near_sdk::{
+    env, ext_contract,
+    json_types::{U128, U64},
+    log, near, require, AccountId, Gas, Promise, PromiseError,
+};
+
+use crate::models::{PaymentMethod, UsdOracleConfig, UsdRate};
+use crate::{Contract, ContractExt};
+
+// Gas for the oracle's get_price_data
+const GAS_FOR_PRICE_DATA: Gas = Gas::from_tgas(10);
+// Gas for storing the oracle's response
+const GAS_FOR_PRICE_DATA_CALLBACK: Gas = Gas::from_tgas(10);
+
+/// Price of one smallest token unit in USD: `multiplier / 10^decimals`
+#[near(serializers = [json])]
+pub struct Price {
+    pub multiplier: U128,
+    pub decimals: u8,
+}
+
+#[near(serializers = [json])]
+pub struct AssetOptionalPrice {
+    pub asset_id: AccountId,
+    pub price: Option<Price>,
+}
+
+/// Response of the price oracle's `get_price_data`
+#[near(serializers = [json])]
+pub struct PriceData {
+    pub timestamp: U64, // Nanoseconds
+    pub recency_duration_sec: u32,
+    pub prices: Vec<AssetOptionalPrice>,
+}
+
+#[ext_contract(ext_price_oracle)]
+pub trait PriceOracle {
+    fn get_price_data(&self, asset_ids: Option<Vec<AccountId>>) -> PriceData;
+}
+
+#[near]
+impl Contract {
+    /// Sets the oracle USD-denominated subscriptions are converted with
+    pub fn set_usd_oracle(&mut self, config: Option<UsdOracleConfig>) {
+        self.require_owner();
+        self.usd_oracle = config;
+        log!("USD oracle updated");
+    }
+
+    /// Gets the oracle USD-denominated subscriptions are converted with
+    pub fn get_usd_oracle(&self) -> Option<UsdOracleConfig> {
+        self.usd_oracle.clone()
+    }
+
+    /// Fetches a token's USD price from the oracle so that USD-denominated subscriptions paid
+    /// in it can be charged. Callable by anyone, typically the worker before processing payments
+    pub fn refresh_usd_rate(&mut self, payment_method: PaymentMethod) -> Promise {
+        let oracle = self.usd_oracle.clone().expect("USD oracle not configured");
+        let asset_id = Self::oracle_asset_id(&oracle, &payment_method);
+
+        ext_price_oracle::ext(oracle.oracle_id)
+            .with_static_gas(GAS_FOR_PRICE_DATA)
+            .get_price_data(Some(vec![asset_id]))
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_PRICE_DATA_CALLBACK)
+                    .on_price_data(payment_method),
+            )
+    }
+
+    /// Stores the rate returned by the oracle
+    #[private]
+    pub fn on_price_data(
+        &mut self,
+        payment_method: PaymentMethod,
+        #[callback_result] result: Result<PriceData, PromiseError>,
+    ) -> Option<UsdRate> {
+        let oracle = self.usd_oracle.clone()?;
+        let asset_id = Self::oracle_asset_id(&oracle, &payment_method);
+        let Ok(data) = result else {
+            log!("Price oracle call failed for {}", asset_id);
+            return None;
+        };
+
+        let price = data
+            .prices
+            .into_iter()
+            .find(|asset| asset.asset_id == asset_id)
+            .and_then(|asset| asset.price);
+        let Some(price) = price else {
+            log!("No price available for {}", asset_id);
+            return None;
+        };
+        require!(price.multiplier.0 > 0, "Invalid oracle price");
+
+        let rate = UsdRate {
+            multiplier: price.multiplier,
+            decimals: price.decimals,
+            updated_at: data.timestamp.0 / 1000000000,
+        };
+        self.usd_rates.insert(payment_method, rate.clone());
+
+        Some(rate)
+    }
+
+    /// Gets the last USD rate fetched for a token
+    pub fn get_usd_rate(&self, payment_method: PaymentMethod) -> Option<UsdRate> {
+        self.usd_rates.get(&payment_method).cloned()
+    }
+}
+
+impl Contract {
+    /// Oracle asset a payment method is priced as; NEAR uses the configured wrapped NEAR asset
+    fn oracle_asset_id(oracle: &UsdOracleConfig, payment_method: &PaymentMethod) -> AccountId {
+        match payment_method {
+            PaymentMethod::Near => oracle.near_asset_id.clone(),
+            PaymentMethod::Ft { token_id } => token_id.clone(),
+            PaymentMethod::Mt { .. } => env::panic_str("Multi-tokens cannot be priced in USD"),
+        }
+    }
+
+    /// Current USD rate for a token, if one was fetched within the oracle's max age
+    pub(crate) fn fresh_usd_rate(&self, payment_method: &PaymentMethod, now: u64) -> Option<UsdRate> {
+        let max_age = self.usd_oracle.as_ref()?.max_age;
+        self.usd_rates
+            .get(payment_method)
+            .filter(|rate| rate.updated_at + max_age >= now)
+            .cloned()
+    }
+
+    /// Converts a USD price in cents to the token's smallest units at `rate`, rounding down
+    pub(crate) fn usd_cents_to_token(cents: u64, rate: &UsdRate) -> Option<u128> {
+        10u128
+            .checked_pow(rate.decimals as u32)?
+            .checked_mul(cents as u128)
+            .map(|value| value / 100 / rate.multiplier.0)
+    }
+}
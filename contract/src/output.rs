@@ -0,0 +1,122 @@
+use near_sdk::serde::Serialize;
+use near_sdk::serde_json;
+
+use crate::models::{Subscription, SubscriptionId, SubscriptionPage};
+use crate::{Contract, MAX_PAGE_SCAN};
+
+// Bytes of JSON a list view returns before it is cut short. Well under the 4 MiB limit on
+// returned data, leaving room for the gas spent serializing it
+pub const MAX_VIEW_OUTPUT_BYTES: usize = 1 << 20;
+
+/// Running estimate of a view's serialized size, so items stop being added before the
+/// response grows too large to return
+pub struct OutputBudget {
+    remaining: usize,
+}
+
+impl Default for OutputBudget {
+    fn default() -> Self {
+        Self {
+            remaining: MAX_VIEW_OUTPUT_BYTES,
+        }
+    }
+}
+
+impl OutputBudget {
+    /// Reserves room for an item, returning false if it does not fit
+    pub fn take<T: Serialize>(&mut self, item: &T) -> bool {
+        // The item's JSON plus the comma separating it from the next
+        let size = serde_json::to_vec(item).map_or(usize::MAX, |json| json.len() + 1);
+        if size > self.remaining {
+            return false;
+        }
+        self.remaining -= size;
+        true
+    }
+}
+
+impl Contract {
+    /// Pages over the subscriptions matching `filter` in creation order, stopping at `limit`
+    /// subscriptions, at `MAX_PAGE_SCAN` sequence numbers or when the output budget runs out
+    pub(crate) fn page_subscriptions(
+        &self,
+        from: Option<String>,
+        limit: u64,
+        filter: impl Fn(&Subscription) -> bool,
+    ) -> SubscriptionPage {
+        let start = from.map_or(1, |cursor| {
+            cursor
+                .parse::<u64>()
+                .expect("Invalid cursor")
+                .saturating_add(1)
+        });
+        let end = self
+            .subscription_nonce
+            .min(start.saturating_add(MAX_PAGE_SCAN).saturating_sub(1));
+        let mut budget = OutputBudget::default();
+        let mut subscriptions = Vec::new();
+        let mut last_seen = start.saturating_sub(1);
+        for sequence_number in start..=end {
+            if subscriptions.len() as u64 >= limit {
+                break;
+            }
+            let subscription = self
+                .subscription_sequence
+                .get(&sequence_number)
+                .and_then(|subscription_id| self.subscriptions.get(subscription_id))
+                .map(Subscription::from)
+                .filter(|subscription| filter(subscription));
+            if let Some(subscription) = subscription {
+                if !budget.take(&subscription) {
+                    break;
+                }
+                subscriptions.push(subscription);
+            }
+            last_seen = sequence_number;
+        }
+
+        let next_cursor = (last_seen < self.subscription_nonce).then(|| last_seen.to_string());
+        SubscriptionPage {
+            subscriptions,
+            has_more: next_cursor.is_some(),
+            next_cursor,
+        }
+    }
+
+    /// Pages over indexed subscriptions, given as `(cursor, ID)` pairs in order from just after
+    /// `from`. Stops at `limit` subscriptions, at `MAX_PAGE_SCAN` entries or when the output
+    /// budget runs out; entries of archived subscriptions are skipped
+    pub(crate) fn page_indexed_subscriptions(
+        &self,
+        entries: impl Iterator<Item = (u64, SubscriptionId)>,
+        from: u64,
+        limit: u64,
+    ) -> SubscriptionPage {
+        let mut budget = OutputBudget::default();
+        let mut subscriptions = Vec::new();
+        let mut last_seen = from;
+        let mut has_more = false;
+        for (scanned, (cursor, subscription_id)) in entries.enumerate() {
+            if subscriptions.len() as u64 >= limit || scanned as u64 == MAX_PAGE_SCAN {
+                has_more = true;
+                break;
+            }
+            if let Some(subscription) = self.subscriptions.get(&subscription_id) {
+                let subscription = Subscription::from(subscription);
+                if !budget.take(&subscription) {
+                    has_more = true;
+                    break;
+                }
+                subscriptions.push(subscription);
+            }
+            last_seen = cursor;
+        }
+
+        let next_cursor = has_more.then(|| last_seen.to_string());
+        SubscriptionPage {
+            subscriptions,
+            has_more,
+            next_cursor,
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use near_sdk::{env, json_types::U128, log, near, require, AccountId, Gas, PromiseError};
+
+use crate::models::PaymentMethod;
+use crate::{Contract, ContractExt};
+
+// Gas for restoring a claimable balance if its payout transfer fails
+const GAS_FOR_CLAIM_CALLBACK: Gas = Gas::from_tgas(10);
+
+#[near]
+impl Contract {
+    /// Opts the calling merchant into accruing payments to a claimable balance, withdrawn with
+    /// `claim_payouts`, instead of being paid on every charge. Revenue split recipients are
+    /// still paid per charge. Not available together with intents settlement
+    pub fn set_claimable_payouts(&mut self, enabled: bool) {
+        let merchant_id = self.require_merchant();
+        let mut settings = self.get_merchant_settings(merchant_id.clone());
+        require!(
+            !enabled || !settings.intents_settlement,
+            "Claimable payouts cannot be combined with intents settlement"
+        );
+        settings.claimable_payouts = enabled;
+        self.merchant_settings.insert(merchant_id.clone(), settings);
+        log!("Claimable payouts for {} set to {}", merchant_id, enabled);
+    }
+
+    /// Gets a merchant's claimable balance in a given token
+    pub fn get_claimable_balance(
+        &self,
+        merchant_id: AccountId,
+        payment_method: PaymentMethod,
+    ) -> U128 {
+        self.claimable_balances
+            .get(&(merchant_id, payment_method))
+            .copied()
+            .unwrap_or(U128(0))
+    }
+
+    /// Pays the calling merchant's whole claimable balance in a token to its payout address.
+    /// Returns the amount claimed
+    pub fn claim_payouts(&mut self, payment_method: PaymentMethod) -> U128 {
+        let merchant_id = self.require_merchant();
+        require!(
+            self.get_merchant_settings(merchant_id.clone())
+                .settlement_window
+                .is_none(),
+            "Payouts are paid out by settlement window"
+        );
+        self.pending_settlements
+            .remove(&(merchant_id.clone(), payment_method.clone()));
+        let amount = self
+            .claimable_balances
+            .remove(&(merchant_id.clone(), payment_method.clone()))
+            .map_or(0, |balance| balance.0);
+        require!(amount > 0, "Nothing to claim");
+
+        let payout_address = self.payout_address_for(&merchant_id);
+        self.transfer_funds(
+            &payment_method,
+            payout_address.clone(),
+            amount,
+            format!("Payout claim by {}", merchant_id),
+        )
+        .then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_CLAIM_CALLBACK)
+                .on_payouts_claimed(merchant_id.clone(), payment_method, U128(amount)),
+        );
+
+        log!("{} claimed {} to {}", merchant_id, amount, payout_address);
+        U128(amount)
+    }
+
+    /// Restores a claimed balance whose payout transfer failed
+    #[private]
+    pub fn on_payouts_claimed(
+        &mut self,
+        merchant_id: AccountId,
+        payment_method: PaymentMethod,
+        amount: U128,
+        #[callback_result] result: Result<(), PromiseError>,
+    ) -> bool {
+        if result.is_ok() {
+            return true;
+        }
+
+        log!("Payout claim failed for {}, restoring balance", merchant_id);
+        self.credit_claimable(&merchant_id, &payment_method, amount.0);
+        false
+    }
+}
+
+impl Contract {
+    /// Whether a merchant's payments accrue in the contract, to be claimed or settled per window
+    pub(crate) fn has_claimable_payouts(&self, merchant_id: &AccountId) -> bool {
+        self.merchant_settings
+            .get(merchant_id)
+            .is_some_and(|settings| {
+                settings.claimable_payouts || settings.settlement_window.is_some()
+            })
+    }
+
+    /// Adds to a merchant's claimable balance
+    pub(crate) fn credit_claimable(
+        &mut self,
+        merchant_id: &AccountId,
+        payment_method: &PaymentMethod,
+        amount: u128,
+    ) {
+        let key = (merchant_id.clone(), payment_method.clone());
+        let balance = self
+            .claimable_balances
+            .get(&key)
+            .map_or(0, |balance| balance.0);
+        self.claimable_balances.insert(key, U128(balance + amount));
+    }
+
+    /// Takes `amount` from a merchant's claimable balance. Returns false, leaving the balance
+    /// untouched, when it does not cover the amount
+    pub(crate) fn debit_claimable(
+        &mut self,
+        merchant_id: &AccountId,
+        payment_method: &PaymentMethod,
+        amount: u128,
+    ) -> bool {
+        let key = (merchant_id.clone(), payment_method.clone());
+        let balance = self.claimable_balances.get(&key).map_or(0, |balance| balance.0);
+        if balance < amount {
+            return false;
+        }
+        if balance == amount {
+            self.claimable_balances.remove(&key);
+        } else {
+            self.claimable_balances.insert(key, U128(balance - amount));
+        }
+        true
+    }
+}
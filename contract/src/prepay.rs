@@ -0,0 +1,240 @@
+use near_sdk::{env, json_types::U128, log, near, require};
+
+use crate::events::Event;
+use crate::fees::BPS_DENOMINATOR;
+use crate::models::{
+    FundingSource, PaymentKind, PaymentMode, PaymentRecord, PayoutPurpose, PriceDenomination,
+    SubscriptionId, SubscriptionStatus,
+};
+use crate::{Contract, ContractExt};
+
+// Most cycles a subscription can have paid for in advance
+const MAX_PREPAID_CYCLES: u32 = 36;
+
+#[near]
+impl Contract {
+    /// Sets the discount the calling merchant gives on prepaid cycles, in basis points
+    pub fn set_prepay_discount_bps(&mut self, discount_bps: u16) {
+        let merchant_id = self.require_merchant();
+        require!(
+            discount_bps as u128 <= BPS_DENOMINATOR,
+            "Discount cannot exceed 10000 basis points"
+        );
+        let mut settings = self.get_merchant_settings(merchant_id.clone());
+        settings.prepay_discount_bps = discount_bps;
+        self.merchant_settings.insert(merchant_id.clone(), settings);
+        log!(
+            "Prepay discount for {} set to {} bps",
+            merchant_id,
+            discount_bps
+        );
+    }
+
+    /// Pays `cycles` billing cycles upfront from the caller's escrow, less the merchant's
+    /// prepay discount. Prepaid cycles are used up before the subscription is charged again.
+    /// Returns the amount paid
+    pub fn prepay(&mut self, subscription_id: SubscriptionId, cycles: u32) -> U128 {
+        let user_id = env::predecessor_account_id();
+        let now = env::block_timestamp() / 1000000000;
+        let subscription = self
+            .subscriptions
+            .get(&subscription_id)
+            .expect("Subscription not found")
+            .clone();
+        require!(
+            subscription.user_id == user_id,
+            "Not authorized to prepay this subscription"
+        );
+        require!(
+            matches!(subscription.status, SubscriptionStatus::Active),
+            "Subscription is not active"
+        );
+        require!(
+            subscription.payment_mode == PaymentMode::Periodic
+                && subscription.denomination == PriceDenomination::Token,
+            "Only token-priced periodic subscriptions can be prepaid"
+        );
+        require!(cycles > 0, "Cycles must be positive");
+        let prepaid_cycles = subscription.prepaid_cycles + cycles;
+        require!(
+            prepaid_cycles <= MAX_PREPAID_CYCLES,
+            "Too many prepaid cycles"
+        );
+        if let Some(max_payments) = subscription.max_payments {
+            require!(
+                subscription.payments_made + prepaid_cycles <= max_payments,
+                "Prepaid cycles exceed max_payments"
+            );
+        }
+
+        let gross = subscription.amount.0 * cycles as u128;
+        let discount_bps = self
+            .get_merchant_settings(subscription.merchant_id.clone())
+            .prepay_discount_bps;
+        let amount = gross - gross * discount_bps as u128 / BPS_DENOMINATOR;
+        if let Some(max_total) = subscription.max_total_spend {
+            require!(
+                subscription.total_spent.0 + amount <= max_total.0,
+                "Prepayment exceeds max_total_spend"
+            );
+        }
+        require!(amount > 0, "Nothing to prepay");
+        require!(
+            self.debit_escrow(&user_id, &subscription.payment_method, amount),
+            "Insufficient escrow balance"
+        );
+
+        let funding = FundingSource {
+            payment_method: subscription.payment_method.clone(),
+            amount: U128(amount),
+        };
+        let payment_number = subscription.payments_made + 1;
+        let memo = self.payment_memo(&subscription, payment_number);
+        let fee = self.pay_merchant(
+            &subscription,
+            &funding,
+            subscription.cycle_index,
+            memo.clone(),
+            PayoutPurpose::Release,
+            now,
+        );
+        let commission = self.referral_commission_for(&subscription, amount - fee);
+
+        let stored = self
+            .subscriptions
+            .get_mut(&subscription_id)
+            .expect("Subscription not found");
+        stored.prepaid_cycles = prepaid_cycles;
+        stored.total_spent = U128(stored.total_spent.0 + amount);
+        stored.updated_at = now;
+
+        self.push_payment_record(PaymentRecord {
+            subscription_id: subscription_id.clone(),
+            kind: PaymentKind::Charge,
+            payment_number,
+            amount: U128(amount),
+            payment_method: subscription.payment_method.clone(),
+            line_items: Vec::new(),
+            memo: Some(memo),
+            fee: U128(fee),
+            referral_commission: U128(commission),
+            payouts: self.payout_legs(&subscription.merchant_id, amount - fee - commission),
+            invoice_number: None,
+            usd_rate: None,
+            amount_override: None,
+            rate_source: None,
+            swap: None,
+            timestamp: now,
+            block_height: env::block_height(),
+            block_timestamp: env::block_timestamp(),
+        });
+
+        Event::CyclesPrepaid {
+            subscription_id,
+            user_id,
+            cycles,
+            amount: U128(amount),
+        }
+        .emit();
+
+        U128(amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{json_types::U128, testing_env, AccountId};
+
+    use crate::models::{
+        PaymentMethod, Subscription, SubscriptionFrequency, SubscriptionStatusV0, SubscriptionV0,
+    };
+    use crate::Contract;
+
+    fn set_predecessor(predecessor: AccountId) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(predecessor)
+            .block_timestamp(1_000 * 1_000_000_000)
+            .build());
+    }
+
+    /// A contract holding a monthly subscription of `accounts(1)` to merchant `accounts(2)`,
+    /// with 30000 in the subscriber's escrow
+    fn contract_with_subscription() -> (Contract, Subscription) {
+        set_predecessor(accounts(0));
+        let mut contract = Contract::new(accounts(0));
+        contract.merchants.insert(accounts(2));
+        let subscription: Subscription = SubscriptionV0 {
+            id: "sub-1".to_string(),
+            user_id: accounts(1),
+            merchant_id: accounts(2),
+            amount: U128(10000),
+            frequency: SubscriptionFrequency::Monthly,
+            next_payment_date: 1_000,
+            status: SubscriptionStatusV0::Active,
+            created_at: 0,
+            updated_at: 0,
+            payment_method: PaymentMethod::Near,
+            max_payments: None,
+            payments_made: 0,
+            end_date: None,
+        }
+        .into();
+        contract
+            .subscriptions
+            .insert(subscription.id.clone(), subscription.clone().into());
+        contract.credit_escrow(&accounts(1), &PaymentMethod::Near, 30000);
+        (contract, subscription)
+    }
+
+    fn escrow_balance(contract: &Contract) -> u128 {
+        contract
+            .get_escrow_balance(accounts(1), PaymentMethod::Near)
+            .0
+    }
+
+    #[test]
+    fn prepays_cycles_at_merchant_discount() {
+        let (mut contract, subscription) = contract_with_subscription();
+        set_predecessor(accounts(2));
+        contract.set_prepay_discount_bps(1000);
+
+        set_predecessor(accounts(1));
+        assert_eq!(contract.prepay(subscription.id.clone(), 3), U128(27000));
+
+        let stored = contract.get_subscription(subscription.id).unwrap();
+        assert_eq!(stored.prepaid_cycles, 3);
+        assert_eq!(stored.total_spent, U128(27000));
+        assert_eq!(escrow_balance(&contract), 3000);
+    }
+
+    #[test]
+    fn uses_prepaid_cycle_before_charging_escrow() {
+        let (mut contract, subscription) = contract_with_subscription();
+        set_predecessor(accounts(1));
+        contract.prepay(subscription.id.clone(), 1);
+
+        set_predecessor(accounts(0));
+        let result = contract.charge_subscription(subscription.id.clone(), None, None, 1_000);
+
+        assert!(result.success);
+        assert_eq!(result.amount, U128(0));
+        let stored = contract.get_subscription(subscription.id).unwrap();
+        assert_eq!(stored.prepaid_cycles, 0);
+        assert_eq!(stored.cycle_index, 1);
+        assert_eq!(escrow_balance(&contract), 20000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Prepayment exceeds max_total_spend")]
+    fn rejects_prepayment_over_max_total_spend() {
+        let (mut contract, subscription) = contract_with_subscription();
+        contract.with_subscription_mut(&subscription.id, |stored| {
+            stored.max_total_spend = Some(U128(25000));
+        });
+
+        set_predecessor(accounts(1));
+        contract.prepay(subscription.id, 3);
+    }
+}
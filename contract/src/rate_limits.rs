@@ -0,0 +1,60 @@
+use near_sdk::{env, log, near, require, AccountId};
+
+use crate::models::{WorkerPaymentUsage, WorkerRateLimit};
+use crate::{Contract, ContractExt};
+
+#[near]
+impl Contract {
+    /// Caps how many payments one worker can process per period of blocks, limiting the damage
+    /// a compromised worker key can do. `None` removes the cap
+    pub fn set_worker_rate_limit(&mut self, rate_limit: Option<WorkerRateLimit>) {
+        self.require_owner();
+        if let Some(rate_limit) = &rate_limit {
+            require!(
+                rate_limit.period_blocks > 0,
+                "Rate limit period must be at least one block"
+            );
+        }
+        self.worker_rate_limit = rate_limit;
+        log!("Worker rate limit updated");
+    }
+
+    /// Gets the cap on payments per worker, if any
+    pub fn get_worker_rate_limit(&self) -> Option<WorkerRateLimit> {
+        self.worker_rate_limit.clone()
+    }
+
+    /// Gets how many payments a worker has processed in its current rate limit period
+    pub fn get_worker_payment_usage(&self, account_id: AccountId) -> Option<WorkerPaymentUsage> {
+        self.worker_payment_usage.get(&account_id).cloned()
+    }
+}
+
+impl Contract {
+    /// Counts a payment against a worker's rate limit, returning false without counting it if
+    /// the worker has reached the limit for the current period
+    pub(crate) fn take_payment_allowance(&mut self, worker_id: &AccountId) -> bool {
+        let Some(rate_limit) = self.worker_rate_limit.clone() else {
+            return true;
+        };
+        let block_height = env::block_height();
+        let period_start = block_height - block_height % rate_limit.period_blocks;
+
+        let payments = self
+            .worker_payment_usage
+            .get(worker_id)
+            .filter(|usage| usage.period_start == period_start)
+            .map_or(0, |usage| usage.payments);
+        if payments >= rate_limit.max_payments {
+            return false;
+        }
+        self.worker_payment_usage.insert(
+            worker_id.clone(),
+            WorkerPaymentUsage {
+                period_start,
+                payments: payments + 1,
+            },
+        );
+        true
+    }
+}
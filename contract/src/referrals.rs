@@ -0,0 +1,115 @@
+use near_sdk::{env, json_types::U128, log, near, require, AccountId, Promise};
+
+use crate::events::Event;
+use crate::fees::BPS_DENOMINATOR;
+use crate::models::{PaymentMethod, ReferralCommission, ReferralEarnings, Subscription};
+use crate::{Contract, ContractExt};
+
+#[near]
+impl Contract {
+    /// Sets the commission the calling merchant pays referrers, or disables referrals with `None`
+    pub fn set_referral_commission(&mut self, commission: Option<ReferralCommission>) {
+        let merchant_id = self.require_merchant();
+        if let Some(commission) = &commission {
+            require!(
+                commission.commission_bps as u128 <= BPS_DENOMINATOR,
+                "Commission cannot exceed 10000 basis points"
+            );
+        }
+
+        let mut settings = self.get_merchant_settings(merchant_id.clone());
+        settings.referral_commission = commission;
+        self.merchant_settings.insert(merchant_id.clone(), settings);
+        log!("Referral commission updated for {}", merchant_id);
+    }
+
+    /// Gets a referrer's commission earnings in a given token
+    pub fn get_referral_earnings(
+        &self,
+        referrer_id: AccountId,
+        payment_method: PaymentMethod,
+    ) -> ReferralEarnings {
+        self.referral_earnings
+            .get(&(referrer_id, payment_method))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Transfers the caller's claimable referral earnings in a given token
+    pub fn claim_referral_earnings(&mut self, payment_method: PaymentMethod) -> Promise {
+        let referrer_id = env::predecessor_account_id();
+        let key = (referrer_id.clone(), payment_method.clone());
+        let earnings = self
+            .referral_earnings
+            .get_mut(&key)
+            .expect("No referral earnings");
+        let amount = earnings.claimable.0;
+        require!(amount > 0, "Nothing to claim");
+        earnings.claimable = U128(0);
+
+        log!("Referrer {} claimed {}", referrer_id, amount);
+
+        self.transfer_funds(
+            &payment_method,
+            referrer_id,
+            amount,
+            "Referral commission".to_string(),
+        )
+    }
+}
+
+impl Contract {
+    /// Commission owed to a subscription's referrer on a payment of `amount` (after the
+    /// platform fee), or zero once the merchant's commission duration has passed
+    pub(crate) fn referral_commission_for(&self, subscription: &Subscription, amount: u128) -> u128 {
+        if subscription.referrer_id.is_none() {
+            return 0;
+        }
+        let Some(commission) = self
+            .merchant_settings
+            .get(&subscription.merchant_id)
+            .and_then(|settings| settings.referral_commission.clone())
+        else {
+            return 0;
+        };
+
+        let now = env::block_timestamp() / 1000000000;
+        if commission
+            .duration
+            .is_some_and(|duration| now >= subscription.created_at + duration)
+        {
+            return 0;
+        }
+
+        amount * commission.commission_bps as u128 / BPS_DENOMINATOR
+    }
+
+    /// Adds a commission to the subscription referrer's claimable balance
+    pub(crate) fn credit_referrer(
+        &mut self,
+        subscription: &Subscription,
+        payment_method: &PaymentMethod,
+        amount: u128,
+    ) {
+        let Some(referrer_id) = subscription.referrer_id.clone() else {
+            return;
+        };
+        if amount == 0 {
+            return;
+        }
+
+        let key = (referrer_id.clone(), payment_method.clone());
+        let mut earnings = self.referral_earnings.get(&key).cloned().unwrap_or_default();
+        earnings.claimable = U128(earnings.claimable.0 + amount);
+        earnings.total_earned = U128(earnings.total_earned.0 + amount);
+        self.referral_earnings.insert(key, earnings);
+
+        Event::ReferralCommissionEarned {
+            subscription_id: subscription.id.clone(),
+            referrer_id,
+            payment_method: payment_method.clone(),
+            amount: U128(amount),
+        }
+        .emit();
+    }
+}
@@ -0,0 +1,775 @@
+use near_sdk::{env, json_types::U128, log, near, require, AccountId, Gas, PromiseError};
+
+use crate::events::Event;
+use crate::fees::BPS_DENOMINATOR;
+use crate::models::{
+    FundingSource, PaymentKind, PaymentMethod, PaymentRecord, PayoutPurpose, RefundDestination,
+    RefundPolicy, Subscription, SubscriptionId,
+};
+use crate::{Contract, ContractExt};
+
+// Gas for handling the result of a refund transfer
+const GAS_FOR_REFUND_CALLBACK: Gas = Gas::from_tgas(15);
+
+#[near]
+impl Contract {
+    /// Forwards a held cooling-off payment to the merchant once the window has lapsed.
+    /// Callable by anyone
+    pub fn release_held_payment(&mut self, subscription_id: SubscriptionId) {
+        let now = env::block_timestamp() / 1000000000;
+        let subscription = self
+            .subscriptions
+            .get(&subscription_id)
+            .expect("Subscription not found");
+        let held = subscription
+            .held_payment
+            .as_ref()
+            .expect("No held payment for this subscription");
+        require!(now >= held.release_at, "Cooling-off period has not ended");
+
+        self.release_held_payment_internal(&subscription_id);
+    }
+
+    /// Declares the calling merchant's refund policy for self-serve refunds. `None` leaves
+    /// refunds to the merchant
+    pub fn set_refund_policy(&mut self, policy: Option<RefundPolicy>) {
+        let merchant_id = self.require_merchant();
+        if let Some(policy) = &policy {
+            require!(
+                policy.refund_bps as u128 <= BPS_DENOMINATOR,
+                "Refund share cannot exceed 10000 basis points"
+            );
+        }
+        let mut settings = self.get_merchant_settings(merchant_id.clone());
+        settings.refund_policy = policy;
+        self.merchant_settings.insert(merchant_id.clone(), settings);
+        log!("Refund policy updated for {}", merchant_id);
+    }
+
+    /// Refunds a charge to the subscriber. Subscribers can claim up to the merchant's refund
+    /// policy within its window; merchants can refund any amount not yet refunded at any time.
+    /// Either way no more than the merchant was paid for the charge can be refunded.
+    /// Refunds are paid from the merchant's claimable balance, or for NEAR charges refunded by
+    /// the merchant, from an attached deposit of exactly the refund amount. `destination`
+    /// defaults to the merchant's refund destination; see `RefundDestination`. Wallet refunds
+    /// that cannot be delivered become credit
+    #[payable]
+    pub fn refund_payment(
+        &mut self,
+        subscription_id: SubscriptionId,
+        payment_number: u32,
+        amount: Option<U128>,
+        destination: Option<RefundDestination>,
+    ) -> U128 {
+        let now = env::block_timestamp() / 1000000000;
+        let caller_id = env::predecessor_account_id();
+        let subscription: Subscription = self
+            .subscriptions
+            .get(&subscription_id)
+            .expect("Subscription not found")
+            .into();
+        let charge = self
+            .payment_records(&subscription_id)
+            .find(|record| {
+                record.kind == PaymentKind::Charge && record.payment_number == payment_number
+            })
+            .cloned()
+            .expect("Charge not found");
+
+        let key = (subscription_id.clone(), payment_number);
+        let refunded = self.refunded_amounts.get(&key).map_or(0, |refunded| refunded.0);
+        // Refunds are funded by the merchant, who only received the charge less the platform
+        // fee and referral commission
+        let net_payout = charge.amount.0 - charge.fee.0 - charge.referral_commission.0;
+        let refundable = if caller_id == subscription.merchant_id {
+            net_payout.saturating_sub(refunded)
+        } else {
+            require!(
+                caller_id == subscription.user_id,
+                "Not authorized to refund this payment"
+            );
+            let policy = self
+                .get_merchant_settings(subscription.merchant_id.clone())
+                .refund_policy
+                .expect("Merchant does not offer self-serve refunds");
+            require!(
+                now <= charge.timestamp + policy.window,
+                "Refund window has ended"
+            );
+            let allowed = charge.amount.0 * policy.refund_bps as u128 / BPS_DENOMINATOR;
+            allowed.min(net_payout).saturating_sub(refunded)
+        };
+        let amount = amount.map_or(refundable, |amount| amount.0);
+        require!(amount > 0, "Nothing to refund");
+        require!(amount <= refundable, "Refund exceeds the refundable amount");
+
+        let destination = destination.unwrap_or_else(|| {
+            self.get_merchant_settings(subscription.merchant_id.clone())
+                .refund_destination
+        });
+        let deposit = env::attached_deposit().as_yoctonear();
+        if destination == RefundDestination::Credit {
+            require!(deposit == 0, "No deposit is needed to refund as credit");
+        } else if deposit > 0 {
+            require!(
+                caller_id == subscription.merchant_id
+                    && charge.payment_method == PaymentMethod::Near
+                    && deposit == amount,
+                "Attached deposit must equal a merchant's NEAR refund"
+            );
+        } else {
+            require!(
+                self.debit_claimable(&subscription.merchant_id, &charge.payment_method, amount),
+                "Merchant balance cannot cover the refund"
+            );
+        }
+        self.refunded_amounts.insert(key, U128(refunded + amount));
+
+        match destination {
+            // The merchant keeps the funds and owes the subscriber credit instead
+            RefundDestination::Credit => self.add_credit(
+                &subscription.user_id,
+                &subscription.merchant_id,
+                &charge.payment_method,
+                amount,
+                "refund",
+            ),
+            _ => self.deliver_refund(&subscription, &charge.payment_method, amount, &destination),
+        }
+
+        self.push_payment_record(PaymentRecord {
+            subscription_id: subscription_id.clone(),
+            kind: PaymentKind::Refund,
+            payment_number,
+            amount: U128(amount),
+            payment_method: charge.payment_method.clone(),
+            line_items: Vec::new(),
+            memo: Some(destination.memo().to_string()),
+            fee: U128(0),
+            referral_commission: U128(0),
+            payouts: Vec::new(),
+            invoice_number: None,
+            usd_rate: None,
+            amount_override: None,
+            rate_source: None,
+            swap: None,
+            timestamp: now,
+            block_height: env::block_height(),
+            block_timestamp: env::block_timestamp(),
+        });
+
+        let reason = if caller_id == subscription.merchant_id {
+            "merchant"
+        } else {
+            "refund_policy"
+        };
+        Event::PaymentRefunded {
+            subscription_id,
+            user_id: subscription.user_id,
+            amount: U128(amount),
+            reason: reason.to_string(),
+        }
+        .emit();
+
+        U128(amount)
+    }
+
+    /// Gives one of the calling merchant's subscribers goodwill credit, which covers their next
+    /// charges in the token before anything is drawn from escrow
+    pub fn issue_credit(
+        &mut self,
+        user_id: AccountId,
+        payment_method: PaymentMethod,
+        amount: U128,
+    ) {
+        let merchant_id = self.require_merchant();
+        require!(amount.0 > 0, "Amount must be positive");
+        self.add_credit(&user_id, &merchant_id, &payment_method, amount.0, "goodwill");
+        log!("Issued {} credit to {}", amount.0, user_id);
+    }
+
+    /// Turns a refund that could not be delivered, e.g. to an account unregistered on the token,
+    /// into credit with the merchant instead of leaving the funds parked in the contract. The
+    /// funds go to the merchant's claimable balance, backing the credit
+    #[private]
+    pub fn on_refund_transferred(
+        &mut self,
+        subscription_id: SubscriptionId,
+        payment_method: PaymentMethod,
+        amount: U128,
+        #[callback_result] result: Result<(), PromiseError>,
+    ) -> bool {
+        if result.is_ok() {
+            return true;
+        }
+
+        let subscription = self
+            .subscriptions
+            .get(&subscription_id)
+            .expect("Subscription not found")
+            .clone();
+        self.credit_claimable(&subscription.merchant_id, &payment_method, amount.0);
+        self.add_credit(
+            &subscription.user_id,
+            &subscription.merchant_id,
+            &payment_method,
+            amount.0,
+            "undelivered_refund",
+        );
+        log!("Refund for subscription {} could not be delivered, credited", subscription_id);
+        false
+    }
+
+    /// Sets where the calling merchant's refunds go unless a refund says otherwise
+    pub fn set_refund_destination(&mut self, destination: RefundDestination) {
+        let merchant_id = self.require_merchant();
+        let mut settings = self.get_merchant_settings(merchant_id.clone());
+        settings.refund_destination = destination;
+        self.merchant_settings.insert(merchant_id.clone(), settings);
+        log!("Refund destination updated for {}", merchant_id);
+    }
+
+    /// Gets the credit a user holds with a merchant in a given token
+    pub fn get_credit(
+        &self,
+        user_id: AccountId,
+        merchant_id: AccountId,
+        payment_method: PaymentMethod,
+    ) -> U128 {
+        self.credits
+            .get(&(user_id, merchant_id, payment_method))
+            .copied()
+            .unwrap_or(U128(0))
+    }
+}
+
+impl Contract {
+    /// Transfers any held payment to the merchant and clears it
+    pub(crate) fn release_held_payment_internal(&mut self, subscription_id: &SubscriptionId) {
+        let now = env::block_timestamp() / 1000000000;
+        let subscription = self
+            .subscriptions
+            .get_mut(subscription_id)
+            .expect("Subscription not found");
+        let Some(held) = subscription.held_payment.take() else {
+            return;
+        };
+        let subscription = subscription.clone();
+        let merchant_id = subscription.merchant_id.clone();
+
+        let memo = self.payment_memo(&subscription, subscription.payments_made);
+        let funding = FundingSource {
+            payment_method: subscription.payment_method.clone(),
+            amount: held.amount,
+        };
+        self.pay_merchant(
+            &subscription,
+            &funding,
+            subscription.cycle_index.saturating_sub(1),
+            memo,
+            PayoutPurpose::Release,
+            now,
+        );
+
+        Event::PaymentReleased {
+            subscription_id: subscription_id.clone(),
+            merchant_id,
+            amount: held.amount,
+        }
+        .emit();
+    }
+
+    /// Returns any held payment to the subscriber, recording the refund in payment history
+    pub(crate) fn refund_held_payment(
+        &mut self,
+        subscription_id: &SubscriptionId,
+        reason: &str,
+    ) -> Option<U128> {
+        let now = env::block_timestamp() / 1000000000;
+        let subscription = self
+            .subscriptions
+            .get_mut(subscription_id)
+            .expect("Subscription not found");
+        let held = subscription.held_payment.take()?;
+        let subscription = Subscription::from(&*subscription);
+        let destination = self
+            .get_merchant_settings(subscription.merchant_id.clone())
+            .refund_destination;
+
+        self.deliver_refund(
+            &subscription,
+            &subscription.payment_method,
+            held.amount.0,
+            &destination,
+        );
+
+        self.push_payment_record(PaymentRecord {
+            subscription_id: subscription_id.clone(),
+            kind: PaymentKind::Refund,
+            payment_number: subscription.payments_made,
+            amount: held.amount,
+            payment_method: subscription.payment_method.clone(),
+            line_items: Vec::new(),
+            memo: Some(destination.memo().to_string()),
+            fee: U128(0),
+            referral_commission: U128(0),
+            payouts: Vec::new(),
+            invoice_number: None,
+            usd_rate: None,
+            amount_override: None,
+            rate_source: None,
+            swap: None,
+            timestamp: now,
+            block_height: env::block_height(),
+            block_timestamp: env::block_timestamp(),
+        });
+
+        Event::PaymentRefunded {
+            subscription_id: subscription_id.clone(),
+            user_id: subscription.user_id.clone(),
+            amount: held.amount,
+            reason: reason.to_string(),
+        }
+        .emit();
+        log!("Refunded {} for subscription: {}", held.amount.0, subscription_id);
+
+        Some(held.amount)
+    }
+
+    /// Credits the unused portion of the last charge when a subscription is canceled mid-cycle.
+    /// The unused share is `amount * (next_payment_date - now) / (next_payment_date - charged_at)`
+    pub(crate) fn credit_prorated_refund(&mut self, subscription_id: &SubscriptionId) -> Option<U128> {
+        let now = env::block_timestamp() / 1000000000;
+        let subscription = self
+            .subscriptions
+            .get(subscription_id)
+            .expect("Subscription not found")
+            .clone();
+        if now >= subscription.next_payment_date {
+            return None;
+        }
+
+        // The most recent charge paid for the cycle that is being cut short
+        let last_charge = self
+            .payment_records(subscription_id)
+            .rev()
+            .find(|record| record.kind == PaymentKind::Charge)?
+            .clone();
+        let period = subscription.next_payment_date.saturating_sub(last_charge.timestamp);
+        if period == 0 {
+            return None;
+        }
+        let unused = subscription.next_payment_date - now;
+        let refund = last_charge.amount.0 * unused as u128 / period as u128;
+        if refund == 0 {
+            return None;
+        }
+
+        self.add_credit(
+            &subscription.user_id,
+            &subscription.merchant_id,
+            &subscription.payment_method,
+            refund,
+            "proration",
+        );
+
+        self.push_payment_record(PaymentRecord {
+            subscription_id: subscription_id.clone(),
+            kind: PaymentKind::Refund,
+            payment_number: last_charge.payment_number,
+            amount: U128(refund),
+            payment_method: subscription.payment_method.clone(),
+            line_items: Vec::new(),
+            memo: None,
+            fee: U128(0),
+            referral_commission: U128(0),
+            payouts: Vec::new(),
+            invoice_number: None,
+            usd_rate: None,
+            amount_override: None,
+            rate_source: None,
+            swap: None,
+            timestamp: now,
+            block_height: env::block_height(),
+            block_timestamp: env::block_timestamp(),
+        });
+        log!(
+            "Prorated refund of {} ({} of {} seconds unused) for subscription: {}",
+            refund,
+            unused,
+            period,
+            subscription_id
+        );
+
+        Some(U128(refund))
+    }
+
+    /// Adds to a user's credit with a merchant
+    pub(crate) fn add_credit(
+        &mut self,
+        user_id: &AccountId,
+        merchant_id: &AccountId,
+        payment_method: &PaymentMethod,
+        amount: u128,
+        reason: &str,
+    ) {
+        let key = (user_id.clone(), merchant_id.clone(), payment_method.clone());
+        let balance = self.credits.get(&key).map_or(0, |credit| credit.0);
+        self.credits.insert(key, U128(balance + amount));
+
+        Event::CreditIssued {
+            user_id: user_id.clone(),
+            merchant_id: merchant_id.clone(),
+            amount: U128(amount),
+            reason: reason.to_string(),
+        }
+        .emit();
+    }
+
+    /// Takes credit towards a subscription's charge, remembering it until the charge settles
+    pub(crate) fn use_credit(&mut self, subscription: &Subscription, amount: u128) {
+        let key = (
+            subscription.user_id.clone(),
+            subscription.merchant_id.clone(),
+            subscription.payment_method.clone(),
+        );
+        let balance = self.credits.get(&key).map_or(0, |credit| credit.0);
+        if balance > amount {
+            self.credits.insert(key, U128(balance - amount));
+        } else {
+            self.credits.remove(&key);
+        }
+        if let Some(stored) = self.subscriptions.get_mut(&subscription.id) {
+            stored.pending_credit = U128(amount);
+        }
+
+        Event::CreditApplied {
+            subscription_id: subscription.id.clone(),
+            user_id: subscription.user_id.clone(),
+            amount: U128(amount),
+        }
+        .emit();
+    }
+
+    /// Gives back the credit taken towards a charge that did not go through
+    pub(crate) fn restore_pending_credit(&mut self, subscription_id: &SubscriptionId) {
+        let Some(subscription) = self.subscriptions.get_mut(subscription_id) else {
+            return;
+        };
+        let credit = std::mem::replace(&mut subscription.pending_credit, U128(0));
+        if credit.0 == 0 {
+            return;
+        }
+        let subscription = subscription.clone();
+        self.add_credit(
+            &subscription.user_id,
+            &subscription.merchant_id,
+            &subscription.payment_method,
+            credit.0,
+            "charge_failed",
+        );
+    }
+
+    /// Returns refunded funds held by the contract to a subscription's subscriber: into their
+    /// escrow, as credit with the merchant backed by the funds, or to their wallet
+    fn deliver_refund(
+        &mut self,
+        subscription: &Subscription,
+        payment_method: &PaymentMethod,
+        amount: u128,
+        destination: &RefundDestination,
+    ) {
+        match destination {
+            RefundDestination::Escrow => {
+                self.credit_escrow(&subscription.user_id, payment_method, amount);
+            }
+            RefundDestination::Credit => {
+                self.credit_claimable(&subscription.merchant_id, payment_method, amount);
+                self.add_credit(
+                    &subscription.user_id,
+                    &subscription.merchant_id,
+                    payment_method,
+                    amount,
+                    "refund",
+                );
+            }
+            RefundDestination::Wallet => self.transfer_refund(subscription, payment_method, amount),
+        }
+    }
+
+    /// Sends a refund to a subscription's subscriber, crediting it if the transfer fails
+    fn transfer_refund(
+        &mut self,
+        subscription: &Subscription,
+        payment_method: &PaymentMethod,
+        amount: u128,
+    ) {
+        self.transfer_funds(
+            payment_method,
+            subscription.user_id.clone(),
+            amount,
+            format!("Subscription refund: {}", subscription.id),
+        )
+        .then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_REFUND_CALLBACK)
+                .on_refund_transferred(
+                    subscription.id.clone(),
+                    payment_method.clone(),
+                    U128(amount),
+                ),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{env, json_types::U128, testing_env, AccountId, PromiseError};
+
+    use crate::models::{
+        PaymentKind, PaymentMethod, PaymentRecord, RefundDestination, RefundPolicy, Subscription,
+        SubscriptionFrequency, SubscriptionStatusV0, SubscriptionV0,
+    };
+    use crate::Contract;
+
+    fn set_context(predecessor: AccountId, now: u64) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(predecessor)
+            .block_timestamp(now * 1_000_000_000)
+            .build());
+    }
+
+    /// A contract holding a monthly NEAR subscription of `accounts(1)` to merchant `accounts(2)`
+    /// charged 10000, with a fee of `fee`, at 1000
+    fn contract_with_charge(fee: u128) -> (Contract, Subscription) {
+        set_context(accounts(0), 1_000);
+        let mut contract = Contract::new(accounts(0));
+        contract.merchants.insert(accounts(2));
+        let subscription: Subscription = SubscriptionV0 {
+            id: "sub-1".to_string(),
+            user_id: accounts(1),
+            merchant_id: accounts(2),
+            amount: U128(10000),
+            frequency: SubscriptionFrequency::Monthly,
+            next_payment_date: 1_000 + 2592000,
+            status: SubscriptionStatusV0::Active,
+            created_at: 0,
+            updated_at: 0,
+            payment_method: PaymentMethod::Near,
+            max_payments: None,
+            payments_made: 1,
+            end_date: None,
+        }
+        .into();
+        contract
+            .subscriptions
+            .insert(subscription.id.clone(), subscription.clone().into());
+        contract.push_payment_record(PaymentRecord {
+            subscription_id: subscription.id.clone(),
+            kind: PaymentKind::Charge,
+            payment_number: 1,
+            amount: U128(10000),
+            payment_method: PaymentMethod::Near,
+            line_items: Vec::new(),
+            memo: None,
+            fee: U128(fee),
+            referral_commission: U128(0),
+            payouts: Vec::new(),
+            invoice_number: None,
+            usd_rate: None,
+            amount_override: None,
+            rate_source: None,
+            swap: None,
+            timestamp: 1_000,
+            block_height: env::block_height(),
+            block_timestamp: env::block_timestamp(),
+        });
+        (contract, subscription)
+    }
+
+    fn credit(contract: &Contract) -> u128 {
+        contract
+            .get_credit(accounts(1), accounts(2), PaymentMethod::Near)
+            .0
+    }
+
+    #[test]
+    fn credits_unused_share_of_canceled_cycle() {
+        let (mut contract, subscription) = contract_with_charge(0);
+
+        // Canceled a quarter of the way into the cycle
+        set_context(accounts(1), 1_000 + 648000);
+        assert_eq!(
+            contract.credit_prorated_refund(&subscription.id),
+            Some(U128(7500))
+        );
+        assert_eq!(credit(&contract), 7500);
+
+        // Nothing is left to refund once the cycle is over
+        set_context(accounts(1), 1_000 + 2592000);
+        assert_eq!(contract.credit_prorated_refund(&subscription.id), None);
+    }
+
+    fn set_refund_policy(contract: &mut Contract, refund_bps: u16) {
+        set_context(accounts(2), 1_000);
+        contract.set_refund_policy(Some(RefundPolicy {
+            window: 86400,
+            refund_bps,
+        }));
+    }
+
+    #[test]
+    fn refunds_subscriber_share_under_policy() {
+        let (mut contract, subscription) = contract_with_charge(0);
+        set_refund_policy(&mut contract, 5000);
+
+        set_context(accounts(1), 2_000);
+        let refunded =
+            contract.refund_payment(subscription.id, 1, None, Some(RefundDestination::Credit));
+
+        assert_eq!(refunded, U128(5000));
+        assert_eq!(credit(&contract), 5000);
+    }
+
+    #[test]
+    fn caps_policy_refund_at_net_payout() {
+        let (mut contract, subscription) = contract_with_charge(1000);
+        set_refund_policy(&mut contract, 10000);
+
+        set_context(accounts(1), 2_000);
+        let refunded =
+            contract.refund_payment(subscription.id, 1, None, Some(RefundDestination::Credit));
+
+        assert_eq!(refunded, U128(9000));
+    }
+
+    #[test]
+    #[should_panic(expected = "Refund window has ended")]
+    fn rejects_policy_refund_after_window() {
+        let (mut contract, subscription) = contract_with_charge(0);
+        set_refund_policy(&mut contract, 5000);
+
+        set_context(accounts(1), 1_000 + 86401);
+        contract.refund_payment(subscription.id, 1, None, Some(RefundDestination::Credit));
+    }
+
+    #[test]
+    fn covers_charge_from_credit_before_escrow() {
+        let (mut contract, subscription) = contract_with_charge(0);
+        contract.with_subscription_mut(&subscription.id, |stored| stored.next_payment_date = 1_000);
+        contract.add_credit(
+            &accounts(1),
+            &accounts(2),
+            &PaymentMethod::Near,
+            12000,
+            "goodwill",
+        );
+
+        let result = contract.charge_subscription(subscription.id.clone(), None, None, 1_000);
+
+        assert!(result.success);
+        assert_eq!(result.amount, U128(0));
+        assert_eq!(credit(&contract), 2000);
+        let stored = contract.get_subscription(subscription.id).unwrap();
+        assert_eq!(stored.payments_made, 2);
+        assert_eq!(stored.pending_credit, U128(0));
+    }
+
+    #[test]
+    fn restores_credit_taken_towards_failed_charge() {
+        let (mut contract, subscription) = contract_with_charge(0);
+        contract.add_credit(
+            &accounts(1),
+            &accounts(2),
+            &PaymentMethod::Near,
+            4000,
+            "goodwill",
+        );
+
+        contract.use_credit(&subscription, 4000);
+        assert_eq!(credit(&contract), 0);
+
+        contract.restore_pending_credit(&subscription.id);
+        assert_eq!(credit(&contract), 4000);
+        let stored = contract.get_subscription(subscription.id).unwrap();
+        assert_eq!(stored.pending_credit, U128(0));
+    }
+
+    fn claimable(contract: &Contract) -> u128 {
+        contract
+            .get_claimable_balance(accounts(2), PaymentMethod::Near)
+            .0
+    }
+
+    #[test]
+    fn reverses_overcharge_as_credit_without_moving_funds() {
+        let (mut contract, subscription) = contract_with_charge(0);
+
+        set_context(accounts(2), 2_000);
+        contract.refund_payment(
+            subscription.id,
+            1,
+            Some(U128(2000)),
+            Some(RefundDestination::Credit),
+        );
+
+        assert_eq!(credit(&contract), 2000);
+        assert_eq!(claimable(&contract), 0);
+    }
+
+    #[test]
+    fn credits_refund_that_could_not_be_delivered() {
+        let (mut contract, subscription) = contract_with_charge(0);
+
+        assert!(!contract.on_refund_transferred(
+            subscription.id,
+            PaymentMethod::Near,
+            U128(3000),
+            Err(PromiseError::Failed),
+        ));
+
+        // The merchant holds the undelivered funds backing the subscriber's credit
+        assert_eq!(credit(&contract), 3000);
+        assert_eq!(claimable(&contract), 3000);
+    }
+
+    #[test]
+    fn refunds_to_merchant_default_destination() {
+        let (mut contract, subscription) = contract_with_charge(0);
+        contract.credit_claimable(&accounts(2), &PaymentMethod::Near, 10000);
+
+        set_context(accounts(2), 2_000);
+        contract.set_refund_destination(RefundDestination::Escrow);
+        contract.refund_payment(subscription.id.clone(), 1, Some(U128(4000)), None);
+
+        assert_eq!(claimable(&contract), 6000);
+        assert_eq!(
+            contract
+                .get_escrow_balance(accounts(1), PaymentMethod::Near)
+                .0,
+            4000
+        );
+        let refund = contract.get_payment_history(subscription.id).pop().unwrap();
+        assert_eq!(refund.kind, PaymentKind::Refund);
+        assert_eq!(
+            refund.memo,
+            Some(RefundDestination::Escrow.memo().to_string())
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Merchant balance cannot cover the refund")]
+    fn rejects_escrow_refund_beyond_merchant_balance() {
+        let (mut contract, subscription) = contract_with_charge(0);
+
+        set_context(accounts(2), 2_000);
+        contract.refund_payment(
+            subscription.id,
+            1,
+            Some(U128(4000)),
+            Some(RefundDestination::Escrow),
+        );
+    }
+}
@@ -0,0 +1,460 @@
+use std::cmp::Reverse;
+
+use near_sdk::{env, json_types::U128, log, near, require, AccountId};
+
+use crate::events::Event;
+use crate::models::{
+    CancellationReason, FailedPayments, PaymentKind, PaymentRecord, PaymentResult, RetryPolicy,
+    Subscription, SubscriptionId, SubscriptionStatus,
+};
+use crate::output::OutputBudget;
+use crate::{GAS_PER_BATCH_PAYMENT, MAX_PAGE_SCAN};
+use crate::{Contract, ContractExt};
+
+#[near]
+impl Contract {
+    /// Sets how failed payments are retried
+    pub fn set_retry_policy(&mut self, retry_policy: RetryPolicy) {
+        self.require_owner();
+        require!(retry_policy.base_delay > 0, "Base delay must be positive");
+        require!(
+            retry_policy.max_delay >= retry_policy.base_delay,
+            "Max delay must be at least the base delay"
+        );
+        self.retry_policy = retry_policy;
+        log!("Retry policy updated");
+    }
+
+    /// Gets how failed payments are retried
+    pub fn get_retry_policy(&self) -> RetryPolicy {
+        self.retry_policy.clone()
+    }
+
+    /// Retries a past-due payment without waiting for a worker, e.g. after the subscriber has
+    /// topped up their escrow. The subscriber can retry straight away; the merchant has to wait
+    /// for the next retry to be due so that retries keep backing off
+    pub fn retry_payment(&mut self, subscription_id: SubscriptionId) -> PaymentResult {
+        let caller = env::predecessor_account_id();
+        let now = env::block_timestamp() / 1000000000;
+
+        let subscription = self
+            .subscriptions
+            .get(&subscription_id)
+            .expect("Subscription not found");
+        require!(
+            caller == subscription.user_id || caller == subscription.merchant_id,
+            "Not authorized to retry this payment"
+        );
+        require!(
+            matches!(subscription.status, SubscriptionStatus::PastDue),
+            "Subscription is not past due"
+        );
+        if caller == subscription.user_id {
+            return self.charge_before_retry(&subscription_id, now);
+        }
+        require!(
+            subscription.next_retry_at.is_some_and(|retry_at| retry_at <= now),
+            "Next retry is not due yet"
+        );
+
+        self.charge_subscription(subscription_id, None, None, now)
+    }
+
+    /// Charges up to `max_cycles` billing cycles a subscription fell behind on, one charge per
+    /// cycle, each recorded separately. Until it has caught up, each charge moves the due date
+    /// on by one period from the previous one, so arrears that remain stay due. Charges that
+    /// settle asynchronously end the call; later calls or worker passes continue from there.
+    /// Callable by the subscriber, the merchant or an approved worker. Only the subscriber can
+    /// charge a past-due subscription before its next retry is due, and a failed charge ends
+    /// the call, so the retry schedule and budget hold for everyone else
+    pub fn process_missed_cycles(
+        &mut self,
+        subscription_id: SubscriptionId,
+        max_cycles: u32,
+    ) -> Vec<PaymentResult> {
+        let caller = env::predecessor_account_id();
+        let now = env::block_timestamp() / 1000000000;
+        let subscription = self
+            .subscriptions
+            .get(&subscription_id)
+            .expect("Subscription not found");
+        let by_subscriber = caller == subscription.user_id;
+        require!(
+            by_subscriber
+                || caller == subscription.merchant_id
+                || self.is_verified_by_approved_codehash(),
+            "Not authorized to process missed cycles"
+        );
+
+        let mut results = Vec::new();
+        for _ in 0..max_cycles {
+            let remaining_gas = env::prepaid_gas().saturating_sub(env::used_gas());
+            if remaining_gas < GAS_PER_BATCH_PAYMENT {
+                log!("Stopping catch-up after {} cycles: insufficient gas", results.len());
+                break;
+            }
+
+            let subscription = self
+                .subscriptions
+                .get_mut(&subscription_id)
+                .expect("Subscription not found");
+            if subscription.next_payment_date > now {
+                break;
+            }
+            subscription.catching_up = true;
+
+            let result = if by_subscriber {
+                self.charge_before_retry(&subscription_id, now)
+            } else {
+                self.charge_subscription(subscription_id.clone(), None, None, now)
+            };
+            let succeeded = result.success;
+            results.push(result);
+            if !succeeded {
+                break;
+            }
+
+            // Confirmed transfers, swaps, top-ups and intents settlement finish asynchronously
+            let pending = self
+                .subscriptions
+                .get(&subscription_id)
+                .is_some_and(|subscription| subscription.settlement_pending);
+            if pending {
+                break;
+            }
+        }
+
+        log!(
+            "Processed {} missed cycles for subscription: {}",
+            results.len(),
+            subscription_id
+        );
+        results
+    }
+
+    /// Gets past-due subscriptions whose next retry is due, earliest retry first, from one shard
+    /// when `shard` is given. A signed call, since only approved workers can list them and
+    /// checking the caller cannot be done in a view
+    pub fn get_retryable_payments(&mut self, limit: u64, shard: Option<u8>) -> Vec<Subscription> {
+        let now = env::block_timestamp() / 1000000000;
+
+        // Verify caller is an approved worker
+        require!(
+            self.is_verified_by_approved_codehash(),
+            "Not an approved worker"
+        );
+
+        let mut retryable: Vec<Subscription> = self
+            .subscriptions
+            .scan(shard)
+            .filter(|(_, subscription)| {
+                matches!(subscription.status, SubscriptionStatus::PastDue)
+                    && subscription.next_retry_at.is_some_and(|retry_at| retry_at <= now)
+            })
+            .map(|(_, subscription)| Subscription::from(subscription))
+            .collect();
+
+        // Earliest retry first, so repeated calls return the same subscriptions however the
+        // map's order shifts
+        retryable.sort_by(|a, b| (a.next_retry_at, &a.id).cmp(&(b.next_retry_at, &b.id)));
+        retryable.truncate(limit as usize);
+        retryable
+    }
+
+    /// Gets a merchant's recovery queue a page of its subscriptions at a time: failed charge
+    /// records since `since` (seconds), newest first, and the subscriptions currently past
+    /// due, oldest first. A page covers up to `limit` of the merchant's subscriptions in
+    /// creation order and stops early when it would be too large to return; pass
+    /// `next_cursor` as `from` to continue
+    pub fn get_failed_payments(
+        &self,
+        merchant_id: AccountId,
+        since: u64,
+        from: Option<String>,
+        limit: u64,
+    ) -> FailedPayments {
+        let from = from.map_or(0, |cursor| cursor.parse::<u64>().expect("Invalid cursor"));
+        let mut budget = OutputBudget::default();
+        let mut failed_records: Vec<PaymentRecord> = Vec::new();
+        let mut past_due = Vec::new();
+        let mut last_seen = from;
+        let mut has_more = false;
+
+        let entries = self.merchant_subscription_ids(&merchant_id, from);
+        for (scanned, (position, subscription_id)) in entries.enumerate() {
+            if scanned as u64 >= limit.min(MAX_PAGE_SCAN) {
+                has_more = true;
+                break;
+            }
+            if let Some(subscription) = self.subscriptions.get(&subscription_id) {
+                let records: Vec<PaymentRecord> = self
+                    .payment_records(&subscription_id)
+                    .filter(|record| {
+                        record.kind == PaymentKind::Failed && record.timestamp >= since
+                    })
+                    .cloned()
+                    .collect();
+                let subscription = matches!(subscription.status, SubscriptionStatus::PastDue)
+                    .then(|| Subscription::from(subscription));
+                let fits = records.iter().all(|record| budget.take(record))
+                    && subscription.as_ref().is_none_or(|subscription| budget.take(subscription));
+                if !fits {
+                    has_more = true;
+                    break;
+                }
+                failed_records.extend(records);
+                past_due.extend(subscription);
+            }
+            last_seen = position;
+        }
+
+        failed_records.sort_by(|a, b| {
+            (Reverse(a.timestamp), &a.subscription_id, a.payment_number)
+                .cmp(&(Reverse(b.timestamp), &b.subscription_id, b.payment_number))
+        });
+        past_due.sort_by(|a: &Subscription, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+
+        FailedPayments {
+            failed_records,
+            past_due,
+            next_cursor: has_more.then(|| last_seen.to_string()),
+        }
+    }
+}
+
+impl Contract {
+    /// Charges a subscription without waiting for its next retry. A failure that did not
+    /// schedule another retry leaves the one it was waiting for in place
+    fn charge_before_retry(&mut self, subscription_id: &SubscriptionId, now: u64) -> PaymentResult {
+        let retry_at = self.with_subscription_mut(subscription_id, |subscription| {
+            subscription.next_retry_at.take()
+        });
+        let result = self.charge_subscription(subscription_id.clone(), None, None, now);
+        self.with_subscription_mut(subscription_id, |subscription| {
+            if subscription.status == SubscriptionStatus::PastDue
+                && subscription.next_retry_at.is_none()
+            {
+                subscription.next_retry_at = retry_at;
+            }
+        });
+        result
+    }
+
+    /// Marks a subscription past due after a transient payment failure and schedules the next
+    /// attempt, doubling the delay each time. Once the retries run out the subscription fails
+    pub(crate) fn schedule_retry(&mut self, subscription_id: &SubscriptionId, now: u64) {
+        let policy = self.retry_policy.clone();
+        let subscription = self
+            .subscriptions
+            .get(subscription_id)
+            .expect("Subscription not found");
+        // Subscriptions paused or ended while the charge was in flight are not retried
+        if !subscription.status.can_transition_to(&SubscriptionStatus::PastDue) {
+            return;
+        }
+        let merchant_id = subscription.merchant_id.clone();
+        let auto_cancel = self.get_merchant_settings(merchant_id).auto_cancel;
+        let (failing_since, retry_count) =
+            self.with_subscription_mut(subscription_id, |subscription| {
+                let failing_since = *subscription.failing_since.get_or_insert(now);
+                (failing_since, subscription.retry_count)
+            });
+        let failures = retry_count + 1;
+
+        // The merchant's auto-cancel policy ends the subscription before retries run out
+        if auto_cancel.is_some_and(|auto_cancel| {
+            failures >= auto_cancel.max_failures || now >= failing_since + auto_cancel.period
+        }) {
+            self.transition_subscription(subscription_id, SubscriptionStatus::Canceled, now);
+            let subscription = self.with_subscription_mut(subscription_id, |subscription| {
+                subscription.next_retry_at = None;
+                subscription.clone()
+            });
+            self.record_failure_cancellation(&subscription, failures, now);
+            self.revoke_subscription_keys(subscription_id, &subscription.user_id);
+            return;
+        }
+
+        if retry_count >= policy.max_retries {
+            self.transition_subscription(subscription_id, SubscriptionStatus::Failed, now);
+            let user_id = self.with_subscription_mut(subscription_id, |subscription| {
+                subscription.next_retry_at = None;
+                subscription.user_id.clone()
+            });
+            log!("Payment retries exhausted for subscription: {}", subscription_id);
+            self.revoke_subscription_keys(subscription_id, &user_id);
+            return;
+        }
+
+        let delay = policy
+            .base_delay
+            .saturating_mul(1u64 << retry_count.min(32))
+            .min(policy.max_delay);
+        self.transition_subscription(subscription_id, SubscriptionStatus::PastDue, now);
+        self.with_subscription_mut(subscription_id, |subscription| {
+            subscription.retry_count += 1;
+            subscription.next_retry_at = Some(now + delay);
+        });
+
+        log!(
+            "Payment retry {} for subscription {} scheduled at {}",
+            retry_count + 1,
+            subscription_id,
+            now + delay
+        );
+    }
+
+    /// Records that a subscription was canceled after repeated payment failures
+    fn record_failure_cancellation(
+        &mut self,
+        subscription: &Subscription,
+        failures: u32,
+        now: u64,
+    ) {
+        let funding = subscription.funding();
+        self.push_payment_record(PaymentRecord {
+            subscription_id: subscription.id.clone(),
+            kind: PaymentKind::Canceled {
+                reason: CancellationReason::PaymentFailure,
+            },
+            payment_number: subscription.payments_made + 1,
+            amount: funding.amount,
+            payment_method: funding.payment_method,
+            line_items: Vec::new(),
+            memo: None,
+            fee: U128(0),
+            referral_commission: U128(0),
+            payouts: Vec::new(),
+            invoice_number: None,
+            usd_rate: None,
+            amount_override: None,
+            rate_source: None,
+            swap: None,
+            timestamp: now,
+            block_height: env::block_height(),
+            block_timestamp: env::block_timestamp(),
+        });
+
+        Event::SubscriptionAutoCanceled {
+            subscription_id: subscription.id.clone(),
+            user_id: subscription.user_id.clone(),
+            failures,
+        }
+        .emit();
+        log!("Subscription {} canceled after {} failed payments", subscription.id, failures);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{json_types::U128, testing_env, AccountId};
+
+    use crate::models::{
+        PaymentError, PaymentMethod, Subscription, SubscriptionFrequency, SubscriptionStatus,
+        SubscriptionStatusV0, SubscriptionV0,
+    };
+    use crate::Contract;
+
+    fn set_predecessor(predecessor: AccountId) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(predecessor)
+            .block_timestamp(1_000 * 1_000_000_000)
+            .build());
+    }
+
+    /// A contract holding a NEAR subscription of `accounts(1)` to `accounts(2)` that is past
+    /// due, with its next retry at 5_000
+    fn contract_with_past_due_subscription() -> Contract {
+        set_predecessor(accounts(0));
+        let mut contract = Contract::new(accounts(0));
+        let subscription = Subscription {
+            status: SubscriptionStatus::PastDue,
+            retry_count: 1,
+            next_retry_at: Some(5_000),
+            ..SubscriptionV0 {
+                id: "sub-1".to_string(),
+                user_id: accounts(1),
+                merchant_id: accounts(2),
+                amount: U128(10000),
+                frequency: SubscriptionFrequency::Monthly,
+                next_payment_date: 500,
+                status: SubscriptionStatusV0::Active,
+                created_at: 0,
+                updated_at: 0,
+                payment_method: PaymentMethod::Near,
+                max_payments: None,
+                payments_made: 0,
+                end_date: None,
+            }
+            .into()
+        };
+        contract
+            .subscriptions
+            .insert(subscription.id.clone(), subscription.into());
+        contract
+    }
+
+    #[test]
+    fn lets_subscriber_retry_before_next_retry() {
+        let mut contract = contract_with_past_due_subscription();
+
+        set_predecessor(accounts(1));
+        let result = contract.retry_payment("sub-1".to_string());
+        assert_eq!(result.error, Some(PaymentError::InsufficientEscrow));
+
+        // The failed attempt was a real retry, so the next one backs off further
+        let stored = contract.get_subscription("sub-1".to_string()).unwrap();
+        assert_eq!(stored.retry_count, 2);
+        assert!(stored
+            .next_retry_at
+            .is_some_and(|retry_at| retry_at > 1_000));
+    }
+
+    #[test]
+    #[should_panic(expected = "Next retry is not due yet")]
+    fn rejects_merchant_retry_before_next_retry() {
+        let mut contract = contract_with_past_due_subscription();
+
+        set_predecessor(accounts(2));
+        contract.retry_payment("sub-1".to_string());
+    }
+
+    #[test]
+    fn keeps_retry_schedule_for_merchant_catch_up() {
+        let mut contract = contract_with_past_due_subscription();
+
+        set_predecessor(accounts(2));
+        let results = contract.process_missed_cycles("sub-1".to_string(), 3);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].error, Some(PaymentError::NotDue));
+
+        let stored = contract.get_subscription("sub-1".to_string()).unwrap();
+        assert_eq!(stored.retry_count, 1);
+        assert_eq!(stored.next_retry_at, Some(5_000));
+    }
+
+    #[test]
+    fn pages_failed_payments_by_merchant_subscription() {
+        let mut contract = contract_with_past_due_subscription();
+        let mut past_due_ids = Vec::new();
+        for _ in 0..2 {
+            let subscription_id = contract.next_subscription_id(&accounts(1), &accounts(2));
+            let mut subscription = contract.get_subscription("sub-1".to_string()).unwrap();
+            subscription.id = subscription_id.clone();
+            contract
+                .subscriptions
+                .insert(subscription_id.clone(), subscription.into());
+            past_due_ids.push(subscription_id);
+        }
+
+        let page = contract.get_failed_payments(accounts(2), 0, None, 1);
+        assert_eq!(page.past_due.len(), 1);
+        assert_eq!(page.past_due[0].id, past_due_ids[0]);
+
+        let page = contract.get_failed_payments(accounts(2), 0, page.next_cursor, 1);
+        assert_eq!(page.past_due[0].id, past_due_ids[1]);
+        assert_eq!(page.next_cursor, None);
+    }
+}
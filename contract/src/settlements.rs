@@ -0,0 +1,279 @@
+use std::collections::BTreeMap;
+
+use near_sdk::{env, json_types::U128, log, near, require, AccountId, Gas, PromiseError};
+
+use crate::events::Event;
+use crate::models::{
+    PaymentKind, PaymentMethod, PendingSettlement, SettlementReport, SettlementSummary,
+    SettlementWindow,
+};
+use crate::{Contract, ContractExt};
+
+// Gas for recording a settlement once its payout transfer resolves
+const GAS_FOR_SETTLEMENT_CALLBACK: Gas = Gas::from_tgas(15);
+
+#[near]
+impl Contract {
+    /// Opts the calling merchant into batched settlement: payments accrue in the contract and
+    /// are paid out in one transfer per window with `settle_payouts`. `None` turns it off, after
+    /// which any remaining balance can be claimed. Not available together with intents settlement
+    pub fn set_settlement_window(&mut self, window: Option<SettlementWindow>) {
+        let merchant_id = self.require_merchant();
+        let mut settings = self.get_merchant_settings(merchant_id.clone());
+        require!(
+            window.is_none() || !settings.intents_settlement,
+            "Settlement windows cannot be combined with intents settlement"
+        );
+        settings.settlement_window = window;
+        self.merchant_settings.insert(merchant_id.clone(), settings);
+        log!("Settlement window updated for {}", merchant_id);
+    }
+
+    /// Gets the payments accrued for a merchant in a token since its last settlement
+    pub fn get_pending_settlement(
+        &self,
+        merchant_id: AccountId,
+        payment_method: PaymentMethod,
+    ) -> Option<PendingSettlement> {
+        self.pending_settlements
+            .get(&(merchant_id, payment_method))
+            .cloned()
+    }
+
+    /// Pays out a merchant's accrued balance in a token once its settlement window has elapsed.
+    /// Callable by anyone, typically a worker. Returns the amount paid out
+    pub fn settle_payouts(
+        &mut self,
+        merchant_id: AccountId,
+        payment_method: PaymentMethod,
+    ) -> U128 {
+        let now = env::block_timestamp() / 1000000000;
+        let window = self
+            .merchant_settings
+            .get(&merchant_id)
+            .and_then(|settings| settings.settlement_window.clone())
+            .expect("Merchant does not use settlement windows");
+
+        let key = (merchant_id.clone(), payment_method.clone());
+        let pending = self
+            .pending_settlements
+            .get(&key)
+            .cloned()
+            .expect("Nothing to settle");
+        require!(
+            now >= pending.window_start + window.seconds(),
+            "Settlement window has not elapsed"
+        );
+
+        self.pending_settlements.remove(&key);
+        let amount = self
+            .claimable_balances
+            .remove(&key)
+            .map_or(0, |balance| balance.0);
+        if amount == 0 {
+            return U128(0);
+        }
+
+        // The report is numbered once the payout has gone through
+        let report = SettlementReport {
+            settlement_number: 0,
+            merchant_id: merchant_id.clone(),
+            payout_address: self.payout_address_for(&merchant_id),
+            payment_method,
+            amount: U128(amount),
+            payment_count: pending.payment_count,
+            window_start: pending.window_start,
+            window_end: now,
+        };
+        self.transfer_funds(
+            &report.payment_method,
+            report.payout_address.clone(),
+            amount,
+            format!("Settlement for {}", merchant_id),
+        )
+        .then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_SETTLEMENT_CALLBACK)
+                .on_payouts_settled(report),
+        );
+
+        U128(amount)
+    }
+
+    /// Records the settlement report for a completed payout, or restores the balance and
+    /// pending window if the transfer failed so the next settlement includes them
+    #[private]
+    pub fn on_payouts_settled(
+        &mut self,
+        mut report: SettlementReport,
+        #[callback_result] result: Result<(), PromiseError>,
+    ) -> Option<u64> {
+        let merchant_id = report.merchant_id.clone();
+        if result.is_err() {
+            log!("Settlement failed for {}, restoring balance", merchant_id);
+            self.credit_claimable(&merchant_id, &report.payment_method, report.amount.0);
+            let key = (merchant_id, report.payment_method);
+            let payment_count = self
+                .pending_settlements
+                .get(&key)
+                .map_or(0, |current| current.payment_count);
+            let restored = PendingSettlement {
+                window_start: report.window_start,
+                payment_count: report.payment_count + payment_count,
+            };
+            self.pending_settlements.insert(key, restored);
+            return None;
+        }
+
+        let settlement_number = self
+            .settlement_counts
+            .get(&merchant_id)
+            .copied()
+            .unwrap_or(0)
+            + 1;
+        self.settlement_counts
+            .insert(merchant_id.clone(), settlement_number);
+        report.settlement_number = settlement_number;
+
+        Event::PayoutsSettled {
+            merchant_id: merchant_id.clone(),
+            settlement_number,
+            payment_method: report.payment_method.clone(),
+            amount: report.amount,
+            payment_count: report.payment_count,
+        }
+        .emit();
+        self.settlement_reports
+            .insert((merchant_id, settlement_number), report);
+
+        Some(settlement_number)
+    }
+
+    /// Gets one of a merchant's settlement reports by number
+    pub fn get_settlement_report(
+        &self,
+        merchant_id: AccountId,
+        settlement_number: u64,
+    ) -> Option<SettlementReport> {
+        self.settlement_reports
+            .get(&(merchant_id, settlement_number))
+            .cloned()
+    }
+
+    /// Gets a merchant's settlement reports in order, starting from `from_index` (0-based)
+    pub fn get_settlement_reports(
+        &self,
+        merchant_id: AccountId,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<SettlementReport> {
+        let count = self
+            .settlement_counts
+            .get(&merchant_id)
+            .copied()
+            .unwrap_or(0);
+        let end = count.min(from_index.saturating_add(limit));
+
+        (from_index..end)
+            .filter_map(|index| {
+                self.settlement_reports
+                    .get(&(merchant_id.clone(), index + 1))
+                    .cloned()
+            })
+            .collect()
+    }
+
+    /// Summarizes a merchant's payments per token for `[period_start, period_end)` (seconds),
+    /// from stored payment records: gross charges, platform fees, referral commissions, refunds
+    /// and what remains as the merchant's net payout
+    pub fn get_settlement_summary(
+        &self,
+        merchant_id: AccountId,
+        period_start: u64,
+        period_end: u64,
+    ) -> Vec<SettlementSummary> {
+        let mut summaries: BTreeMap<PaymentMethod, SettlementSummary> = BTreeMap::new();
+
+        for (subscription_id, subscription) in self.subscriptions.iter() {
+            if subscription.merchant_id != merchant_id {
+                continue;
+            }
+            for record in self
+                .payment_records(subscription_id)
+                .filter(|record| record.timestamp >= period_start && record.timestamp < period_end)
+            {
+                let summary = summaries
+                    .entry(record.payment_method.clone())
+                    .or_insert_with(|| SettlementSummary {
+                        payment_method: record.payment_method.clone(),
+                        gross_volume: U128(0),
+                        fees: U128(0),
+                        referral_commissions: U128(0),
+                        refunds: U128(0),
+                        net_payout: U128(0),
+                        charge_count: 0,
+                        refund_count: 0,
+                    });
+                match record.kind {
+                    PaymentKind::Charge => {
+                        summary.gross_volume.0 += record.amount.0;
+                        summary.fees.0 += record.fee.0;
+                        summary.referral_commissions.0 += record.referral_commission.0;
+                        summary.charge_count += 1;
+                    }
+                    PaymentKind::Refund => {
+                        summary.refunds.0 += record.amount.0;
+                        summary.refund_count += 1;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        summaries
+            .into_values()
+            .map(|mut summary| {
+                summary.net_payout = U128(
+                    summary
+                        .gross_volume
+                        .0
+                        .saturating_sub(summary.fees.0)
+                        .saturating_sub(summary.referral_commissions.0)
+                        .saturating_sub(summary.refunds.0),
+                );
+                summary
+            })
+            .collect()
+    }
+}
+
+impl Contract {
+    /// Counts a payment accrued for a merchant toward its current settlement window, opening
+    /// the window on the first payment. No-op for merchants without a settlement window
+    pub(crate) fn track_settlement(
+        &mut self,
+        merchant_id: &AccountId,
+        payment_method: &PaymentMethod,
+    ) {
+        let uses_windows = self
+            .merchant_settings
+            .get(merchant_id)
+            .is_some_and(|settings| settings.settlement_window.is_some());
+        if !uses_windows {
+            return;
+        }
+
+        let key = (merchant_id.clone(), payment_method.clone());
+        let pending = match self.pending_settlements.get(&key) {
+            Some(pending) => PendingSettlement {
+                window_start: pending.window_start,
+                payment_count: pending.payment_count + 1,
+            },
+            None => PendingSettlement {
+                window_start: env::block_timestamp() / 1000000000,
+                payment_count: 1,
+            },
+        };
+        self.pending_settlements.insert(key, pending);
+    }
+}
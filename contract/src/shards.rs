@@ -0,0 +1,178 @@
+use near_sdk::{env, log, near, require, store::IterableMap, AccountId};
+
+use crate::models::{SubscriptionId, VSubscription};
+use crate::{Contract, ContractExt};
+
+// Number of shards subscriptions are split across
+pub(crate) const SUBSCRIPTION_SHARDS: u8 = 16;
+
+/// Subscriptions split across fixed shards by a hash of their ID, so maintenance passes can
+/// walk one shard at a time instead of the whole set. Mirrors the `IterableMap` methods the
+/// contract uses; iterating without a shard walks every shard in turn
+#[near(serializers = [borsh])]
+pub struct SubscriptionShards {
+    shards: Vec<IterableMap<SubscriptionId, VSubscription>>,
+}
+
+#[near]
+impl Contract {
+    /// Gets the number of shards subscriptions are split across. Maintenance calls that take a
+    /// `shard` accept 0 up to this number
+    pub fn get_subscription_shard_count(&self) -> u8 {
+        SUBSCRIPTION_SHARDS
+    }
+
+    /// Gets the worker each shard is assigned to, indexed by shard. Empty until a worker
+    /// registers
+    pub fn get_shard_assignments(&self) -> Vec<AccountId> {
+        self.shard_assignments.clone()
+    }
+
+    /// Gets the shards assigned to a worker. `claim_due_batch` only returns subscriptions
+    /// from these
+    pub fn get_worker_shards(&self, account_id: AccountId) -> Vec<u8> {
+        (0..SUBSCRIPTION_SHARDS)
+            .filter(|&shard| self.shard_assignments.get(shard as usize) == Some(&account_id))
+            .collect()
+    }
+}
+
+impl Contract {
+    /// Assigns every shard to one registered worker, round robin in account ID order, so
+    /// concurrent workers never scan or charge the same subscriptions. Called whenever a
+    /// worker joins or leaves; with more workers than shards the extra workers get none
+    pub(crate) fn assign_shards(&mut self) {
+        let mut workers: Vec<AccountId> = self.worker_by_account_id.keys().cloned().collect();
+        workers.sort();
+        self.shard_assignments = if workers.is_empty() {
+            Vec::new()
+        } else {
+            (0..SUBSCRIPTION_SHARDS as usize)
+                .map(|shard| workers[shard % workers.len()].clone())
+                .collect()
+        };
+        log!("Shards assigned across {} workers", workers.len());
+    }
+
+    /// Whether a subscription is in a shard assigned to `worker_id`. Every subscription is
+    /// while no shards are assigned
+    pub(crate) fn is_assigned_to(
+        &self,
+        subscription_id: &SubscriptionId,
+        worker_id: &AccountId,
+    ) -> bool {
+        self.shard_assignments.is_empty()
+            || self
+                .shard_assignments
+                .get(SubscriptionShards::shard_of(subscription_id))
+                .is_some_and(|assigned| assigned == worker_id)
+    }
+}
+
+impl SubscriptionShards {
+    /// Creates the shards under `prefix` followed by the shard index
+    pub fn new(prefix: u8) -> Self {
+        Self {
+            shards: (0..SUBSCRIPTION_SHARDS)
+                .map(|shard| IterableMap::new(vec![prefix, shard]))
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, subscription_id: &SubscriptionId) -> Option<&VSubscription> {
+        self.shards[Self::shard_of(subscription_id)].get(subscription_id)
+    }
+
+    pub fn get_mut(&mut self, subscription_id: &SubscriptionId) -> Option<&mut VSubscription> {
+        self.shards[Self::shard_of(subscription_id)].get_mut(subscription_id)
+    }
+
+    pub fn insert(
+        &mut self,
+        subscription_id: SubscriptionId,
+        subscription: VSubscription,
+    ) -> Option<VSubscription> {
+        self.shards[Self::shard_of(&subscription_id)].insert(subscription_id, subscription)
+    }
+
+    pub fn remove(&mut self, subscription_id: &SubscriptionId) -> Option<VSubscription> {
+        self.shards[Self::shard_of(subscription_id)].remove(subscription_id)
+    }
+
+    pub fn contains_key(&self, subscription_id: &SubscriptionId) -> bool {
+        self.shards[Self::shard_of(subscription_id)].contains_key(subscription_id)
+    }
+
+    pub fn len(&self) -> u32 {
+        self.shards.iter().map(|shard| shard.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&SubscriptionId, &VSubscription)> {
+        self.scan(None)
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&SubscriptionId, &mut VSubscription)> {
+        self.scan_mut(None)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &SubscriptionId> {
+        self.iter().map(|(subscription_id, _)| subscription_id)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &VSubscription> {
+        self.iter().map(|(_, subscription)| subscription)
+    }
+
+    /// Iterates one shard, or every shard when `shard` is `None`
+    pub fn scan(
+        &self,
+        shard: Option<u8>,
+    ) -> impl Iterator<Item = (&SubscriptionId, &VSubscription)> {
+        Self::assert_valid_shard(shard);
+        self.shards
+            .iter()
+            .enumerate()
+            .filter(move |(index, _)| shard.is_none_or(|shard| shard as usize == *index))
+            .flat_map(|(_, shard)| shard.iter())
+    }
+
+    /// Iterates one shard, or every shard when `shard` is `None`, mutably
+    pub fn scan_mut(
+        &mut self,
+        shard: Option<u8>,
+    ) -> impl Iterator<Item = (&SubscriptionId, &mut VSubscription)> {
+        Self::assert_valid_shard(shard);
+        self.shards
+            .iter_mut()
+            .enumerate()
+            .filter(move |(index, _)| shard.is_none_or(|shard| shard as usize == *index))
+            .flat_map(|(_, shard)| shard.iter_mut())
+    }
+
+    pub fn flush(&mut self) {
+        for shard in self.shards.iter_mut() {
+            shard.flush();
+        }
+    }
+
+    /// Whether a subscription is stored in `shard`; every subscription is when `shard` is `None`
+    pub fn in_shard(subscription_id: &SubscriptionId, shard: Option<u8>) -> bool {
+        Self::assert_valid_shard(shard);
+        shard.is_none_or(|shard| Self::shard_of(subscription_id) == shard as usize)
+    }
+
+    fn shard_of(subscription_id: &SubscriptionId) -> usize {
+        (env::sha256(subscription_id.as_bytes())[0] % SUBSCRIPTION_SHARDS) as usize
+    }
+
+    fn assert_valid_shard(shard: Option<u8>) {
+        require!(
+            shard.is_none_or(|shard| shard < SUBSCRIPTION_SHARDS),
+            "Shard out of range"
+        );
+    }
+}
@@ -0,0 +1,258 @@
+use near_sdk::{
+    env, ext_contract, json_types::U128, log, near, require, AccountId, Gas, NearToken,
+    PromiseError,
+};
+
+use crate::models::{
+    PaymentError, PaymentMethod, PaymentResult, StakingPreference, Subscription, SubscriptionId,
+};
+use crate::{Contract, ContractExt};
+
+// Gas for staking escrow with the liquid staking pool
+const GAS_FOR_STAKE: Gas = Gas::from_tgas(50);
+// Gas for instantly unstaking through the pool's liquidity
+const GAS_FOR_LIQUID_UNSTAKE: Gas = Gas::from_tgas(75);
+// Gas for recording the staking result, including a retried charge
+const GAS_FOR_STAKING_CALLBACK: Gas = Gas::from_tgas(60);
+// Extra shares burnt on a just-in-time unstake to absorb the pool's liquidity fee
+const UNSTAKE_BUFFER_BPS: u128 = 300;
+
+/// Liquid unstake outcome; pools may return more fields, only the NEAR received is used
+#[near(serializers = [json])]
+pub struct LiquidUnstakeResult {
+    pub near: U128,
+}
+
+/// Liquid staking pool interface (stNEAR-style): staking mints shares worth at least one NEAR
+/// each, and liquid unstaking burns shares for NEAR immediately
+#[ext_contract(ext_liquid_staking)]
+pub trait LiquidStaking {
+    fn deposit_and_stake(&mut self) -> U128;
+    fn liquid_unstake(
+        &mut self,
+        st_near_to_burn: U128,
+        min_expected_near: U128,
+    ) -> LiquidUnstakeResult;
+}
+
+#[near]
+impl Contract {
+    /// Sets the liquid staking pool idle NEAR escrow can be staked with. Shares are tracked
+    /// per pool, so it can only change once all staked escrow has been unstaked
+    pub fn set_staking_pool(&mut self, pool_id: Option<AccountId>) {
+        self.require_owner();
+        require!(
+            pool_id == self.staking_pool || self.staked_escrow_total.0 == 0,
+            "Staked escrow must be unstaked before changing pools"
+        );
+        self.staking_pool = pool_id;
+        log!("Staking pool updated");
+    }
+
+    /// Opts the caller in to staking NEAR escrow above `liquid_buffer`, which is always kept
+    /// liquid for upcoming charges. `None` opts out
+    pub fn set_escrow_staking(&mut self, preference: Option<StakingPreference>) {
+        let user_id = env::predecessor_account_id();
+        match preference {
+            Some(preference) => {
+                self.staking_preferences.insert(user_id.clone(), preference);
+            }
+            None => {
+                self.staking_preferences.remove(&user_id);
+            }
+        }
+        log!("Escrow staking updated for {}", user_id);
+    }
+
+    /// Gets a user's staking opt-in
+    pub fn get_escrow_staking(&self, user_id: AccountId) -> Option<StakingPreference> {
+        self.staking_preferences.get(&user_id).cloned()
+    }
+
+    /// Gets the pool shares held for a user's staked escrow
+    pub fn get_staked_escrow(&self, user_id: AccountId) -> U128 {
+        self.staked_escrow.get(&user_id).copied().unwrap_or(U128(0))
+    }
+
+    /// Stakes an opted-in user's NEAR escrow above their liquid buffer. Callable by the user or
+    /// an approved worker. Returns the amount sent to the pool
+    pub fn stake_idle_escrow(&mut self, user_id: AccountId) -> U128 {
+        require!(
+            env::predecessor_account_id() == user_id || self.is_verified_by_approved_codehash(),
+            "Not authorized to stake this escrow"
+        );
+        let pool_id = self
+            .staking_pool
+            .clone()
+            .expect("No staking pool configured");
+        let preference = self
+            .staking_preferences
+            .get(&user_id)
+            .cloned()
+            .expect("Escrow staking not enabled");
+
+        let balance = self
+            .get_escrow_balance(user_id.clone(), PaymentMethod::Near)
+            .0;
+        let amount = balance.saturating_sub(preference.liquid_buffer.0);
+        if amount == 0 {
+            return U128(0);
+        }
+        require!(self.debit_escrow(&user_id, &PaymentMethod::Near, amount));
+
+        ext_liquid_staking::ext(pool_id)
+            .with_static_gas(GAS_FOR_STAKE)
+            .with_attached_deposit(NearToken::from_yoctonear(amount))
+            .deposit_and_stake()
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_STAKING_CALLBACK)
+                    .on_escrow_staked(user_id.clone(), U128(amount)),
+            );
+
+        log!("Staking {} NEAR of escrow for {}", amount, user_id);
+        U128(amount)
+    }
+
+    /// Unstakes pool shares back into the caller's NEAR escrow, accepting no less than
+    /// `min_expected_near`
+    pub fn unstake_escrow(&mut self, shares: U128, min_expected_near: U128) {
+        let user_id = env::predecessor_account_id();
+        self.start_liquid_unstake(&user_id, shares.0, min_expected_near.0, None);
+    }
+
+    /// Records the shares minted for staked escrow, or returns the NEAR to escrow if staking
+    /// failed
+    #[private]
+    pub fn on_escrow_staked(
+        &mut self,
+        user_id: AccountId,
+        amount: U128,
+        #[callback_result] shares: Result<U128, PromiseError>,
+    ) -> U128 {
+        let Ok(shares) = shares else {
+            log!("Staking failed for {}", user_id);
+            self.credit_escrow(&user_id, &PaymentMethod::Near, amount.0);
+            return U128(0);
+        };
+
+        let held = self.get_staked_escrow(user_id.clone()).0;
+        self.staked_escrow.insert(user_id, U128(held + shares.0));
+        self.staked_escrow_total = U128(self.staked_escrow_total.0 + shares.0);
+        shares
+    }
+
+    /// Credits NEAR from a liquid unstake to escrow, or restores the shares if it failed, then
+    /// retries the charge it was started for, if any
+    #[private]
+    pub fn on_escrow_unstaked(
+        &mut self,
+        user_id: AccountId,
+        shares: U128,
+        subscription_id: Option<SubscriptionId>,
+        #[callback_result] result: Result<LiquidUnstakeResult, PromiseError>,
+    ) -> Option<PaymentResult> {
+        match result {
+            Ok(result) => {
+                self.staked_escrow_total = U128(self.staked_escrow_total.0 - shares.0);
+                self.credit_escrow(&user_id, &PaymentMethod::Near, result.near.0);
+                log!("Unstaked {} NEAR to escrow for {}", result.near.0, user_id);
+            }
+            Err(_) => {
+                log!("Unstaking failed for {}", user_id);
+                let held = self.get_staked_escrow(user_id.clone()).0;
+                self.staked_escrow.insert(user_id, U128(held + shares.0));
+            }
+        }
+
+        let subscription_id = subscription_id?;
+        let now = env::block_timestamp() / 1000000000;
+        let subscription = self
+            .subscriptions
+            .get_mut(&subscription_id)
+            .expect("Subscription not found");
+        subscription.settlement_pending = false;
+
+        Some(self.charge_subscription(subscription_id, None, None, now))
+    }
+}
+
+impl Contract {
+    /// Unstakes just enough of a subscriber's staked escrow to cover a NEAR charge escrow falls
+    /// short of, once per cycle, and retries the charge when the NEAR arrives. Returns None when
+    /// nothing is staked or the shortfall was already unstaked for this cycle
+    pub(crate) fn start_unstake_for_charge(
+        &mut self,
+        subscription: &Subscription,
+        now: u64,
+    ) -> Option<PaymentResult> {
+        if subscription.payment_method != PaymentMethod::Near
+            || subscription.top_up_cycle == Some(subscription.cycle_index)
+        {
+            return None;
+        }
+        let held = self.get_staked_escrow(subscription.user_id.clone()).0;
+        if held == 0 {
+            return None;
+        }
+
+        let balance = self
+            .get_escrow_balance(subscription.user_id.clone(), PaymentMethod::Near)
+            .0;
+        let shortfall = subscription.amount.0.saturating_sub(balance);
+        if shortfall == 0 {
+            return None;
+        }
+
+        // Shares are worth at least one NEAR, so burning the shortfall plus a buffer covers it
+        let shares = (shortfall + shortfall * UNSTAKE_BUFFER_BPS / 10000).min(held);
+
+        let stored = self.subscriptions.get_mut(&subscription.id)?;
+        stored.top_up_cycle = Some(subscription.cycle_index);
+        stored.settlement_pending = true;
+        self.start_liquid_unstake(
+            &subscription.user_id,
+            shares,
+            shortfall,
+            Some(subscription.id.clone()),
+        );
+
+        Some(PaymentResult {
+            success: false,
+            subscription_id: subscription.id.clone(),
+            amount: subscription.amount,
+            timestamp: now,
+            error: Some(PaymentError::TopUpRequested),
+        })
+    }
+
+    fn start_liquid_unstake(
+        &mut self,
+        user_id: &AccountId,
+        shares: u128,
+        min_expected_near: u128,
+        subscription_id: Option<SubscriptionId>,
+    ) {
+        let pool_id = self
+            .staking_pool
+            .clone()
+            .expect("No staking pool configured");
+        let held = self.get_staked_escrow(user_id.clone()).0;
+        require!(shares > 0 && shares <= held, "Not enough staked escrow");
+        if held == shares {
+            self.staked_escrow.remove(user_id);
+        } else {
+            self.staked_escrow
+                .insert(user_id.clone(), U128(held - shares));
+        }
+
+        ext_liquid_staking::ext(pool_id)
+            .with_static_gas(GAS_FOR_LIQUID_UNSTAKE)
+            .liquid_unstake(U128(shares), U128(min_expected_near))
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_STAKING_CALLBACK)
+                    .on_escrow_unstaked(user_id.clone(), U128(shares), subscription_id),
+            );
+    }
+}
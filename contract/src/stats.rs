@@ -0,0 +1,54 @@
+use near_sdk::json_types::U128;
+use near_sdk::near;
+use near_sdk::store::IterableMap;
+
+use crate::models::{ContractStats, PaymentMethod};
+use crate::{Contract, ContractExt};
+
+#[near]
+impl Contract {
+    /// Gets contract-wide totals in one call, from counters kept up to date as subscriptions,
+    /// merchants, workers and payments change
+    pub fn get_stats(&self) -> ContractStats {
+        ContractStats {
+            subscriptions: self.subscription_counts.clone(),
+            merchants: self.merchants.len(),
+            workers: self.worker_by_account_id.len(),
+            volume_processed: Self::token_totals(&self.volume_processed),
+            fees_collected: Self::token_totals(&self.fees_collected),
+        }
+    }
+}
+
+impl Contract {
+    /// Adds a successful charge to the volume processed in its token
+    pub(crate) fn record_volume(&mut self, payment_method: &PaymentMethod, amount: u128) {
+        Self::add_token_total(&mut self.volume_processed, payment_method, amount);
+    }
+
+    /// Adds a platform fee, including any worker share, to the fees collected in its token
+    pub(crate) fn record_fee_collected(&mut self, payment_method: &PaymentMethod, fee: u128) {
+        Self::add_token_total(&mut self.fees_collected, payment_method, fee);
+    }
+
+    fn add_token_total(
+        totals: &mut IterableMap<PaymentMethod, U128>,
+        payment_method: &PaymentMethod,
+        amount: u128,
+    ) {
+        if amount == 0 {
+            return;
+        }
+        let total = totals.get(payment_method).map_or(0, |total| total.0);
+        totals.insert(payment_method.clone(), U128(total.saturating_add(amount)));
+    }
+
+    fn token_totals(totals: &IterableMap<PaymentMethod, U128>) -> Vec<(PaymentMethod, U128)> {
+        let mut totals: Vec<(PaymentMethod, U128)> = totals
+            .iter()
+            .map(|(payment_method, total)| (payment_method.clone(), *total))
+            .collect();
+        totals.sort_by(|(a, _), (b, _)| a.cmp(b));
+        totals
+    }
+}
@@ -0,0 +1,400 @@
+use near_sdk::{env, json_types::U128, log, near, require, AccountId, NearToken, Promise};
+
+use crate::ft::StorageBalance;
+use crate::models::{StorageAccount, StoragePayer, SubscriptionId};
+use crate::{Contract, ContractExt};
+
+// Bytes taken by an account's own storage record, covered by the minimum deposit
+const ACCOUNT_STORAGE_BYTES: u64 = 125;
+
+/// NEP-145 bounds on an account's storage deposit
+#[near(serializers = [json])]
+pub struct StorageBalanceBounds {
+    pub min: U128,
+    pub max: Option<U128>,
+}
+
+#[near]
+impl Contract {
+    /// Deposits attached NEAR towards the storage of an account's subscriptions and keys, the
+    /// caller's by default. With `registration_only` only the minimum deposit is kept on
+    /// registration and the rest refunded
+    #[payable]
+    pub fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let amount = env::attached_deposit().as_yoctonear();
+        let min_deposit = Self::storage_cost(ACCOUNT_STORAGE_BYTES);
+        let registration_only = registration_only.unwrap_or(false);
+
+        let mut account = self.storage_accounts.get(&account_id).cloned();
+        let refund = match account.as_mut() {
+            Some(_) if registration_only => amount,
+            Some(account) => {
+                account.deposit = U128(account.deposit.0 + amount);
+                0
+            }
+            None => {
+                require!(
+                    amount >= min_deposit,
+                    format!("Storage deposit must be at least {}", min_deposit)
+                );
+                let deposit = if registration_only {
+                    min_deposit
+                } else {
+                    amount
+                };
+                account = Some(StorageAccount {
+                    deposit: U128(deposit),
+                    used_bytes: ACCOUNT_STORAGE_BYTES,
+                });
+                amount - deposit
+            }
+        };
+        let account = account.expect("Storage account not found");
+        self.storage_accounts
+            .insert(account_id.clone(), account.clone());
+        if refund > 0 {
+            Promise::new(env::predecessor_account_id()).transfer(NearToken::from_yoctonear(refund));
+        }
+
+        log!("Storage deposit of {} for {}", amount - refund, account_id);
+        Self::storage_balance(&account)
+    }
+
+    /// Withdraws the caller's storage deposit not used by their subscriptions and keys, all of
+    /// it by default. Requires exactly 1 yoctoNEAR attached
+    #[payable]
+    pub fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        near_sdk::assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let mut account = self
+            .storage_accounts
+            .get(&account_id)
+            .cloned()
+            .expect("Account is not registered for storage");
+        let available = Self::storage_available(&account);
+        let amount = amount.map_or(available, |amount| amount.0);
+        require!(
+            amount <= available,
+            "Amount exceeds the available storage balance"
+        );
+
+        account.deposit = U128(account.deposit.0 - amount);
+        self.storage_accounts
+            .insert(account_id.clone(), account.clone());
+        if amount > 0 {
+            Promise::new(account_id.clone()).transfer(NearToken::from_yoctonear(amount));
+        }
+
+        log!("Storage withdrawal of {} for {}", amount, account_id);
+        Self::storage_balance(&account)
+    }
+
+    /// Unregisters the caller, refunding their storage deposit. Accounts still using storage
+    /// for subscriptions or keys cannot unregister, so `force` is not supported. Requires
+    /// exactly 1 yoctoNEAR attached
+    #[payable]
+    pub fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        near_sdk::assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let Some(account) = self.storage_accounts.get(&account_id).cloned() else {
+            return false;
+        };
+        require!(
+            account.used_bytes <= ACCOUNT_STORAGE_BYTES,
+            if force == Some(true) {
+                "Force unregistering is not supported while storage is in use"
+            } else {
+                "Cannot unregister while storage is in use"
+            }
+        );
+
+        self.storage_accounts.remove(&account_id);
+        Promise::new(account_id.clone()).transfer(NearToken::from_yoctonear(account.deposit.0));
+
+        log!("Storage unregistered for {}", account_id);
+        true
+    }
+
+    /// Gets the minimum storage deposit; there is no maximum
+    pub fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        StorageBalanceBounds {
+            min: U128(Self::storage_cost(ACCOUNT_STORAGE_BYTES)),
+            max: None,
+        }
+    }
+
+    /// Gets an account's storage deposit and how much of it is not in use
+    pub fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.storage_accounts
+            .get(&account_id)
+            .map(Self::storage_balance)
+    }
+}
+
+impl Contract {
+    /// Charges an account's storage deposit for what the call has stored since `initial_usage`
+    /// bytes, failing when the deposit does not cover it. Returns the bytes charged
+    pub(crate) fn charge_storage(&mut self, account_id: &AccountId, initial_usage: u64) -> u64 {
+        self.flush_subscription_storage();
+        let used_bytes = env::storage_usage().saturating_sub(initial_usage);
+        if used_bytes == 0 {
+            return 0;
+        }
+
+        let mut account = self
+            .storage_accounts
+            .get(account_id)
+            .cloned()
+            .unwrap_or_else(|| {
+                env::panic_str(&format!(
+                    "Attach a storage deposit of at least {} with storage_deposit first",
+                    Self::storage_cost(ACCOUNT_STORAGE_BYTES + used_bytes)
+                ))
+            });
+        let cost = Self::storage_cost(used_bytes);
+        let available = Self::storage_available(&account);
+        require!(
+            cost <= available,
+            format!(
+                "Attach a storage deposit of {} more with storage_deposit",
+                cost - available
+            )
+        );
+
+        account.used_bytes += used_bytes;
+        self.storage_accounts.insert(account_id.clone(), account);
+        used_bytes
+    }
+
+    /// Charges a subscription's storage since `initial_usage` to `payer_id`, remembering it so
+    /// the storage is refunded to them when the subscription is archived
+    pub(crate) fn charge_subscription_storage(
+        &mut self,
+        subscription_id: &SubscriptionId,
+        payer_id: &AccountId,
+        initial_usage: u64,
+    ) {
+        // The record is stored before charging so its own bytes are charged too
+        let mut payer = StoragePayer {
+            account_id: payer_id.clone(),
+            bytes: 0,
+        };
+        self.subscription_storage
+            .insert(subscription_id.clone(), payer.clone());
+        payer.bytes = self.charge_storage(payer_id, initial_usage);
+        self.subscription_storage
+            .insert(subscription_id.clone(), payer);
+    }
+
+    /// Returns up to `bytes` of storage to an account's deposit, never below what its own
+    /// record takes. Returns the bytes released
+    pub(crate) fn release_storage(&mut self, account_id: &AccountId, bytes: u64) -> u64 {
+        let Some(account) = self.storage_accounts.get_mut(account_id) else {
+            return 0;
+        };
+        let released = bytes.min(account.used_bytes.saturating_sub(ACCOUNT_STORAGE_BYTES));
+        account.used_bytes -= released;
+        released
+    }
+
+    /// Writes out cached subscription collections so `env::storage_usage` reflects them
+    pub(crate) fn flush_subscription_storage(&mut self) {
+        self.subscriptions.flush();
+        self.subscription_keys.flush();
+        self.due_heap.flush();
+        self.due_heap_positions.flush();
+        self.merchant_subscription_counts.flush();
+        self.subscription_storage.flush();
+        self.subscription_sequence.flush();
+        self.subscriptions_by_user.flush();
+        self.subscriptions_by_merchant.flush();
+        self.merchant_subscription_totals.flush();
+        self.keys_by_subscription.flush();
+        self.key_derivations.flush();
+        self.key_labels.flush();
+    }
+
+    fn storage_balance(account: &StorageAccount) -> StorageBalance {
+        StorageBalance {
+            total: account.deposit,
+            available: U128(Self::storage_available(account)),
+        }
+    }
+
+    fn storage_available(account: &StorageAccount) -> u128 {
+        account
+            .deposit
+            .0
+            .saturating_sub(Self::storage_cost(account.used_bytes))
+    }
+
+    pub(crate) fn storage_cost(bytes: u64) -> u128 {
+        env::storage_byte_cost().as_yoctonear() * bytes as u128
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{env, json_types::U128, testing_env, AccountId, NearToken};
+
+    use super::ACCOUNT_STORAGE_BYTES;
+    use crate::models::{
+        PaymentMethod, StorageAccount, Subscription, SubscriptionFrequency, SubscriptionStatusV0,
+        SubscriptionV0,
+    };
+    use crate::Contract;
+
+    fn set_context(predecessor: AccountId, deposit: u128) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(predecessor)
+            .attached_deposit(NearToken::from_yoctonear(deposit))
+            .build());
+    }
+
+    #[test]
+    fn keeps_only_minimum_deposit_for_registration() {
+        set_context(accounts(0), 0);
+        let mut contract = Contract::new(accounts(0));
+        let min_deposit = Contract::storage_cost(ACCOUNT_STORAGE_BYTES);
+
+        set_context(accounts(1), min_deposit + 1000);
+        let balance = contract.storage_deposit(None, Some(true));
+
+        assert_eq!(balance.total.0, min_deposit);
+        assert_eq!(balance.available.0, 0);
+    }
+
+    #[test]
+    fn charges_stored_bytes_to_the_deposit() {
+        set_context(accounts(0), 0);
+        let mut contract = Contract::new(accounts(0));
+        let deposit = Contract::storage_cost(ACCOUNT_STORAGE_BYTES + 1000);
+        set_context(accounts(1), deposit);
+        contract.storage_deposit(None, None);
+
+        let initial_usage = env::storage_usage();
+        env::storage_write(b"data", &[0; 200]);
+        let charged = contract.charge_storage(&accounts(1), initial_usage);
+
+        let balance = contract.storage_balance_of(accounts(1)).unwrap();
+        assert!(charged > 200);
+        assert_eq!(
+            balance.available.0,
+            deposit - Contract::storage_cost(ACCOUNT_STORAGE_BYTES + charged)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Attach a storage deposit of")]
+    fn rejects_storage_beyond_the_deposit() {
+        set_context(accounts(0), 0);
+        let mut contract = Contract::new(accounts(0));
+        set_context(
+            accounts(1),
+            Contract::storage_cost(ACCOUNT_STORAGE_BYTES + 10),
+        );
+        contract.storage_deposit(None, None);
+
+        let initial_usage = env::storage_usage();
+        env::storage_write(b"data", &[0; 200]);
+        contract.charge_storage(&accounts(1), initial_usage);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot unregister while storage is in use")]
+    fn rejects_unregistering_while_storage_is_in_use() {
+        set_context(accounts(0), 0);
+        let mut contract = Contract::new(accounts(0));
+        set_context(
+            accounts(1),
+            Contract::storage_cost(ACCOUNT_STORAGE_BYTES + 1000),
+        );
+        contract.storage_deposit(None, None);
+        let initial_usage = env::storage_usage();
+        env::storage_write(b"data", &[0; 200]);
+        contract.charge_storage(&accounts(1), initial_usage);
+
+        set_context(accounts(1), 1);
+        contract.storage_unregister(None);
+    }
+
+    #[test]
+    fn releases_storage_down_to_the_account_record() {
+        set_context(accounts(0), 0);
+        let mut contract = Contract::new(accounts(0));
+        contract.storage_accounts.insert(
+            accounts(1),
+            StorageAccount {
+                deposit: U128(Contract::storage_cost(ACCOUNT_STORAGE_BYTES + 500)),
+                used_bytes: ACCOUNT_STORAGE_BYTES + 500,
+            },
+        );
+
+        assert_eq!(contract.release_storage(&accounts(1), 1000), 500);
+        assert_eq!(
+            contract
+                .storage_accounts
+                .get(&accounts(1))
+                .unwrap()
+                .used_bytes,
+            ACCOUNT_STORAGE_BYTES
+        );
+        assert_eq!(contract.release_storage(&accounts(2), 1000), 0);
+    }
+
+    #[test]
+    fn returns_archived_subscription_storage_to_its_payer() {
+        set_context(accounts(0), 0);
+        let mut contract = Contract::new(accounts(0));
+        contract.set_archive_retention_period(0);
+        set_context(
+            accounts(1),
+            Contract::storage_cost(ACCOUNT_STORAGE_BYTES + 10000),
+        );
+        contract.storage_deposit(None, None);
+
+        let initial_usage = env::storage_usage();
+        let subscription: Subscription = SubscriptionV0 {
+            id: "sub-1".to_string(),
+            user_id: accounts(1),
+            merchant_id: accounts(2),
+            amount: U128(10000),
+            frequency: SubscriptionFrequency::Monthly,
+            next_payment_date: 0,
+            status: SubscriptionStatusV0::Canceled,
+            created_at: 0,
+            updated_at: 0,
+            payment_method: PaymentMethod::Near,
+            max_payments: None,
+            payments_made: 0,
+            end_date: None,
+        }
+        .into();
+        contract
+            .subscriptions
+            .insert(subscription.id.clone(), subscription.clone().into());
+        contract.charge_subscription_storage(&subscription.id, &accounts(1), initial_usage);
+        let charged = contract
+            .storage_accounts
+            .get(&accounts(1))
+            .unwrap()
+            .used_bytes;
+        assert!(charged > ACCOUNT_STORAGE_BYTES);
+
+        contract.archive_subscription(subscription.id);
+
+        let used_bytes = contract
+            .storage_accounts
+            .get(&accounts(1))
+            .unwrap()
+            .used_bytes;
+        assert!(used_bytes < charged);
+    }
+}
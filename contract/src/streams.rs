@@ -0,0 +1,233 @@
+use near_sdk::{env, json_types::U128, log, near, require};
+
+use crate::models::{
+    FundingSource, PaymentKind, PaymentMode, PaymentRecord, PayoutPurpose, Subscription,
+    SubscriptionId, SubscriptionStatus,
+};
+use crate::{Contract, ContractExt};
+
+#[near]
+impl Contract {
+    /// Pays a streaming subscription's merchant everything accrued since the last claim, as far
+    /// as the subscriber's escrow covers it. Returns the amount paid, before fees
+    pub fn claim_stream(&mut self, subscription_id: SubscriptionId) -> U128 {
+        let now = env::block_timestamp() / 1000000000;
+        let subscription = self
+            .subscriptions
+            .get(&subscription_id)
+            .expect("Subscription not found");
+        require!(
+            subscription.merchant_id == env::predecessor_account_id(),
+            "Not authorized to claim this stream"
+        );
+        require!(
+            subscription.payment_mode == PaymentMode::Stream,
+            "Subscription is not streaming"
+        );
+
+        U128(self.settle_stream(&subscription_id, now))
+    }
+
+    /// Gets what a streaming subscription has accrued to its merchant and not yet been claimed,
+    /// ignoring whether the subscriber's escrow covers it
+    pub fn get_stream_accrued(&self, subscription_id: SubscriptionId) -> U128 {
+        let now = env::block_timestamp() / 1000000000;
+        let subscription = self
+            .subscriptions
+            .get(&subscription_id)
+            .expect("Subscription not found");
+        U128(Self::stream_accrued(subscription, now).0)
+    }
+}
+
+impl Contract {
+    /// Accrued amount of a streaming subscription and the time it is accrued up to. Streams
+    /// accrue `amount / period` per second while active, until the end date and up to the
+    /// lifetime spending cap
+    pub(crate) fn stream_accrued(subscription: &Subscription, now: u64) -> (u128, u64) {
+        let accruing = subscription.payment_mode == PaymentMode::Stream
+            && matches!(subscription.status, SubscriptionStatus::Active);
+        let until = subscription
+            .end_date
+            .map_or(now, |end_date| now.min(end_date));
+        if !accruing || until <= subscription.stream_claimed_until {
+            return (0, subscription.stream_claimed_until);
+        }
+
+        let elapsed = (until - subscription.stream_claimed_until) as u128;
+        let accrued = subscription.amount.0 * elapsed / subscription.frequency.seconds() as u128;
+        let accrued = match subscription.max_total_spend {
+            Some(max_total) => accrued.min(max_total.0.saturating_sub(subscription.total_spent.0)),
+            None => accrued,
+        };
+        (accrued, until)
+    }
+
+    /// Pays a streaming subscription's merchant what has accrued, taking the platform fee and
+    /// recording the payment. When escrow runs short, only the covered share of the elapsed
+    /// time is paid for. Returns the amount paid
+    pub(crate) fn settle_stream(&mut self, subscription_id: &SubscriptionId, now: u64) -> u128 {
+        let subscription = self
+            .subscriptions
+            .get(subscription_id)
+            .expect("Subscription not found")
+            .clone();
+        let (accrued, until) = Self::stream_accrued(&subscription, now);
+        if accrued == 0 {
+            return 0;
+        }
+
+        let balance = self
+            .get_escrow_balance(
+                subscription.user_id.clone(),
+                subscription.payment_method.clone(),
+            )
+            .0;
+        let paid = accrued.min(balance);
+        if paid == 0 {
+            log!(
+                "Escrow cannot cover the stream for subscription: {}",
+                subscription_id
+            );
+            return 0;
+        }
+        let claimed_until = subscription.stream_claimed_until
+            + ((until - subscription.stream_claimed_until) as u128 * paid / accrued) as u64;
+        self.debit_escrow(&subscription.user_id, &subscription.payment_method, paid);
+
+        let funding = FundingSource {
+            payment_method: subscription.payment_method.clone(),
+            amount: U128(paid),
+        };
+        let payment_number = subscription.payments_made + 1;
+        let memo = self.payment_memo(&subscription, payment_number);
+        let fee = self.pay_merchant(
+            &subscription,
+            &funding,
+            subscription.cycle_index,
+            memo.clone(),
+            PayoutPurpose::Release,
+            now,
+        );
+        let commission = self.referral_commission_for(&subscription, paid - fee);
+
+        let stored = self
+            .subscriptions
+            .get_mut(subscription_id)
+            .expect("Subscription not found");
+        stored.stream_claimed_until = claimed_until;
+        stored.payments_made = payment_number;
+        stored.total_spent = U128(stored.total_spent.0 + paid);
+        stored.updated_at = now;
+
+        self.push_payment_record(PaymentRecord {
+            subscription_id: subscription_id.clone(),
+            kind: PaymentKind::Charge,
+            payment_number,
+            amount: U128(paid),
+            payment_method: subscription.payment_method.clone(),
+            line_items: Vec::new(),
+            memo: Some(memo),
+            fee: U128(fee),
+            referral_commission: U128(commission),
+            payouts: self.payout_legs(&subscription.merchant_id, paid - fee - commission),
+            invoice_number: None,
+            usd_rate: None,
+            amount_override: None,
+            rate_source: None,
+            swap: None,
+            timestamp: now,
+            block_height: env::block_height(),
+            block_timestamp: env::block_timestamp(),
+        });
+        log!(
+            "Stream paid {} up to {} for subscription: {}",
+            paid,
+            claimed_until,
+            subscription_id
+        );
+
+        paid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{json_types::U128, testing_env};
+
+    use crate::models::{
+        PaymentMethod, PaymentMode, Subscription, SubscriptionFrequency, SubscriptionStatusV0,
+        SubscriptionV0,
+    };
+    use crate::Contract;
+
+    /// A monthly stream of `accounts(1)` to `accounts(2)` accruing 1 per second from 1000
+    fn stream() -> Subscription {
+        Subscription {
+            payment_mode: PaymentMode::Stream,
+            stream_claimed_until: 1_000,
+            ..SubscriptionV0 {
+                id: "sub-1".to_string(),
+                user_id: accounts(1),
+                merchant_id: accounts(2),
+                amount: U128(2592000),
+                frequency: SubscriptionFrequency::Monthly,
+                next_payment_date: 1_000,
+                status: SubscriptionStatusV0::Active,
+                created_at: 1_000,
+                updated_at: 1_000,
+                payment_method: PaymentMethod::Near,
+                max_payments: None,
+                payments_made: 0,
+                end_date: None,
+            }
+            .into()
+        }
+    }
+
+    #[test]
+    fn accrues_stream_per_second_up_to_end_and_cap() {
+        assert_eq!(Contract::stream_accrued(&stream(), 1_500), (500, 1_500));
+
+        let ended = Subscription {
+            end_date: Some(1_200),
+            ..stream()
+        };
+        assert_eq!(Contract::stream_accrued(&ended, 1_500), (200, 1_200));
+
+        let capped = Subscription {
+            max_total_spend: Some(U128(300)),
+            total_spent: U128(200),
+            ..stream()
+        };
+        assert_eq!(Contract::stream_accrued(&capped, 1_500).0, 100);
+    }
+
+    #[test]
+    fn pays_only_the_share_of_stream_escrow_covers() {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(2))
+            .block_timestamp(1_500 * 1_000_000_000)
+            .build());
+        let mut contract = Contract::new(accounts(0));
+        let subscription = stream();
+        contract
+            .subscriptions
+            .insert(subscription.id.clone(), subscription.clone().into());
+        contract.credit_escrow(&accounts(1), &PaymentMethod::Near, 250);
+
+        assert_eq!(contract.claim_stream(subscription.id.clone()), U128(250));
+
+        let stored = contract.get_subscription(subscription.id).unwrap();
+        assert_eq!(stored.stream_claimed_until, 1_250);
+        assert_eq!(stored.total_spent, U128(250));
+        assert_eq!(
+            contract
+                .get_escrow_balance(accounts(1), PaymentMethod::Near)
+                .0,
+            0
+        );
+    }
+}
@@ -0,0 +1,262 @@
+use near_sdk::{
+    env, json_types::U128, log, near, require, serde_json, AccountId, Gas, NearToken,
+    PromiseError,
+};
+
+use crate::events::Event;
+use crate::ft::ext_ft;
+use crate::models::{
+    ChargeDetails, FundingSource, PaymentError, PaymentMethod, PaymentResult, PayoutPurpose,
+    Subscription, SubscriptionId, SwapConversion, SwapFunding, UsdRate,
+};
+use crate::{Contract, ContractExt};
+
+// Gas for ft_transfer_call into the swap adapter, including the swap itself
+const GAS_FOR_SWAP: Gas = Gas::from_tgas(100);
+// Gas for settling a swapped payment, including the payouts
+const GAS_FOR_SWAP_CALLBACK: Gas = Gas::from_tgas(60);
+
+#[near]
+impl Contract {
+    /// Sets the swap adapter used to pay subscriptions from another token. The adapter receives
+    /// the input token via `ft_transfer_call` with a msg of `{"token_out", "amount_out",
+    /// "receiver_id"}`, must deliver exactly `amount_out` and returns unused input
+    pub fn set_swap_adapter(&mut self, adapter_id: Option<AccountId>) {
+        self.require_owner();
+        self.swap_adapter = adapter_id;
+        log!("Swap adapter updated");
+    }
+
+    /// Lets a subscriber pay a subscription from escrow in another token, swapped at charge
+    /// time with at most `max_slippage_bps` over the oracle price. `None` turns swapping off
+    pub fn set_swap_funding(
+        &mut self,
+        subscription_id: SubscriptionId,
+        swap_funding: Option<SwapFunding>,
+    ) {
+        let user_id = env::predecessor_account_id();
+        if let Some(swap_funding) = &swap_funding {
+            self.assert_payment_method_allowed(&PaymentMethod::Ft {
+                token_id: swap_funding.token_in.clone(),
+            });
+            require!(
+                swap_funding.max_slippage_bps <= 10000,
+                "Slippage cannot exceed 10000 basis points"
+            );
+        }
+
+        let subscription = self
+            .subscriptions
+            .get_mut(&subscription_id)
+            .expect("Subscription not found");
+        require!(
+            subscription.user_id == user_id,
+            "Not authorized to update this subscription"
+        );
+        if let Some(swap_funding) = &swap_funding {
+            let swappable = matches!(
+                &subscription.payment_method,
+                PaymentMethod::Ft { token_id } if *token_id != swap_funding.token_in
+            );
+            require!(
+                swappable,
+                "Swaps need a fungible token payment method different from the input token"
+            );
+        }
+        subscription.swap_funding = swap_funding;
+        subscription.updated_at = env::block_timestamp() / 1000000000;
+
+        log!("Swap funding updated for subscription: {}", subscription_id);
+    }
+
+    /// Settles a swapped payment: pays the merchant from the swap output and returns unused
+    /// input to escrow, or refunds the input and schedules a retry if the swap failed
+    #[private]
+    pub fn on_swap_payment(
+        &mut self,
+        subscription_id: SubscriptionId,
+        swap: SwapConversion,
+        charge: ChargeDetails,
+        #[callback_result] used: Result<U128, PromiseError>,
+    ) -> bool {
+        let now = env::block_timestamp() / 1000000000;
+        let subscription = self
+            .subscriptions
+            .get_mut(&subscription_id)
+            .expect("Subscription not found");
+        subscription.settlement_pending = false;
+        let subscription = subscription.clone();
+
+        let token_in = swap.token_in.clone();
+        let input_method = PaymentMethod::Ft {
+            token_id: token_in.clone(),
+        };
+        let used = used.map_or(0, |used| used.0).min(swap.amount_in.0);
+        if swap.amount_in.0 > used {
+            self.credit_escrow(&subscription.user_id, &input_method, swap.amount_in.0 - used);
+        }
+
+        // An exact-output swap either delivers the full amount or uses none of the input
+        if used == 0 {
+            log!("Swap failed for subscription: {}", subscription_id);
+            self.schedule_retry(&subscription_id, now);
+            return false;
+        }
+
+        // The charge counts once the payouts of the swap output are confirmed
+        let funding = FundingSource {
+            payment_method: subscription.payment_method.clone(),
+            amount: charge.amount,
+        };
+        let memo = self.payment_memo(&subscription, subscription.payments_made + 1);
+        self.pay_merchant(
+            &subscription,
+            &funding,
+            charge.cycle_index,
+            memo,
+            PayoutPurpose::Charge(charge),
+            now,
+        );
+        self.record_swap_conversion(
+            &subscription_id,
+            SwapConversion {
+                amount_in: U128(used),
+                ..swap
+            },
+        );
+
+        Event::PaymentSwapped {
+            subscription_id,
+            token_in,
+            amount_in: U128(used),
+            payment_method: funding.payment_method,
+            amount_out: funding.amount,
+        }
+        .emit();
+
+        true
+    }
+}
+
+impl Contract {
+    /// Starts paying a subscription by swapping the subscriber's escrowed input token for its
+    /// payment token. The charge is recorded once the swap settles in `on_swap_payment`
+    pub(crate) fn start_swap_payment(
+        &mut self,
+        subscription: &Subscription,
+        swap_funding: &SwapFunding,
+        charge: ChargeDetails,
+        now: u64,
+    ) -> PaymentResult {
+        let subscription_id = subscription.id.clone();
+        let failure = |error: PaymentError| PaymentResult {
+            success: false,
+            subscription_id: subscription_id.clone(),
+            amount: subscription.amount,
+            timestamp: now,
+            error: Some(error),
+        };
+
+        let Some(adapter_id) = self.swap_adapter.clone() else {
+            return failure(PaymentError::SwapUnavailable);
+        };
+        let PaymentMethod::Ft { token_id: token_out } = subscription.payment_method.clone() else {
+            return failure(PaymentError::SwapUnavailable);
+        };
+        if !self.payout_accounts_registered(&subscription.merchant_id, &token_out) {
+            return failure(PaymentError::PayoutNotRegistered);
+        }
+
+        // Quote the input from oracle prices and allow for slippage on top
+        let input_method = PaymentMethod::Ft {
+            token_id: swap_funding.token_in.clone(),
+        };
+        let rates = self
+            .fresh_usd_rate(&input_method, now)
+            .zip(self.fresh_usd_rate(&subscription.payment_method, now));
+        let quote = rates.as_ref().and_then(|(rate_in, rate_out)| {
+            Self::swap_quote(subscription.amount.0, rate_in, rate_out)
+        });
+        let (Some((rate_in, rate_out)), Some(quote)) = (rates, quote) else {
+            return failure(PaymentError::PriceUnavailable);
+        };
+        let amount_in = quote + quote * swap_funding.max_slippage_bps as u128 / 10000;
+
+        if !self.debit_escrow(&subscription.user_id, &input_method, amount_in) {
+            self.schedule_retry(&subscription_id, now);
+            return failure(PaymentError::InsufficientEscrow);
+        }
+        if let Some(stored) = self.subscriptions.get_mut(&subscription_id) {
+            stored.settlement_pending = true;
+        }
+
+        let msg = serde_json::json!({
+            "token_out": token_out,
+            "amount_out": subscription.amount,
+            "receiver_id": env::current_account_id(),
+        })
+        .to_string();
+        ext_ft::ext(swap_funding.token_in.clone())
+            .with_attached_deposit(NearToken::from_yoctonear(1))
+            .with_static_gas(GAS_FOR_SWAP)
+            .ft_transfer_call(
+                adapter_id.clone(),
+                U128(amount_in),
+                Some(format!("Subscription swap: {}", subscription_id)),
+                msg,
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_SWAP_CALLBACK)
+                    .on_swap_payment(
+                        subscription_id.clone(),
+                        SwapConversion {
+                            adapter_id: adapter_id.clone(),
+                            token_in: swap_funding.token_in.clone(),
+                            amount_in: U128(amount_in),
+                            rate_in,
+                            rate_out,
+                        },
+                        charge,
+                    ),
+            );
+
+        log!(
+            "Swapping up to {} of {} for {} of {}",
+            amount_in,
+            swap_funding.token_in,
+            subscription.amount.0,
+            token_out
+        );
+
+        PaymentResult {
+            success: true,
+            subscription_id,
+            amount: subscription.amount,
+            timestamp: now,
+            error: None,
+        }
+    }
+
+    /// Adds the swap a charge was paid through to its payment record, the latest one written
+    fn record_swap_conversion(&mut self, subscription_id: &SubscriptionId, swap: SwapConversion) {
+        let rate_source = self.usd_oracle.as_ref().map(|oracle| oracle.oracle_id.clone());
+        let record = self.latest_payment_record_mut(subscription_id);
+        if let Some(record) = record {
+            record.rate_source = rate_source;
+            record.swap = Some(swap);
+        }
+    }
+
+    /// Input needed to buy `amount_out` at oracle prices:
+    /// `amount_out * out_multiplier * 10^in_decimals / (10^out_decimals * in_multiplier)`
+    fn swap_quote(amount_out: u128, rate_in: &UsdRate, rate_out: &UsdRate) -> Option<u128> {
+        let value = amount_out.checked_mul(rate_out.multiplier.0)?;
+        let scaled = if rate_in.decimals >= rate_out.decimals {
+            value.checked_mul(10u128.checked_pow((rate_in.decimals - rate_out.decimals) as u32)?)?
+        } else {
+            value / 10u128.checked_pow((rate_out.decimals - rate_in.decimals) as u32)?
+        };
+        Some(scaled / rate_in.multiplier.0)
+    }
+}
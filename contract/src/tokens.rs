@@ -0,0 +1,158 @@
+use near_sdk::{env, json_types::U128, log, near, require, AccountId, Gas, Promise, PromiseError};
+
+use crate::ft::{ext_ft, FungibleTokenMetadata};
+use crate::models::PaymentMethod;
+use crate::{Contract, ContractExt};
+
+// Gas for fetching a token's ft_metadata
+const GAS_FOR_FT_METADATA: Gas = Gas::from_tgas(5);
+// Gas for caching a token's decimals
+const GAS_FOR_FT_METADATA_CALLBACK: Gas = Gas::from_tgas(5);
+// Decimals of NEAR amounts (yoctoNEAR)
+const NEAR_DECIMALS: u8 = 24;
+
+#[near]
+impl Contract {
+    /// Allows subscriptions to be paid in a fungible token, fetching its decimals
+    pub fn add_allowed_token(&mut self, token_id: AccountId) {
+        self.require_owner();
+        self.allowed_tokens.insert(token_id.clone());
+        self.refresh_token_decimals(token_id.clone());
+        log!("Token allowed: {}", token_id);
+    }
+
+    /// Stops new subscriptions from being paid in a fungible token. Existing ones keep working
+    pub fn remove_allowed_token(&mut self, token_id: AccountId) {
+        self.require_owner();
+        self.allowed_tokens.remove(&token_id);
+        log!("Token removed: {}", token_id);
+    }
+
+    /// Gets the fungible tokens subscriptions can be paid in, ordered by account ID
+    pub fn get_allowed_tokens(&self) -> Vec<AccountId> {
+        let mut tokens: Vec<AccountId> = self.allowed_tokens.iter().cloned().collect();
+        tokens.sort();
+        tokens
+    }
+
+    /// Fetches and caches a token's decimals from its `ft_metadata`. Callable by anyone
+    pub fn refresh_token_decimals(&mut self, token_id: AccountId) -> Promise {
+        ext_ft::ext(token_id.clone())
+            .with_static_gas(GAS_FOR_FT_METADATA)
+            .ft_metadata()
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_FT_METADATA_CALLBACK)
+                    .on_ft_metadata(token_id),
+            )
+    }
+
+    /// Caches the decimals from a token's metadata
+    #[private]
+    pub fn on_ft_metadata(
+        &mut self,
+        token_id: AccountId,
+        #[callback_result] metadata: Result<FungibleTokenMetadata, PromiseError>,
+    ) -> Option<u8> {
+        let Ok(metadata) = metadata else {
+            log!("Could not fetch metadata of {}", token_id);
+            return None;
+        };
+
+        self.token_decimals
+            .insert(token_id.clone(), metadata.decimals);
+        log!("Decimals of {} set to {}", token_id, metadata.decimals);
+        Some(metadata.decimals)
+    }
+
+    /// Gets the decimals of a payment method, if known
+    pub fn get_token_decimals(&self, payment_method: PaymentMethod) -> Option<u8> {
+        match payment_method {
+            PaymentMethod::Near => Some(NEAR_DECIMALS),
+            PaymentMethod::Ft { token_id } => self.token_decimals.get(&token_id).copied(),
+            // Multi-token decimals are per token and not tracked
+            PaymentMethod::Mt { .. } => None,
+        }
+    }
+
+    /// Sets how many fractional digits amounts in a token may be given to. Amounts below one
+    /// unit at that precision, e.g. "5" meant as 5 USDC with 4 digits, are almost certainly not
+    /// in the token's smallest units and are rejected. `None` turns the check off
+    pub fn set_amount_precision(&mut self, payment_method: PaymentMethod, fraction_digits: Option<u8>) {
+        self.require_owner();
+        match fraction_digits {
+            Some(fraction_digits) => {
+                self.amount_precisions
+                    .insert(payment_method.clone(), fraction_digits);
+            }
+            None => {
+                self.amount_precisions.remove(&payment_method);
+            }
+        }
+        log!(
+            "Amount precision of {:?} set to {:?}",
+            payment_method,
+            fraction_digits
+        );
+    }
+
+    /// Gets how many fractional digits amounts in a token may be given to, if checked
+    pub fn get_amount_precision(&self, payment_method: PaymentMethod) -> Option<u8> {
+        self.amount_precisions.get(&payment_method).copied()
+    }
+
+    /// Converts a decimal amount such as "5" or "4.99" into the token's smallest units
+    pub fn to_token_units(&self, payment_method: PaymentMethod, amount: String) -> U128 {
+        let decimals = self
+            .get_token_decimals(payment_method)
+            .expect("Token decimals not loaded, call refresh_token_decimals");
+        let (whole, fraction) = amount.split_once('.').unwrap_or((&amount, ""));
+        require!(
+            fraction.len() <= decimals as usize,
+            "Amount has more decimal places than the token"
+        );
+
+        let digits = format!("{}{:0<width$}", whole, fraction, width = decimals as usize);
+        let units = digits.parse::<u128>().expect("Invalid amount");
+        U128(units)
+    }
+}
+
+impl Contract {
+    /// Whether a payment method can be used for new subscriptions. NEAR is always allowed
+    pub(crate) fn is_payment_method_allowed(&self, payment_method: &PaymentMethod) -> bool {
+        match payment_method {
+            PaymentMethod::Near => true,
+            PaymentMethod::Ft { token_id } => self.allowed_tokens.contains(token_id),
+            PaymentMethod::Mt { contract_id, .. } => {
+                self.allowed_mt_contracts.contains(contract_id)
+            }
+        }
+    }
+
+    pub(crate) fn assert_payment_method_allowed(&self, payment_method: &PaymentMethod) {
+        require!(
+            self.is_payment_method_allowed(payment_method),
+            "Token is not on the allowlist"
+        );
+    }
+
+    /// Rejects non-zero amounts too small to be meant in the token's smallest units, for tokens
+    /// with an amount precision set. Tokens whose decimals are not cached yet are not checked
+    pub(crate) fn assert_valid_token_amount(&self, payment_method: &PaymentMethod, amount: U128) {
+        if amount.0 == 0 {
+            return;
+        }
+        let Some(&fraction_digits) = self.amount_precisions.get(payment_method) else {
+            return;
+        };
+        let Some(decimals) = self.get_token_decimals(payment_method.clone()) else {
+            return;
+        };
+        let min_amount = 10u128.pow(decimals.saturating_sub(fraction_digits) as u32);
+        require!(
+            amount.0 >= min_amount,
+            "Amount is below the token's smallest sensible unit; amounts are in the token's smallest units"
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use near_sdk::{
+    env, ext_contract, json_types::U128, log, near, require, serde_json, AccountId, Gas,
+    PromiseError,
+};
+
+use crate::models::{
+    FundingRule, PaymentError, PaymentMethod, PaymentResult, Subscription, SubscriptionId,
+};
+use crate::{Contract, ContractExt};
+
+// Gas for asking a funding source to send funds, including its transfer to this contract
+const GAS_FOR_PULL_FUNDS: Gas = Gas::from_tgas(60);
+// Gas for retrying the charge once funds have been pulled
+const GAS_FOR_TOP_UP_CALLBACK: Gas = Gas::from_tgas(60);
+
+/// Interface funding sources (vaults, streaming contracts) implement to top up escrow. They send
+/// `amount` of `token_id` owned by `owner_id` to `receiver_id` with `ft_transfer_call` and `msg`,
+/// and return that transfer's promise so the charge is retried only after it lands
+#[ext_contract(ext_funding_source)]
+pub trait TopUpSource {
+    fn pull_funds(
+        &mut self,
+        owner_id: AccountId,
+        token_id: AccountId,
+        amount: U128,
+        receiver_id: AccountId,
+        msg: String,
+    );
+}
+
+#[near]
+impl Contract {
+    /// Links a funding source that escrow in a token is topped up from when it cannot cover a
+    /// charge, pulling at most `max_per_pull` each time
+    pub fn set_funding_rule(&mut self, rule: FundingRule) {
+        let user_id = env::predecessor_account_id();
+        self.assert_payment_method_allowed(&PaymentMethod::Ft {
+            token_id: rule.token_id.clone(),
+        });
+        require!(rule.max_per_pull.0 > 0, "max_per_pull must be positive");
+
+        self.funding_rules
+            .insert((user_id.clone(), rule.token_id.clone()), rule);
+        log!("Funding rule updated for {}", user_id);
+    }
+
+    /// Unlinks the caller's funding source for a token
+    pub fn remove_funding_rule(&mut self, token_id: AccountId) {
+        let user_id = env::predecessor_account_id();
+        self.funding_rules.remove(&(user_id.clone(), token_id));
+        log!("Funding rule removed for {}", user_id);
+    }
+
+    /// Gets a user's funding rule for a token
+    pub fn get_funding_rule(&self, user_id: AccountId, token_id: AccountId) -> Option<FundingRule> {
+        self.funding_rules.get(&(user_id, token_id)).cloned()
+    }
+
+    /// Retries a charge after its escrow was topped up from a funding source
+    #[private]
+    pub fn on_top_up(
+        &mut self,
+        subscription_id: SubscriptionId,
+        #[callback_result] result: Result<(), PromiseError>,
+    ) -> PaymentResult {
+        let now = env::block_timestamp() / 1000000000;
+        if result.is_err() {
+            log!("Top-up failed for subscription: {}", subscription_id);
+        }
+
+        let subscription = self
+            .subscriptions
+            .get_mut(&subscription_id)
+            .expect("Subscription not found");
+        subscription.settlement_pending = false;
+
+        self.charge_subscription(subscription_id, None, None, now)
+    }
+}
+
+impl Contract {
+    /// Asks the subscriber's funding source to cover an escrow shortfall, once per cycle, and
+    /// retries the charge when the funds arrive. Returns None when no top-up applies
+    pub(crate) fn start_top_up(
+        &mut self,
+        subscription: &Subscription,
+        now: u64,
+    ) -> Option<PaymentResult> {
+        let PaymentMethod::Ft { token_id } = &subscription.payment_method else {
+            return None;
+        };
+        if subscription.top_up_cycle == Some(subscription.cycle_index) {
+            return None;
+        }
+        let rule = self
+            .funding_rules
+            .get(&(subscription.user_id.clone(), token_id.clone()))?
+            .clone();
+
+        let balance = self
+            .escrow_balances
+            .get(&(
+                subscription.user_id.clone(),
+                subscription.payment_method.clone(),
+            ))
+            .map_or(0, |balance| balance.0);
+        let shortfall = subscription.amount.0.saturating_sub(balance);
+        if shortfall == 0 || shortfall > rule.max_per_pull.0 {
+            return None;
+        }
+
+        let stored = self.subscriptions.get_mut(&subscription.id)?;
+        stored.top_up_cycle = Some(subscription.cycle_index);
+        stored.settlement_pending = true;
+
+        let msg = serde_json::json!({ "beneficiary_id": subscription.user_id }).to_string();
+        ext_funding_source::ext(rule.source_id.clone())
+            .with_static_gas(GAS_FOR_PULL_FUNDS)
+            .pull_funds(
+                subscription.user_id.clone(),
+                token_id.clone(),
+                U128(shortfall),
+                env::current_account_id(),
+                msg,
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_TOP_UP_CALLBACK)
+                    .on_top_up(subscription.id.clone()),
+            );
+
+        log!(
+            "Pulling {} of {} from {} for subscription {}",
+            shortfall,
+            token_id,
+            rule.source_id,
+            subscription.id
+        );
+
+        Some(PaymentResult {
+            success: false,
+            subscription_id: subscription.id.clone(),
+            amount: subscription.amount,
+            timestamp: now,
+            error: Some(PaymentError::TopUpRequested),
+        })
+    }
+}
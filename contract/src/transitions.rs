@@ -0,0 +1,60 @@
+use near_sdk::require;
+
+use crate::models::{Subscription, SubscriptionId, SubscriptionStatus};
+use crate::Contract;
+
+impl Contract {
+    /// Runs `f` on a stored subscription in place, so it is written back once with only the
+    /// fields `f` changed, instead of being cloned out and reinserted. `f` must not change the
+    /// status, which only `transition_subscription` does after checking the transition is
+    /// allowed; this panics if it does
+    pub(crate) fn with_subscription_mut<R>(
+        &mut self,
+        subscription_id: &SubscriptionId,
+        f: impl FnOnce(&mut Subscription) -> R,
+    ) -> R {
+        let subscription = self
+            .subscriptions
+            .get_mut(subscription_id)
+            .expect("Subscription not found");
+        let status = subscription.status.clone();
+        let result = f(subscription);
+        require!(
+            subscription.status == status,
+            "Subscription status changes must go through transition_subscription"
+        );
+        result
+    }
+
+    /// Moves a subscription to `status`, panicking if the transition is not allowed, and
+    /// keeps the status counts and due-date index in step. Returns the status it moved from
+    pub(crate) fn transition_subscription(
+        &mut self,
+        subscription_id: &SubscriptionId,
+        status: SubscriptionStatus,
+        now: u64,
+    ) -> SubscriptionStatus {
+        let subscription = self
+            .subscriptions
+            .get_mut(subscription_id)
+            .expect("Subscription not found");
+        require!(
+            subscription.status.can_transition_to(&status),
+            format!(
+                "Cannot move subscription from {:?} to {:?}",
+                subscription.status, status
+            )
+        );
+        let previous_status = std::mem::replace(&mut subscription.status, status.clone());
+        subscription.updated_at = now;
+        let merchant_id = subscription.merchant_id.clone();
+        let next_payment_date = subscription.next_payment_date;
+        self.record_status_change(&merchant_id, &previous_status, &status);
+        if status.keeps_due_date() {
+            self.index_due_date(subscription_id, next_payment_date);
+        } else {
+            self.unindex_due_date(subscription_id);
+        }
+        previous_status
+    }
+}
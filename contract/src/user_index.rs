@@ -0,0 +1,146 @@
+use near_sdk::{log, near, require, AccountId};
+
+use crate::models::{Subscription, SubscriptionId};
+use crate::{Contract, ContractExt};
+
+#[near]
+impl Contract {
+    /// Indexes subscriptions by user that were created before the user index existed, reading
+    /// `limit` creation sequence numbers from `from`. Returns the sequence number to continue
+    /// from
+    pub fn index_user_subscriptions(&mut self, from: u64, limit: u64) -> u64 {
+        self.require_owner();
+        require!(limit > 0, "Limit must be positive");
+        let end = from.saturating_add(limit).min(self.subscription_nonce + 1);
+        let mut indexed = 0;
+        for sequence_number in from..end {
+            let Some(subscription_id) = self.subscription_sequence.get(&sequence_number).cloned()
+            else {
+                continue;
+            };
+            let Some(user_id) = self
+                .subscriptions
+                .get(&subscription_id)
+                .map(|subscription| subscription.user_id.clone())
+            else {
+                continue;
+            };
+            self.index_user_subscription(&user_id, &subscription_id);
+            indexed += 1;
+        }
+        log!("Indexed {} subscriptions by user", indexed);
+        end
+    }
+}
+
+impl Contract {
+    /// Adds a subscription to its user's subscriptions, unless it is already there
+    pub(crate) fn index_user_subscription(
+        &mut self,
+        user_id: &AccountId,
+        subscription_id: &SubscriptionId,
+    ) {
+        let mut subscription_ids = self.user_subscription_ids(user_id);
+        if subscription_ids.contains(subscription_id) {
+            return;
+        }
+        subscription_ids.push(subscription_id.clone());
+        self.subscriptions_by_user
+            .insert(user_id.clone(), subscription_ids);
+    }
+
+    /// Removes a subscription from its user's subscriptions
+    pub(crate) fn unindex_user_subscription(
+        &mut self,
+        user_id: &AccountId,
+        subscription_id: &SubscriptionId,
+    ) {
+        let Some(subscription_ids) = self.subscriptions_by_user.get_mut(user_id) else {
+            return;
+        };
+        subscription_ids.retain(|indexed| indexed != subscription_id);
+        if subscription_ids.is_empty() {
+            self.subscriptions_by_user.remove(user_id);
+        }
+    }
+
+    /// Gets the IDs of a user's subscriptions, in creation order
+    pub(crate) fn user_subscription_ids(&self, user_id: &AccountId) -> Vec<SubscriptionId> {
+        self.subscriptions_by_user
+            .get(user_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Gets a user's subscriptions, in creation order
+    pub(crate) fn user_subscriptions(
+        &self,
+        user_id: &AccountId,
+    ) -> impl Iterator<Item = Subscription> + '_ {
+        self.subscriptions_by_user
+            .get(user_id)
+            .into_iter()
+            .flatten()
+            .filter_map(|subscription_id| self.subscriptions.get(subscription_id))
+            .map(Subscription::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::{json_types::U128, testing_env, AccountId};
+
+    use crate::models::{
+        PaymentMethod, Subscription, SubscriptionFrequency, SubscriptionStatusV0, SubscriptionV0,
+    };
+    use crate::Contract;
+
+    /// Stores a new NEAR subscription of `user_id` to `accounts(3)`, returning its ID
+    fn add_subscription(contract: &mut Contract, user_id: AccountId) -> String {
+        let subscription_id = contract.next_subscription_id(&user_id, &accounts(3));
+        let subscription: Subscription = SubscriptionV0 {
+            id: subscription_id.clone(),
+            user_id,
+            merchant_id: accounts(3),
+            amount: U128(10000),
+            frequency: SubscriptionFrequency::Monthly,
+            next_payment_date: 0,
+            status: SubscriptionStatusV0::Active,
+            created_at: 0,
+            updated_at: 0,
+            payment_method: PaymentMethod::Near,
+            max_payments: None,
+            payments_made: 0,
+            end_date: None,
+        }
+        .into();
+        contract
+            .subscriptions
+            .insert(subscription_id.clone(), subscription.into());
+        subscription_id
+    }
+
+    #[test]
+    fn pages_user_subscriptions_from_index() {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(accounts(0))
+            .predecessor_account_id(accounts(0))
+            .build());
+        let mut contract = Contract::new(accounts(0));
+        let first = add_subscription(&mut contract, accounts(1));
+        add_subscription(&mut contract, accounts(2));
+        let second = add_subscription(&mut contract, accounts(1));
+
+        let page = contract.get_user_subscriptions(accounts(1), None, Some(1));
+        assert_eq!(page.subscriptions.len(), 1);
+        assert_eq!(page.subscriptions[0].id, first);
+        assert!(page.has_more);
+
+        let page = contract.get_user_subscriptions(accounts(1), page.next_cursor, Some(1));
+        assert_eq!(page.subscriptions.len(), 1);
+        assert_eq!(page.subscriptions[0].id, second);
+        assert!(!page.has_more);
+        assert_eq!(page.next_cursor, None);
+    }
+}
@@ -2,3 +2,12 @@ pub fn vec_to_fixed<T, const N: usize>(v: Vec<T>) -> [T; N] {
     v.try_into()
         .unwrap_or_else(|v: Vec<T>| panic!("Expected a Vec of length {} but it was {}", N, v.len()))
 }
+
+/// Returns true if an optional bound is no looser than `limit`, where `None` means unbounded
+pub fn within_limit<T: Ord>(value: Option<T>, limit: Option<T>) -> bool {
+    match (value, limit) {
+        (_, None) => true,
+        (None, Some(_)) => false,
+        (Some(value), Some(limit)) => value <= limit,
+    }
+}
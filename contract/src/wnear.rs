@@ -0,0 +1,178 @@
+use near_sdk::{
+    env, ext_contract, json_types::U128, log, near, AccountId, Gas, NearToken, PromiseError,
+};
+
+use crate::models::{PaymentError, PaymentMethod, PaymentResult, Subscription, SubscriptionId};
+use crate::{Contract, ContractExt};
+
+// Gas for wrapping or unwrapping NEAR on the wNEAR contract
+const GAS_FOR_WNEAR: Gas = Gas::from_tgas(10);
+// Gas for crediting escrow after wrapping or unwrapping, including a retried charge
+const GAS_FOR_WNEAR_CALLBACK: Gas = Gas::from_tgas(60);
+
+/// wNEAR (wrap.near) interface
+#[ext_contract(ext_wnear)]
+pub trait WrappedNear {
+    fn near_deposit(&mut self);
+    fn near_withdraw(&mut self, amount: U128);
+}
+
+#[near]
+impl Contract {
+    /// Sets the wNEAR contract. Subscriptions paid in it can draw on NEAR escrow, wrapped when a
+    /// charge needs it, and wNEAR deposits can be unwrapped into NEAR escrow. The contract must
+    /// be registered on it
+    pub fn set_wnear_contract(&mut self, wnear_id: Option<AccountId>) {
+        self.require_owner();
+        self.wnear_contract = wnear_id;
+        log!("wNEAR contract updated");
+    }
+
+    /// Gets the wNEAR contract
+    pub fn get_wnear_contract(&self) -> Option<AccountId> {
+        self.wnear_contract.clone()
+    }
+
+    /// Credits wrapped NEAR to a user's wNEAR escrow, or returns it to their NEAR escrow if
+    /// wrapping failed, then retries the charge it was wrapped for
+    #[private]
+    pub fn on_near_wrapped(
+        &mut self,
+        user_id: AccountId,
+        amount: U128,
+        subscription_id: SubscriptionId,
+        #[callback_result] result: Result<(), PromiseError>,
+    ) -> PaymentResult {
+        let now = env::block_timestamp() / 1000000000;
+        let wnear_id = self
+            .wnear_contract
+            .clone()
+            .expect("No wNEAR contract configured");
+        let payment_method = match result {
+            Ok(()) => PaymentMethod::Ft { token_id: wnear_id },
+            Err(_) => {
+                log!("Wrapping NEAR failed for {}", user_id);
+                PaymentMethod::Near
+            }
+        };
+        self.credit_escrow(&user_id, &payment_method, amount.0);
+
+        let subscription = self
+            .subscriptions
+            .get_mut(&subscription_id)
+            .expect("Subscription not found");
+        subscription.settlement_pending = false;
+
+        self.charge_subscription(subscription_id, None, None, now)
+    }
+
+    /// Credits unwrapped NEAR to a user's NEAR escrow, or keeps the deposit as wNEAR escrow if
+    /// unwrapping failed
+    #[private]
+    pub fn on_wnear_unwrapped(
+        &mut self,
+        user_id: AccountId,
+        wnear_id: AccountId,
+        amount: U128,
+        #[callback_result] result: Result<(), PromiseError>,
+    ) -> bool {
+        let unwrapped = result.is_ok();
+        let payment_method = if unwrapped {
+            PaymentMethod::Near
+        } else {
+            log!("Unwrapping wNEAR failed for {}", user_id);
+            PaymentMethod::Ft { token_id: wnear_id }
+        };
+        self.credit_escrow(&user_id, &payment_method, amount.0);
+
+        unwrapped
+    }
+}
+
+impl Contract {
+    /// Whether a payment method is the configured wNEAR token
+    pub(crate) fn is_wnear(&self, payment_method: &PaymentMethod) -> bool {
+        matches!(
+            payment_method,
+            PaymentMethod::Ft { token_id } if Some(token_id) == self.wnear_contract.as_ref()
+        )
+    }
+
+    /// Unwraps a wNEAR deposit held by the contract into the beneficiary's NEAR escrow
+    pub(crate) fn unwrap_deposit(&mut self, beneficiary_id: AccountId, amount: U128) {
+        let wnear_id = self
+            .wnear_contract
+            .clone()
+            .expect("No wNEAR contract configured");
+        ext_wnear::ext(wnear_id.clone())
+            .with_attached_deposit(NearToken::from_yoctonear(1))
+            .with_static_gas(GAS_FOR_WNEAR)
+            .near_withdraw(amount)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_WNEAR_CALLBACK)
+                    .on_wnear_unwrapped(beneficiary_id, wnear_id, amount),
+            );
+    }
+
+    /// Wraps enough of a subscriber's NEAR escrow to cover a wNEAR charge their wNEAR escrow
+    /// falls short of, once per cycle, and retries the charge once wrapped. Returns None when
+    /// the subscription is not paid in wNEAR or NEAR escrow cannot cover the shortfall
+    pub(crate) fn start_wrap_for_charge(
+        &mut self,
+        subscription: &Subscription,
+        now: u64,
+    ) -> Option<PaymentResult> {
+        if !self.is_wnear(&subscription.payment_method)
+            || subscription.top_up_cycle == Some(subscription.cycle_index)
+        {
+            return None;
+        }
+        let wnear_id = self.wnear_contract.clone()?;
+
+        let balance = self
+            .get_escrow_balance(
+                subscription.user_id.clone(),
+                subscription.payment_method.clone(),
+            )
+            .0;
+        let shortfall = subscription.funding().amount.0.saturating_sub(balance);
+        if shortfall == 0
+            || !self.debit_escrow(&subscription.user_id, &PaymentMethod::Near, shortfall)
+        {
+            return None;
+        }
+
+        let stored = self.subscriptions.get_mut(&subscription.id)?;
+        stored.top_up_cycle = Some(subscription.cycle_index);
+        stored.settlement_pending = true;
+
+        ext_wnear::ext(wnear_id)
+            .with_attached_deposit(NearToken::from_yoctonear(shortfall))
+            .with_static_gas(GAS_FOR_WNEAR)
+            .near_deposit()
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(GAS_FOR_WNEAR_CALLBACK)
+                    .on_near_wrapped(
+                        subscription.user_id.clone(),
+                        U128(shortfall),
+                        subscription.id.clone(),
+                    ),
+            );
+
+        log!(
+            "Wrapping {} NEAR from escrow for subscription {}",
+            shortfall,
+            subscription.id
+        );
+
+        Some(PaymentResult {
+            success: false,
+            subscription_id: subscription.id.clone(),
+            amount: subscription.amount,
+            timestamp: now,
+            error: Some(PaymentError::TopUpRequested),
+        })
+    }
+}
@@ -0,0 +1,75 @@
+use near_sdk::{env, log, near, require, AccountId};
+
+use crate::events::Event;
+use crate::models::{Worker, WorkerInfo};
+use crate::{Contract, ContractExt};
+
+#[near]
+impl Contract {
+    /// Removes a worker, such as a decommissioned or compromised TEE. Its processing leases
+    /// lapse straight away; fees it has earned can still be claimed, and its bond withdrawn
+    /// after the cooldown unless slashed first
+    pub fn remove_worker(&mut self, account_id: AccountId) {
+        self.require_owner();
+        self.unregister_worker(account_id);
+    }
+
+    /// Removes the calling worker, for TEEs being shut down, starting the cooldown on its bond
+    pub fn deregister_worker(&mut self) {
+        self.unregister_worker(env::predecessor_account_id());
+    }
+
+    /// Pages over registered workers ordered by account ID, starting after `from`, with how
+    /// long ago each was attested and last did work
+    pub fn get_workers(&self, from: Option<AccountId>, limit: Option<u64>) -> Vec<WorkerInfo> {
+        let now = env::block_timestamp() / 1000000000;
+        let mut account_ids: Vec<&AccountId> = self
+            .worker_by_account_id
+            .keys()
+            .filter(|account_id| from.as_ref().is_none_or(|from| *account_id > from))
+            .collect();
+        account_ids.sort();
+
+        account_ids
+            .into_iter()
+            .take(limit.unwrap_or(u64::MAX) as usize)
+            .map(|account_id| {
+                let worker: Worker = self.get_worker(account_id.clone());
+                WorkerInfo {
+                    account_id: account_id.clone(),
+                    attestation_age: (worker.verified_at > 0)
+                        .then(|| now.saturating_sub(worker.verified_at)),
+                    last_seen_at: self.worker_last_seen.get(account_id).copied(),
+                    codehash: worker.codehash,
+                    checksum: worker.checksum,
+                    tee_type: worker.tee_type,
+                }
+            })
+            .collect()
+    }
+}
+
+impl Contract {
+    fn unregister_worker(&mut self, worker_id: AccountId) {
+        require!(
+            self.worker_by_account_id.remove(&worker_id).is_some(),
+            "Worker not found"
+        );
+        self.attestation_nonces.remove(&worker_id);
+        self.worker_last_seen.remove(&worker_id);
+        self.unlock_worker_bond(&worker_id, env::block_timestamp() / 1000000000);
+        self.assign_shards();
+
+        Event::WorkerRemoved {
+            worker_id: worker_id.clone(),
+            removed_by: env::predecessor_account_id(),
+        }
+        .emit();
+        log!("Worker removed: {}", worker_id);
+    }
+
+    /// Notes that a worker claimed or processed subscriptions
+    pub(crate) fn record_worker_activity(&mut self, worker_id: &AccountId, now: u64) {
+        self.worker_last_seen.insert(worker_id.clone(), now);
+    }
+}
@@ -4,7 +4,6 @@ import {
   setKey,
   getAccount,
   contractCall,
-  contractView,
 } from "@neardefi/shade-agent-js";
 const { KeyPair } = nearAPI;
 
@@ -23,9 +22,35 @@ interface Subscription {
   [key: string]: any;
 }
 
+/**
+ * Why the contract did not make a payment, as returned in `PaymentResult.error`
+ */
+type PaymentError =
+  | "NotDue"
+  | "NotActive"
+  | "InsufficientEscrow"
+  | "MaxPaymentsReached"
+  | "EndDateReached"
+  | "UnauthorizedKey"
+  | "TransferFailed"
+  | "SubscriptionNotFound"
+  | "CycleAlreadyCharged"
+  | "ExceedsMaxAmountPerCharge"
+  | "ExceedsMaxTotalSpend"
+  | "ExceedsMerchantLimit"
+  | "PayoutNotRegistered"
+  | "PriceUnavailable"
+  | "SettlementPending"
+  | "SwapUnavailable"
+  | "TopUpRequested"
+  | "BelowMinimumCharge"
+  | "Streaming"
+  | "Leased"
+  | "RateLimited";
+
 interface PaymentResult {
   success: boolean;
-  error?: string;
+  error?: PaymentError | null;
 }
 
 /**
@@ -238,11 +263,11 @@ export class ShadeAgent {
    */
   async checkDueSubscriptions(limit = 10): Promise<void> {
     try {
-      // Get due subscriptions from the contract
-      const dueSubscriptions = (await contractView({
+      // Lease due subscriptions from the contract so other workers skip them
+      const dueSubscriptions = (await contractCall({
         accountId: getAccount(),
-        methodName: "get_due_subscriptions",
-        args: { limit },
+        methodName: "claim_due_batch",
+        args: { limit, lease_seconds: 300 },
       })) as Subscription[];
 
       console.log(`Found ${dueSubscriptions.length} due subscriptions`);
@@ -313,43 +338,58 @@ export class ShadeAgent {
         );
         this.processingQueue.delete(subscriptionId);
       } else {
-        const errorMessage = result?.error || "Unknown error";
+        const error = result?.error;
         console.error(
-          `Payment failed for subscription ${subscriptionId}: ${errorMessage}`,
+          `Payment failed for subscription ${subscriptionId}: ${error ?? "Unknown error"}`,
         );
 
         // Handle specific error cases
-        if (errorMessage.includes("Subscription is not active")) {
-          // Subscription is no longer active, remove from queue
-          console.log(
-            `Subscription ${subscriptionId} is not active, removing from queue`,
-          );
-          this.processingQueue.delete(subscriptionId);
-        } else if (errorMessage.includes("Payment is not due yet")) {
-          // Payment is not due yet, remove from queue
-          console.log(
-            `Payment for subscription ${subscriptionId} is not due yet, removing from queue`,
-          );
-          this.processingQueue.delete(subscriptionId);
-        } else if (
-          errorMessage.includes("Maximum number of payments reached") ||
-          errorMessage.includes("Subscription end date reached")
-        ) {
-          // Subscription has ended, remove from queue
-          console.log(
-            `Subscription ${subscriptionId} has ended, removing from queue`,
-          );
-          this.processingQueue.delete(subscriptionId);
-        } else if (errorMessage.includes("Key is not authorized")) {
-          // Key is not authorized, try to register it again
-          console.log(
-            `Key is not authorized for subscription ${subscriptionId}, trying to register again`,
-          );
-          await this.registerSubscriptionKey(subscriptionId, keyPair.publicKey);
-          this.retryPayment(subscriptionId, retryCount);
-        } else {
-          // Other errors, retry if possible
-          this.retryPayment(subscriptionId, retryCount);
+        switch (error) {
+          case "NotActive":
+          case "SubscriptionNotFound":
+            // Subscription is no longer active, remove from queue
+            console.log(
+              `Subscription ${subscriptionId} is not active, removing from queue`,
+            );
+            this.processingQueue.delete(subscriptionId);
+            break;
+          case "NotDue":
+          case "CycleAlreadyCharged":
+          case "SettlementPending":
+          case "TopUpRequested":
+          case "Leased":
+            // Payment is not due yet or is already being handled, remove from queue
+            console.log(
+              `Payment for subscription ${subscriptionId} is not due or already handled, removing from queue`,
+            );
+            this.processingQueue.delete(subscriptionId);
+            break;
+          case "MaxPaymentsReached":
+          case "EndDateReached":
+            // Subscription has ended, remove from queue
+            console.log(
+              `Subscription ${subscriptionId} has ended, removing from queue`,
+            );
+            this.processingQueue.delete(subscriptionId);
+            break;
+          case "Streaming":
+            // Streams are claimed rather than charged per cycle, remove from queue
+            console.log(
+              `Subscription ${subscriptionId} is paid as a stream, removing from queue`,
+            );
+            this.processingQueue.delete(subscriptionId);
+            break;
+          case "UnauthorizedKey":
+            // Key is not authorized, try to register it again
+            console.log(
+              `Key is not authorized for subscription ${subscriptionId}, trying to register again`,
+            );
+            await this.registerSubscriptionKey(subscriptionId, keyPair.publicKey);
+            this.retryPayment(subscriptionId, retryCount);
+            break;
+          default:
+            // Other errors, such as TransferFailed or InsufficientEscrow, retry if possible
+            this.retryPayment(subscriptionId, retryCount);
         }
       }
     } catch (error) {
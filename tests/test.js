@@ -1,10 +1,15 @@
 import test from "ava";
 import fs from "fs";
+import crypto from "crypto";
 import * as dotenv from "dotenv";
 dotenv.config({ path: "./.env.development.local" });
 
-const { NEXT_PUBLIC_accountId: accountId, NEXT_PUBLIC_contractId: contractId } =
-  process.env;
+const {
+  NEXT_PUBLIC_accountId: accountId,
+  NEXT_PUBLIC_contractId: contractId,
+  DSTACK_SIMULATOR_ENDPOINT: tappdEndpoint,
+  FRESH_COLLATERAL_ID: freshCollateralId,
+} = process.env;
 import * as nearAPI from "near-api-js";
 
 import {
@@ -66,16 +71,131 @@ test("init contract", async (t) => {
   t.pass();
 });
 
-test("call register_worker with quote", async (t) => {
+// the sample collateral is only fresh until the earlier nextUpdate of its TCB info and QE identity
+const collateralExpiresAt = Math.floor(
+  Math.min(
+    Date.parse(JSON.parse(collateral.tcb_info).nextUpdate),
+    Date.parse(JSON.parse(collateral.qe_identity).nextUpdate),
+  ) / 1000,
+);
+
+test("should fail: pin expired sample collateral", async (t) => {
+  // the sample collateral's nextUpdate has passed, so it cannot be pinned
+  try {
+    await contractCall({
+      contractId,
+      methodName: "set_collateral",
+      args: {
+        id: "sample",
+        collateral_json: JSON.stringify(collateral),
+        expires_at: collateralExpiresAt,
+      },
+    });
+    t.fail("expired collateral was pinned");
+  } catch (e) {
+    t.true(
+      /Collateral must expire in the future/gim.test(JSON.stringify(e)),
+      "collateral rejected as expired",
+    );
+  }
+});
+
+// the quote's report_data must commit to the registering worker and carry its nonce:
+// sha256("{account_id}\n{public_key}\n{codehash}") followed by the 32 byte nonce
+const codehash = "bar";
+let nonce;
+let bond;
+
+const workerPublicKey = () => {
+  const publicKey = getDevAccountKeyPair().getPublicKey();
+  // the contract encodes the signer key with its curve type byte, as subscription keys are
+  return nearAPI.utils.serialize.base_encode(
+    Buffer.concat([Buffer.from([publicKey.keyType]), Buffer.from(publicKey.data)]),
+  );
+};
+
+const reportData = () =>
+  crypto
+    .createHash("sha256")
+    .update(`${accountId}\n${workerPublicKey()}\n${codehash}`)
+    .digest("hex") + nonce;
+
+test("call get_bond_policy", async (t) => {
+  const policy = await contractView({
+    contractId,
+    methodName: "get_bond_policy",
+    args: {},
+  });
+
+  console.log("bond policy", policy);
+  bond = policy.required;
+
+  t.pass();
+});
+
+test("call request_attestation_nonce", async (t) => {
+  const res = await contractCall({
+    contractId,
+    methodName: "request_attestation_nonce",
+    args: {},
+  });
+
+  console.log("attestation nonce", res);
+  nonce = res.nonce;
+
+  t.is(nonce.length, 64);
+});
+
+test("should fail: call register_worker with sample quote", async (t) => {
+  // the expired sample collateral was not pinned, so registering panics and the bond is
+  // refunded
+  try {
+    await contractCall({
+      contractId,
+      methodName: "register_worker",
+      args: {
+        quote_hex,
+        collateral_id: "sample",
+        checksum: "foo",
+        codehash,
+      },
+      attachedDeposit: bond,
+    });
+    t.fail("quote was accepted without fresh collateral");
+  } catch (e) {
+    t.true(
+      /Collateral not found/gim.test(JSON.stringify(e)),
+      "quote rejected without fresh collateral",
+    );
+  }
+});
+
+test("call register_worker with bound quote", async (t) => {
+  if (!tappdEndpoint || !freshCollateralId) {
+    t.log("set DSTACK_SIMULATOR_ENDPOINT to get a bound quote (yarn tappd:run)");
+    t.log("and FRESH_COLLATERAL_ID to unexpired collateral pinned with set_collateral");
+    t.pass();
+    return;
+  }
+
+  // ask the TEE for a quote over the worker's report_data as is, without hashing it
+  const quoteRes = await fetch(`${tappdEndpoint}/prpc/Tappd.TdxQuote?json`, {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify({ report_data: reportData(), hash_algorithm: "raw" }),
+  });
+  const { quote } = await quoteRes.json();
+
   const res = await contractCall({
     contractId,
     methodName: "register_worker",
     args: {
-      quote_hex,
-      collateral: JSON.stringify(collateral),
+      quote_hex: quote,
+      collateral_id: freshCollateralId,
       checksum: "foo",
-      codehash: "bar",
+      codehash,
     },
+    attachedDeposit: bond,
   });
 
   console.log("was worker registered?", res);
@@ -84,12 +204,17 @@ test("call register_worker with quote", async (t) => {
 });
 
 test("call is_worker_verified", async (t) => {
+  if (!tappdEndpoint || !freshCollateralId) {
+    t.pass();
+    return;
+  }
+
   // will throw if the worker was not registered
   await contractCall({
     contractId,
     methodName: "is_verified_by_codehash",
     args: {
-      codehash: "bar",
+      codehash,
     },
   });
 